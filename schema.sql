@@ -27,7 +27,10 @@ CREATE TABLE IF NOT EXISTS Batches (
     settings JSON NOT NULL,  -- Generation settings stored as JSON
     user TEXT NOT NULL,  -- User who generated the batch
     gallery TEXT NOT NULL,  -- URL for the image gallery
-    FOREIGN KEY (user) REFERENCES Users(user)
+    init_image TEXT,  -- Picture the batch started from (--init), if any
+    parent TEXT,  -- Batch this one was made from, e.g. by upscaling, if any
+    FOREIGN KEY (user) REFERENCES Users(user),
+    FOREIGN KEY (parent) REFERENCES Batches(uuid)
 );
 
 CREATE INDEX IF NOT EXISTS Batches_user ON Batches(user);
@@ -37,6 +40,9 @@ CREATE TABLE IF NOT EXISTS Images (
     batch_index INTEGER,
     url TEXT NOT NULL,  -- URL for the finished picture
     uuid TEXT,  -- Batch to which the image belongs
+    prompt TEXT,  -- Prompts after wildcard expansion; these may differ per image
+    style_prompt TEXT,
+    negative_prompt TEXT,
     FOREIGN KEY (uuid) REFERENCES Batches(uuid)
 );
 
@@ -52,6 +58,41 @@ CREATE TABLE IF NOT EXISTS Votes (
 
 CREATE INDEX IF NOT EXISTS Votes_user ON Votes(user);
 
+CREATE TABLE IF NOT EXISTS Presets (
+    user TEXT NOT NULL,  -- Owner, in the same format as Users.user
+    name TEXT NOT NULL,  -- Lowercase; used as $name in prompts
+    text TEXT NOT NULL,  -- Command-line fragment the name expands to
+    shared INTEGER NOT NULL DEFAULT 0,  -- 1 if other users may copy it
+    FOREIGN KEY (user) REFERENCES Users(user),
+    PRIMARY KEY (user, name)
+);
+
+CREATE TABLE IF NOT EXISTS Attempts (
+    model TEXT NOT NULL,
+    workflow TEXT,  -- Workflow file it ran
+    width INTEGER NOT NULL,
+    height INTEGER NOT NULL,
+    steps INTEGER,
+    pictures INTEGER NOT NULL,  -- Pictures in the whole request
+    server TEXT,  -- host:port of the ComfyUI server
+    queue_seconds REAL,  -- Time spent waiting for a server
+    backend_seconds REAL NOT NULL,  -- GPU time if it finished, or how long it ran if it didn't
+    retries INTEGER NOT NULL DEFAULT 0,  -- Batches sent to the server again after failing
+    outcome TEXT NOT NULL,  -- 'ok', 'cancelled', or the kind of error; see telemetry::Outcome
+    finished INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))  -- Unix time
+);
+
+CREATE INDEX IF NOT EXISTS Attempts_finished ON Attempts(finished);
+
+-- Requests that are queued or generating. Rows go once they've completed, failed or been
+-- cancelled; AUTOINCREMENT keeps their ids from being handed out again.
+CREATE TABLE IF NOT EXISTS Queue (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,  -- Job id, as used by !cancel
+    request JSON NOT NULL,  -- The parsed request
+    reply_to JSON NOT NULL,  -- Where the results go; see generator::ReplyTo
+    queued INTEGER NOT NULL DEFAULT (strftime('%s', 'now'))  -- Unix time
+);
+
 CREATE TABLE IF NOT EXISTS BotPaused (
   reason TEXT
 );
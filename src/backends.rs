@@ -0,0 +1,221 @@
+// The pool of ComfyUI servers that the generator spreads its work over.
+//
+// Each server runs up to its concurrency limit of jobs at once, and may be limited to a few
+// models. Loading a checkpoint takes a server a while, so jobs prefer a server that last ran
+// the same one. Servers that stop answering are taken out of the rotation until a health
+// check finds them again.
+
+use std::time::{Duration, Instant};
+
+use crate::{
+    config::{BotConfig, ComfyServer},
+    generator::ParsedRequest,
+    scheduler::FairShare,
+};
+
+/// How often to check on the servers.
+pub const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Clone, Debug)]
+struct Server {
+    config: ComfyServer,
+    active: usize,
+    healthy: bool,
+    /// The checkpoint it loaded last.
+    checkpoint: Option<String>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Pool {
+    servers: Vec<Server>,
+}
+
+impl Pool {
+    /// Brings the list of servers in line with the config, remembering what we knew about
+    /// the ones that are still there. New servers are assumed to be up.
+    pub fn sync(&mut self, configs: &[ComfyServer]) {
+        let mut old = std::mem::take(&mut self.servers);
+        for config in configs {
+            let server = match old
+                .iter()
+                .position(|s| s.config.address() == config.address())
+            {
+                Some(i) => {
+                    let mut server = old.remove(i);
+                    server.config = config.clone();
+                    server
+                }
+                None => Server {
+                    config: config.clone(),
+                    active: 0,
+                    healthy: true,
+                    checkpoint: None,
+                },
+            };
+            self.servers.push(server);
+        }
+    }
+
+    /// Records a health check. Returns whether anything changed.
+    pub fn set_healthy(&mut self, address: &str, healthy: bool) -> bool {
+        let Some(server) = self.find(address) else {
+            return false;
+        };
+        let changed = server.healthy != healthy;
+        server.healthy = healthy;
+        changed
+    }
+
+    /// Whether any server, up or down, is allowed to run the model.
+    pub fn can_run(&self, model_name: &str) -> bool {
+        self.servers.iter().any(|s| s.config.allows(model_name))
+    }
+
+    /// Picks the next job to start from the queue, and the server to run it on. None if no
+    /// server has room for any of them.
+    ///
+    /// The job is whichever the fair share says goes first, counting the cost of loading its
+    /// checkpoint on servers that have another one loaded. Of the servers that could run it,
+    /// the least busy wins. Earlier jobs win ties.
+    pub fn pick<T>(
+        &self,
+        config: &BotConfig,
+        fair: &FairShare,
+        queue: &[(ParsedRequest, T)],
+        now: Instant,
+    ) -> Option<(usize, ComfyServer)> {
+        let mut best: Option<((f64, usize), usize, usize)> = None;
+        for (i, (request, _)) in queue.iter().enumerate() {
+            let checkpoint = config.models.get(&request.model_name).map(|m| &m.baseline);
+            for (s, server) in self.servers.iter().enumerate() {
+                if !server.healthy
+                    || server.active >= server.config.concurrency
+                    || !server.config.allows(&request.model_name)
+                {
+                    continue;
+                }
+                // A server that hasn't loaded anything yet has to load something either way.
+                let switching =
+                    server.checkpoint.is_some() && server.checkpoint.as_ref() != checkpoint;
+                let key = (
+                    fair.priority(config, request, switching, now),
+                    server.active,
+                );
+                if best.is_none_or(|(b, _, _)| key < b) {
+                    best = Some((key, i, s));
+                }
+            }
+        }
+        best.map(|(_, i, s)| (i, self.servers[s].config.clone()))
+    }
+
+    /// Marks a job as started on the server.
+    pub fn start(&mut self, config: &BotConfig, address: &str, request: &ParsedRequest) {
+        let checkpoint = config
+            .models
+            .get(&request.model_name)
+            .map(|m| m.baseline.clone());
+        if let Some(server) = self.find(address) {
+            server.active += 1;
+            server.checkpoint = checkpoint;
+        }
+    }
+
+    /// Marks a job as finished on the server, one way or another.
+    pub fn finish(&mut self, address: &str) {
+        if let Some(server) = self.find(address) {
+            server.active = server.active.saturating_sub(1);
+        }
+    }
+
+    fn find(&mut self, address: &str) -> Option<&mut Server> {
+        self.servers
+            .iter_mut()
+            .find(|s| s.config.address() == address)
+    }
+}
+
+/// Whether the server is answering.
+pub async fn is_up(server: &ComfyServer) -> bool {
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(5))
+        .build();
+    let Ok(client) = client else {
+        return false;
+    };
+    client
+        .get(format!("http://{}/system_stats", server.address()))
+        .send()
+        .await
+        .and_then(|r| r.error_for_status())
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::testconfig, scheduler::test_job};
+
+    fn server(port: u16, concurrency: usize, models: &[&str]) -> ComfyServer {
+        ComfyServer {
+            host: "localhost".to_string(),
+            port,
+            concurrency,
+            models: models.iter().map(|m| m.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn test_pick() {
+        let config = testconfig();
+        let fair = FairShare::default();
+        let now = Instant::now();
+        let mut pool = Pool::default();
+        pool.sync(&[server(1, 1, &[]), server(2, 2, &["foo"])]);
+        assert_eq!(pool.pick::<()>(&config, &fair, &[], now), None);
+
+        // Server 2 can't run flux, so that goes to 1.
+        let queue = vec![test_job("flux", "a", 1)];
+        let (i, picked) = pool.pick(&config, &fair, &queue, now).unwrap();
+        assert_eq!((i, picked.port), (0, 1));
+        pool.start(&config, &picked.address(), &queue[0].0);
+
+        // Server 1 is full now, and nothing else can run flux.
+        assert_eq!(pool.pick(&config, &fair, &queue, now), None);
+        let queue = vec![test_job("flux", "a", 1), test_job("foo", "b", 1)];
+        let (i, picked) = pool.pick(&config, &fair, &queue, now).unwrap();
+        assert_eq!((i, picked.port), (1, 2));
+        pool.start(&config, &picked.address(), &queue[1].0);
+        pool.finish(&picked.address());
+
+        // Once it's done, server 1 is preferred for flux, since it has it loaded, and server 2
+        // has foo...
+        pool.finish("localhost:1");
+        pool.sync(&[server(1, 1, &[]), server(2, 2, &[])]);
+        let queue = vec![test_job("flux", "a", 1)];
+        assert_eq!(pool.pick(&config, &fair, &queue, now).unwrap().1.port, 1);
+        // ...unless it's down.
+        assert!(pool.set_healthy("localhost:1", false));
+        assert!(!pool.set_healthy("localhost:1", false));
+        assert_eq!(pool.pick(&config, &fair, &queue, now).unwrap().1.port, 2);
+        // Health checks only count for servers we know.
+        assert!(!pool.set_healthy("localhost:3", false));
+    }
+
+    #[test]
+    fn test_sync() {
+        let config = testconfig();
+        let fair = FairShare::default();
+        let now = Instant::now();
+        let mut pool = Pool::default();
+        pool.sync(&[server(1, 1, &[])]);
+        pool.start(&config, "localhost:1", &test_job("foo", "a", 1).0);
+        // Still busy after a config change.
+        pool.sync(&[server(2, 1, &[]), server(1, 1, &[])]);
+        let queue = vec![test_job("foo", "a", 1)];
+        assert_eq!(pool.pick(&config, &fair, &queue, now).unwrap().1.port, 2);
+        assert!(pool.can_run("flux"));
+        pool.sync(&[server(2, 1, &["foo"])]);
+        assert!(!pool.can_run("flux"));
+    }
+}
@@ -13,13 +13,15 @@ use lazy_static::lazy_static;
 use log::{error, info};
 use notify::{EventHandler, Watcher};
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
+use tokio::sync::{watch, RwLock};
+
+use crate::{validation, weighting::PromptSyntax};
 
 lazy_static! {
     static ref CONFIG_PATH: &'static Path = Path::new("config.toml");
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct BotConfig {
     pub owner: String,
     pub command_prefix: String,
@@ -29,6 +31,14 @@ pub struct BotConfig {
     pub irc: Vec<IrcConfig>,
     pub aliases: HashMap<String, String>,
     pub models: HashMap<String, BotModelConfig>,
+    /// Directory of wildcard files, for __name__ in prompts.
+    #[serde(default)]
+    pub wildcard_dir: Option<String>,
+    /// LoRAs that prompts can ask for with <lora:name:weight>.
+    #[serde(default)]
+    pub loras: HashMap<String, LoraConfig>,
+    #[serde(default)]
+    pub scheduler: SchedulerConfig,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -39,6 +49,53 @@ pub struct BotBackend {
     pub webhost: String,
     pub webdir: String,
     pub webdir_internal: String,
+    /// ComfyUI servers to spread the work over. If there are none, it's just host and port.
+    #[serde(default)]
+    pub servers: Vec<ComfyServer>,
+}
+
+impl BotBackend {
+    /// The ComfyUI servers to use.
+    pub fn servers(&self) -> Vec<ComfyServer> {
+        if self.servers.is_empty() {
+            vec![ComfyServer {
+                host: self.host.clone(),
+                port: self.port,
+                concurrency: 1,
+                models: vec![],
+            }]
+        } else {
+            self.servers.clone()
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ComfyServer {
+    pub host: String,
+    pub port: u16,
+    /// How many jobs to give it at once. ComfyUI queues them, so more than one keeps the GPU
+    /// busy in between.
+    #[serde(default = "ComfyServer::default_concurrency")]
+    pub concurrency: usize,
+    /// The models it can run, if not all of them.
+    #[serde(default)]
+    pub models: Vec<String>,
+}
+
+impl ComfyServer {
+    fn default_concurrency() -> usize {
+        1
+    }
+
+    /// host:port, which is also how servers are told apart.
+    pub fn address(&self) -> String {
+        format!("{}:{}", self.host, self.port)
+    }
+
+    pub fn allows(&self, model_name: &str) -> bool {
+        self.models.is_empty() || self.models.iter().any(|m| m == model_name)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -55,11 +112,21 @@ pub struct IrcConfig {
     pub password: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct BotModelConfig {
     pub description: String,
     pub workflow: String,
+    /// Variant of the workflow that starts from a picture, for --init. It loads
+    /// __INIT_IMAGE__ instead of starting from an empty latent, and denoises by __DENOISE__.
+    pub img2img_workflow: Option<String>,
+    /// Like img2img_workflow, but only redraws the white part of __MASK_IMAGE__, for --mask.
+    pub inpaint_workflow: Option<String>,
+    /// Enlarges __INIT_IMAGE__, redrawing the details by __DENOISE__. For --upscale and the
+    /// U buttons on Discord.
+    pub upscale_workflow: Option<String>,
     pub baseline: String,
+    /// Which LoRAs fit this model, e.g. "sdxl" or "flux". Models without one can't use any.
+    pub family: Option<String>,
     pub refiner: Option<String>,
     pub vae: Option<String>,
     pub default_positive: String,
@@ -67,6 +134,138 @@ pub struct BotModelConfig {
     pub default_steps: Option<u32>,
     pub base_resolution: Option<u32>,
     pub style_connector: Option<String>,
+    /// What --sampler accepts. Leave empty if the workflow has no __SAMPLER__.
+    #[serde(default)]
+    pub samplers: Vec<String>,
+    pub default_sampler: Option<String>,
+    /// What --scheduler accepts. Leave empty if the workflow has no __SCHEDULER__.
+    #[serde(default)]
+    pub schedulers: Vec<String>,
+    pub default_scheduler: Option<String>,
+    /// Inputs to set on the workflow's nodes, as "node.input" = value. The node is an id, a
+    /// title or a class_type, so models can share a workflow and still differ a little.
+    #[serde(default)]
+    pub overrides: HashMap<String, serde_json::Value>,
+    #[serde(flatten)]
+    pub capabilities: ModelCapabilities,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct LoraConfig {
+    pub description: String,
+    /// The lora_name for LoraLoader, relative to ComfyUI's loras directory.
+    pub file: String,
+    /// Model families it was trained for; see BotModelConfig::family.
+    pub families: Vec<String>,
+    /// Words it needs in the prompt to do anything. They're added if missing.
+    #[serde(default)]
+    pub triggers: Vec<String>,
+    #[serde(default = "LoraConfig::default_weight")]
+    pub default_weight: f32,
+}
+
+impl LoraConfig {
+    fn default_weight() -> f32 {
+        1.0
+    }
+}
+
+/// How the GPUs are shared out; see the scheduler module.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct SchedulerConfig {
+    /// How long until GPU time someone used counts half as much.
+    pub half_life_minutes: f64,
+    /// What loading a different checkpoint costs, in GPU seconds.
+    pub model_switch_seconds: f64,
+    /// Shares by user; everyone else gets 1. Twice the weight, twice the GPU time.
+    pub weights: HashMap<String, f64>,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            half_life_minutes: 30.0,
+            model_switch_seconds: 20.0,
+            weights: HashMap::new(),
+        }
+    }
+}
+
+impl SchedulerConfig {
+    pub fn weight(&self, user: &str) -> f64 {
+        self.weights
+            .get(user)
+            .copied()
+            .filter(|w| *w > 0.0)
+            .unwrap_or(1.0)
+    }
+}
+
+/// What a model can do, so we don't have to guess from its name.
+/// These go directly in the model's table, and all of them are optional.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ModelCapabilities {
+    /// Whether the workflow does anything with --no. If not, its negative prompt is left empty.
+    pub supports_negative: bool,
+    /// Whether the workflow does anything with --style. If not, the style is dropped.
+    pub supports_style: bool,
+    /// Most images per backend call. Flux gains nothing from batching.
+    pub max_batch: u32,
+    /// Most pixels per backend call, summed over the batch.
+    /// This also caps the size of a single image.
+    pub pixel_budget: u64,
+    /// Width and height are rounded down to a multiple of this.
+    pub resolution_stride: u32,
+    pub default_cfg: f32,
+    pub min_cfg: f32,
+    pub max_cfg: f32,
+    /// How the workflow wants emphasis written; see the weighting module.
+    pub prompt_syntax: PromptSyntax,
+}
+
+impl Default for ModelCapabilities {
+    fn default() -> Self {
+        Self {
+            supports_negative: true,
+            supports_style: true,
+            max_batch: 16,
+            pixel_budget: 4 * 1024 * 1024,
+            resolution_stride: 64,
+            default_cfg: 5.5,
+            min_cfg: 1.0,
+            max_cfg: 80.0,
+            prompt_syntax: PromptSyntax::default(),
+        }
+    }
+}
+
+impl ModelCapabilities {
+    /// A short summary for the help system.
+    pub fn describe(&self) -> String {
+        let mut parts = vec![format!(
+            "CFG {} ({}–{})",
+            self.default_cfg, self.min_cfg, self.max_cfg
+        )];
+        if !self.supports_negative {
+            parts.push("ignores --no".to_string());
+        }
+        if !self.supports_style {
+            parts.push("ignores --style".to_string());
+        }
+        match self.prompt_syntax {
+            PromptSyntax::Comfy => {}
+            PromptSyntax::NovelAi => parts.push("weights as {word}".to_string()),
+            PromptSyntax::Plain => parts.push("ignores weights".to_string()),
+        }
+        if self.max_batch == 1 {
+            parts.push("one image at a time".to_string());
+        }
+        let megapixels = self.pixel_budget as f64 / (1024.0 * 1024.0);
+        parts.push(format!("up to {megapixels:.1} MP"));
+        parts.join(", ")
+    }
 }
 
 struct ConfigEventHandler {
@@ -92,6 +291,8 @@ impl EventHandler for ConfigEventHandler {
 pub struct BotConfigModule {
     config_path: String,
     data: Arc<RwLock<BotConfig>>,
+    /// What's wrong with the config, one line each, for the owner.
+    problems: Arc<watch::Sender<Vec<String>>>,
 }
 
 impl BotConfigModule {
@@ -101,12 +302,14 @@ impl BotConfigModule {
         let config = BotConfigModule {
             data: Arc::new(RwLock::new(read_config(&config_path)?)),
             config_path,
+            problems: Arc::new(watch::channel(vec![]).0),
         };
         tokio::task::spawn(config.clone().updater());
         Ok(config)
     }
 
     /// This watches the config file for changes, and updates the config.
+    /// Changes that break a model are refused; see the validation module.
     async fn updater(self) {
         let (tx, mut rx) = futures::channel::mpsc::unbounded();
         let watcher = ConfigEventHandler { tx };
@@ -114,20 +317,60 @@ impl BotConfigModule {
             .expect("Error creating config watcher")
             .watch(&CONFIG_PATH, notify::RecursiveMode::NonRecursive)
             .expect("Error watching config file");
+
+        // There's nothing to fall back to at startup, so here problems are only reported.
+        let config = self.snapshot().await;
+        let object_info = validation::object_info(&config.backend).await;
+        let problems = validation::check(&config, &object_info);
+        for line in validation::describe(&problems) {
+            error!("Config problem: {line}");
+        }
+        self.report(validation::describe(&problems));
+
         while let Some(event) = rx.next().await {
             if let notify::EventKind::Modify(_) = event.kind {
                 match read_config(&self.config_path) {
                     Ok(new_config) => {
+                        let object_info = validation::object_info(&new_config.backend).await;
+                        let problems = validation::check(&new_config, &object_info);
+                        let new_problems = validation::new_problems(
+                            &*self.data.read().await,
+                            &new_config,
+                            &problems,
+                        );
+                        if !new_problems.is_empty() {
+                            let mut report = vec!["Not loading the new config:".to_string()];
+                            report.extend(validation::describe(&new_problems));
+                            error!("{}", report.join("\n"));
+                            self.report(report);
+                            continue;
+                        }
                         update_config(&mut *self.data.write().await, new_config);
+                        self.report(validation::describe(&problems));
                     }
                     Err(err) => {
                         error!("Error reading config: {:?}", err);
+                        self.report(vec![format!("Error reading config: {err:#}")]);
                     }
                 }
             }
         }
     }
 
+    /// Tells whoever's listening about the config's problems, unless they've heard already.
+    fn report(&self, problems: Vec<String>) {
+        self.problems.send_if_modified(|current| {
+            let changed = *current != problems;
+            *current = problems;
+            changed
+        });
+    }
+
+    /// The config's problems, as of the last time it was loaded. Empty if it's fine.
+    pub fn problems(&self) -> watch::Receiver<Vec<String>> {
+        self.problems.subscribe()
+    }
+
     /// Copies the current config.
     pub async fn snapshot(&self) -> BotConfig {
         self.data.read().await.clone()
@@ -1,20 +1,29 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 /// This wraps a simple sqlite database.
 /// The database stores per-user settings and a log of generated images.
 ///
 use anyhow::{bail, Context, Result};
 
-use log::{info, trace};
+use log::{info, trace, warn};
 use rusqlite::{params, Connection, OptionalExtension};
 use tokio::sync::Mutex;
 
 use crate::{
     config::BotConfigModule,
-    generator::{CompletedRequest, ParsedRequest, UserRequest},
+    generator::{CompletedRequest, JobId, ParsedRequest, ReplyTo, Source, UserRequest},
+    scheduler::Timings,
+    settings::UserSettings,
+    telemetry::{Attempt, ModelStats},
     utils,
 };
 
+/// How far back timings count. Older ones may be from other hardware.
+const TIMINGS_WINDOW_DAYS: u32 = 30;
+
 struct Database {
     config: BotConfigModule,
     conn: Connection,
@@ -39,26 +48,62 @@ impl DatabaseModule {
     fn maybe_init_db(conn: &Connection) -> Result<()> {
         conn.execute_batch(include_str!("../schema.sql"))
             .context("failed to initialize database")?;
+        Self::migrate(conn)
+    }
+
+    /// CREATE TABLE IF NOT EXISTS won't add columns to existing tables,
+    /// so anything added to schema.sql later also needs to be listed here.
+    fn migrate(conn: &Connection) -> Result<()> {
+        const ADDED_COLUMNS: &[(&str, &str, &str)] = &[
+            ("Images", "prompt", "TEXT"),
+            ("Images", "style_prompt", "TEXT"),
+            ("Images", "negative_prompt", "TEXT"),
+            ("Batches", "init_image", "TEXT"),
+            ("Batches", "parent", "TEXT REFERENCES Batches(uuid)"),
+        ];
+        for (table, column, decl) in ADDED_COLUMNS {
+            let exists = conn
+                .prepare(&format!(
+                    "SELECT 1 FROM pragma_table_info('{table}') WHERE name = ?"
+                ))?
+                .exists([column])?;
+            if !exists {
+                info!("Adding column {}.{}", table, column);
+                conn.execute(
+                    &format!("ALTER TABLE {table} ADD COLUMN {column} {decl}"),
+                    [],
+                )
+                .with_context(|| format!("failed to add column {table}.{column}"))?;
+            }
+        }
         Ok(())
     }
 
     // Non-public functions do NOT lock the database mutex.
     fn user_id(&self, base: &UserRequest) -> String {
-        match base.source {
-            crate::generator::Source::Discord => format!("discord:{}", base.user),
-            crate::generator::Source::Irc => format!("irc:{}", base.user),
-            crate::generator::Source::Unknown => format!("unknown:{}", base.user),
+        Self::user_id_for(&base.source, &base.user)
+    }
+
+    fn user_id_for(source: &Source, user: &str) -> String {
+        match source {
+            Source::Discord => format!("discord:{}", user),
+            Source::Irc => format!("irc:{}", user),
+            Source::Unknown => format!("unknown:{}", user),
         }
     }
 
     fn ensure_user(&self, db: &mut Connection, base: &UserRequest) -> String {
         let userid = self.user_id(base);
+        Self::ensure_user_id(db, &userid);
+        userid
+    }
+
+    fn ensure_user_id(db: &Connection, userid: &str) {
         db.execute(
             "INSERT OR IGNORE INTO users (user, settings) VALUES (?, ?)",
-            params![&userid, "{}"],
+            params![userid, "{}"],
         )
         .expect("failed to insert user");
-        userid
     }
 
     // Public functions MUST lock the database mutex.
@@ -105,19 +150,18 @@ impl DatabaseModule {
         self.ensure_user(&mut db.conn, &c.base.base);
 
         // Create a gallery of the images.
-        let overview = utils::overview_of_pictures(&c.images)?;
-        let all: Vec<Vec<u8>> = std::iter::once(overview)
-            .chain(c.images.clone().into_iter())
-            .collect();
+        let overview = c.overview()?;
+        let all: Vec<Vec<u8>> = std::iter::once(overview).chain(c.images.clone()).collect();
         // And upload them.
-        let urls = utils::upload_images(&db.config, &c.uuid, all)
+        let extension = c.base.output_format.extension();
+        let urls = utils::upload_images(&db.config, &c.uuid, all, extension)
             .await
             .context("failed to upload images")?;
 
         // Create the batch entry.
         db.conn
             .execute(
-                "INSERT INTO batches (uuid, original_prompt, prompt, style_prompt, settings, user, gallery) VALUES (?, ?, ?, ?, ?, ?, ?)",
+                "INSERT INTO batches (uuid, original_prompt, prompt, style_prompt, settings, user, gallery, init_image, parent) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
                 params![
                     &c.uuid.to_string(),
                     if let Some(dream) = &c.base.base.dream { &dream } else { "NULL" },
@@ -126,6 +170,8 @@ impl DatabaseModule {
                     serde_json::to_string(&c.base).expect("failed to serialize settings"),
                     self.user_id(&c.base.base),
                     urls[0],
+                    c.base.init.as_ref().map(|i| &i.url),
+                    c.base.base.parent,
                 ],
             )
             .expect("failed to insert batch");
@@ -136,10 +182,18 @@ impl DatabaseModule {
                 // Skip the overview.
                 continue;
             }
+            let prompts = &c.prompts[i - 1];
             db.conn
                 .execute(
-                    "INSERT INTO images (batch_index, url, uuid) VALUES (?, ?, ?)",
-                    params![i, url, &c.uuid.to_string(),],
+                    "INSERT INTO images (batch_index, url, uuid, prompt, style_prompt, negative_prompt) VALUES (?, ?, ?, ?, ?, ?)",
+                    params![
+                        i,
+                        url,
+                        &c.uuid.to_string(),
+                        prompts.linguistic,
+                        prompts.supporting,
+                        prompts.negative,
+                    ],
                 )
                 .expect("failed to insert image");
         }
@@ -163,6 +217,27 @@ impl DatabaseModule {
         }
     }
 
+    /// Galleries of a batch and the batches it was made from, starting with the batch itself.
+    /// Stops after a while, in case someone has been very persistent.
+    pub async fn get_lineage(&self, uuid: &str) -> Result<Vec<String>> {
+        let db = self.0.lock().await;
+        let mut stmt = db.conn.prepare(
+            "WITH RECURSIVE lineage(gallery, parent, depth) AS (
+                SELECT gallery, parent, 0 FROM batches WHERE uuid = ?
+                UNION ALL
+                SELECT b.gallery, b.parent, l.depth + 1
+                FROM batches b JOIN lineage l ON b.uuid = l.parent
+                WHERE l.depth < 9
+            )
+            SELECT gallery FROM lineage ORDER BY depth",
+        )?;
+        let galleries = stmt
+            .query_map([uuid], |row| row.get(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()
+            .context("failed to get lineage")?;
+        Ok(galleries)
+    }
+
     pub async fn get_seen_changelog_entries(&self, user: &str) -> Result<HashSet<String>> {
         // The hashes are stored as the seen column in the Changelog_viewed table.
         let db = self.0.lock().await;
@@ -190,6 +265,295 @@ impl DatabaseModule {
         Ok(())
     }
 
+    /// Returns the user's default settings. Users we haven't seen before have none.
+    pub async fn get_user_settings(&self, source: &Source, user: &str) -> Result<UserSettings> {
+        let db = self.0.lock().await;
+        let settings: Option<String> = db
+            .conn
+            .query_row(
+                "SELECT settings FROM users WHERE user = ?",
+                [Self::user_id_for(source, user)],
+                |row| row.get(0),
+            )
+            .optional()
+            .context("failed to get user settings")?
+            .flatten();
+        match settings {
+            Some(settings) => {
+                serde_json::from_str(&settings).context("failed to parse user settings")
+            }
+            None => Ok(UserSettings::default()),
+        }
+    }
+
+    pub async fn set_user_settings(
+        &self,
+        source: &Source,
+        user: &str,
+        settings: &UserSettings,
+    ) -> Result<()> {
+        let db = self.0.lock().await;
+        let userid = Self::user_id_for(source, user);
+        Self::ensure_user_id(&db.conn, &userid);
+        db.conn
+            .execute(
+                "UPDATE users SET settings = ? WHERE user = ?",
+                params![
+                    serde_json::to_string(settings).context("failed to serialize settings")?,
+                    userid
+                ],
+            )
+            .context("failed to update user settings")?;
+        Ok(())
+    }
+
+    /// Returns the user's presets, by name.
+    pub async fn get_presets(
+        &self,
+        source: &Source,
+        user: &str,
+    ) -> Result<HashMap<String, String>> {
+        let db = self.0.lock().await;
+        let mut stmt = db
+            .conn
+            .prepare("SELECT name, text FROM presets WHERE user = ?")?;
+        let presets = stmt
+            .query_map([Self::user_id_for(source, user)], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })?
+            .collect::<rusqlite::Result<_>>()
+            .context("failed to get presets")?;
+        Ok(presets)
+    }
+
+    /// Lists the user's presets as (name, text, shared), sorted by name.
+    /// If shared_only is set, only the ones they've shared.
+    pub async fn list_presets(
+        &self,
+        source: &Source,
+        user: &str,
+        shared_only: bool,
+    ) -> Result<Vec<(String, String, bool)>> {
+        let db = self.0.lock().await;
+        let mut stmt = db.conn.prepare(
+            "SELECT name, text, shared FROM presets WHERE user = ? AND (shared OR NOT ?) ORDER BY name",
+        )?;
+        let presets = stmt
+            .query_map(
+                params![Self::user_id_for(source, user), shared_only],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )?
+            .collect::<rusqlite::Result<_>>()
+            .context("failed to list presets")?;
+        Ok(presets)
+    }
+
+    /// Creates or replaces a preset. Replacing one keeps its shared flag.
+    pub async fn save_preset(
+        &self,
+        source: &Source,
+        user: &str,
+        name: &str,
+        text: &str,
+    ) -> Result<()> {
+        let db = self.0.lock().await;
+        let userid = Self::user_id_for(source, user);
+        Self::ensure_user_id(&db.conn, &userid);
+        db.conn
+            .execute(
+                "INSERT INTO presets (user, name, text) VALUES (?, ?, ?)
+                 ON CONFLICT (user, name) DO UPDATE SET text = excluded.text",
+                params![userid, name, text],
+            )
+            .context("failed to save preset")?;
+        Ok(())
+    }
+
+    /// Deletes a preset. Returns false if there was no such preset.
+    pub async fn delete_preset(&self, source: &Source, user: &str, name: &str) -> Result<bool> {
+        let db = self.0.lock().await;
+        let deleted = db
+            .conn
+            .execute(
+                "DELETE FROM presets WHERE user = ? AND name = ?",
+                params![Self::user_id_for(source, user), name],
+            )
+            .context("failed to delete preset")?;
+        Ok(deleted > 0)
+    }
+
+    /// Shares or unshares a preset. Returns false if there was no such preset.
+    pub async fn set_preset_shared(
+        &self,
+        source: &Source,
+        user: &str,
+        name: &str,
+        shared: bool,
+    ) -> Result<bool> {
+        let db = self.0.lock().await;
+        let updated = db
+            .conn
+            .execute(
+                "UPDATE presets SET shared = ? WHERE user = ? AND name = ?",
+                params![shared, Self::user_id_for(source, user), name],
+            )
+            .context("failed to share preset")?;
+        Ok(updated > 0)
+    }
+
+    /// Keeps a request that's been accepted, so it survives a restart.
+    pub async fn save_queued(
+        &self,
+        id: JobId,
+        request: &ParsedRequest,
+        reply_to: &ReplyTo,
+    ) -> Result<()> {
+        let db = self.0.lock().await;
+        db.conn
+            .execute(
+                "INSERT INTO queue (id, request, reply_to) VALUES (?, ?, ?)",
+                params![
+                    id,
+                    serde_json::to_string(request).context("failed to serialize request")?,
+                    serde_json::to_string(reply_to).context("failed to serialize reply_to")?,
+                ],
+            )
+            .context("failed to save queued request")?;
+        Ok(())
+    }
+
+    /// Forgets a queued request, which is done with one way or another.
+    pub async fn mark_done(&self, id: JobId) -> Result<()> {
+        let db = self.0.lock().await;
+        db.conn
+            .execute("DELETE FROM queue WHERE id = ?", [id])
+            .context("failed to mark request done")?;
+        Ok(())
+    }
+
+    /// Requests that were queued or generating when the bot stopped, oldest first.
+    /// Ones we can't read any more are dropped.
+    pub async fn get_pending(&self) -> Result<Vec<(JobId, ParsedRequest, ReplyTo)>> {
+        let db = self.0.lock().await;
+        let mut stmt = db
+            .conn
+            .prepare("SELECT id, request, reply_to FROM queue ORDER BY id")?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<rusqlite::Result<Vec<(JobId, String, String)>>>()
+            .context("failed to get pending requests")?;
+        let mut pending = vec![];
+        for (id, request, reply_to) in rows {
+            let parsed = serde_json::from_str(&request)
+                .and_then(|request| Ok((request, serde_json::from_str(&reply_to)?)));
+            match parsed {
+                Ok((request, reply_to)) => pending.push((id, request, reply_to)),
+                Err(e) => {
+                    warn!("Dropping queued request {id}: {e}");
+                    db.conn
+                        .execute("DELETE FROM queue WHERE id = ?", [id])
+                        .context("failed to drop queued request")?;
+                }
+            }
+        }
+        Ok(pending)
+    }
+
+    /// The first job id that hasn't been used. Finished jobs are deleted, so this asks
+    /// SQLite for the highest id the queue has ever had.
+    pub async fn next_job_id(&self) -> Result<JobId> {
+        let db = self.0.lock().await;
+        db.conn
+            .query_row(
+                "SELECT COALESCE((SELECT seq + 1 FROM sqlite_sequence WHERE name = 'Queue'), 0)",
+                [],
+                |row| row.get(0),
+            )
+            .context("failed to get next job id")
+    }
+
+    /// Records a try at generating something, whether it worked or not.
+    pub async fn record_attempt(&self, attempt: &Attempt) -> Result<()> {
+        let db = self.0.lock().await;
+        db.conn
+            .execute(
+                "INSERT INTO attempts (model, workflow, width, height, steps, pictures, server, queue_seconds, backend_seconds, retries, outcome) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                params![
+                    attempt.model,
+                    attempt.workflow,
+                    attempt.width,
+                    attempt.height,
+                    attempt.steps,
+                    attempt.pictures,
+                    attempt.server,
+                    attempt.queue_wait.as_secs_f64(),
+                    attempt.backend_time.as_secs_f64(),
+                    attempt.retries,
+                    attempt.outcome.as_str(),
+                ],
+            )
+            .context("failed to record attempt")?;
+        Ok(())
+    }
+
+    /// Average seconds per picture over the last while, by model and resolution.
+    pub async fn get_timings(&self) -> Result<Timings> {
+        let db = self.0.lock().await;
+        let mut stmt = db.conn.prepare(
+            "SELECT model, width, height, SUM(backend_seconds) / SUM(pictures) FROM attempts
+             WHERE finished > strftime('%s', 'now') - ? * 86400 AND pictures > 0 AND outcome = 'ok'
+             GROUP BY model, width, height",
+        )?;
+        let timings = stmt
+            .query_map([TIMINGS_WINDOW_DAYS], |row| {
+                Ok(((row.get(0)?, row.get(1)?, row.get(2)?), row.get(3)?))
+            })?
+            .collect::<rusqlite::Result<_>>()
+            .context("failed to get timings")?;
+        Ok(Timings::new(timings))
+    }
+
+    /// How each model has done over the last few days, busiest first.
+    pub async fn get_stats(&self, days: u32) -> Result<Vec<ModelStats>> {
+        let db = self.0.lock().await;
+        let mut stmt = db.conn.prepare(
+            "SELECT model, COUNT(*), SUM(outcome = 'cancelled'),
+                 SUM(CASE WHEN outcome = 'ok' THEN backend_seconds END)
+                     / SUM(CASE WHEN outcome = 'ok' THEN pictures END),
+                 AVG(queue_seconds)
+             FROM attempts WHERE finished > strftime('%s', 'now') - ? * 86400
+             GROUP BY model ORDER BY COUNT(*) DESC, model",
+        )?;
+        let mut stats = stmt
+            .query_map([days], |row| {
+                Ok(ModelStats {
+                    model: row.get(0)?,
+                    attempts: row.get(1)?,
+                    cancelled: row.get(2)?,
+                    errors: vec![],
+                    seconds_per_picture: row.get(3)?,
+                    queue_wait: row.get(4)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("failed to get stats")?;
+        let mut stmt = db.conn.prepare(
+            "SELECT model, outcome, COUNT(*) FROM attempts
+             WHERE finished > strftime('%s', 'now') - ? * 86400 AND outcome NOT IN ('ok', 'cancelled')
+             GROUP BY model, outcome ORDER BY COUNT(*) DESC, outcome",
+        )?;
+        let errors = stmt
+            .query_map([days], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<rusqlite::Result<Vec<(String, String, u32)>>>()
+            .context("failed to get errors")?;
+        for (model, outcome, count) in errors {
+            if let Some(s) = stats.iter_mut().find(|s| s.model == model) {
+                s.errors.push((outcome, count));
+            }
+        }
+        Ok(stats)
+    }
+
     /// Updates user stats to track the public/private generation ratio.
     pub(crate) async fn update_user_stats(
         &self,
@@ -1,9 +1,11 @@
+use std::time::{Duration, Instant};
+
 use anyhow::{bail, Context as anyhowCtx, Result};
 use log::{debug, error, info, trace};
 
 use serenity::{
     async_trait,
-    builder::{CreateActionRow, CreateInputText},
+    builder::{CreateActionRow, CreateButton, CreateInputText},
     model::prelude::{
         application_command::{ApplicationCommandInteraction, CommandDataOptionValue},
         command::{Command, CommandOptionType},
@@ -15,14 +17,17 @@ use serenity::{
     prelude::*,
 };
 
-use tokio_stream::StreamExt;
+use tokio_stream::{Stream, StreamExt};
 
 use crate::{
     changelog,
-    generator::{self, GenerationEvent, ParsedRequest, UserRequest},
-    help, utils, BotContext,
+    generator::{self, GenerationEvent, Progress, ReplyTo, Resumed, UserRequest},
+    help, parser, presets, settings, utils, BotContext,
 };
 
+/// How often to show progress within a stage.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
 pub struct DiscordTask {
     context: BotContext,
     token: String,
@@ -33,6 +38,124 @@ struct Handler {
     action_buttons: CreateActionRow,
 }
 
+/// Picture URLs end in <uuid>.<index>.<ext>, where the UUID is the batch's.
+fn batch_uuid(url: &str) -> Result<&str> {
+    Ok(url
+        .rsplit_once('/')
+        .context("Expected a URL with a UUID")?
+        .1
+        .split_once('.')
+        .context("Expected a URL with an index")?
+        .0)
+}
+
+fn button(label: &str, custom_id: &str, style: ButtonStyle) -> CreateButton {
+    let mut button = CreateButton::default();
+    button.style(style).label(label).custom_id(custom_id);
+    button
+}
+
+/// Buttons for a finished batch, to go below the action buttons:
+/// - Grids get U buttons in the same layout as the contact sheet, if they fit.
+/// - Everything else gets a row of U buttons, then V buttons (if the model can start from a
+///   picture), then the extras.
+fn picture_rows(
+    images: usize,
+    grid_columns: Option<usize>,
+    variants: bool,
+    extras: Vec<CreateButton>,
+) -> Vec<CreateActionRow> {
+    fn rows_of(buttons: Vec<CreateButton>, width: usize) -> Vec<CreateActionRow> {
+        buttons
+            .chunks(width.max(1))
+            .map(|chunk| {
+                let mut row = CreateActionRow::default();
+                for button in chunk {
+                    row.add_button(button.clone());
+                }
+                row
+            })
+            .collect()
+    }
+    let upscales = (1..=images)
+        .map(|i| {
+            button(
+                &format!("U{i}"),
+                &format!("upscale.{i}"),
+                ButtonStyle::Primary,
+            )
+        })
+        .collect::<Vec<_>>();
+    let mut rows = match grid_columns {
+        Some(columns) => {
+            let width = if columns <= 5 && images <= 4 * columns {
+                columns
+            } else {
+                utils::gallery_geometry(images).0 as usize
+            };
+            rows_of(upscales, width)
+        }
+        None => {
+            let mut others = vec![];
+            if variants {
+                others.extend((1..=images).map(|i| {
+                    button(
+                        &format!("V{i}"),
+                        &format!("variant.{i}"),
+                        ButtonStyle::Secondary,
+                    )
+                }));
+            }
+            others.extend(extras);
+            let mut rows = rows_of(upscales, 5);
+            rows.extend(rows_of(others, 5));
+            rows
+        }
+    };
+    // Discord allows five rows, and the action buttons take the first.
+    rows.truncate(4);
+    rows
+}
+
+/// The value of a modal's text field.
+fn modal_value(interaction: &ModalSubmitInteraction, custom_id: &str) -> Option<String> {
+    interaction
+        .data
+        .components
+        .iter()
+        .flat_map(|row| &row.components)
+        .find_map(|component| match component {
+            ActionRowComponent::InputText(text) if text.custom_id == custom_id => {
+                Some(text.value.clone())
+            }
+            _ => None,
+        })
+}
+
+/// Modal submissions and button presses both answer with followup messages.
+#[async_trait]
+trait FollowupTarget: Send + Sync {
+    async fn followup(&self, ctx: &Context, content: &str, ephemeral: bool) -> Result<Message>;
+}
+
+#[async_trait]
+impl FollowupTarget for &ModalSubmitInteraction {
+    async fn followup(&self, ctx: &Context, content: &str, ephemeral: bool) -> Result<Message> {
+        self.create_followup_message(&ctx.http, |m| m.content(content).ephemeral(ephemeral))
+            .await
+            .context("Sending followup")
+    }
+}
+
+#[async_trait]
+impl FollowupTarget for &MessageComponentInteraction {
+    async fn followup(&self, ctx: &Context, content: &str, ephemeral: bool) -> Result<Message> {
+        self.create_followup_message(&ctx.http, |m| m.content(content).ephemeral(ephemeral))
+            .await
+            .context("Sending followup")
+    }
+}
+
 impl DiscordTask {
     pub fn new(context: BotContext) -> Result<Self> {
         let token =
@@ -56,14 +179,12 @@ impl DiscordTask {
 
 struct DiscordMessageData {
     /// Accessible from the start:
-    // The user who requested the image.
-    pub user: String,
     pub mention: String,
     // The prompt that was used to generate the image.
     pub prompt: String,
     pub seed: Option<u32>,
-    // True for /dream, false for /prompt.
-    pub is_dream: bool,
+    /// Flags the model is going to ignore.
+    pub warnings: Vec<String>,
     /// Accessible if there is a changelog entry:
     pub changelog: Option<String>,
     /// Accessible after LLM enhancement:
@@ -71,11 +192,42 @@ struct DiscordMessageData {
     pub comment: Option<String>,
     /// Accessible *while* generating:
     pub queue_pos: Option<u32>,
-    pub gen_pct: Option<u32>,
+    /// Unix times it should start and be done, while it's queued.
+    pub queue_eta: Option<(u64, u64)>,
+    pub progress: Option<Progress>,
     /// Accessible after the image is generated:
     pub gallery_url: Option<String>,
+    /// Galleries of the batches this one was made from, nearest first.
+    pub lineage: Vec<String>,
     /// Accessible if there is an error:
     pub error: Option<String>,
+    /// Set once the user has cancelled it.
+    pub cancelled: bool,
+}
+
+impl DiscordMessageData {
+    fn new(request: &UserRequest, mention: String) -> Self {
+        Self {
+            mention,
+            prompt: if let Some(dream) = request.dream.as_ref() {
+                dream.clone()
+            } else {
+                request.raw.clone()
+            },
+            seed: None,
+            warnings: vec![],
+            enhanced: None,
+            comment: None,
+            gallery_url: None,
+            lineage: vec![],
+            error: None,
+            cancelled: false,
+            changelog: None,
+            queue_pos: None,
+            queue_eta: None,
+            progress: None,
+        }
+    }
 }
 
 // Discord message formatter.
@@ -85,8 +237,10 @@ struct DiscordMessageData {
 // - Changelog entry (if present)
 // - Error message (if present)
 // - Gallery link for the image server.
+// - Links to the batches this one was made from.
 // - Original prompt
 // - Seed
+// - Warnings about ignored flags
 // - 1st paragraph of enhanced prompt
 // - The entire comment.
 // - The rest of the enhanced prompt.
@@ -98,13 +252,28 @@ fn format_message(data: &DiscordMessageData) -> String {
     if let Some(error) = &data.error {
         message.push_str(&format!("Error: {error}\n"));
     }
+    if data.cancelled {
+        message.push_str("Cancelled.\n");
+    }
     if let Some(url) = &data.gallery_url {
         message.push_str(&format!("Gallery: {url}\n"));
     }
+    if !data.lineage.is_empty() {
+        // The brackets stop Discord from embedding every ancestor.
+        let links = data
+            .lineage
+            .iter()
+            .map(|url| format!("<{url}>"))
+            .collect::<Vec<_>>();
+        message.push_str(&format!("From: {}\n", links.join(" ← ")));
+    }
     message.push_str(&format!("`{}`\n\n", data.prompt));
     if let Some(seed) = data.seed {
         message.push_str(&format!("Seed: {}\n\n", seed));
     }
+    for warning in &data.warnings {
+        message.push_str(&format!("Note: {warning}\n"));
+    }
     // So much for the easy stuff. Let's see how much space is left.
     let mut remaining = 1950 - message.len();
     let mut enhanced =
@@ -152,9 +321,12 @@ fn format_message(data: &DiscordMessageData) -> String {
     // And the queue position / generation percentage / ETA.
     if let Some(queue_pos) = data.queue_pos {
         message.push_str(&format!("\n\nQueued at position #{queue_pos}"));
+        if let Some((start, finish)) = data.queue_eta {
+            message.push_str(&format!(". Starts <t:{start}:R>, done <t:{finish}:R>"));
+        }
     }
-    if let Some(gen_pct) = data.gen_pct {
-        message.push_str(&format!("\n\nGeneration progress: {gen_pct}%"));
+    if let Some(progress) = &data.progress {
+        message.push_str(&format!("\n\nGeneration progress: {progress}"));
     }
 
     // Fix any tripled newlines.
@@ -212,6 +384,8 @@ impl Handler {
                         dream: Some(prompt.to_string()),
                         source: generator::Source::Discord,
                         comment: None,
+                        parent: None,
+                        settings: None,
                         private: command.guild_id.is_none(),
                     }
                 } else {
@@ -223,6 +397,8 @@ impl Handler {
                 let mut style = None;
                 let mut ar = None;
                 let mut model = None;
+                let mut image = None;
+                let mut mask = None;
                 for option in &command.data.options {
                     match option.name.as_str() {
                         "prompt" => {
@@ -237,6 +413,12 @@ impl Handler {
                         "model" => {
                             model = option.resolved.as_ref();
                         }
+                        "image" => {
+                            image = option.resolved.as_ref();
+                        }
+                        "mask" => {
+                            mask = option.resolved.as_ref();
+                        }
                         unknown => {
                             bail!("Unknown option: {}", unknown);
                         }
@@ -264,18 +446,34 @@ impl Handler {
                 } else {
                     None
                 };
+                let image = if let Some(CommandDataOptionValue::Attachment(image)) = image {
+                    Some(image)
+                } else {
+                    None
+                };
+                let mask = if let Some(CommandDataOptionValue::Attachment(mask)) = mask {
+                    Some(mask)
+                } else {
+                    None
+                };
 
                 // Stick these on the end of the 'command line' so that the parser
                 // can pick them up.
                 let mut raw = prompt.to_string();
                 if let Some(style) = style {
-                    raw.push_str(&format!(" --style {}", style));
+                    raw.push_str(&format!(" --style {}", parser::escape_prompt(style)));
                 }
                 if let Some(ar) = ar {
-                    raw.push_str(&format!(" --ar {}", ar));
+                    raw.push_str(&format!(" --ar {}", parser::quote_value(ar)));
                 }
                 if let Some(model) = model {
-                    raw.push_str(&format!(" --model {}", model));
+                    raw.push_str(&format!(" --model {}", parser::quote_value(model)));
+                }
+                if let Some(image) = image {
+                    raw.push_str(&format!(" --init {}", parser::quote_value(&image.url)));
+                }
+                if let Some(mask) = mask {
+                    raw.push_str(&format!(" --mask {}", parser::quote_value(&mask.url)));
                 }
 
                 // Now we can generate.
@@ -285,57 +483,167 @@ impl Handler {
                     dream: None,
                     source: generator::Source::Discord,
                     comment: None,
+                    parent: None,
+                    settings: None,
                     private: command.guild_id.is_none(),
                 }
             }
+            "settings" | "preset" => {
+                let params = match command
+                    .data
+                    .options
+                    .first()
+                    .and_then(|o| o.resolved.as_ref())
+                {
+                    Some(CommandDataOptionValue::String(params)) => params.as_str(),
+                    _ => "",
+                };
+                let user = command.user.to_string();
+                let source = generator::Source::Discord;
+                let text = if cmd == "settings" {
+                    settings::handler(&self.context, "/", source, &user, params).await?
+                } else {
+                    presets::handler(&self.context, "/", source, &user, params).await?
+                };
+                // These are personal, so only the user gets to see them.
+                command
+                    .create_followup_message(&ctx.http, |message| {
+                        message.content(text).ephemeral(true)
+                    })
+                    .await
+                    .context("Sending response")?;
+                command
+                    .delete_original_interaction_response(&ctx.http)
+                    .await
+                    .context("Deleting deferred response")?;
+                return Ok(());
+            }
+            "queue" => {
+                let entries = self.context.image_generator.queue().await?;
+                let text = if entries.is_empty() {
+                    "Nothing's queued.".to_string()
+                } else {
+                    entries
+                        .iter()
+                        .map(|e| e.to_string())
+                        .collect::<Vec<_>>()
+                        .join("\n")
+                };
+                // Only the one who asked needs to see it.
+                command
+                    .create_followup_message(&ctx.http, |message| {
+                        message
+                            .content(utils::segment_lines_condensed(&text, 1900).swap_remove(0))
+                            .ephemeral(true)
+                    })
+                    .await
+                    .context("Sending response")?;
+                command
+                    .delete_original_interaction_response(&ctx.http)
+                    .await
+                    .context("Deleting deferred response")?;
+                return Ok(());
+            }
             x => bail!("Unknown command: {}", x),
         };
 
-        let statusbox = command
-            .edit_original_interaction_response(&ctx.http, |f| f.content("Dreaming..."))
-            .await
-            .context("Creating initial statusbox")?;
+        let dm = self
+            .private_delivery(ctx, &command.user, command.guild_id)
+            .await?;
+        let statusbox = match dm {
+            Some(dm) => {
+                command
+                    .edit_original_interaction_response(&ctx.http, |f| {
+                        f.content("Sending the results by DM.")
+                    })
+                    .await
+                    .context("Updating interaction response")?;
+                dm.send_message(&ctx.http, |m| m.content("Dreaming..."))
+                    .await
+                    .context("Creating initial statusbox")?
+            }
+            None => command
+                .edit_original_interaction_response(&ctx.http, |f| f.content("Dreaming..."))
+                .await
+                .context("Creating initial statusbox")?,
+        };
 
-        let is_private = command.guild_id.is_none();
+        let is_private = command.guild_id.is_none() || dm.is_some();
 
         self.do_generate(ctx, statusbox, request, mention_user, is_private)
             .await
     }
 
+    /// Users who turned on the private setting get their results by DM, wherever they asked.
+    /// Returns the DM channel, unless they're already talking to us privately.
+    async fn private_delivery(
+        &self,
+        ctx: &Context,
+        user: &User,
+        guild_id: Option<GuildId>,
+    ) -> Result<Option<ChannelId>> {
+        if guild_id.is_none() {
+            return Ok(None);
+        }
+        let settings = self
+            .context
+            .db
+            .get_user_settings(&generator::Source::Discord, &user.to_string())
+            .await?;
+        if !settings.is_private() {
+            return Ok(None);
+        }
+        let dm = user
+            .create_dm_channel(&ctx.http)
+            .await
+            .context("Opening DM channel")?;
+        Ok(Some(dm.id))
+    }
+
+    /// Creates the statusbox for a followup generation, in a DM if the user wants privacy.
+    /// Returns it, plus whether the results are private.
+    async fn followup_statusbox(
+        &self,
+        ctx: &Context,
+        interaction: impl FollowupTarget,
+        user: &User,
+        guild_id: Option<GuildId>,
+    ) -> Result<(Message, bool)> {
+        let dm = self.private_delivery(ctx, user, guild_id).await?;
+        let statusbox = match dm {
+            Some(dm) => {
+                interaction
+                    .followup(ctx, "Sending the results by DM.", true)
+                    .await?;
+                dm.send_message(&ctx.http, |m| m.content("Dreaming..."))
+                    .await
+                    .context("Creating initial statusbox")?
+            }
+            None => interaction.followup(ctx, "Dreaming...", false).await?,
+        };
+        Ok((statusbox, guild_id.is_none() || dm.is_some()))
+    }
+
     async fn do_generate(
         &self,
         ctx: &Context,
-        mut statusbox: Message,
+        statusbox: Message,
         request: UserRequest,
         mention_user: Mention,
         is_private: bool,
     ) -> Result<()> {
-        let mut stream = Box::pin(
-            self.context
-                .image_generator
-                .generate(request.clone(), is_private)
-                .await,
-        );
+        let reply_to = ReplyTo::Discord {
+            channel: statusbox.channel_id.0,
+            message: statusbox.id.0,
+        };
+        let stream = self
+            .context
+            .image_generator
+            .generate(request.clone(), is_private, reply_to)
+            .await;
 
         // We'll be repeatedly updating the statusbox with the latest progress.
-        let mut status_data = DiscordMessageData {
-            user: request.user.clone(),
-            mention: mention_user.to_string(),
-            prompt: if let Some(dream) = request.dream.as_ref() {
-                dream.clone()
-            } else {
-                request.raw.clone()
-            },
-            seed: None,
-            is_dream: request.dream.is_some(),
-            enhanced: None,
-            comment: None,
-            gallery_url: None,
-            error: None,
-            changelog: None,
-            queue_pos: None,
-            gen_pct: None,
-        };
+        let mut status_data = DiscordMessageData::new(&request, mention_user.to_string());
 
         // When generating, we first create an interaction response in which we
         // display event data such as queue #s.
@@ -345,6 +653,45 @@ impl Handler {
         // However, we might want to stick a changelog entry in there.
         status_data.changelog =
             changelog::get_new_changelog_entry(&self.context, &request.user).await?;
+        self.follow(ctx, statusbox, status_data, stream).await
+    }
+
+    /// Picks up a request from before a restart, in the status message it had.
+    async fn resume(&self, ctx: &Context, job: Resumed) -> Result<()> {
+        let ReplyTo::Discord { channel, message } = job.reply_to else {
+            bail!("{:?} isn't on Discord", job.reply_to);
+        };
+        let channel = ChannelId(channel);
+        let statusbox = match channel.message(&ctx.http, message).await {
+            Result::Ok(statusbox) => statusbox,
+            // Someone deleted it, so start another.
+            Err(_) => channel
+                .send_message(&ctx.http, |m| m.content("Dreaming..."))
+                .await
+                .context("Creating statusbox")?,
+        };
+        let base = &job.request.base;
+        let mut status_data = DiscordMessageData::new(base, base.user.clone());
+        if base.dream.is_some() {
+            status_data.enhanced = Some(base.raw.clone());
+        }
+        status_data.comment.clone_from(&base.comment);
+        status_data.seed = Some(job.request.seed);
+        status_data
+            .warnings
+            .push("Picked up again after a restart.".to_string());
+        self.follow(ctx, statusbox, status_data, job.events).await
+    }
+
+    /// Keeps the statusbox up to date as the request goes, and posts the pictures.
+    async fn follow(
+        &self,
+        ctx: &Context,
+        mut statusbox: Message,
+        mut status_data: DiscordMessageData,
+        stream: impl Stream<Item = GenerationEvent>,
+    ) -> Result<()> {
+        let mut stream = std::pin::pin!(stream);
 
         async fn update_statusbox(
             ctx: &Context,
@@ -357,7 +704,56 @@ impl Handler {
                 .context("Updating statusbox")
         }
 
+        /// Shows the preview under the status, in place of the last one.
+        /// None takes it away.
+        async fn update_preview(
+            ctx: &Context,
+            preview: Option<Vec<u8>>,
+            boxx: &mut Message,
+        ) -> Result<()> {
+            boxx.edit(&ctx.http, |message| {
+                message.remove_all_attachments().set_embeds(vec![]);
+                if let Some(preview) = preview {
+                    message
+                        .attachment(AttachmentType::Bytes {
+                            data: preview.into(),
+                            filename: "preview.jpg".to_string(),
+                        })
+                        .embed(|e| e.title("Preview").attachment("preview.jpg"));
+                }
+                message
+            })
+            .await
+            .context("Updating preview")
+        }
+
+        /// Shows a Cancel button for the job, or with None, takes it away.
+        async fn update_cancel_button(
+            ctx: &Context,
+            job: Option<generator::JobId>,
+            boxx: &mut Message,
+        ) -> Result<()> {
+            boxx.edit(&ctx.http, |message| {
+                message.components(|c| {
+                    if let Some(job) = job {
+                        c.create_action_row(|row| {
+                            row.add_button(button(
+                                "Cancel",
+                                &format!("cancel.{job}"),
+                                ButtonStyle::Danger,
+                            ))
+                        });
+                    }
+                    c
+                })
+            })
+            .await
+            .context("Updating cancel button")
+        }
+
         update_statusbox(ctx, &status_data, &mut statusbox).await?;
+        let mut last_progress = Instant::now();
+        let mut has_preview = false;
 
         while let Some(event) = stream.next().await {
             trace!("Event: {:?}", event);
@@ -373,35 +769,111 @@ impl Handler {
                     status_data.seed = Some(parsed.seed);
                     update_statusbox(ctx, &status_data, &mut statusbox).await?;
                 }
-                GenerationEvent::Queued(n) => {
+                GenerationEvent::Warning(warning) => {
+                    status_data.warnings.push(warning);
+                    update_statusbox(ctx, &status_data, &mut statusbox).await?;
+                }
+                GenerationEvent::Queued(n, eta) => {
                     if n > 0 {
                         status_data.queue_pos = Some(n);
+                        let unix = |d: Duration| {
+                            (std::time::SystemTime::now() + d)
+                                .duration_since(std::time::UNIX_EPOCH)
+                                .map_or(0, |t| t.as_secs())
+                        };
+                        status_data.queue_eta = eta.map(|eta| (unix(eta.start), unix(eta.finish)));
                     } else {
                         status_data.queue_pos = None;
                     }
                     update_statusbox(ctx, &status_data, &mut statusbox).await?;
                 }
-                GenerationEvent::Generating(percent) => {
+                GenerationEvent::Generating(progress) => {
+                    // There's an update per sampling step, which is more than Discord wants.
+                    // New stages always show; steps only now and then.
+                    let new_stage = status_data
+                        .progress
+                        .as_ref()
+                        .is_none_or(|p| p.stage != progress.stage);
                     status_data.queue_pos = None;
-                    status_data.gen_pct = Some(percent);
-                    update_statusbox(ctx, &status_data, &mut statusbox).await?;
+                    status_data.progress = Some(progress);
+                    if new_stage || last_progress.elapsed() >= PROGRESS_INTERVAL {
+                        last_progress = Instant::now();
+                        update_statusbox(ctx, &status_data, &mut statusbox).await?;
+                    }
+                }
+                GenerationEvent::Preview(preview) => {
+                    has_preview = true;
+                    update_preview(ctx, Some(preview), &mut statusbox).await?;
                 }
-                GenerationEvent::Error(e) => {
-                    status_data.error = Some(e.to_string());
+                GenerationEvent::Accepted(job) => {
+                    update_cancel_button(ctx, Some(job), &mut statusbox).await?;
+                }
+                GenerationEvent::Error(_) | GenerationEvent::Cancelled => {
+                    match event {
+                        GenerationEvent::Error(e) => status_data.error = Some(e.to_string()),
+                        _ => status_data.cancelled = true,
+                    }
+                    status_data.queue_pos = None;
+                    status_data.progress = None;
                     update_statusbox(ctx, &status_data, &mut statusbox).await?;
+                    // It's not going anywhere now.
+                    update_cancel_button(ctx, None, &mut statusbox).await?;
+                    if has_preview {
+                        update_preview(ctx, None, &mut statusbox).await?;
+                    }
                 }
                 GenerationEvent::Completed(c) => {
                     status_data.queue_pos = None;
-                    status_data.gen_pct = None;
+                    status_data.progress = None;
                     // TODO: Add gallery url once the ROcket server is up.
 
                     // Add images to the database & upload them.
-                    let gallery_geometry = utils::gallery_geometry(c.images.len());
                     let urls = self.context.db.add_image_batch(&c).await?;
+                    // Show where this batch came from, if it came from somewhere.
+                    if let Some(parent) = &c.base.base.parent {
+                        status_data.lineage = self.context.db.get_lineage(parent).await?;
+                    }
+                    // Grids can't be varied; which cell would it vary? Upscales can be
+                    // inpainted, but varying them would just be a worse retry.
+                    let (can_vary, can_inpaint, can_start_from) = self
+                        .context
+                        .config
+                        .with_config(|config| {
+                            config.models.get(&c.base.model_name).map_or(
+                                (false, false, false),
+                                |m| {
+                                    (
+                                        c.base.grid.is_none()
+                                            && !c.base.upscale
+                                            && c.base.workflow_placeholders(m).is_ok_and(|p| {
+                                                p.iter().any(|p| p == "__VARIATION_SEED__")
+                                            }),
+                                        c.base.upscale
+                                            && c.images.len() == 1
+                                            && m.inpaint_workflow.is_some(),
+                                        m.img2img_workflow.is_some(),
+                                    )
+                                },
+                            )
+                        })
+                        .await;
+                    let mut extras = vec![];
+                    if can_vary {
+                        extras.push(button("Vary", "vary", ButtonStyle::Secondary));
+                    }
+                    if can_inpaint {
+                        extras.push(button("Inpaint", "inpaint.1", ButtonStyle::Secondary));
+                    }
+                    let picture_rows = picture_rows(
+                        c.images.len(),
+                        c.base.grid.as_ref().map(|g| g.columns()),
+                        can_start_from,
+                        extras,
+                    );
 
                     // Create the final message, with:
-                    // - One row with a delete, restyle, and retry button.
-                    // - NxM rows of upscale buttons (up to 4x4).
+                    // - One row with a delete, restyle, retry, edit and help button.
+                    // - Rows of U and V buttons; see picture_rows.
                     let text = format_message(&status_data);
                     let image_url = urls[0].clone();
 
@@ -412,23 +884,10 @@ impl Handler {
                                 .add_embed(|e| e.image(&image_url))
                                 .content(text)
                                 .components(|c| {
-                                    let mut c = c.add_action_row(self.action_buttons.clone());
-                                    // Given a 2x3 gallery geometry, add 3 rows of 2 buttons each.
-                                    for y in 0..gallery_geometry.1 {
-                                        let mut row = CreateActionRow::default();
-                                        for x in 0..gallery_geometry.0 {
-                                            let index = y * gallery_geometry.0 + x + 1;
-                                            row = row
-                                                .create_button(|b| {
-                                                    b.style(ButtonStyle::Primary)
-                                                        .label(format!("U{}", index))
-                                                        .custom_id(format!("upscale.{}", index))
-                                                })
-                                                .clone();
-                                        }
-                                        c = c.add_action_row(row);
-                                    }
-                                    c
+                                    let c = c.add_action_row(self.action_buttons.clone());
+                                    picture_rows
+                                        .into_iter()
+                                        .fold(c, |c, row| c.add_action_row(row))
                                 })
                         })
                         .await
@@ -453,55 +912,45 @@ impl Handler {
     ) -> Result<()> {
         let _ = interaction.defer(&ctx.http).await;
         let is_private = interaction.guild_id.is_none();
-        // Basically just editing.
-        match interaction.data.custom_id.as_str() {
+        // Both modals end in a new command line.
+        let raw = match interaction.data.custom_id.as_str() {
             "edit.submit" => {
                 debug!("Received edit submission");
-                // Grab the prompt from the input text.
-                let prompt = interaction
-                    .data
-                    .components
-                    .first()
-                    .context("Expected a component")?
-                    .components
-                    .first()
-                    .context("Expected a component")?;
-                match prompt {
-                    ActionRowComponent::InputText(text) => {
-                        let text = text.value.clone();
-                        // Now we can generate.
-                        let request = generator::UserRequest {
-                            user: interaction.user.to_string(),
-                            raw: text,
-                            dream: None,
-                            source: generator::Source::Discord,
-                            comment: None,
-                            private: is_private,
-                        };
-                        let statusbox = interaction
-                            .create_followup_message(&ctx.http, |message| {
-                                message.content("Dreaming...")
-                            })
-                            .await
-                            .context("Creating initial statusbox")?;
-                        self.do_generate(
-                            ctx,
-                            statusbox,
-                            request,
-                            interaction.user.mention(),
-                            is_private,
-                        )
-                        .await?;
-                    }
-                    _ => {
-                        bail!("Expected an input component");
-                    }
-                }
+                modal_value(interaction, "edit.prompt").context("Expected a prompt")?
+            }
+            "inpaint.submit" => {
+                debug!("Received inpaint submission");
+                let prompt =
+                    modal_value(interaction, "inpaint.prompt").context("Expected a prompt")?;
+                let mask = modal_value(interaction, "inpaint.mask").context("Expected a mask")?;
+                format!("{prompt} --mask {}", parser::quote_value(mask.trim()))
             }
             unknown => {
                 bail!("Unknown modal submission: {}", unknown);
             }
-        }
+        };
+        // Now we can generate.
+        let request = generator::UserRequest {
+            user: interaction.user.to_string(),
+            raw,
+            dream: None,
+            source: generator::Source::Discord,
+            comment: None,
+            parent: None,
+            settings: None,
+            private: is_private,
+        };
+        let (statusbox, is_private) = self
+            .followup_statusbox(ctx, interaction, &interaction.user, interaction.guild_id)
+            .await?;
+        self.do_generate(
+            ctx,
+            statusbox,
+            request,
+            interaction.user.mention(),
+            is_private,
+        )
+        .await?;
         Ok(())
     }
 
@@ -572,6 +1021,25 @@ impl Handler {
             return Ok(());
         }
         match command {
+            "cancel" => {
+                let _ = component.defer(&ctx.http).await;
+                let job = params.parse().context("Expected a job id")?;
+                let cancelled = self
+                    .context
+                    .image_generator
+                    .cancel(
+                        Some(job),
+                        &component.user.to_string(),
+                        generator::Source::Discord,
+                        false,
+                    )
+                    .await?;
+                if cancelled == 0 {
+                    component
+                        .followup(ctx, "That's not yours, or it's already done.", true)
+                        .await?;
+                }
+            }
             "delete" => {
                 // Just delete it.
                 // Maybe later we can use a modal to verify.
@@ -582,39 +1050,141 @@ impl Handler {
                     .context("Deleting potentially NSFW message")?;
             }
             "upscale" => {
-                // TODO: Actually do upscaling.
                 let _ = component.defer(&ctx.http).await;
                 debug!("Upscaling: {:?}", params);
-                // Anyway, this sums up as "Find the url in the message, and replace it with the requested invidual image.
-                // However, Discord sometimes fails to install an embed. As a fallback we'll look for a textual URL.
-                let url = {
-                    // Is there an embed?
-                    if let Some(embed) = component.message.embeds.first() {
-                        if let Some(url) = embed.image.as_ref() {
-                            url.url.as_ref()
-                        } else {
-                            // Is there a URL in the content?
-                            let content = &component.message.content;
-                            if let Some(url) = utils::extract_url(content) {
-                                url
-                            } else {
-                                bail!("expected a URL in the message");
+                let (picture, uuid, _, mut request) =
+                    self.chosen_picture(component, params).await?;
+                let (can_upscale, can_inpaint) = self
+                    .context
+                    .config
+                    .with_config(|config| {
+                        config
+                            .models
+                            .get(&request.model_name)
+                            .map_or((false, false), |m| {
+                                (m.upscale_workflow.is_some(), m.inpaint_workflow.is_some())
+                            })
+                    })
+                    .await;
+                if !can_upscale {
+                    // Nothing better to offer than the picture on its own.
+                    component
+                        .create_followup_message(&ctx.http, |message| {
+                            message.content(&picture);
+                            if can_inpaint {
+                                message.components(|c| {
+                                    c.create_action_row(|row| {
+                                        row.create_button(|b| {
+                                            b.style(ButtonStyle::Secondary)
+                                                .label("Inpaint")
+                                                .custom_id("inpaint")
+                                        })
+                                    })
+                                });
                             }
-                        }
-                    } else {
-                        bail!("expected to find an embed or a URL in the message");
-                    }
+                            message
+                        })
+                        .await
+                        .context("Sending new message")?;
+                    return Ok(());
+                }
+                // A new batch of one, starting from the chosen picture.
+                request.count = 1;
+                request.variation = None;
+                request.init = Some(generator::InitImage {
+                    url: picture,
+                    strength: generator::DEFAULT_UPSCALE_STRENGTH,
+                    mask: None,
+                });
+                request.upscale = true;
+                self.generate_child(ctx, component, request, uuid).await?;
+            }
+            "variant" => {
+                let _ = component.defer(&ctx.http).await;
+                debug!("Making variants of: {:?}", params);
+                let (picture, uuid, parent, mut request) =
+                    self.chosen_picture(component, params).await?;
+                // As many as the batch had, with fresh seeds, drawn over the chosen picture.
+                // Grid cells and upscales are one picture each, which is too few to choose from.
+                if parent.grid.is_none() && !parent.upscale {
+                    request.count = parent.count;
+                } else {
+                    request.count = generator::ParsedRequest::default().count;
+                }
+                request.seed = rand::random();
+                request.variation = None;
+                request.upscale = false;
+                request.init = Some(generator::InitImage {
+                    url: picture,
+                    strength: generator::DEFAULT_VARIANT_STRENGTH,
+                    mask: None,
+                });
+                self.generate_child(ctx, component, request, uuid).await?;
+            }
+            "inpaint" => {
+                // Either a batch of one, or just the picture's URL.
+                let url = match component
+                    .message
+                    .embeds
+                    .first()
+                    .and_then(|e| e.image.as_ref())
+                {
+                    Some(image) => utils::get_individual_url(&image.url, params)?,
+                    None => utils::extract_url(&component.message.content)
+                        .context("expected a URL in the message")?
+                        .to_string(),
                 };
-
-                let replacement = utils::get_individual_url(url, params)?;
-                debug!("Replacing {} with {}", url, replacement);
-                // Send a new message with the new url.
+                let mut request = self
+                    .context
+                    .db
+                    .get_parameters_for_batch(batch_uuid(&url)?)
+                    .await?
+                    .context("No generation parameters found for this picture.")?;
+                // Start from this picture, with everything else as it was. The mask is asked
+                // for separately; modals can't take attachments, so it has to be a link.
+                request.grid = None;
+                request.upscale = false;
+                request.init = Some(generator::InitImage {
+                    url,
+                    strength: generator::DEFAULT_INPAINT_STRENGTH,
+                    mask: None,
+                });
+                let raw = request.to_command_line();
                 component
-                    .create_followup_message(&ctx.http, |message| message.content(replacement))
+                    .create_interaction_response(&ctx.http, |f| {
+                        f.kind(InteractionResponseType::Modal)
+                            .interaction_response_data(|data| {
+                                data.title("Inpaint")
+                                    .custom_id("inpaint.submit")
+                                    .components(|c| {
+                                        c.create_action_row(|f| {
+                                            f.add_input_text({
+                                                let mut t = CreateInputText::default();
+                                                t.placeholder("What to draw in the masked part")
+                                                    .value(&raw)
+                                                    .custom_id("inpaint.prompt")
+                                                    .style(component::InputTextStyle::Paragraph)
+                                                    .label("Prompt");
+                                                t
+                                            })
+                                        })
+                                        .create_action_row(|f| {
+                                            f.add_input_text({
+                                                let mut t = CreateInputText::default();
+                                                t.placeholder("Link to a black and white picture")
+                                                    .custom_id("inpaint.mask")
+                                                    .style(component::InputTextStyle::Short)
+                                                    .label("Mask (white is redrawn)");
+                                                t
+                                            })
+                                        })
+                                    })
+                            })
+                    })
                     .await
-                    .context("Sending new message")?;
+                    .context("Sending inpaint modal")?;
             }
-            "retry" | "restyle" | "edit" => {
+            "retry" | "restyle" | "edit" | "vary" => {
                 // First, we need to retrieve the original generation parameters from the database.
                 // All we have to work with is the UUID. That should be plenty.
                 let url = component
@@ -628,14 +1198,7 @@ impl Handler {
                     .url
                     .clone();
                 debug!("Retrieving parameters for {}", url);
-                // The URL contains the UUID in the final component.
-                let uuid = url
-                    .rsplit_once('/')
-                    .context("Expected a URL with a UUID")?
-                    .1
-                    .split_once('.')
-                    .context("Expected a URL with an index")?
-                    .0;
+                let uuid = batch_uuid(&url)?;
                 debug!("UUID: {}", uuid);
                 // Now we can retrieve the parameters.
                 let request = self.context.db.get_parameters_for_batch(uuid).await?;
@@ -647,19 +1210,20 @@ impl Handler {
                         request.supporting_prompt = generator::choose_random_style().to_string();
                         request.base.dream = None;
                     }
-                    // Recreate the raw prompt.
-                    // TODO: Really we should just pass the *already parsed* request in.
-                    let (width, height) = utils::simplify_fraction(request.width, request.height);
-                    let mut raw = format!(
-                        "{} --ar {}:{} --model {}",
-                        request.linguistic_prompt, width, height, request.model_name
-                    );
-                    if request.linguistic_prompt != request.supporting_prompt {
-                        raw.push_str(&format!(" --style {}", request.supporting_prompt));
-                    }
-                    if !request.negative_prompt.is_empty() {
-                        raw.push_str(&format!(" --no {}", request.negative_prompt));
+                    if command == "vary" {
+                        // Same picture, a little different. The batch's seed stays, and so
+                        // does its strength if it was already a variation.
+                        let strength = request
+                            .variation
+                            .map_or(generator::DEFAULT_VARIATION_STRENGTH, |v| v.strength);
+                        request.variation = Some(generator::Variation {
+                            seed: rand::random(),
+                            strength,
+                        });
                     }
+                    // Recreate the raw prompt. This round-trips exactly, so the retry uses
+                    // the same seed, count, resolution and so on.
+                    let raw = request.to_command_line();
                     if command == "edit" {
                         component
                             .create_interaction_response(&ctx.http, |f| {
@@ -689,13 +1253,9 @@ impl Handler {
                     } else {
                         let _ = component.defer(&ctx.http).await;
                         request.base.raw = raw;
-                        let statusbox = component
-                            .create_followup_message(&ctx.http, |message| {
-                                message.content("Dreaming...")
-                            })
-                            .await
-                            .context("Creating initial statusbox")?;
-                        let is_private = component.guild_id.is_none();
+                        let (statusbox, is_private) = self
+                            .followup_statusbox(ctx, component, &component.user, component.guild_id)
+                            .await?;
                         self.do_generate(
                             ctx,
                             statusbox,
@@ -716,6 +1276,102 @@ impl Handler {
         Ok(())
     }
 
+    /// Finds the picture a U or V button refers to.
+    /// Returns its URL, its batch's UUID, the batch's settings, and the settings for just
+    /// that picture.
+    async fn chosen_picture(
+        &self,
+        component: &MessageComponentInteraction,
+        params: &str,
+    ) -> Result<(
+        String,
+        String,
+        generator::ParsedRequest,
+        generator::ParsedRequest,
+    )> {
+        // Find the url in the message, and replace it with the requested invidual image.
+        // However, Discord sometimes fails to install an embed. As a fallback we'll look for a textual URL.
+        let url = {
+            // Is there an embed?
+            if let Some(embed) = component.message.embeds.first() {
+                if let Some(url) = embed.image.as_ref() {
+                    url.url.as_ref()
+                } else {
+                    // Is there a URL in the content?
+                    let content = &component.message.content;
+                    if let Some(url) = utils::extract_url(content) {
+                        url
+                    } else {
+                        bail!("expected a URL in the message");
+                    }
+                }
+            } else {
+                bail!("expected to find an embed or a URL in the message");
+            }
+        };
+
+        let picture = utils::get_individual_url(url, params)?;
+        debug!("Replacing {} with {}", url, picture);
+        let uuid = batch_uuid(&picture)?.to_string();
+        let parent = self
+            .context
+            .db
+            .get_parameters_for_batch(&uuid)
+            .await?
+            .context("No generation parameters found for this batch.")?;
+        let index = params
+            .parse::<usize>()
+            .ok()
+            .filter(|i| *i >= 1)
+            .context("Expected a picture number")?;
+        // Grid pictures each have their own settings. Otherwise, without a variation, each
+        // picture in a batch had the next seed along.
+        let request = match &parent.grid {
+            Some(grid) => generator::ParsedRequest {
+                base: parent.base.clone(),
+                ..grid
+                    .cells
+                    .get(index - 1)
+                    .context("No such picture in the grid")?
+                    .clone()
+            },
+            None if parent.variation.is_none() => generator::ParsedRequest {
+                seed: parent.seed.wrapping_add(index as u32 - 1),
+                ..parent.clone()
+            },
+            None => parent.clone(),
+        };
+        let request = generator::ParsedRequest {
+            grid: None,
+            ..request
+        };
+        Ok((picture, uuid, parent, request))
+    }
+
+    /// Queues a new batch made from one of another batch's pictures.
+    async fn generate_child(
+        &self,
+        ctx: &Context,
+        component: &MessageComponentInteraction,
+        mut request: generator::ParsedRequest,
+        parent: String,
+    ) -> Result<()> {
+        request.base.raw = request.to_command_line();
+        request.base.dream = None;
+        request.base.parent = Some(parent);
+        let (statusbox, is_private) = self
+            .followup_statusbox(ctx, component, &component.user, component.guild_id)
+            .await?;
+        self.do_generate(
+            ctx,
+            statusbox,
+            request.base,
+            component.user.mention(),
+            is_private,
+        )
+        .await
+    }
+
     fn new(context: BotContext) -> Self {
         let action_buttons = CreateActionRow::default()
             .create_button(|b| {
@@ -910,11 +1566,65 @@ impl EventHandler for Handler {
                     }
                     o
                 })
+                .create_option(|o| {
+                    o.name("image")
+                     .description("Picture to start from, as with --init")
+                     .kind(CommandOptionType::Attachment)
+                     .required(false)
+                })
+                .create_option(|o| {
+                    o.name("mask")
+                     .description("Black and white picture of what to redraw in the image; white is redrawn")
+                     .kind(CommandOptionType::Attachment)
+                     .required(false)
+                })
+            })
+             // preset
+             // - command (text)
+             .create_application_command(|c| {
+                c.name(cname("preset"))
+                 .description("Save, list, share and delete named prompt presets")
+                 .create_option(|o| {
+                    o.name("command")
+                     .description("e.g. \"save moody --style film noir\", \"list\", \"share moody\"")
+                     .kind(CommandOptionType::String)
+                     .required(false)
+                 })
+            })
+             // queue
+             .create_application_command(|c| {
+                c.name(cname("queue"))
+                 .description("See what's queued and generating, and when it should be done")
+            })
+             // settings
+             // - change (text)
+             .create_application_command(|c| {
+                c.name(cname("settings"))
+                 .description("View or change your default settings")
+                 .create_option(|o| {
+                    o.name("change")
+                     .description("e.g. \"model flux\", \"clear model\" or \"clear\". Leave empty to view.")
+                     .kind(CommandOptionType::String)
+                     .required(false)
+                 })
             })
         }).await;
 
         if let Err(e) = commands {
             panic!("Error registering commands: {:?}", e);
         }
+
+        // Requests from before a restart carry on in their old status messages.
+        let resumed = self
+            .context
+            .image_generator
+            .take_resumed(|r| matches!(r, ReplyTo::Discord { .. }))
+            .await;
+        let resumes = resumed.into_iter().map(|job| async {
+            if let Err(e) = self.resume(&ctx, job).await {
+                error!("Error resuming a request: {:?}", e);
+            }
+        });
+        futures::future::join_all(resumes).await;
     }
 }
@@ -2,10 +2,12 @@
 // Each is logically a superset of the previous, and the final struct includes the output.
 
 use std::{
+    collections::{HashMap, HashSet},
     fmt::Debug,
+    net::IpAddr,
     pin::Pin,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicU32, AtomicU64, Ordering},
         Arc,
     },
     time::Duration,
@@ -16,29 +18,74 @@ use async_stream::try_stream;
 use futures::{
     channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
     select,
-    stream::{empty, FusedStream},
+    stream::FusedStream,
     FutureExt, SinkExt, Stream, StreamExt,
 };
 use lazy_static::lazy_static;
 use log::{debug, info, trace, warn};
-use rand::seq::SliceRandom;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 use reqwest::RequestBuilder;
 use serde::{Deserialize, Serialize};
-use tokio::sync::RwLock;
-use tokio_retry::{strategy::ExponentialBackoff, Retry};
+use serde_json::json;
+use tokio::sync::{oneshot, watch, RwLock};
+use tokio_retry::{strategy::ExponentialBackoff, RetryIf};
 use tokio_tungstenite as ws;
 use uuid::Uuid;
 
 use crate::{
-    config::{BotBackend, BotConfig, BotConfigModule},
+    backends::{self, Pool},
+    config::{BotConfig, BotConfigModule, BotModelConfig, ComfyServer, ModelCapabilities},
     db::DatabaseModule,
     gpt::PromptGeneratorModule,
+    lora,
+    parser::{self, CommandLine, GridAxis, ParseError, Spanned},
+    scheduler::{self, Eta, FairShare, Timings},
+    settings::UserSettings,
+    telemetry::{Attempt, Outcome},
     utils,
+    weighting::{self, PromptSyntax},
+    wildcards::Wildcards,
+    workflow::{self, Workflow},
 };
 
+/// Grids are generated one image at a time, so they get to be a little bigger than batches.
+const MAX_GRID_CELLS: usize = 16;
+
 /// Used to determine if a model name is close enough to a real model name.
 /// And for the tests.
-const SIMILARITY_THRESHOLD: f64 = 0.7;
+pub(crate) const SIMILARITY_THRESHOLD: f64 = 0.7;
+
+/// Variation strength for --variation-seed on its own, and for the Vary button.
+/// Enough to notice, not enough to lose the picture.
+pub const DEFAULT_VARIATION_STRENGTH: f32 = 0.15;
+
+/// Strength for --init without --strength. Keeps the composition, redoes the details.
+pub const DEFAULT_INIT_STRENGTH: f32 = 0.6;
+
+/// Strength for --mask without --strength. The masked part is meant to change completely.
+pub const DEFAULT_INPAINT_STRENGTH: f32 = 1.0;
+
+/// Strength for the V buttons on Discord: the same picture, drawn again a little differently.
+pub const DEFAULT_VARIANT_STRENGTH: f32 = 0.4;
+
+/// Strength for --upscale without --strength. Sharper details, same picture.
+pub const DEFAULT_UPSCALE_STRENGTH: f32 = 0.35;
+
+/// Init images are re-encoded before they go to the backend, so this is mostly about
+/// not downloading something silly.
+const MAX_INIT_IMAGE_BYTES: u64 = 20 * 1024 * 1024;
+
+/// How many redirects we follow when fetching an init image.
+const MAX_INIT_IMAGE_REDIRECTS: usize = 5;
+
+/// The least time between previews of a batch.
+const PREVIEW_INTERVAL: Duration = Duration::from_secs(4);
+
+/// How many servers a job may lose before we give up on it.
+const MAX_FAILOVERS: u32 = 3;
+
+/// How much of each prompt !queue shows.
+const QUEUE_PROMPT_CHARS: usize = 50;
 
 /// generate() is the entry point for the generator.
 /// It returns a stream of these.
@@ -50,17 +97,128 @@ pub enum GenerationEvent {
     /// Parsing has completed successfully
     /// and the request is ready to be sent to the backend.
     Parsed(ParsedRequest),
-    /// The request is queued for processing, at position N.
-    Queued(u32),
-    /// Generation has started, and is N% complete (0-100).
-    Generating(u32),
+    /// The request is queued for processing, at position N, and should be done around then.
+    /// There's no ETA if none of the servers that could run it are up.
+    Queued(u32, Option<Eta>),
+    /// Generation has started, and has got this far.
+    Generating(Progress),
+    /// A rough look at the picture being generated, now and then while sampling.
+    Preview(JpegBlob),
+    /// The request uses flags the model will ignore. Sent after Parsed, once per problem.
+    Warning(String),
     /// Generation has completed.
     Completed(CompletedRequest),
+    /// The request has reached the generator, and can be cancelled by this id from now on.
+    Accepted(JobId),
+    /// The request was cancelled. Nothing more will come.
+    Cancelled,
     /// Something broke.
     /// The generator has stopped.
     Error(anyhow::Error),
 }
 
+/// Identifies a request from the time it reaches the generator, e.g. for cancelling it.
+pub type JobId = u64;
+
+/// Why a job stopped, when it was asked to.
+#[derive(Debug)]
+struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// What ImageGeneratorModule::run is asked to do.
+enum Command {
+    Generate(JobId, Box<ParsedRequest>, UnboundedSender<GenerationEvent>),
+    Cancel(CancelRequest, oneshot::Sender<usize>),
+    Queue(oneshot::Sender<Vec<QueueEntry>>),
+}
+
+/// A request in the queue, or generating, as !queue shows it.
+#[derive(Clone, Debug)]
+pub struct QueueEntry {
+    pub id: JobId,
+    pub request: ParsedRequest,
+    pub running: bool,
+    pub eta: Option<Eta>,
+}
+
+impl std::fmt::Display for QueueEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let request = &self.request;
+        let pictures = if request.count == 1 {
+            "picture"
+        } else {
+            "pictures"
+        };
+        write!(
+            f,
+            "#{} {}: {}, {} {pictures}",
+            self.id, request.base.user, request.model_name, request.count
+        )?;
+        if request.base.private {
+            write!(f, ", private")?;
+        } else {
+            let prompt = &request.linguistic_prompt;
+            match prompt.char_indices().nth(QUEUE_PROMPT_CHARS) {
+                Some((cut, _)) => write!(f, ", \"{}…\"", &prompt[..cut])?,
+                None => write!(f, ", \"{prompt}\"")?,
+            }
+        }
+        match (self.running, self.eta) {
+            (true, Some(eta)) => write!(f, " — generating, {eta}"),
+            (true, None) => write!(f, " — generating"),
+            (false, Some(eta)) => write!(f, " — {eta}"),
+            (false, None) => write!(f, " — waiting for a server"),
+        }
+    }
+}
+
+/// Which jobs to cancel: the one with the id, or all of the user's.
+/// Users can only cancel their own jobs, unless `anyone` is set.
+struct CancelRequest {
+    id: Option<JobId>,
+    user: String,
+    source: Source,
+    anyone: bool,
+}
+
+impl CancelRequest {
+    fn matches(&self, id: JobId, request: &ParsedRequest) -> bool {
+        self.id.is_none_or(|wanted| wanted == id)
+            && (self.anyone
+                || (request.base.user == self.user && request.base.source == self.source))
+    }
+}
+
+/// How far along a request is.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Progress {
+    /// 0-100, over the whole request.
+    pub percent: u32,
+    /// What the backend is doing, e.g. "KSampler" or "VAE Decode", if we know.
+    pub stage: Option<String>,
+    /// Steps done out of the total, for stages that have steps.
+    pub steps: Option<(u32, u32)>,
+}
+
+impl std::fmt::Display for Progress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}%", self.percent)?;
+        match (&self.stage, self.steps) {
+            (Some(stage), Some((step, steps))) => write!(f, " ({stage}, step {step}/{steps})"),
+            (Some(stage), None) => write!(f, " ({stage})"),
+            (None, Some((step, steps))) => write!(f, " (step {step}/{steps})"),
+            (None, None) => std::fmt::Result::Ok(()),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct UserRequest {
     // The name of the user who made the request.
@@ -71,6 +229,52 @@ pub struct UserRequest {
     #[serde(default)]
     pub private: bool,
     pub comment: Option<String>, // Sometimes filled in by GPT-4.
+    /// The batch this one was made from, e.g. by upscaling one of its pictures.
+    #[serde(default)]
+    pub parent: Option<String>,
+    /// The settings it was made with. Retries and other replays use these, rather than
+    /// whatever the user has now, so that they come out the same.
+    #[serde(default)]
+    pub settings: Option<UserSettings>,
+}
+
+/// File format for the finished pictures. Chosen with !settings.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Jpeg,
+    Png,
+    Webp,
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "jpeg",
+            OutputFormat::Png => "png",
+            OutputFormat::Webp => "webp",
+        }
+    }
+}
+
+impl std::fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.extension())
+    }
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(OutputFormat::Jpeg),
+            "png" => Ok(OutputFormat::Png),
+            "webp" => Ok(OutputFormat::Webp),
+            _ => bail!("Format must be jpeg, png or webp, not {s:?}"),
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -80,6 +284,23 @@ pub enum Source {
     Unknown,
 }
 
+/// Where a request's results go. It's kept with the queue, so they still get there after a
+/// restart.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ReplyTo {
+    /// A channel, or a nick for private messages, on the IRC server with this address.
+    Irc { server: String, target: String },
+    /// The status message on Discord, which shows how it's going.
+    Discord { channel: u64, message: u64 },
+}
+
+/// A request that was queued when the bot last stopped, and is going again.
+pub struct Resumed {
+    pub request: ParsedRequest,
+    pub reply_to: ReplyTo,
+    pub events: Pin<Box<dyn Stream<Item = GenerationEvent> + Send + Sync>>,
+}
+
 // ParsedRequest adds the computed resolution, aesthetic values, etc.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ParsedRequest {
@@ -98,6 +319,99 @@ pub struct ParsedRequest {
     // Width and height cannot be set directly; they are derived from --ar.
     pub width: u32,
     pub height: u32,
+    #[serde(default)]
+    pub grid: Option<Grid>, // --xy
+    #[serde(default)]
+    pub output_format: OutputFormat, // From settings only.
+    // None means the model's default.
+    #[serde(default)]
+    pub sampler: Option<String>, // --sampler
+    #[serde(default)]
+    pub scheduler: Option<String>, // --scheduler
+    #[serde(default)]
+    pub variation: Option<Variation>, // --variation-seed, --variation-strength
+    #[serde(default)]
+    pub init: Option<InitImage>, // --init, --strength
+    #[serde(default)]
+    pub upscale: bool, // --upscale
+}
+
+/// Blends a little noise from a second seed into every image, for small changes around a
+/// picture we like. Every image keeps the request's seed, and gets its own variation seed.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Variation {
+    pub seed: u32,
+    /// 0 is the original picture, 1 is a whole new one.
+    pub strength: f32,
+}
+
+/// A picture to start from, instead of noise. Discord attachments end up here too.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InitImage {
+    pub url: String,
+    /// How much of the picture gets redrawn. 0 keeps it as it is, 1 ignores it.
+    pub strength: f32,
+    /// For inpainting; only the masked part is redrawn.
+    #[serde(default)]
+    pub mask: Option<Mask>,
+}
+
+/// Which part of an init image to redraw.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mask {
+    /// A separate picture, where white is redrawn and black is kept.
+    Url(String),
+    /// The transparent part of the init image.
+    Alpha,
+}
+
+/// What render_samples calls the pictures it pretends to start from.
+pub const SAMPLE_INIT_IMAGE: &str = "sample-init.png";
+pub const SAMPLE_MASK_IMAGE: &str = "sample-mask.png";
+
+/// Names the backend gave our pictures, once they're uploaded.
+#[derive(Clone, Debug, PartialEq)]
+struct UploadedImages {
+    init: String,
+    mask: Option<String>,
+}
+
+/// An X/Y grid: one picture per combination of axis values, all with the same seed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Grid {
+    pub x: GridAxis,
+    pub y: Option<GridAxis>,
+    /// One request per cell, row by row. Each generates a single image.
+    pub cells: Vec<ParsedRequest>,
+}
+
+impl Grid {
+    pub fn axes(&self) -> impl Iterator<Item = &GridAxis> {
+        std::iter::once(&self.x).chain(&self.y)
+    }
+
+    pub fn columns(&self) -> usize {
+        self.x.values.len()
+    }
+
+    /// Labels for the contact sheet: one per column, and one per row if there's a Y axis.
+    /// Search-and-replace values are shown as-is, since the name would just be noise.
+    pub fn labels(&self) -> (Vec<String>, Vec<String>) {
+        fn label(axis: &GridAxis) -> Vec<String> {
+            axis.values
+                .iter()
+                .map(|v| match axis.name.as_str() {
+                    "S/R" => v.clone(),
+                    name => format!("{name}={v}"),
+                })
+                .collect()
+        }
+        (
+            label(&self.x),
+            self.y.as_ref().map(label).unwrap_or_default(),
+        )
+    }
 }
 
 impl Default for ParsedRequest {
@@ -109,6 +423,8 @@ impl Default for ParsedRequest {
                 raw: "".to_string(),
                 source: Source::Unknown,
                 comment: None,
+                parent: None,
+                settings: None,
                 private: true,
             },
             model_name: "default".to_string(),
@@ -124,6 +440,13 @@ impl Default for ParsedRequest {
             seed: 0,
             width: 1024,
             height: 1024,
+            grid: None,
+            output_format: OutputFormat::default(),
+            sampler: None,
+            scheduler: None,
+            variation: None,
+            init: None,
+            upscale: false,
         }
     }
 }
@@ -131,7 +454,34 @@ impl Default for ParsedRequest {
 pub struct CompletedRequest {
     pub base: ParsedRequest,
     pub images: Vec<JpegBlob>,
+    // One per image, after wildcard expansion.
+    pub prompts: Vec<ImagePrompts>,
     pub uuid: Uuid,
+    /// How long the server spent generating it, not counting its queue.
+    pub gpu_time: Duration,
+}
+
+/// The prompts a single image was actually generated from.
+/// These differ from the batch's prompts if the request used wildcards.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImagePrompts {
+    pub linguistic: String,
+    pub supporting: String,
+    pub negative: String,
+}
+
+impl CompletedRequest {
+    /// The overview picture shown to the user. Grids get a labeled contact sheet.
+    pub fn overview(&self) -> Result<Vec<u8>> {
+        let format = self.base.output_format;
+        match &self.base.grid {
+            Some(grid) => {
+                let (x_labels, y_labels) = grid.labels();
+                utils::labeled_overview_of_pictures(&self.images, &x_labels, &y_labels, format)
+            }
+            None => utils::overview_of_pictures(&self.images, format),
+        }
+    }
 }
 
 impl Debug for CompletedRequest {
@@ -139,6 +489,7 @@ impl Debug for CompletedRequest {
         f.debug_struct("CompletedRequest")
             .field("base", &self.base)
             .field("images", &self.images.len().to_string())
+            .field("gpu_time", &self.gpu_time)
             .finish()
     }
 }
@@ -169,130 +520,130 @@ impl ParsedRequest {
         Ok((width, height))
     }
 
-    pub fn from_request(config: &BotConfig, request: UserRequest) -> Result<Self> {
+    pub fn from_request(
+        config: &BotConfig,
+        mut request: UserRequest,
+        settings: &UserSettings,
+        presets: &HashMap<String, String>,
+    ) -> Result<Self> {
         // This parses the !dream IRC/Discord command.
-        // It's basically a command line, but with special handling for --style and --no.
-        // TODO: Load some of this from config.json.
+        // The syntax is handled by the parser module; here we apply the user's defaults and
+        // the config.
+        let settings = request
+            .settings
+            .get_or_insert_with(|| settings.clone())
+            .clone();
+        request.private |= settings.is_private();
+        let mut cmd = parser::parse_with_presets(&request.raw, presets)?;
+        settings.apply(&mut cmd);
+        let output_format = settings.format.unwrap_or_default();
+        if cmd.xy.is_empty() {
+            let parsed = Self::from_command_line(config, request, &cmd)?;
+            return Ok(Self {
+                output_format,
+                ..parsed
+            });
+        }
+
+        // It's a grid. Every cell is parsed as if the axis values had been given as options,
+        // so they get the same validation as everything else.
+        if let Some(count) = &cmd.count {
+            return Err(ParseError::new(
+                &request.raw,
+                count.span.clone(),
+                "--count can't be combined with --xy; the grid decides how many pictures there are",
+            )
+            .into());
+        }
+        let axes = std::mem::take(&mut cmd.xy);
+        let cell_count = axes.iter().map(|a| a.value.values.len()).product::<usize>();
+        if cell_count > MAX_GRID_CELLS {
+            let span = axes[0].span.start..axes.last().unwrap().span.end;
+            return Err(ParseError::new(
+                &request.raw,
+                span,
+                format!("That's {cell_count} pictures; grids are limited to {MAX_GRID_CELLS}"),
+            )
+            .into());
+        }
+        // Everything shares one seed, unless the seed is an axis.
+        cmd.seed.get_or_insert(Spanned {
+            value: Self::default_seed(),
+            span: 0..0,
+        });
+        cmd.count = Some(Spanned {
+            value: 1,
+            span: 0..0,
+        });
+        let (x, y) = (&axes[0], axes.get(1));
+        let rows = match y {
+            Some(y) => y.value.values.iter().map(Some).collect(),
+            None => vec![None],
+        };
+        let mut cells = vec![];
+        for y_value in rows {
+            let row = match (y, y_value) {
+                (Some(y), Some(value)) => cmd.with_axis_value(&request.raw, y, value)?,
+                _ => cmd.clone(),
+            };
+            for x_value in &x.value.values {
+                let cell = row.with_axis_value(&request.raw, x, x_value)?;
+                cells.push(Self::from_command_line(config, request.clone(), &cell)?);
+            }
+        }
+        let mut parsed = Self::from_command_line(config, request, &cmd)?;
+        parsed.count = cells.len() as u32;
+        parsed.output_format = output_format;
+        parsed.grid = Some(Grid {
+            x: x.value.clone(),
+            y: y.map(|y| y.value.clone()),
+            cells,
+        });
+        Ok(parsed)
+    }
+
+    /// The default seed is the POSIX timestamp.
+    fn default_seed() -> u32 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32
+    }
+
+    /// Applies the config to a parsed command line.
+    fn from_command_line(
+        config: &BotConfig,
+        request: UserRequest,
+        cmd: &CommandLine,
+    ) -> Result<Self> {
+        let error_at = |span: std::ops::Range<usize>, message: String| {
+            anyhow::Error::new(ParseError::new(&request.raw, span, message))
+        };
         let mut parsed = ParsedRequest {
-            // Default seed is the POSIX timestamp.
             base: request.clone(),
-            seed: std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as u32,
+            seed: Self::default_seed(),
             ..Default::default()
         };
-        // Parsing outputs.
-        let mut linguistic_prompt = vec![];
-        let mut supporting_prompt = vec![];
-        let mut negative_prompt = vec![];
-        let mut override_wh = None;
-
-        // Parsing state.
-        let mut last_option = None;
-        let mut last_value = None;
-        let mut reading_supporting_prompt = false;
-        let mut reading_negative_prompt = false;
-
-        // For a final pre-processing step, turn — (em dash) into -- (double dash).
-        // Because Apple.
-        let raw = request.raw.replace('—', "--");
-        let mut ar = "1:1";
-
-        for token in raw.split_whitespace() {
-            let mut add_to_prompt = |token| {
-                if reading_supporting_prompt {
-                    supporting_prompt.push(token);
-                } else if reading_negative_prompt {
-                    negative_prompt.push(token);
-                } else {
-                    linguistic_prompt.push(token);
-                }
-            };
-            if last_option.is_some() {
-                // Did we previously see an option (that was missing its argument)?
-                last_value = Some(token);
-            } else if token == "-" || token == "--" {
-                // These should be treated as part of the prompt.
-                add_to_prompt(token);
-            } else if token == "--no" {
-                reading_supporting_prompt = false;
-                reading_negative_prompt = true;
-            } else if token == "--style" {
-                reading_negative_prompt = false;
-                reading_supporting_prompt = true;
-            } else if token == "--prompt" {
-                reading_supporting_prompt = false;
-                reading_negative_prompt = false;
-            } else if let Some(token) = token.strip_prefix("--") {
-                // It's an option, but does it include the value?
-                let mut parts = token.splitn(2, '=');
-                last_option = Some(parts.next().unwrap());
-                last_value = parts.next();
-            } else if let Some(token) = token.strip_prefix('-') {
-                // It's a short-form option, which never includes the value.
-                last_option = Some(token);
-            } else {
-                // It's part of one of the prompts.
-                add_to_prompt(token);
-            }
-            // Did we just finish reading an option?
-            // First, check for flags that take no arguments.
-            match last_option {
-                Some("np") => {
-                    parsed.use_pos_default = false;
-                    last_option = None;
-                }
-                Some("nn") => {
-                    parsed.use_neg_default = false;
-                    last_option = None;
-                }
-                _ => {}
-            };
-            // Then, check for flags that do take arguments.
-            if let Some(value) = last_value {
-                match last_option.unwrap() {
-                    "model" | "m" => value.clone_into(&mut parsed.model_name),
-                    "style" | "s" => supporting_prompt.push(value),
-                    "scale" => {
-                        parsed.guidance_scale = value.parse().context("Scale must be a number")?
-                    }
-                    "aesthetic" | "a" => {
-                        parsed.aesthetic_scale =
-                            value.parse().context("Aesthetic scale must be a number")?
-                    }
-                    "steps" => {
-                        parsed.steps = Some(value.parse().context("Steps must be a number")?)
-                    }
-                    "count" | "c" => {
-                        parsed.count = value.parse().context("Count must be a number")?
-                    }
-                    "seed" => parsed.seed = value.parse().context("Seed must be a number")?,
-                    "w" => {
-                        let w = value.parse().context("Width must be an integer")?;
-                        if let Some((_, h)) = override_wh {
-                            override_wh = Some((w, h));
-                        } else {
-                            override_wh = Some((w, parsed.height));
-                        }
-                    }
-                    "h" => {
-                        let h = value.parse().context("Height must be an integer")?;
-                        if let Some((w, _)) = override_wh {
-                            override_wh = Some((w, h));
-                        } else {
-                            override_wh = Some((parsed.width, h));
-                        }
-                    }
-                    "ar" => {
-                        ar = value;
-                    }
-                    x => bail!("Unknown option: {}", x),
-                }
-                last_option = None;
-                last_value = None;
-            }
+        if let Some(model) = &cmd.model {
+            model.value.clone_into(&mut parsed.model_name);
+        }
+        parsed.use_pos_default = !cmd.no_pos_default;
+        parsed.use_neg_default = !cmd.no_neg_default;
+        if let Some(scale) = &cmd.guidance_scale {
+            parsed.guidance_scale = scale.value;
+        }
+        if let Some(aesthetic) = &cmd.aesthetic_scale {
+            parsed.aesthetic_scale = aesthetic.value;
+        }
+        parsed.steps = cmd.steps.as_ref().map(|s| s.value);
+        if let Some(count) = &cmd.count {
+            parsed.count = count.value;
         }
+        if let Some(seed) = &cmd.seed {
+            parsed.seed = seed.value;
+        }
+        // Errors about the model point at --model, if there was one.
+        let model_span = cmd.model.as_ref().map(|m| m.span.clone());
 
         // Do some final validation.
         if !config.models.contains_key(&parsed.model_name) {
@@ -308,11 +659,14 @@ impl ParsedRequest {
             }
             if let Some(best_model) = best_model {
                 if best_similarity < SIMILARITY_THRESHOLD {
-                    bail!(
+                    let message = format!(
                         "Unknown model: {}. Did you mean {}?",
-                        parsed.model_name,
-                        best_model
+                        parsed.model_name, best_model
                     );
+                    match model_span {
+                        Some(span) => return Err(error_at(span, message)),
+                        None => bail!(message),
+                    }
                 } else {
                     parsed.model_name.clone_from(best_model);
                 }
@@ -325,61 +679,439 @@ impl ParsedRequest {
             .models
             .get(&parsed.model_name)
             .context("no such model")?;
+        for (what, value, allowed, choice) in [
+            (
+                "sampler",
+                &cmd.sampler,
+                &model_config.samplers,
+                &mut parsed.sampler,
+            ),
+            (
+                "scheduler",
+                &cmd.scheduler,
+                &model_config.schedulers,
+                &mut parsed.scheduler,
+            ),
+        ] {
+            if let Some(value) = value {
+                *choice = Some(
+                    choose_from(&parsed.model_name, what, &value.value, allowed)
+                        .map_err(|e| error_at(value.span.clone(), e.to_string()))?,
+                );
+            }
+        }
 
-        if let Some((w, h)) = override_wh {
-            (parsed.width, parsed.height) = (w, h);
-        } else {
-            let base_resolution = model_config.base_resolution.unwrap_or(1024);
-            (parsed.width, parsed.height) =
-                ParsedRequest::parse_aspect_ratio(64, base_resolution, ar)
-                    .ok()
-                    .or_else(|| ParsedRequest::parse_aspect_ratio(64, 1024, "1:1").ok())
-                    .unwrap();
+        let capabilities = &model_config.capabilities;
+        if cmd.guidance_scale.is_none() {
+            parsed.guidance_scale = capabilities.default_cfg;
+        }
+        let is_link = |value: &str| value.starts_with("https://") || value.starts_with("http://");
+        if let Some(init) = &cmd.init {
+            if model_config.img2img_workflow.is_none() {
+                return Err(error_at(
+                    init.span.clone(),
+                    format!("{} can't start from a picture", parsed.model_name),
+                ));
+            }
+            if !is_link(&init.value) {
+                return Err(error_at(
+                    init.span.clone(),
+                    "--init needs a link to a picture".to_string(),
+                ));
+            }
+            let mask = match &cmd.mask {
+                Some(mask) if model_config.inpaint_workflow.is_none() => {
+                    return Err(error_at(
+                        mask.span.clone(),
+                        format!("{} can't inpaint", parsed.model_name),
+                    ));
+                }
+                Some(mask) if mask.value.eq_ignore_ascii_case("alpha") => Some(Mask::Alpha),
+                Some(mask) if is_link(&mask.value) => Some(Mask::Url(mask.value.clone())),
+                Some(mask) => {
+                    return Err(error_at(
+                        mask.span.clone(),
+                        "--mask needs a link to a black and white picture, or `alpha` to redraw the transparent part of --init".to_string(),
+                    ));
+                }
+                None => None,
+            };
+            let strength = match &cmd.strength {
+                Some(strength) if !(0.0..=1.0).contains(&strength.value) => {
+                    return Err(error_at(
+                        strength.span.clone(),
+                        "Strength must be between 0 and 1".to_string(),
+                    ));
+                }
+                Some(strength) => strength.value,
+                None if mask.is_some() => DEFAULT_INPAINT_STRENGTH,
+                None if cmd.upscale.is_some() => DEFAULT_UPSCALE_STRENGTH,
+                None => DEFAULT_INIT_STRENGTH,
+            };
+            if let Some(span) = &cmd.upscale {
+                if model_config.upscale_workflow.is_none() {
+                    return Err(error_at(
+                        span.clone(),
+                        format!("{} can't upscale", parsed.model_name),
+                    ));
+                }
+                if mask.is_some() {
+                    return Err(error_at(
+                        span.clone(),
+                        "Upscaling redraws the whole picture, so it can't use --mask".to_string(),
+                    ));
+                }
+                parsed.upscale = true;
+            }
+            parsed.init = Some(InitImage {
+                url: init.value.clone(),
+                strength,
+                mask,
+            });
+        } else if let Some(option) = cmd
+            .strength
+            .as_ref()
+            .map(|s| ("--strength", s.span.clone()))
+            .or_else(|| cmd.mask.as_ref().map(|m| ("--mask", m.span.clone())))
+            .or_else(|| cmd.upscale.as_ref().map(|span| ("--upscale", span.clone())))
+        {
+            let (flag, span) = option;
+            return Err(error_at(
+                span,
+                format!("{flag} needs a picture to start from; use --init"),
+            ));
+        }
+
+        if let Some(span) = cmd
+            .variation_seed
+            .as_ref()
+            .map(|s| s.span.clone())
+            .or_else(|| cmd.variation_strength.as_ref().map(|s| s.span.clone()))
+        {
+            // Whether that's possible depends on the workflow, which depends on --init.
+            if !parsed
+                .workflow_placeholders(model_config)?
+                .iter()
+                .any(|p| p == "__VARIATION_SEED__")
+            {
+                return Err(error_at(
+                    span,
+                    format!("{} doesn't support variations", parsed.model_name),
+                ));
+            }
+            let strength = match &cmd.variation_strength {
+                Some(strength) if !(0.0..=1.0).contains(&strength.value) => {
+                    return Err(error_at(
+                        strength.span.clone(),
+                        "Variation strength must be between 0 and 1".to_string(),
+                    ));
+                }
+                Some(strength) => strength.value,
+                None => DEFAULT_VARIATION_STRENGTH,
+            };
+            parsed.variation = Some(Variation {
+                seed: match &cmd.variation_seed {
+                    Some(seed) => seed.value,
+                    None => rand::random(),
+                },
+                strength,
+            });
+        }
+
+        let base_resolution = model_config.base_resolution.unwrap_or(1024);
+        let stride = capabilities.resolution_stride.max(1);
+        (parsed.width, parsed.height) = match &cmd.aspect_ratio {
+            Some(ar) => ParsedRequest::parse_aspect_ratio(stride, base_resolution, &ar.value)
+                .map_err(|e| error_at(ar.span.clone(), format!("{e}")))?,
+            None => ParsedRequest::parse_aspect_ratio(stride, base_resolution, "1:1")?,
+        };
+        // -w and -h override the aspect ratio.
+        for (dimension, value) in [
+            (&mut parsed.width, &cmd.width),
+            (&mut parsed.height, &cmd.height),
+        ] {
+            if let Some(value) = value {
+                if value.value < 64 || value.value < stride {
+                    return Err(error_at(
+                        value.span.clone(),
+                        format!(
+                            "Width and height must be at least {} pixels",
+                            stride.max(64)
+                        ),
+                    ));
+                }
+                // Same as for --ar, the model needs a multiple of its stride.
+                *dimension = value.value - value.value % stride;
+            }
         }
 
         // Final sanity checks.
-        if linguistic_prompt.is_empty() {
+        if cmd.linguistic_prompt.is_empty() {
             bail!("Linguistic prompt is required");
         }
-        if !(1.0..=80.0).contains(&parsed.guidance_scale) {
-            bail!("Scale must be between 1 and 80");
+        let wildcards = Wildcards::new(config);
+        let prompts = [
+            &cmd.linguistic_prompt,
+            &cmd.supporting_prompt,
+            &cmd.negative_prompt,
+        ];
+        for prompt in prompts {
+            if let Some((name, suggestion)) = wildcards.find_unknown(&prompt.join(" "))? {
+                let pattern = format!("__{name}__");
+                let start = request.raw.find(&pattern).unwrap_or_default();
+                let message = format!("Unknown wildcard {pattern}. Did you mean __{suggestion}__?");
+                return Err(error_at(start..start + pattern.len(), message));
+            }
+        }
+        let all_prompts = prompts.map(|p| p.join(" ")).join(" ");
+        lora::resolve(config, &parsed.model_name, &all_prompts).map_err(|e| {
+            let start = request.raw.find(&e.tag).unwrap_or_default();
+            error_at(start..start + e.tag.len(), e.message)
+        })?;
+        if !(capabilities.min_cfg..=capabilities.max_cfg).contains(&parsed.guidance_scale) {
+            let span = cmd
+                .guidance_scale
+                .as_ref()
+                .map(|s| s.span.clone())
+                .unwrap_or_default();
+            return Err(error_at(
+                span,
+                format!(
+                    "Scale must be between {} and {} for {}",
+                    capabilities.min_cfg, capabilities.max_cfg, parsed.model_name
+                ),
+            ));
         }
         if !(1.0..=30.0).contains(&parsed.aesthetic_scale) {
-            bail!("Aesthetic scale must be between 1 and 30");
+            let span = cmd
+                .aesthetic_scale
+                .as_ref()
+                .map(|s| s.span.clone())
+                .unwrap_or_default();
+            return Err(error_at(
+                span,
+                "Aesthetic scale must be between 1 and 30".to_string(),
+            ));
         }
-        if let Some(s) = parsed.steps {
-            if s < 1 {
-                bail!("We're done! Wasn't that fast?")
+        if let Some(steps) = &cmd.steps {
+            if steps.value < 1 {
+                return Err(error_at(
+                    steps.span.clone(),
+                    "We're done! Wasn't that fast?".to_string(),
+                ));
+            }
+        }
+        if let Some(count) = &cmd.count {
+            if count.value < 1 {
+                return Err(error_at(
+                    count.span.clone(),
+                    "Count must be at least 1".to_string(),
+                ));
             }
         }
         if parsed.count > 9 {
             parsed.count = 9;
         }
-        if parsed.max_batch_size() < 1 || (parsed.width * parsed.height > 2048 * 2048) {
-            bail!("Resolution is too high");
+        if parsed.max_batch_size(capabilities) < 1 {
+            let megapixels = capabilities.pixel_budget as f64 / (1024.0 * 1024.0);
+            bail!(
+                "Resolution is too high; {} can do at most {megapixels:.1} megapixels",
+                parsed.model_name
+            );
         }
 
         // Generate the final command.
         let parsed = ParsedRequest {
-            linguistic_prompt: linguistic_prompt.join(" "),
-            supporting_prompt: supporting_prompt.join(" "),
-            negative_prompt: negative_prompt.join(" "),
+            linguistic_prompt: cmd.linguistic_prompt.join(" "),
+            supporting_prompt: cmd.supporting_prompt.join(" "),
+            negative_prompt: cmd.negative_prompt.join(" "),
             ..parsed
         };
+        // Expanding once catches wildcards that refer to themselves, before we queue anything.
+        parsed.expand_for_image(config, 0)?;
         info!("Parsed configuration: {:?}", parsed);
         Ok(parsed)
     }
 
-    /// In general we have a limit of 4 Megapixels per request.
-    /// There's no benefit to Flux from batching images, so don't.
-    fn max_batch_size(&self) -> u32 {
-        if self.model_name.contains("flux") {
+    /// Returns the prompts for image N of the batch, with alternations and wildcards expanded.
+    /// This is deterministic given the seed.
+    pub fn expand_for_image(&self, config: &BotConfig, index: u32) -> Result<ImagePrompts> {
+        let wildcards = Wildcards::new(config);
+        let mut rng = StdRng::seed_from_u64(self.seed as u64 + index as u64);
+        Ok(ImagePrompts {
+            linguistic: wildcards.expand(&self.linguistic_prompt, &mut rng)?,
+            supporting: wildcards.expand(&self.supporting_prompt, &mut rng)?,
+            negative: wildcards.expand(&self.negative_prompt, &mut rng)?,
+        })
+    }
+
+    /// Turns the request back into a command line.
+    /// from_request() parses this into an identical ParsedRequest, apart from base.raw,
+    /// so every setting is spelled out explicitly; retries depend on that.
+    pub fn to_command_line(&self) -> String {
+        let mut parts = vec![
+            parser::escape_prompt(&self.linguistic_prompt),
+            format!("--model {}", parser::quote_value(&self.model_name)),
+            format!("--seed {}", self.seed),
+        ];
+        // A grid's count is implied by its axes.
+        if let Some(grid) = &self.grid {
+            parts.push(format!("--xy {}", parser::quote_value(&grid.x.to_string())));
+            if let Some(y) = &grid.y {
+                parts.push(parser::quote_value(&y.to_string()));
+            }
+        } else {
+            parts.push(format!("--count {}", self.count));
+        }
+        // If the axes change the resolution, fixing the width and height would defeat them.
+        let axis_names = self
+            .grid
+            .iter()
+            .flat_map(|g| g.axes().map(|a| a.name.as_str()))
+            .collect::<Vec<_>>();
+        if axis_names.contains(&"model") && !axis_names.contains(&"ar") {
+            let (mut a, mut b) = (self.width, self.height);
+            while b != 0 {
+                (a, b) = (b, a % b);
+            }
+            parts.push(format!("--ar {}:{}", self.width / a, self.height / a));
+        } else if !axis_names.contains(&"ar") {
+            parts.push(format!("-w {} -h {}", self.width, self.height));
+        }
+        let defaults = Self::default();
+        if let Some(steps) = self.steps {
+            parts.push(format!("--steps {steps}"));
+        }
+        // The default scale depends on the model, so it's always spelled out. Unless the grid
+        // varies it, by way of a scale axis or models with different defaults.
+        let varying_scale = self
+            .grid
+            .iter()
+            .flat_map(|g| g.cells.iter())
+            .any(|c| c.guidance_scale != self.guidance_scale);
+        if !varying_scale {
+            parts.push(format!("--scale {}", self.guidance_scale));
+        }
+        if self.aesthetic_scale != defaults.aesthetic_scale {
+            parts.push(format!("--aesthetic {}", self.aesthetic_scale));
+        }
+        if !self.use_pos_default {
+            parts.push("--np".to_string());
+        }
+        if !self.use_neg_default {
+            parts.push("--nn".to_string());
+        }
+        if !self.supporting_prompt.is_empty() {
+            parts.push(format!(
+                "--style {}",
+                parser::escape_prompt(&self.supporting_prompt)
+            ));
+        }
+        if let Some(variation) = &self.variation {
+            parts.push(format!(
+                "--variation-seed {} --variation-strength {}",
+                variation.seed, variation.strength
+            ));
+        }
+        if let Some(init) = &self.init {
+            parts.push(format!(
+                "--init {} --strength {}",
+                parser::quote_value(&init.url),
+                init.strength
+            ));
+            match &init.mask {
+                Some(Mask::Url(url)) => parts.push(format!("--mask {}", parser::quote_value(url))),
+                Some(Mask::Alpha) => parts.push("--mask alpha".to_string()),
+                None => {}
+            }
+            if self.upscale {
+                parts.push("--upscale".to_string());
+            }
+        }
+        if let Some(sampler) = &self.sampler {
+            parts.push(format!("--sampler {}", parser::quote_value(sampler)));
+        }
+        if let Some(scheduler) = &self.scheduler {
+            parts.push(format!("--scheduler {}", parser::quote_value(scheduler)));
+        }
+        if !self.negative_prompt.is_empty() {
+            parts.push(format!(
+                "--no {}",
+                parser::escape_prompt(&self.negative_prompt)
+            ));
+        }
+        parts.join(" ")
+    }
+
+    /// How many images fit in one backend call, given the model's pixel budget.
+    /// Zero if even a single image is too large.
+    fn max_batch_size(&self, capabilities: &ModelCapabilities) -> u32 {
+        let pixels_per_image = self.width as u64 * self.height as u64;
+        let fits = capabilities.pixel_budget / pixels_per_image.max(1);
+        // Variations share their base noise, and the backend can't do that within a batch.
+        // Upscaling works on the one picture it loads.
+        let max_batch = if self.variation.is_some() || self.upscale {
             1
         } else {
-            let max_pixels = (4 * 1024 * 1024) as f32;
-            let pixels_per_image = (self.width * self.height) as f32;
-            (max_pixels / pixels_per_image) as u32
+            capabilities.max_batch
+        };
+        fits.min(max_batch as u64) as u32
+    }
+
+    /// Lists the flags the model is going to ignore, so we can tell the user.
+    /// For grids, this covers every cell.
+    pub fn warnings(&self, config: &BotConfig) -> Vec<String> {
+        let mut warnings: Vec<String> = vec![];
+        let cells = self.grid.iter().flat_map(|g| g.cells.iter());
+        for request in std::iter::once(self).chain(cells) {
+            let Some(model_config) = config.models.get(&request.model_name) else {
+                continue;
+            };
+            let capabilities = &model_config.capabilities;
+            let mut ignored = vec![];
+            if !request.negative_prompt.is_empty() && !capabilities.supports_negative {
+                ignored.push("--no");
+            }
+            if !request.supporting_prompt.is_empty() && !capabilities.supports_style {
+                ignored.push("--style");
+            }
+            // Anything else depends on whether the workflow has somewhere to put it. If it
+            // can't be loaded, generating will say so.
+            let placeholders = request.workflow_placeholders(model_config).ok();
+            let uses = |placeholder: &str| {
+                placeholders
+                    .as_ref()
+                    .is_none_or(|p| p.iter().any(|p| p == placeholder))
+            };
+            if request.guidance_scale != capabilities.default_cfg
+                && !uses("__BASE_CFG__")
+                && !uses("__REFINER_CFG__")
+            {
+                ignored.push("--scale");
+            }
+            let prompts = [
+                &request.linguistic_prompt,
+                &request.supporting_prompt,
+                &request.negative_prompt,
+            ];
+            if capabilities.prompt_syntax == PromptSyntax::Plain
+                && prompts.iter().any(|p| weighting::has_weights(p))
+            {
+                ignored.push("(word:weight) emphasis");
+            }
+            if request.aesthetic_scale != Self::default().aesthetic_scale
+                && !uses("__POSITIVE_A_SCORE__")
+            {
+                ignored.push("--aesthetic");
+            }
+            for flag in ignored {
+                let warning = format!("{} ignores {flag}", request.model_name);
+                if !warnings.contains(&warning) {
+                    warnings.push(warning);
+                }
+            }
         }
+        warnings
     }
 
     /// Builds a query string for the backend.
@@ -387,198 +1119,471 @@ impl ParsedRequest {
     /// - Load the model config from models.json. This can fail, if the model doesn't exist anymore.
     /// - Load the workflow "JSON" from the model config. This actually has __STANDIN__ placeholders for the parameters.
     /// - Replace the placeholders with the actual parameters.
-    /// - Confirm that the result is valid JSON.
-    /// - Take the text, and pass it to /prompt as POST data.
+    /// - Take the workflow, and pass it to /prompt as POST data.
+    ///
+    /// For --init, uploaded has the names the backend gave our pictures.
     fn build_query(
         &self,
         config: &BotConfig,
+        server: &ComfyServer,
         batch_size: u32,
         seed_offset: u32,
-    ) -> Result<RequestBuilder> {
-        // First, check for aliases.
-        let model_name = config
-            .aliases
-            .get(&self.model_name)
-            .cloned()
-            .unwrap_or(self.model_name.clone());
-        // Then, load the model config.
-        let model_config = config
-            .models
-            .get(&model_name)
-            .ok_or_else(|| anyhow::anyhow!("no such model: {}", model_name))?;
-        // Load the workflow.
-        let workflow =
-            std::fs::read_to_string(&model_config.workflow).context("failed to read workflow")?;
-        // Replace the placeholders.
-        let linguistic_prompt = if self.use_pos_default && !model_config.default_positive.is_empty()
-        {
-            model_config.default_positive.clone() + ", " + &self.linguistic_prompt
-        } else {
-            self.linguistic_prompt.clone()
+        uploaded: Option<&UploadedImages>,
+    ) -> Result<Query> {
+        let workflow = self.render_workflow(config, batch_size, seed_offset, uploaded)?;
+        let stages = workflow.stage_names();
+        let client_id = format!("{}-{}", config.backend.client_id, Uuid::new_v4());
+        #[derive(Debug, Serialize)]
+        struct Request {
+            prompt: serde_json::Value,
+            client_id: String,
+        }
+        let request = Request {
+            prompt: workflow.into_value(),
+            client_id: client_id.clone(),
         };
-        let negative_prompt = if self.use_neg_default {
-            self.negative_prompt.clone() + ", " + &model_config.default_negative
-        } else {
-            self.negative_prompt.clone()
+        // Take the text, and pass it to /prompt as POST data.
+        let request = serde_json::to_string(&request).context("failed to serialize request")?;
+        let request = reqwest::Client::new()
+            .post(format!("http://{}/prompt", server.address()))
+            .body(request);
+        Ok(Query {
+            request,
+            client_id,
+            stages,
+        })
+    }
+
+    /// Renders each of a model's workflows with made-up settings, to check them before anyone
+    /// picks the model. Pictures are called SAMPLE_INIT_IMAGE and SAMPLE_MASK_IMAGE.
+    /// Returns the workflow's file with the result.
+    pub fn render_samples(config: &BotConfig, model_name: &str) -> Vec<(String, Result<Workflow>)> {
+        let Some(model_config) = config.models.get(model_name) else {
+            return vec![];
         };
-        let steps = self
-            .steps
-            .unwrap_or(model_config.default_steps.unwrap_or(30));
-        let steps_cutover = (steps as f32 * 0.5) as u32;
-        info!("Generating {} images in {} steps", self.count, steps);
-        info!("Linguistic prompt: {}", linguistic_prompt);
-        info!("Supporting prompt: {}", self.supporting_prompt);
-        info!("Negative prompt: {}", negative_prompt);
+        let base = ParsedRequest {
+            model_name: model_name.to_string(),
+            linguistic_prompt: "a cat".to_string(),
+            guidance_scale: model_config.capabilities.default_cfg,
+            ..Default::default()
+        };
+        let init = |mask| InitImage {
+            url: String::new(),
+            strength: DEFAULT_INIT_STRENGTH,
+            mask,
+        };
+        let uploaded = UploadedImages {
+            init: SAMPLE_INIT_IMAGE.to_string(),
+            mask: Some(SAMPLE_MASK_IMAGE.to_string()),
+        };
+        let variants = [
+            (Some(&model_config.workflow), base.clone()),
+            (
+                model_config.img2img_workflow.as_ref(),
+                ParsedRequest {
+                    init: Some(init(None)),
+                    ..base.clone()
+                },
+            ),
+            (
+                model_config.inpaint_workflow.as_ref(),
+                ParsedRequest {
+                    init: Some(init(Some(Mask::Alpha))),
+                    ..base.clone()
+                },
+            ),
+            (
+                model_config.upscale_workflow.as_ref(),
+                ParsedRequest {
+                    init: Some(init(None)),
+                    upscale: true,
+                    ..base.clone()
+                },
+            ),
+        ];
+        variants
+            .into_iter()
+            .filter_map(|(workflow, request)| {
+                let uploaded = request.init.as_ref().map(|_| &uploaded);
+                Some((
+                    workflow?.clone(),
+                    request.render_workflow(config, 1, 0, uploaded),
+                ))
+            })
+            .collect()
+    }
+
+    /// Which of the model's workflows the request needs, if it has one. Starting from a
+    /// picture needs a different one, as do inpainting and upscaling.
+    pub fn workflow<'a>(&self, model_config: &'a BotModelConfig) -> Option<&'a str> {
+        match &self.init {
+            None => Some(&model_config.workflow),
+            Some(init) if init.mask.is_some() => model_config.inpaint_workflow.as_deref(),
+            Some(_) if self.upscale => model_config.upscale_workflow.as_deref(),
+            Some(_) => model_config.img2img_workflow.as_deref(),
+        }
+    }
+
+    /// The __PLACEHOLDER__s in the workflow the request needs, which is how workflows
+    /// declare what they support. None at all if the model has no such workflow.
+    pub fn workflow_placeholders(&self, model_config: &BotModelConfig) -> Result<Vec<String>> {
+        match self.workflow(model_config) {
+            Some(path) => Ok(Workflow::load(path)?.placeholders()),
+            None => Ok(vec![]),
+        }
+    }
 
-        fn json_encode_string(s: &str) -> String {
-            let mut result = String::new();
-            for c in s.chars() {
-                match c {
-                    '"' => result.push_str("\\\""),
-                    '\\' => result.push_str("\\\\"),
-                    '\n' => result.push_str("\\n"),
-                    '\r' => result.push_str("\\r"),
-                    '\t' => result.push_str("\\t"),
-                    _ => result.push(c),
+    /// Sampling steps, counting the model's default.
+    pub fn total_steps(&self, model_config: &BotModelConfig) -> u32 {
+        self.steps
+            .unwrap_or(model_config.default_steps.unwrap_or(30))
+    }
+
+    /// The workflow for this request, filled in.
+    fn render_workflow(
+        &self,
+        config: &BotConfig,
+        batch_size: u32,
+        seed_offset: u32,
+        uploaded: Option<&UploadedImages>,
+    ) -> Result<Workflow> {
+        // First, check for aliases.
+        let model_name = config
+            .aliases
+            .get(&self.model_name)
+            .cloned()
+            .unwrap_or(self.model_name.clone());
+        // Then, load the model config.
+        let model_config = config
+            .models
+            .get(&model_name)
+            .ok_or_else(|| anyhow::anyhow!("no such model: {}", model_name))?;
+        // Load the workflow.
+        let (init_image, mask_image, denoise) = match (&self.init, uploaded) {
+            (Some(init), Some(uploaded)) => {
+                if init.mask.is_some() && uploaded.mask.is_none() {
+                    bail!("the mask hasn't been uploaded");
                 }
+                let mask_image = uploaded.mask.as_deref().unwrap_or_default();
+                (uploaded.init.as_str(), mask_image, init.strength)
             }
-            result
-        }
+            (Some(_), None) => bail!("the init image hasn't been uploaded"),
+            (None, _) => ("", "", 1.0),
+        };
+        let Some(workflow) = self.workflow(model_config) else {
+            bail!("{model_name} can't do that with a picture");
+        };
+        let mut workflow = Workflow::load(workflow)?;
+        // Emphasis goes in whichever syntax the workflow understands. The model's own defaults
+        // are written for it already.
+        let capabilities = &model_config.capabilities;
+        let syntax = capabilities.prompt_syntax;
+        // Prompts the model can't use are dropped, not left for the workflow to misuse.
+        // The user was warned about them when they asked.
+        let user_supporting = match capabilities.supports_style {
+            true => self.supporting_prompt.as_str(),
+            false => "",
+        };
+        let user_negative = match capabilities.supports_negative {
+            true => self.negative_prompt.as_str(),
+            false => "",
+        };
+        // LoRA tags become nodes, rather than words, and bring their trigger words along.
+        let loras = lora::resolve(
+            config,
+            &model_name,
+            &[&self.linguistic_prompt, user_supporting, user_negative].join(" "),
+        )?;
+        let user_linguistic = lora::add_triggers(&lora::strip(&self.linguistic_prompt), &loras);
+        let user_linguistic = weighting::translate(&user_linguistic, syntax);
+        let supporting_prompt = weighting::translate(&lora::strip(user_supporting), syntax);
+        let user_negative = weighting::translate(&lora::strip(user_negative), syntax);
+        // Replace the placeholders.
+        let linguistic_prompt = if self.use_pos_default && !model_config.default_positive.is_empty()
+        {
+            model_config.default_positive.clone() + ", " + &user_linguistic
+        } else {
+            user_linguistic
+        };
+        let negative_prompt = if !capabilities.supports_negative {
+            String::new()
+        } else if self.use_neg_default {
+            user_negative + ", " + &model_config.default_negative
+        } else {
+            user_negative
+        };
+        let steps = self.total_steps(model_config);
+        let steps_cutover = (steps as f32 * 0.5) as u32;
+        // Without a variation, each image in a request gets the next seed along. With one, the
+        // seed stays put and the variation seed moves instead.
+        let (seed, variation_seed, variation_strength) = match self.variation {
+            Some(variation) => (
+                self.seed,
+                variation.seed.wrapping_add(seed_offset),
+                variation.strength,
+            ),
+            None => (self.seed.wrapping_add(seed_offset), 0, 0.0),
+        };
+        info!("Generating {} images in {} steps", self.count, steps);
+        info!("Linguistic prompt: {}", linguistic_prompt);
+        info!("Supporting prompt: {}", supporting_prompt);
+        info!("Negative prompt: {}", negative_prompt);
 
-        let combined_prompt = if self.supporting_prompt.is_empty() {
+        let combined_prompt = if supporting_prompt.is_empty() {
             linguistic_prompt.clone()
         } else {
-            let supporting_prompt = &self.supporting_prompt;
             let style_connector = model_config.style_connector.as_deref().unwrap_or(": ");
             format!("{linguistic_prompt}. {style_connector}{supporting_prompt}")
         };
 
-        let workflow = workflow
-            .replace(
+        let mut values = HashMap::from([
+            (
                 "__REFINER_CHECKPOINT__",
-                &json_encode_string(
-                    model_config
-                        .refiner
-                        .as_ref()
-                        .unwrap_or(&model_config.baseline),
-                ),
-            )
-            .replace(
-                "__BASE_CHECKPOINT__",
-                &json_encode_string(&model_config.baseline),
-            )
-            .replace(
-                "__VAE__",
-                &json_encode_string(model_config.vae.as_ref().unwrap_or(&"".to_string())),
-            )
-            .replace("__NEGATIVE_PROMPT__", &json_encode_string(&negative_prompt))
-            .replace("__PROMPT_A__", &json_encode_string(&linguistic_prompt))
-            .replace("__PROMPT_B__", &json_encode_string(&self.supporting_prompt))
-            .replace("__COMBINED_PROMPT__", &json_encode_string(&combined_prompt))
-            .replace("__STEPS_TOTAL__", &steps.to_string())
-            .replace("__STEPS_HALF__", &(steps / 2).to_string())
-            .replace("__FIRST_PASS_END_AT_STEP__", &steps_cutover.to_string())
-            .replace("__WIDTH__", &self.width.to_string())
-            .replace("__HEIGHT__", &self.height.to_string())
-            .replace("__WIDTH_d2__", &(self.width / 2).to_string())
-            .replace("__HEIGHT_d2__", &(self.height / 2).to_string())
-            .replace("__2xWIDTH__", &(self.width * 2).to_string())
-            .replace("__2xHEIGHT__", &(self.height * 2).to_string())
-            .replace("__4xWIDTH__", &(self.width * 4).to_string())
-            .replace("__4xHEIGHT__", &(self.height * 4).to_string())
-            .replace("__SEED__", &(self.seed + seed_offset).to_string())
-            .replace("__BASE_CFG__", &self.guidance_scale.to_string())
-            .replace("__REFINER_CFG__", &self.guidance_scale.to_string())
-            .replace("__BATCH_SIZE__", &batch_size.to_string())
-            .replace("__POSITIVE_A_SCORE__", &self.aesthetic_scale.to_string())
-            .replace("__NEGATIVE_A_SCORE__", "1.0");
-        // Confirm that the result is valid JSON.
-        let workflow: serde_json::Value =
-            serde_json::from_str(&workflow).context("failed to parse augmented workflow")?;
-        #[derive(Debug, Serialize)]
-        struct Request {
-            prompt: serde_json::Value,
-            client_id: String,
+                json!(model_config
+                    .refiner
+                    .as_ref()
+                    .unwrap_or(&model_config.baseline)),
+            ),
+            ("__BASE_CHECKPOINT__", json!(model_config.baseline)),
+            ("__VAE__", json!(model_config.vae.as_deref().unwrap_or(""))),
+            ("__NEGATIVE_PROMPT__", json!(negative_prompt)),
+            ("__PROMPT_A__", json!(linguistic_prompt)),
+            ("__PROMPT_B__", json!(supporting_prompt)),
+            ("__COMBINED_PROMPT__", json!(combined_prompt)),
+            ("__STEPS_TOTAL__", json!(steps)),
+            ("__STEPS_HALF__", json!(steps / 2)),
+            ("__FIRST_PASS_END_AT_STEP__", json!(steps_cutover)),
+            ("__WIDTH__", json!(self.width)),
+            ("__HEIGHT__", json!(self.height)),
+            ("__WIDTH_d2__", json!(self.width / 2)),
+            ("__HEIGHT_d2__", json!(self.height / 2)),
+            ("__2xWIDTH__", json!(self.width * 2)),
+            ("__2xHEIGHT__", json!(self.height * 2)),
+            ("__4xWIDTH__", json!(self.width * 4)),
+            ("__4xHEIGHT__", json!(self.height * 4)),
+            ("__SEED__", json!(seed)),
+            ("__VARIATION_SEED__", json!(variation_seed)),
+            (
+                "__VARIATION_STRENGTH__",
+                workflow::float(variation_strength),
+            ),
+            ("__BASE_CFG__", workflow::float(self.guidance_scale)),
+            ("__REFINER_CFG__", workflow::float(self.guidance_scale)),
+            ("__BATCH_SIZE__", json!(batch_size)),
+            ("__INIT_IMAGE__", json!(init_image)),
+            ("__MASK_IMAGE__", json!(mask_image)),
+            ("__DENOISE__", workflow::float(denoise)),
+            (
+                "__POSITIVE_A_SCORE__",
+                workflow::float(self.aesthetic_scale),
+            ),
+            ("__NEGATIVE_A_SCORE__", json!(1.0)),
+        ]);
+        // Workflows without these placeholders have the choice baked in.
+        for (placeholder, what, choice, default) in [
+            (
+                "__SAMPLER__",
+                "default_sampler",
+                &self.sampler,
+                &model_config.default_sampler,
+            ),
+            (
+                "__SCHEDULER__",
+                "default_scheduler",
+                &self.scheduler,
+                &model_config.default_scheduler,
+            ),
+        ] {
+            if workflow.uses(placeholder) {
+                let Some(value) = choice.as_ref().or(default.as_ref()) else {
+                    bail!("{model_name}'s workflow uses {placeholder}, but it has no {what}");
+                };
+                values.insert(placeholder, json!(value));
+            }
+        }
+        workflow
+            .fill(&values)
+            .with_context(|| format!("{model_name}'s workflow is broken"))?;
+        for (target, value) in &model_config.overrides {
+            let Some((node, input)) = target.rsplit_once('.') else {
+                bail!("{model_name} has an override for {target}, which should be node.input");
+            };
+            workflow
+                .set_input(node, input, value.clone())
+                .with_context(|| format!("{model_name} has an override for {target}"))?;
+        }
+        lora::inject(&mut workflow, &model_config.baseline, &loras)
+            .with_context(|| format!("{model_name} can't use LoRAs"))?;
+        Ok(workflow)
+    }
+
+    /// Megapixels over the whole request, which is roughly what it costs to generate.
+    pub fn megapixels(&self) -> f64 {
+        self.count as f64 * self.width as f64 * self.height as f64 / 1e6
+    }
+}
+
+/// A filled-in workflow, ready to send to a server.
+struct Query {
+    request: RequestBuilder,
+    /// Who we are to ComfyUI. It only tells the client that queued a prompt how it's going,
+    /// so every query gets its own.
+    client_id: String,
+    /// What to call each node while it runs.
+    stages: HashMap<String, String>,
+}
+
+/// Follows a prompt through ComfyUI's websocket messages.
+struct ProgressTracker {
+    prompt_id: Option<String>,
+    stages: HashMap<String, String>,
+    /// Nodes that have run, or were cached.
+    done: HashSet<String>,
+    current: Option<String>,
+    steps: Option<(u32, u32)>,
+}
+
+impl ProgressTracker {
+    fn new(stages: HashMap<String, String>) -> Self {
+        Self {
+            prompt_id: None,
+            stages,
+            done: HashSet::new(),
+            current: None,
+            steps: None,
         }
-        let request = Request {
-            prompt: workflow,
-            client_id: config.backend.client_id.clone(),
-        };
-        // Take the text, and pass it to /prompt as POST data.
-        let request = serde_json::to_string(&request).context("failed to serialize request")?;
-        let request = reqwest::Client::new()
-            .post(format!(
-                "http://{}:{}/prompt",
-                config.backend.host, config.backend.port
-            ))
-            .body(request);
-        Ok(request)
     }
 
-    fn score(&self, previous_request: &ParsedRequest) -> f32 {
-        let mut score = 0.0;
-        // On an average picture, this subtracts 2.
-        score -= (self.count as f32) / (Self::default().count as f32);
-        // Prefer to alternate between users.
-        if self.base.user != previous_request.base.user {
-            score += 2.0;
+    /// Takes a websocket message. Returns the progress through the prompt, with percent
+    /// covering just this prompt, if the message said anything about it.
+    fn update(&mut self, msg: &serde_json::Value) -> Option<Progress> {
+        let data = &msg["data"];
+        // Messages that name a prompt should name ours.
+        if let (Some(ours), Some(theirs)) = (&self.prompt_id, data["prompt_id"].as_str()) {
+            if ours != theirs {
+                return None;
+            }
         }
-        // Prefer *not* to alternate models.
-        if self.model_name != previous_request.model_name {
-            score -= 3.0;
+        match msg["type"].as_str()? {
+            "execution_cached" => {
+                let nodes = data["nodes"].as_array()?;
+                self.done
+                    .extend(nodes.iter().filter_map(|n| n.as_str()).map(str::to_string));
+            }
+            "executing" => {
+                if let Some(previous) = self.current.take() {
+                    self.done.insert(previous);
+                }
+                // A null node means the prompt is done, which the history will tell us.
+                self.current = Some(data["node"].as_str()?.to_string());
+                self.steps = None;
+            }
+            "progress" => {
+                if let Some(node) = data["node"].as_str() {
+                    self.current = Some(node.to_string());
+                }
+                let value = data["value"].as_u64()? as u32;
+                let max = data["max"].as_u64()? as u32;
+                self.steps = Some((value, max));
+            }
+            _ => return None,
+        }
+        Some(self.progress())
+    }
+
+    fn progress(&self) -> Progress {
+        let total = self.stages.len().max(1) as f64;
+        let partial = match self.steps {
+            Some((value, max)) if max > 0 => value as f64 / max as f64,
+            _ => 0.0,
+        };
+        let done = self.done.len() as f64 + partial;
+        Progress {
+            percent: (100.0 * done / total).min(100.0) as u32,
+            stage: self
+                .current
+                .as_ref()
+                .map(|id| self.stages.get(id).cloned().unwrap_or_else(|| id.clone())),
+            steps: self.steps,
         }
-        score
     }
 }
 
 struct ImageGenerator {
     db: DatabaseModule,
     config: BotConfigModule,
-    command_sender: UnboundedSender<(ParsedRequest, UnboundedSender<GenerationEvent>)>,
-    load: Arc<AtomicUsize>,
+    command_sender: UnboundedSender<Command>,
+    next_job: Arc<AtomicU64>,
     prompt_generator: PromptGeneratorModule,
+    /// Requests from before a restart, until whoever asked for them picks them up.
+    resumed: Vec<Resumed>,
 }
 
-type EventStream = Pin<Box<dyn Send + FusedStream<Item = GenerationEvent>>>;
-
 #[derive(Clone)]
 pub struct ImageGeneratorModule(Arc<RwLock<ImageGenerator>>);
 
 impl ImageGeneratorModule {
-    pub fn new(
+    /// Starts the generator, with whatever was still queued when it last stopped.
+    pub async fn new(
         db: DatabaseModule,
         config: BotConfigModule,
         prompt_generator: PromptGeneratorModule,
     ) -> Result<Self> {
         let (tx, rx) = unbounded();
+        let (health_tx, health_rx) = unbounded();
+        tokio::task::spawn(Self::check_health(config.clone(), health_tx));
+        // Ids carry on from before, so nothing gets cancelled by mistake.
+        let next_job = db.next_job_id().await?;
+        let mut resumed = vec![];
+        for (id, request, reply_to) in db.get_pending().await? {
+            info!("Resuming job {id}");
+            let (events_tx, events) = unbounded();
+            events_tx
+                .unbounded_send(GenerationEvent::Accepted(id))
+                .expect("just created");
+            tx.unbounded_send(Command::Generate(id, Box::new(request.clone()), events_tx))
+                .expect("just created");
+            let is_private = request.base.private;
+            resumed.push(Resumed {
+                request,
+                reply_to,
+                events: Box::pin(counting_stats(db.clone(), is_private, events)),
+            });
+        }
         let generator = ImageGeneratorModule(Arc::new(RwLock::new(ImageGenerator {
             db,
             config,
             command_sender: tx,
-            load: Arc::new(AtomicUsize::new(0)),
+            next_job: Arc::new(AtomicU64::new(next_job)),
             prompt_generator,
+            resumed,
         })));
 
-        tokio::task::spawn(generator.clone().run(rx));
+        tokio::task::spawn(generator.clone().run(rx, health_rx));
         Ok(generator)
     }
 
-    /// Waits until nothing is being generated, then returns.
-    pub async fn wait_until_idle(&self) {
-        loop {
-            let load = self.0.read().await.load.load(Ordering::Relaxed);
-            if load == 0 {
-                return;
-            }
-            tokio::time::sleep(Duration::from_secs(1)).await;
-        }
+    /// Hands over the requests from before the restart that should go where `wanted` says.
+    pub async fn take_resumed(&self, wanted: impl Fn(&ReplyTo) -> bool) -> Vec<Resumed> {
+        let mut generator = self.0.write().await;
+        let (taken, kept) = std::mem::take(&mut generator.resumed)
+            .into_iter()
+            .partition(|r| wanted(&r.reply_to));
+        generator.resumed = kept;
+        taken
     }
 
-    /// Generates a single batch of images.
+    /// Generates a single batch of images, returning them and how long the server spent on them.
+    /// Progress and previews go to `events`; the progress percent covers just this batch.
+    /// If `cancel` becomes true, the prompt is taken off the server and this fails with
+    /// Cancelled.
     async fn generate_batch(
-        backend: &BotBackend,
-        request: RequestBuilder,
-    ) -> Result<Vec<JpegBlob>> {
+        server: &ComfyServer,
+        query: Query,
+        format: OutputFormat,
+        events: UnboundedSender<GenerationEvent>,
+        mut cancel: watch::Receiver<bool>,
+    ) -> Result<(Vec<JpegBlob>, Duration)> {
         #[derive(Deserialize)]
         struct ComfyUIResponse {
             prompt_id: String,
@@ -597,7 +1602,27 @@ impl ImageGeneratorModule {
             details: String,
         }
 
-        let response = request.send().await.context("failed to send request")?;
+        // Listen before queueing the prompt, so we don't miss the start.
+        let mut ws_client = ws::connect_async(format!(
+            "ws://{}/ws?clientId={}",
+            server.address(),
+            query.client_id
+        ))
+        .await
+        .context("failed to connect to websocket")?
+        .0;
+        let mut tracker = ProgressTracker::new(query.stages);
+        // When the prompt got to the front of the server's queue.
+        let mut started: Option<std::time::Instant> = None;
+        if *cancel.borrow() {
+            return Err(Cancelled.into());
+        }
+
+        let response = query
+            .request
+            .send()
+            .await
+            .context("failed to send request")?;
         let text = response.text().await.context("failed to read response")?;
         trace!("Response: {}", text);
         let parsed = match serde_json::from_str::<ComfyUIResponse>(&text) {
@@ -620,33 +1645,61 @@ impl ImageGeneratorModule {
 
         let prompt_id = parsed.prompt_id;
         debug!("Got prompt ID {}", prompt_id);
+        tracker.prompt_id = Some(prompt_id.clone());
         // Now, we need to poll the history endpoint until it's done.
-        // We limit the traffic by reading the websocket, only polling when it update or if
-        // it's been a second.
+        // We limit the traffic by reading the websocket, only polling when the queue changes
+        // or the prompt finishes. Everything else on it is progress.
         let mut filenames: Option<Vec<String>> = None;
-        let mut ws_client = ws::connect_async(format!(
-            "ws://{}:{}/ws?clientId={}",
-            backend.host, backend.port, prompt_id
-        ))
-        .await
-        .context("failed to connect to websocket")?
-        .0;
-        for iteration in 0..10 {
+        let mut last_preview: Option<std::time::Instant> = None;
+        for _ in 0..10 {
             // Loop across websocket messages until we get one indicating completion.
             loop {
                 select! {
                     msg = ws_client.next() => {
-                        if let Some(Result::Ok(tungstenite::protocol::Message::Text(msg))) = msg {
-                            // Parse as JSON.
-                            let msg: serde_json::Value = serde_json::from_str(&msg).context("failed to parse websocket message")?;
-                            if msg.get("type").and_then(|t| t.as_str()) == Some("status") {
+                        match msg {
+                            Some(Result::Ok(tungstenite::protocol::Message::Text(msg))) => {
+                                // Parse as JSON.
+                                let msg: serde_json::Value = serde_json::from_str(&msg).context("failed to parse websocket message")?;
+                                match msg["type"].as_str() {
+                                    Some("status" | "execution_success" | "execution_error") => break,
+                                    Some("executing") if msg["data"]["node"].is_null() => break,
+                                    _ => {
+                                        if let Some(update) = tracker.update(&msg) {
+                                            started.get_or_insert_with(std::time::Instant::now);
+                                            // Nobody listening is fine.
+                                            let _ = events.unbounded_send(GenerationEvent::Generating(update));
+                                        }
+                                    }
+                                }
+                            },
+                            // Preview images. Those come with every step, which is more than
+                            // anyone needs.
+                            Some(Result::Ok(tungstenite::protocol::Message::Binary(frame))) => {
+                                if last_preview.is_some_and(|at| at.elapsed() < PREVIEW_INTERVAL) {
+                                    continue;
+                                }
+                                let Some(image) = preview_image(&frame) else {
+                                    continue;
+                                };
+                                match utils::convert_image(image.to_vec(), OutputFormat::Jpeg) {
+                                    Result::Ok(jpeg) => {
+                                        last_preview = Some(std::time::Instant::now());
+                                        let _ = events.unbounded_send(GenerationEvent::Preview(jpeg));
+                                    },
+                                    Err(e) => debug!("Couldn't read a preview: {e:#}"),
+                                }
+                            },
+                            msg => {
+                                warn!("Got unexpected websocket message: {:?}", msg);
                                 break;
-                            }
-                        } else {
-                            warn!("Got unexpected websocket message: {:?}", msg);
-                            break;
+                            },
                         }
                     },
+                    _ = cancel.changed().fuse() => {
+                        // The sender going away means nobody wants the pictures either.
+                        Self::cancel_prompt(server, &prompt_id).await;
+                        return Err(Cancelled.into());
+                    },
                     _ = futures_time::task::sleep(futures_time::time::Duration::from_secs(90)).fuse() => {
                         warn!("Websocket sleep timed out");
                         // Really this should never happen, but try to recover anyway.
@@ -657,10 +1710,7 @@ impl ImageGeneratorModule {
             trace!("Polling history");
             let client = reqwest::Client::new();
             let history: serde_json::Value = client
-                .get(format!(
-                    "http://{}:{}/history/{}",
-                    backend.host, backend.port, prompt_id
-                ))
+                .get(format!("http://{}/history/{}", server.address(), prompt_id))
                 .send()
                 .await
                 .context("failed to poll history")?
@@ -710,12 +1760,13 @@ impl ImageGeneratorModule {
         }
         // If we didn't get any filenames, we timed out.
         let filenames = filenames.ok_or_else(|| anyhow::anyhow!("timed out waiting for images"))?;
+        let gpu_time = started.map_or(Duration::ZERO, |at| at.elapsed());
         // Now, we need to download the images.
         let client = reqwest::Client::new();
         let mut final_images = Vec::new();
         for filename in filenames {
             let image = client
-                .get(format!("http://{}:{}/view", backend.host, backend.port))
+                .get(format!("http://{}/view", server.address()))
                 .query(&[("filename", filename)])
                 .send()
                 .await
@@ -723,159 +1774,724 @@ impl ImageGeneratorModule {
                 .bytes()
                 .await
                 .context("failed to read image")?;
-            final_images
-                .push(utils::convert_to_jpeg(image.into()).context("failed to convert image")?);
+            final_images.push(
+                utils::convert_image(image.into(), format).context("failed to convert image")?,
+            );
+        }
+
+        Ok((final_images, gpu_time))
+    }
+
+    /// Takes a prompt off a server: out of its queue if it's waiting, or interrupted if it's
+    /// running. Failing that, it finishes and nobody looks at the result.
+    async fn cancel_prompt(server: &ComfyServer, prompt_id: &str) {
+        let result = async {
+            let client = reqwest::Client::new();
+            let queue: serde_json::Value = client
+                .get(format!("http://{}/queue", server.address()))
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            let running = queue["queue_running"]
+                .as_array()
+                .is_some_and(|items| items.iter().any(|item| item[1] == prompt_id));
+            let (endpoint, body) = if running {
+                // Newer versions only interrupt the prompt given; older ones whatever's running,
+                // which is the same thing here.
+                ("interrupt", json!({ "prompt_id": prompt_id }))
+            } else {
+                ("queue", json!({ "delete": [prompt_id] }))
+            };
+            client
+                .post(format!("http://{}/{endpoint}", server.address()))
+                .json(&body)
+                .send()
+                .await?
+                .error_for_status()?;
+            debug!("Cancelled {prompt_id} with /{endpoint}");
+            anyhow::Ok(())
+        };
+        if let Err(e) = result.await {
+            warn!("Couldn't cancel {prompt_id}: {e:#}");
+        }
+    }
+
+    /// Fetches a picture for --init or --mask. Users pick the link, so only public http(s)
+    /// hosts are allowed, and redirects are followed by hand to check each hop.
+    async fn fetch_image(url: &str) -> Result<Vec<u8>> {
+        let mut url = reqwest::Url::parse(url).with_context(|| format!("{url} isn't a link"))?;
+        for _ in 0..=MAX_INIT_IMAGE_REDIRECTS {
+            let response = Self::public_client(&url)
+                .await?
+                .get(url.clone())
+                .send()
+                .await
+                .with_context(|| format!("Failed to fetch {url}"))?;
+            if response.status().is_redirection() {
+                let location = response
+                    .headers()
+                    .get(reqwest::header::LOCATION)
+                    .and_then(|l| l.to_str().ok())
+                    .with_context(|| format!("{url} redirects nowhere"))?;
+                url = url.join(location)?;
+                continue;
+            }
+            let mut response = response
+                .error_for_status()
+                .with_context(|| format!("Failed to fetch {url}"))?;
+            if response.content_length().unwrap_or(0) > MAX_INIT_IMAGE_BYTES {
+                bail!("{url} is too large");
+            }
+            // The length is only a claim, so count as we go.
+            let mut bytes = vec![];
+            while let Some(chunk) = response
+                .chunk()
+                .await
+                .with_context(|| format!("Failed to fetch {url}"))?
+            {
+                bytes.extend_from_slice(&chunk);
+                if bytes.len() as u64 > MAX_INIT_IMAGE_BYTES {
+                    bail!("{url} is too large");
+                }
+            }
+            return Ok(bytes);
+        }
+        bail!("{url} redirects too many times");
+    }
+
+    /// A client that fetches the URL from wherever its host resolves to now, provided that's
+    /// on the public internet. Pinning the address stops the DNS changing its mind afterwards.
+    async fn public_client(url: &reqwest::Url) -> Result<reqwest::Client> {
+        if !matches!(url.scheme(), "http" | "https") {
+            bail!("{url} isn't an http or https link");
+        }
+        let host = url
+            .host_str()
+            .with_context(|| format!("{url} has no host"))?;
+        let port = url.port_or_known_default().unwrap_or(80);
+        let addr = tokio::net::lookup_host((host.trim_matches(['[', ']']), port))
+            .await
+            .with_context(|| format!("Couldn't look up {host}"))?
+            .collect::<Vec<_>>();
+        let Some(first) = addr.first() else {
+            bail!("Couldn't look up {host}");
+        };
+        if addr.iter().any(|a| is_internal(a.ip())) {
+            bail!("{host} isn't on the public internet");
+        }
+        Ok(reqwest::Client::builder()
+            .redirect(reqwest::redirect::Policy::none())
+            .resolve(host, *first)
+            .timeout(Duration::from_secs(60))
+            .build()?)
+    }
+
+    /// Uploads a PNG to the backend. Returns the name LoadImage knows it by.
+    async fn upload_png(server: &ComfyServer, png: Vec<u8>) -> Result<String> {
+        let part = reqwest::multipart::Part::bytes(png)
+            .file_name(format!("sd-bot-{}.png", uuid::Uuid::new_v4()))
+            .mime_str("image/png")?;
+        let form = reqwest::multipart::Form::new().part("image", part);
+
+        #[derive(Deserialize)]
+        struct UploadResponse {
+            name: String,
+            #[serde(default)]
+            subfolder: String,
         }
 
-        Ok(final_images)
+        let uploaded: UploadResponse = reqwest::Client::new()
+            .post(format!("http://{}/upload/image", server.address()))
+            .multipart(form)
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            .context("Failed to upload a picture to the backend")?
+            .json()
+            .await
+            .context("Failed to parse the upload response")?;
+        debug!("Uploaded {}", uploaded.name);
+        Ok(if uploaded.subfolder.is_empty() {
+            uploaded.name
+        } else {
+            format!("{}/{}", uploaded.subfolder, uploaded.name)
+        })
     }
 
-    /// Runs the generator loop for a single request.
-    /// Locks self for an instant at startup.
+    /// Fetches the pictures for --init and --mask, and uploads them to the backend.
+    async fn upload_init_image(server: &ComfyServer, init: &InitImage) -> Result<UploadedImages> {
+        let image = Self::fetch_image(&init.url).await?;
+        let mask = match &init.mask {
+            Some(mask) => {
+                let separate = match mask {
+                    Mask::Url(url) => Some(Self::fetch_image(url).await?),
+                    Mask::Alpha => None,
+                };
+                let mask = utils::inpaint_mask(&image, separate.as_deref())
+                    .context("Failed to make the inpainting mask")?;
+                Some(Self::upload_png(server, mask).await?)
+            }
+            None => None,
+        };
+        // Re-encoding makes sure it's a picture, and that the backend only sees PNGs we made.
+        let png = utils::convert_image(image, OutputFormat::Png)
+            .context("The init image isn't a picture I can read")?;
+        Ok(UploadedImages {
+            init: Self::upload_png(server, png).await?,
+            mask,
+        })
+    }
+
+    /// Runs the generator loop for a single request, on the given server.
+    /// Locks self for an instant at startup. Stops with Cancelled once `cancel` is true.
     async fn do_generate(
         &self,
         request: ParsedRequest,
+        server: ComfyServer,
+        cancel: watch::Receiver<bool>,
+        retries: Arc<AtomicU32>,
     ) -> impl FusedStream<Item = GenerationEvent> {
         let config = { self.0.read().await.config.snapshot().await };
         try_stream! {
-            let backend = &config.backend;
-            let mut remaining = request.count;
-            let mut seed_offset = 0;
+            let server = &server;
+            // A grid is a series of single-image requests, run back to back.
+            let jobs = match &request.grid {
+                Some(grid) => grid.cells.clone(),
+                None => vec![request.clone()],
+            };
             let mut final_images = Vec::new();
-            while remaining > 0 {
-                // Calculate % remaining.
-                let percent = 100.0 * (1.0 - (remaining as f64 / request.count as f64));
-                yield GenerationEvent::Generating(percent as u32);
-
-                let batch_size = if request.model_name == "pixart" {
-                    // HACK WARNING
-                    1
-                } else {
-                    std::cmp::min(remaining, request.max_batch_size())
+            let mut all_prompts = Vec::new();
+            let mut gpu_time = Duration::ZERO;
+            // Grid cells all start from the same picture, so it only needs uploading once.
+            let mut uploaded: Option<(InitImage, UploadedImages)> = None;
+            for job in jobs {
+                let init_images = match &job.init {
+                    Some(init) => {
+                        let reusable = uploaded
+                            .as_ref()
+                            .is_some_and(|(i, _)| i.url == init.url && i.mask == init.mask);
+                        if !reusable {
+                            let images = Self::upload_init_image(server, init).await?;
+                            uploaded = Some((init.clone(), images));
+                        }
+                        uploaded.as_ref().map(|(_, images)| images.clone())
+                    }
+                    None => None,
                 };
+                // Every image gets its own wildcard expansion. The backend takes one prompt per
+                // batch, so batches can only contain consecutive images that came out the same.
+                let prompts = (0..job.count)
+                    .map(|i| job.expand_for_image(&config, i))
+                    .collect::<Result<Vec<_>>>()?;
+                let mut seed_offset = 0;
+                while seed_offset < job.count {
+                    if *cancel.borrow() {
+                        Err(Cancelled)?;
+                    }
+                    // Calculate % remaining.
+                    let done = all_prompts.len() as u32 + seed_offset;
+                    let percent = 100.0 * (done as f64 / request.count as f64);
+                    yield GenerationEvent::Generating(Progress {
+                        percent: percent as u32,
+                        ..Default::default()
+                    });
 
-                let retry_strategy = ExponentialBackoff::from_millis(50).max_delay(std::time::Duration::from_secs(2)).take(5);
-                let images = Retry::spawn(retry_strategy, || async {
-                    debug!("Generating batch of {} images", batch_size);
-                    let request = request.build_query(&config, batch_size, seed_offset).context("Failed to build query")?;
-                    Self::generate_batch(backend, request).await.context("Failed to generate batch")
-                }).await.context("Ran out of retries")?;
+                    let capabilities = &config
+                        .models
+                        .get(&job.model_name)
+                        .context("Model disappeared from the config")?
+                        .capabilities;
+                    let max_batch_size = job.max_batch_size(capabilities).max(1);
+                    let batch_prompts = &prompts[seed_offset as usize];
+                    let batch_size = prompts[seed_offset as usize..]
+                        .iter()
+                        .take(max_batch_size as usize)
+                        .take_while(|p| *p == batch_prompts)
+                        .count() as u32;
+                    let batch_request = ParsedRequest {
+                        linguistic_prompt: batch_prompts.linguistic.clone(),
+                        supporting_prompt: batch_prompts.supporting.clone(),
+                        negative_prompt: batch_prompts.negative.clone(),
+                        ..job.clone()
+                    };
 
-                final_images.extend(images);
+                    let retry_strategy = ExponentialBackoff::from_millis(50).max_delay(std::time::Duration::from_secs(2)).take(5);
+                    let (events_tx, mut events_rx) = unbounded();
+                    let tries = AtomicU32::new(0);
+                    let batch = RetryIf::spawn(retry_strategy, || async {
+                        if tries.fetch_add(1, Ordering::Relaxed) > 0 {
+                            retries.fetch_add(1, Ordering::Relaxed);
+                        }
+                        debug!("Generating batch of {} images", batch_size);
+                        let query = batch_request.build_query(&config, server, batch_size, seed_offset, init_images.as_ref()).context("Failed to build query")?;
+                        Self::generate_batch(server, query, job.output_format, events_tx.clone(), cancel.clone()).await.context("Failed to generate batch")
+                    }, |e: &anyhow::Error| !e.is::<Cancelled>());
+                    let mut batch = Box::pin(batch.fuse());
+                    // Pass on progress until the batch is done. The percentage is for the
+                    // batch, so scale it to the whole request.
+                    let images = loop {
+                        let event = select! {
+                            images = batch => break images,
+                            event = events_rx.next() => match event {
+                                Some(GenerationEvent::Generating(progress)) => {
+                                    let done = done as f64 + batch_size as f64 * progress.percent as f64 / 100.0;
+                                    GenerationEvent::Generating(Progress {
+                                        percent: (100.0 * done / request.count as f64) as u32,
+                                        ..progress
+                                    })
+                                },
+                                Some(event) => event,
+                                None => continue,
+                            },
+                        };
+                        yield event;
+                    };
+                    drop(batch);
+                    let (images, batch_time) = images.context("Ran out of retries")?;
 
-                remaining -= batch_size;
-                seed_offset += batch_size;
+                    final_images.extend(images);
+                    gpu_time += batch_time;
+
+                    seed_offset += batch_size;
+                }
+                all_prompts.extend(prompts);
             }
             let completed_request = CompletedRequest {
                 base: request,
                 images: final_images,
+                prompts: all_prompts,
                 uuid: uuid::Uuid::new_v4(),
+                gpu_time,
             };
             yield GenerationEvent::Completed(completed_request);
-        }.map(|r| r.unwrap_or_else(GenerationEvent::Error))
+        }.map(|r| r.unwrap_or_else(|e: anyhow::Error| if e.is::<Cancelled>() {
+            GenerationEvent::Cancelled
+        } else {
+            GenerationEvent::Error(e)
+        }))
     }
 
-    /// Returns the highest-scoring request in the queue, if any.
-    fn highest_scoring<T>(
-        queue: &mut Vec<(ParsedRequest, T)>,
-        previous_request: &ParsedRequest,
-    ) -> Option<(ParsedRequest, T)> {
-        if queue.is_empty() {
-            return None;
-        }
-        let mut highest_score = f32::NEG_INFINITY;
-        let mut highest_index = 0;
-        for (i, (request, _)) in queue.iter().enumerate() {
-            // TODO: This is a very simple scoring function.
-            // It should be improved.
-            // Currently: Prefers older requests, prefers requests with the same model, prefers requests with different users.
-            let score = request.score(previous_request) - i as f32;
-            if score >= highest_score {
-                highest_score = score;
-                highest_index = i;
+    /// Checks on every server now and then, and reports whether each is up.
+    async fn check_health(config: BotConfigModule, results: UnboundedSender<(String, bool)>) {
+        loop {
+            tokio::time::sleep(backends::HEALTH_CHECK_INTERVAL).await;
+            let servers = config.with_config(|c| c.backend.servers()).await;
+            let checks = servers
+                .iter()
+                .map(|server| async move { (server.address(), backends::is_up(server).await) });
+            for result in futures::future::join_all(checks).await {
+                if results.unbounded_send(result).is_err() {
+                    return;
+                }
             }
         }
-        let (request, tx) = queue.remove(highest_index);
-        Some((request, tx))
     }
 
     /// Core of the generator.
-    /// This background task picks pictures off the queue, prioritizes them based on a cost metric, and generates them.
-    /// It sends updates back to the requester.
-    pub async fn run(
+    /// This background task picks pictures off the queue, by each user's fair share of GPU time,
+    /// and generates them on whichever servers have room. It sends updates back to the requester.
+    ///
+    /// If a job fails because its server went away, it goes back in the queue for another
+    /// server, rather than failing. It takes its turn by fair share like everything else.
+    async fn run(
         self,
-        mut command_receiver: UnboundedReceiver<(ParsedRequest, UnboundedSender<GenerationEvent>)>,
+        mut command_receiver: UnboundedReceiver<Command>,
+        mut health_checks: UnboundedReceiver<(String, bool)>,
     ) {
+        /// A job in the queue.
+        struct Waiting {
+            id: JobId,
+            tx: UnboundedSender<GenerationEvent>,
+            /// How many servers it's lost.
+            failovers: u32,
+            /// When it joined the queue, or went back in.
+            since: std::time::Instant,
+        }
+        /// A job that's generating, or whose server is being checked after an error.
+        struct Job {
+            request: ParsedRequest,
+            tx: UnboundedSender<GenerationEvent>,
+            server: ComfyServer,
+            failovers: u32,
+            error: Option<anyhow::Error>,
+            cancel: watch::Sender<bool>,
+            /// What the user was charged up front.
+            estimate: f64,
+            started: std::time::Instant,
+            /// How long the server really spent on it, once it's done.
+            gpu_time: Option<Duration>,
+            /// How long it was queued.
+            waited: Duration,
+            /// How long it took, or ran before it failed. Set once it's done.
+            took: Duration,
+            retries: Arc<AtomicU32>,
+        }
+        /// Passes an event on to whoever asked. They may have stopped listening, which is no
+        /// reason to stop the queue for everyone else.
+        fn tell(id: JobId, tx: &UnboundedSender<GenerationEvent>, event: GenerationEvent) {
+            if let Err(e) = tx.unbounded_send(event) {
+                debug!("Nobody's listening to job {id} anymore: {e}");
+            }
+        }
+        /// Keeps the telemetry for a job, now that we know how it went.
+        async fn record(
+            db: &DatabaseModule,
+            config: &BotConfigModule,
+            job: &Job,
+            outcome: Outcome,
+        ) {
+            let attempt = config
+                .with_config(|c| {
+                    Attempt::new(
+                        c,
+                        &job.request,
+                        &job.server,
+                        job.waited,
+                        job.took,
+                        job.retries.load(Ordering::Relaxed),
+                        outcome,
+                    )
+                })
+                .await;
+            if let Err(e) = db.record_attempt(&attempt).await {
+                warn!("Couldn't record {attempt:?}: {e:#}");
+            }
+        }
+        /// ETAs for the jobs that are running, by id, and for the queue.
+        fn forecast(
+            config: &BotConfig,
+            pool: &Pool,
+            fair: &FairShare,
+            timings: &Timings,
+            jobs: &HashMap<JobId, Job>,
+            queue: &[(ParsedRequest, Waiting)],
+        ) -> (Vec<(JobId, Eta)>, Vec<Option<Eta>>) {
+            // Jobs with an error are done, or about to be retried.
+            let running = jobs
+                .iter()
+                .filter(|(_, job)| job.error.is_none())
+                .collect::<Vec<_>>();
+            let (etas, queued) = scheduler::forecast(
+                config,
+                pool,
+                fair,
+                timings,
+                &running
+                    .iter()
+                    .map(|(_, job)| (job.server.address(), &job.request, job.started.elapsed()))
+                    .collect::<Vec<_>>(),
+                queue,
+                std::time::Instant::now(),
+            );
+            let ids = running.iter().map(|(id, _)| **id);
+            (ids.zip(etas).collect(), queued)
+        }
+        let config = self.0.read().await.config.clone();
+        let db = self.0.read().await.db.clone();
+        let mut pool = Pool::default();
+        let mut fair = FairShare::default();
+        // How long things usually take, for ETAs.
+        let mut timings = db.get_timings().await.unwrap_or_else(|e| {
+            warn!("Couldn't get timings: {e:#}");
+            Timings::default()
+        });
         // Queue of pictures-to-be-generated.
-        let mut queue: Vec<(ParsedRequest, UnboundedSender<GenerationEvent>)> = vec![];
-        // Stream for the currently generating picture, if any.
-        let mut current_gen: EventStream = Box::pin(empty());
-        let mut current_tx: Option<UnboundedSender<GenerationEvent>> = None;
-        // Previously generated picture... if any.
-        let mut previous_request: Option<ParsedRequest> = None;
-        loop {
-            // Update the load.
-            let current_load = queue.len() + if current_tx.is_some() { 1 } else { 0 };
-            self.0
-                .write()
-                .await
-                .load
-                .store(current_load, Ordering::Relaxed);
-
-            // Wait for a new command or the current generation to finish.
-            if current_tx.is_none() {
-                // We're not generating anything right now, so we can pick something off the queue.
-                if let Some((request, tx)) =
-                    Self::highest_scoring(&mut queue, &previous_request.unwrap_or_default())
-                {
-                    // We found something to generate.
-                    current_gen = Box::pin(self.do_generate(request.clone()).await);
-                    current_tx = Some(tx);
-                    previous_request = Some(request);
-                } else {
-                    // Nothing to generate.
-                    previous_request = None;
+        let mut queue: Vec<(ParsedRequest, Waiting)> = vec![];
+        let mut jobs: HashMap<JobId, Job> = HashMap::new();
+        // Events from the generating jobs, tagged by job. None means the job is done.
+        let mut running = futures::stream::SelectAll::<
+            Pin<Box<dyn Send + Stream<Item = (JobId, Option<GenerationEvent>)>>>,
+        >::new();
+        // Whether a failed job's server is still up.
+        let (probe_tx, mut probes) = unbounded::<(JobId, bool)>();
+        // Takes a job out of the saved queue, now that it's over.
+        let done = |id: JobId| {
+            let db = db.clone();
+            async move {
+                if let Err(e) = db.mark_done(id).await {
+                    warn!("Couldn't mark job {id} done: {e:#}");
                 }
             }
+        };
+        loop {
+            // Start whatever there's room for.
+            pool.sync(&config.with_config(|c| c.backend.servers()).await);
+            loop {
+                let now = std::time::Instant::now();
+                let started = config
+                    .with_config(|c| {
+                        let (index, server) = pool.pick(c, &fair, &queue, now)?;
+                        let (request, waiting) = queue.remove(index);
+                        pool.start(c, &server.address(), &request);
+                        let estimate = fair.estimate(&request);
+                        fair.charge(c, &request.base.user, estimate, now);
+                        Some((request, waiting, server, estimate))
+                    })
+                    .await;
+                let Some((request, waiting, server, estimate)) = started else {
+                    break;
+                };
+                debug!("Generating job {} on {}", waiting.id, server.address());
+                let id = waiting.id;
+                let (cancel, cancelled) = watch::channel(false);
+                let retries = Arc::new(AtomicU32::new(0));
+                let events = self
+                    .do_generate(request.clone(), server.clone(), cancelled, retries.clone())
+                    .await;
+                running.push(Box::pin(
+                    events
+                        .map(move |event| (id, Some(event)))
+                        .chain(futures::stream::once(async move { (id, None) })),
+                ));
+                jobs.insert(
+                    id,
+                    Job {
+                        request,
+                        tx: waiting.tx,
+                        server,
+                        failovers: waiting.failovers,
+                        error: None,
+                        cancel,
+                        estimate,
+                        started: now,
+                        gpu_time: None,
+                        waited: now.saturating_duration_since(waiting.since),
+                        took: Duration::ZERO,
+                        retries,
+                    },
+                );
+            }
+
             select! {
-                // New event on the generating stream.
-                event = current_gen.next() => {
+                // New event from one of the generating jobs.
+                event = running.next() => {
+                    let Some((id, event)) = event else {
+                        continue;
+                    };
                     match event {
+                        // Hold on to errors until we know whether the server is to blame.
+                        Some(GenerationEvent::Error(e)) => {
+                            if let Some(job) = jobs.get_mut(&id) {
+                                job.error = Some(e);
+                            }
+                        },
                         Some(event) => {
                             // Send the event to the requester.
-                            if let Some(mut tx) = current_tx.as_ref() {
-                                tx.send(event).await.expect("failed to send event");
+                            if let Some(job) = jobs.get_mut(&id) {
+                                if let GenerationEvent::Completed(c) = &event {
+                                    job.gpu_time = Some(c.gpu_time);
+                                }
+                                tell(id, &job.tx, event);
                             }
                         },
                         None => {
                             // Generation is done.
-                            current_tx = None;
-                            current_gen = Box::pin(empty());
+                            let Some(job) = jobs.get_mut(&id) else {
+                                continue;
+                            };
+                            pool.finish(&job.server.address());
+                            // Correct the estimate. Without a measurement, the time it took
+                            // will do.
+                            job.took = job.gpu_time.unwrap_or_else(|| job.started.elapsed());
+                            let took = job.took.as_secs_f64();
+                            let user = &job.request.base.user;
+                            let now = std::time::Instant::now();
+                            config
+                                .with_config(|c| fair.charge(c, user, took - job.estimate, now))
+                                .await;
+                            // Errors are recorded once we know whether the server is to blame.
+                            if job.gpu_time.is_some() {
+                                fair.measured(&job.request, took);
+                                record(&db, &config, job, Outcome::Ok).await;
+                                match db.get_timings().await {
+                                    Result::Ok(t) => timings = t,
+                                    Err(e) => warn!("Couldn't update timings: {e:#}"),
+                                }
+                            } else if job.error.is_none() {
+                                let outcome = if *job.cancel.borrow() { Outcome::Cancelled } else { Outcome::Other };
+                                record(&db, &config, job, outcome).await;
+                            }
+                            if job.error.is_some() {
+                                let server = job.server.clone();
+                                let probe_tx = probe_tx.clone();
+                                tokio::task::spawn(async move {
+                                    let _ = probe_tx.unbounded_send((id, backends::is_up(&server).await));
+                                });
+                            } else {
+                                jobs.remove(&id);
+                                done(id).await;
+                            }
                         },
                     }
                 },
-                // New picture to generate.
-                command = command_receiver.next() => {
-                    if let Some((request, mut tx)) = command {
-                        let qsz = queue.len() + if current_tx.is_some() { 1 } else { 0 };
-                        tx.send(GenerationEvent::Queued(qsz as u32)).await.expect("failed to send queued event");
-                        queue.push((request, tx));
+                // A failed job's server has been checked.
+                probe = probes.next() => {
+                    let Some((id, up)) = probe else {
+                        continue;
+                    };
+                    let Some(mut job) = jobs.remove(&id) else {
+                        continue;
+                    };
+                    let address = job.server.address();
+                    if !up {
+                        pool.set_healthy(&address, false);
+                    }
+                    let outcome = match &job.error {
+                        _ if *job.cancel.borrow() => Outcome::Cancelled,
+                        _ if !up => Outcome::ServerLost,
+                        Some(error) => Outcome::of_error(error),
+                        None => Outcome::Other,
+                    };
+                    record(&db, &config, &job, outcome).await;
+                    if *job.cancel.borrow() {
+                        tell(id, &job.tx, GenerationEvent::Cancelled);
+                        done(id).await;
+                    } else if up || job.failovers >= MAX_FAILOVERS {
+                        let error = job.error.take().expect("probed a job without an error");
+                        tell(id, &job.tx, GenerationEvent::Error(error));
+                        done(id).await;
                     } else {
-                        panic!("command channel closed");
+                        warn!("{address} went away; trying the job again elsewhere");
+                        queue.push((job.request, Waiting {
+                            id,
+                            tx: job.tx,
+                            failovers: job.failovers + 1,
+                            since: std::time::Instant::now(),
+                        }));
+                    }
+                },
+                // A server came up, or went away.
+                health = health_checks.next() => {
+                    if let Some((address, up)) = health {
+                        if pool.set_healthy(&address, up) {
+                            if up {
+                                info!("{address} is back");
+                            } else {
+                                warn!("{address} isn't answering");
+                            }
+                        }
+                    }
+                },
+                command = command_receiver.next() => {
+                    match command {
+                        // New picture to generate.
+                        Some(Command::Generate(id, request, tx)) => {
+                            if !pool.can_run(&request.model_name) {
+                                let error = anyhow::anyhow!("None of the servers can run {}", request.model_name);
+                                tell(id, &tx, GenerationEvent::Error(error));
+                                done(id).await;
+                                continue;
+                            }
+                            let qsz = queue.len() + jobs.len();
+                            queue.push((*request, Waiting { id, tx, failovers: 0, since: std::time::Instant::now() }));
+                            let (_, etas) = config.with_config(|c| forecast(c, &pool, &fair, &timings, &jobs, &queue)).await;
+                            let eta = etas.last().copied().flatten();
+                            let (_, waiting) = queue.last().expect("just pushed");
+                            tell(waiting.id, &waiting.tx, GenerationEvent::Queued(qsz as u32, eta));
+                        },
+                        // Queued jobs just go away. Running ones are told to stop, and say
+                        // Cancelled themselves once they have.
+                        Some(Command::Cancel(cancel, reply)) => {
+                            let mut cancelled = 0;
+                            let mut kept = vec![];
+                            for (request, waiting) in queue.drain(..) {
+                                if cancel.matches(waiting.id, &request) {
+                                    tell(waiting.id, &waiting.tx, GenerationEvent::Cancelled);
+                                    done(waiting.id).await;
+                                    cancelled += 1;
+                                } else {
+                                    kept.push((request, waiting));
+                                }
+                            }
+                            queue = kept;
+                            for (id, job) in &jobs {
+                                if cancel.matches(*id, &job.request) && !*job.cancel.borrow() {
+                                    job.cancel.send_replace(true);
+                                    cancelled += 1;
+                                }
+                            }
+                            let _ = reply.send(cancelled);
+                        },
+                        // What's going on, in the order it should happen.
+                        Some(Command::Queue(reply)) => {
+                            let (running, etas) = config.with_config(|c| forecast(c, &pool, &fair, &timings, &jobs, &queue)).await;
+                            let mut entries = running.into_iter().map(|(id, eta)| QueueEntry {
+                                id,
+                                request: jobs[&id].request.clone(),
+                                running: true,
+                                eta: Some(eta),
+                            }).collect::<Vec<_>>();
+                            entries.sort_by_key(|e| e.eta.map(|eta| eta.finish));
+                            let mut queued = queue.iter().zip(etas).map(|((request, waiting), eta)| QueueEntry {
+                                id: waiting.id,
+                                request: request.clone(),
+                                running: false,
+                                eta,
+                            }).collect::<Vec<_>>();
+                            // Nones last.
+                            queued.sort_by_key(|e| e.eta.map_or((1, Duration::ZERO), |eta| (0, eta.start)));
+                            entries.extend(queued);
+                            let _ = reply.send(entries);
+                        },
+                        None => panic!("command channel closed"),
                     }
                 }
             }
         }
     }
 
+    /// Cancels jobs: the one with the id, or all of the user's. Users can only cancel their
+    /// own jobs, unless `anyone` is set. Returns how many were cancelled.
+    pub async fn cancel(
+        &self,
+        id: Option<JobId>,
+        user: &str,
+        source: Source,
+        anyone: bool,
+    ) -> Result<usize> {
+        let (reply, cancelled) = oneshot::channel();
+        let request = CancelRequest {
+            id,
+            user: user.to_string(),
+            source,
+            anyone,
+        };
+        self.0
+            .write()
+            .await
+            .command_sender
+            .send(Command::Cancel(request, reply))
+            .await
+            .context("The generator has stopped")?;
+        cancelled.await.context("The generator has stopped")
+    }
+
+    /// Everything queued or generating, in the order it should happen.
+    pub async fn queue(&self) -> Result<Vec<QueueEntry>> {
+        let (reply, entries) = oneshot::channel();
+        self.0
+            .write()
+            .await
+            .command_sender
+            .send(Command::Queue(reply))
+            .await
+            .context("The generator has stopped")?;
+        entries.await.context("The generator has stopped")
+    }
+
+    /// Generates pictures for the request. Once it's been accepted, it's saved, and a restart
+    /// picks it up again; see take_resumed. The results then go to `reply_to`.
     pub async fn generate(
         &self,
         mut request: UserRequest,
         is_private: bool,
+        reply_to: ReplyTo,
     ) -> impl Stream<Item = GenerationEvent> + '_ {
         let db_for_completion = self.0.read().await.db.clone();
         let (tx, rx) = unbounded();
-        try_stream! {
+        let events = try_stream! {
             if let Some(ref dream) = request.dream {
                 // This is a dream request. We need to generate a prompt for it.
                 debug!("Generating prompt for {:?}", request);
@@ -894,28 +2510,54 @@ impl ImageGeneratorModule {
             }
 
             // TODO: Snapshot the config here, keep it for the scope of the request.
-            let parsed = ParsedRequest::from_request(&self.0.read().await.config.snapshot().await, request)?;
+            let db = self.0.read().await.db.clone();
+            let settings = db.get_user_settings(&request.source, &request.user).await?;
+            let presets = db.get_presets(&request.source, &request.user).await?;
+            let parsed = ParsedRequest::from_request(&self.0.read().await.config.snapshot().await, request, &settings, &presets)?;
             // Check if the user is making too many private requests.
             self.0.read().await.db.check_privacy_limit(&parsed, is_private)
                 .await
                 .context("While checking privacy limit")?;
             yield GenerationEvent::Parsed(parsed.clone());
+            let warnings = self.0.read().await.config.with_config(|c| parsed.warnings(c)).await;
+            for warning in warnings {
+                yield GenerationEvent::Warning(warning);
+            }
 
-            self.0.write().await.command_sender.send((parsed.clone(), tx)).await.expect("failed to send command");
-        }.map(|r| r.unwrap_or_else(GenerationEvent::Error))
-         .chain(rx)
-         .then(move |ev| {
-            let db = db_for_completion.clone();
-            async move {
-                if let GenerationEvent::Completed(ref ev) = ev {
-                    db.update_user_stats(&ev.base, is_private).await.expect("failed to update user stats");
-                }
-                ev
+            let id = self.0.read().await.next_job.fetch_add(1, Ordering::Relaxed);
+            yield GenerationEvent::Accepted(id);
+            // So that !queue doesn't show the prompt of something sent by DM.
+            let mut queued = parsed.clone();
+            queued.base.private |= is_private;
+            if let Err(e) = db.save_queued(id, &queued, &reply_to).await {
+                warn!("Couldn't save job {id}; it won't survive a restart: {e:#}");
             }
-        })
+            self.0.write().await.command_sender.send(Command::Generate(id, Box::new(queued), tx)).await.expect("failed to send command");
+        }.map(|r| r.unwrap_or_else(GenerationEvent::Error))
+         .chain(rx);
+        counting_stats(db_for_completion, is_private, events)
     }
 }
 
+/// Counts what comes out toward the user's stats.
+fn counting_stats(
+    db: DatabaseModule,
+    is_private: bool,
+    events: impl Stream<Item = GenerationEvent>,
+) -> impl Stream<Item = GenerationEvent> {
+    events.then(move |ev| {
+        let db = db.clone();
+        async move {
+            if let GenerationEvent::Completed(ref ev) = ev {
+                db.update_user_stats(&ev.base, is_private)
+                    .await
+                    .expect("failed to update user stats");
+            }
+            ev
+        }
+    })
+}
+
 lazy_static! {
     pub static ref STYLES: Vec<(&'static str, &'static str)> = vec![
         ("Shōnen Anime", "Shōnen Anime, action-oriented, Akira Toriyama (Dragon Ball), youthful, vibrant, dynamic"),
@@ -946,14 +2588,82 @@ lazy_static! {
     ];
 }
 
+/// The picture in one of ComfyUI's binary websocket frames, if it's a preview.
+/// A frame starts with its type as a big-endian u32. Type 1 is a plain preview, followed by
+/// the image format; type 4 has a length-prefixed block of JSON metadata instead.
+fn preview_image(frame: &[u8]) -> Option<&[u8]> {
+    let word = |at: usize| -> Option<usize> {
+        let bytes = frame.get(at..at + 4)?;
+        Some(u32::from_be_bytes(bytes.try_into().ok()?) as usize)
+    };
+    match word(0)? {
+        1 => frame.get(8..),
+        4 => frame.get(8 + word(4)?..),
+        _ => None,
+    }
+    .filter(|image| !image.is_empty())
+}
+
+/// Checks a --sampler or --scheduler value against the model's list.
+/// Returns the value as the config spells it, or a message saying what would work.
+fn choose_from(model_name: &str, what: &str, value: &str, allowed: &[String]) -> Result<String> {
+    if allowed.is_empty() {
+        bail!("{model_name} doesn't let you choose a {what}");
+    }
+    if let Some(choice) = allowed.iter().find(|a| a.eq_ignore_ascii_case(value)) {
+        return Ok(choice.clone());
+    }
+    let mut message = format!("{model_name} doesn't support the {value} {what}.");
+    let best = allowed
+        .iter()
+        .map(|a| (strsim::jaro_winkler(&value.to_lowercase(), a), a))
+        .max_by(|a, b| a.0.total_cmp(&b.0));
+    if let Some((similarity, best)) = best {
+        if similarity >= SIMILARITY_THRESHOLD {
+            message += &format!(" Did you mean {best}?");
+        }
+    }
+    message += &format!(" It has: {}", allowed.join(", "));
+    bail!(message)
+}
+
 pub fn choose_random_style() -> &'static str {
     let mut rng = rand::thread_rng();
     let (_, style) = STYLES.choose(&mut rng).unwrap();
     style
 }
 
+/// Whether the address is the bot itself, its local network, or otherwise somewhere a user
+/// shouldn't be able to make it fetch from.
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                // Carrier-grade NAT.
+                || (a == 100 && (64..128).contains(&b))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_internal(ip.into()),
+            None => {
+                ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local()
+            }
+        },
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use rand::Rng;
+
     use super::*;
 
     fn most_similar(model: &str) -> (f64, String) {
@@ -987,6 +2697,855 @@ mod tests {
         assert_eq!(best_model, target);
     }
 
+    fn request(raw: &str) -> UserRequest {
+        UserRequest {
+            user: "tester".to_string(),
+            dream: None,
+            raw: raw.to_string(),
+            source: Source::Unknown,
+            private: false,
+            comment: None,
+            parent: None,
+            settings: None,
+        }
+    }
+
+    fn parse(raw: &str) -> Result<ParsedRequest> {
+        ParsedRequest::from_request(
+            &crate::config::testconfig(),
+            request(raw),
+            &UserSettings::default(),
+            &HashMap::new(),
+        )
+    }
+
+    /// The JSON that build_query would send for a single image.
+    fn query(parsed: ParsedRequest) -> serde_json::Value {
+        let config = crate::config::testconfig();
+        // Pretend the init image, if any, has been uploaded already.
+        let uploaded = parsed.init.as_ref().map(|init| UploadedImages {
+            init: "init.png".to_string(),
+            mask: init.mask.as_ref().map(|_| "mask.png".to_string()),
+        });
+        let request = parsed
+            .build_query(
+                &config,
+                &config.backend.servers()[0],
+                1,
+                0,
+                uploaded.as_ref(),
+            )
+            .unwrap()
+            .request
+            .build()
+            .unwrap();
+        serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_from_request() {
+        let parsed = parse(
+            "a cat --style film noir --no dogs -m fu --seed 7 -c 3 --steps 20 --scale 7 -a 5 --ar 2:1 --np",
+        )
+        .unwrap();
+        assert_eq!(parsed.linguistic_prompt, "a cat");
+        assert_eq!(parsed.supporting_prompt, "film noir");
+        assert_eq!(parsed.negative_prompt, "dogs");
+        assert_eq!(parsed.model_name, "foo");
+        assert_eq!(parsed.seed, 7);
+        assert_eq!(parsed.count, 3);
+        assert_eq!(parsed.steps, Some(20));
+        assert_eq!(parsed.guidance_scale, 7.0);
+        assert_eq!(parsed.aesthetic_scale, 5.0);
+        assert_eq!((parsed.width, parsed.height), (1408, 704));
+        assert!(!parsed.use_pos_default);
+        assert!(parsed.use_neg_default);
+    }
+
+    #[test]
+    fn test_from_request_defaults() {
+        let parsed = parse("a cat").unwrap();
+        assert_eq!(parsed.model_name, "foo");
+        assert_eq!((parsed.width, parsed.height), (1024, 1024));
+        assert_eq!(parsed.count, ParsedRequest::default().count);
+        assert_eq!(parsed.steps, None);
+        // Counts are clamped.
+        assert_eq!(parse("a cat -c 20").unwrap().count, 9);
+        // Close-enough model names are corrected.
+        assert_eq!(parse("a cat -m flx").unwrap().model_name, "flux");
+    }
+
+    #[test]
+    fn test_from_request_settings() {
+        let config = crate::config::testconfig();
+        let settings = UserSettings {
+            model: Some("flux".to_string()),
+            ar: Some("2:1".to_string()),
+            negative: Some("blurry".to_string()),
+            count: Some(2),
+            format: Some(OutputFormat::Png),
+            ..Default::default()
+        };
+        let with_settings = |raw: &str| {
+            ParsedRequest::from_request(&config, request(raw), &settings, &HashMap::new()).unwrap()
+        };
+        let parsed = with_settings("a cat");
+        assert_eq!(parsed.model_name, "flux");
+        assert_eq!((parsed.width, parsed.height), (1408, 704));
+        assert_eq!(parsed.negative_prompt, "blurry");
+        assert_eq!(parsed.count, 2);
+        assert_eq!(parsed.output_format, OutputFormat::Png);
+
+        // Explicit flags win.
+        let parsed = with_settings("a cat -m fu --ar 1:1 --no dogs -c 3");
+        assert_eq!(parsed.model_name, "foo");
+        assert_eq!((parsed.width, parsed.height), (1024, 1024));
+        assert_eq!(parsed.negative_prompt, "dogs");
+        assert_eq!(parsed.count, 3);
+
+        // A default count doesn't get in the way of grids.
+        let parsed = with_settings("a cat --xy steps=10,20,30");
+        assert_eq!(parsed.count, 3);
+        assert_eq!(parsed.output_format, OutputFormat::Png);
+
+        // Retries keep the settings the request was made with, even after they've changed.
+        let private = UserSettings {
+            private: Some(true),
+            ..settings.clone()
+        };
+        let original =
+            ParsedRequest::from_request(&config, request("a cat"), &private, &HashMap::new())
+                .unwrap();
+        assert!(original.base.private);
+        let mut retry = original.base.clone();
+        retry.raw = original.to_command_line();
+        let retried =
+            ParsedRequest::from_request(&config, retry, &Default::default(), &HashMap::new())
+                .unwrap();
+        assert_eq!(retried.negative_prompt, "blurry");
+        assert_eq!(retried.output_format, OutputFormat::Png);
+        assert!(retried.base.private);
+        // An empty negative stays empty.
+        let original = parse("a cat").unwrap();
+        let mut retry = original.base.clone();
+        retry.raw = original.to_command_line();
+        let retried =
+            ParsedRequest::from_request(&config, retry, &settings, &HashMap::new()).unwrap();
+        assert_eq!(retried.negative_prompt, "");
+        assert_eq!(retried.output_format, OutputFormat::Jpeg);
+    }
+
+    #[test]
+    fn test_from_request_width_height() {
+        let parsed = parse("a cat -w 512").unwrap();
+        assert_eq!((parsed.width, parsed.height), (512, 1024));
+        let parsed = parse("a cat --ar 16:9 -h 512").unwrap();
+        assert_eq!(parsed.height, 512);
+        assert_eq!(parsed.width, 1344);
+    }
+
+    #[test]
+    fn test_from_request_errors() {
+        let message = |raw: &str| format!("{:#}", parse(raw).unwrap_err());
+        // Invalid aspect ratios are errors, not a silent 1:1.
+        assert!(message("a cat --ar 3x2").contains("at column 12"));
+        assert!(message("a cat --ar 10:1").contains("between 1:4 and 4:1"));
+        assert!(message("a cat -m nonexistent").contains("at column 10"));
+        assert!(message("a cat --scale 100").contains("at column 15"));
+        assert!(message("a cat -c 0").contains("at column 10"));
+        assert!(message("a cat -w 10").contains("at column 10"));
+        assert!(message("--ar 1:1").contains("Linguistic prompt is required"));
+        assert!(message("a cat -w 100000 -h 100000").contains("Resolution is too high"));
+    }
+
+    #[test]
+    fn test_sampler_and_scheduler() {
+        let parsed = parse("a cat -m flux --sampler Euler --scheduler beta").unwrap();
+        assert_eq!(parsed.sampler.as_deref(), Some("euler"));
+        assert_eq!(parsed.scheduler.as_deref(), Some("beta"));
+        assert_eq!(parse("a cat -m flux").unwrap().sampler, None);
+
+        let message = |raw: &str| format!("{:#}", parse(raw).unwrap_err());
+        let err = message("a cat -m flux --sampler eular");
+        assert!(err.contains("Did you mean euler?"), "{err}");
+        assert!(err.contains("at column 25"), "{err}");
+        let err = message("a cat -m flux --scheduler karras");
+        assert!(
+            err.contains("It has: simple, sgm_uniform, beta, normal"),
+            "{err}"
+        );
+        let err = message("a cat -m foo --sampler euler");
+        assert!(
+            err.contains("foo doesn't let you choose a sampler"),
+            "{err}"
+        );
+
+        // The workflow gets either the choice or the model's default.
+        let workflow = |parsed: ParsedRequest| {
+            let body = query(parsed);
+            let sampler = body["prompt"]["16"]["inputs"]["sampler_name"].clone();
+            let scheduler = body["prompt"]["17"]["inputs"]["scheduler"].clone();
+            (sampler, scheduler)
+        };
+        assert_eq!(
+            workflow(parse("a cat -m flux --sampler deis").unwrap()),
+            ("deis".into(), "sgm_uniform".into())
+        );
+        assert_eq!(
+            workflow(parse("a cat -m flux").unwrap()),
+            ("dpmpp_2m".into(), "sgm_uniform".into())
+        );
+    }
+
+    #[test]
+    fn test_prompt_weighting() {
+        // SDXL gets ComfyUI syntax, whatever the user wrote.
+        let body = query(parse("a {red} (cat:1.3) --np").unwrap());
+        assert_eq!(
+            body["prompt"]["75"]["inputs"]["text_g"],
+            "a (red:1.05) (cat:1.3)"
+        );
+        // Flux doesn't understand weights at all.
+        let parsed = parse("a {red} (cat:1.3) -m flux").unwrap();
+        assert_eq!(
+            parsed.warnings(&crate::config::testconfig()),
+            vec!["flux ignores (word:weight) emphasis"]
+        );
+        let body = query(parsed);
+        assert_eq!(body["prompt"]["6"]["inputs"]["text"], "a red cat");
+        // The stored prompt stays as written.
+        assert_eq!(
+            parse("a {red} (cat:1.3)").unwrap().linguistic_prompt,
+            "a {red} (cat:1.3)"
+        );
+    }
+
+    #[test]
+    fn test_unsupported_prompts() {
+        // Flux has no use for --no, so it never gets one, defaults included.
+        let parsed = parse("a cat --no dogs -m flux").unwrap();
+        assert_eq!(
+            parsed.warnings(&crate::config::testconfig()),
+            vec!["flux ignores --no"]
+        );
+        let body = query(parsed);
+        assert_eq!(body["prompt"]["28"]["inputs"]["text"], "");
+        let body = query(parse("a cat --no dogs -m flux --nn").unwrap());
+        assert_eq!(body["prompt"]["28"]["inputs"]["text"], "");
+        // SDXL does.
+        let body = query(parse("a cat --no dogs --nn").unwrap());
+        assert!(body.to_string().contains("dogs"));
+    }
+
+    #[test]
+    fn test_variations() {
+        let parsed = parse("a cat -m flux --seed 10 --variation-seed 5 -c 2").unwrap();
+        assert_eq!(
+            parsed.variation,
+            Some(Variation {
+                seed: 5,
+                strength: DEFAULT_VARIATION_STRENGTH
+            })
+        );
+        assert_eq!(
+            parsed.max_batch_size(&crate::config::testconfig().models["flux"].capabilities),
+            1
+        );
+        // The second image keeps the seed, and moves the variation seed.
+        let config = crate::config::testconfig();
+        let request = parsed
+            .build_query(&config, &config.backend.servers()[0], 1, 1, None)
+            .unwrap()
+            .request
+            .build()
+            .unwrap();
+        let body: serde_json::Value =
+            serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+        let noise = &body["prompt"]["25"]["inputs"];
+        assert_eq!(noise["noise_seed"], 10);
+        assert_eq!(noise["variation_seed"], 6);
+        // Strength on its own picks a variation seed.
+        let parsed = parse("a cat -m flux --variation-strength 0.5").unwrap();
+        assert_eq!(parsed.variation.unwrap().strength, 0.5);
+        // No variation, no noise mixing.
+        let body = query(parse("a cat -m flux").unwrap());
+        assert_eq!(body["prompt"]["25"]["inputs"]["variation_strength"], 0.0);
+        // Seeds wrap around at the end of the batch, rather than overflowing.
+        let request = parse("a cat -m flux --seed 4294967295 -c 4")
+            .unwrap()
+            .build_query(&config, &config.backend.servers()[0], 1, 2, None)
+            .unwrap()
+            .request
+            .build()
+            .unwrap();
+        let body: serde_json::Value =
+            serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert_eq!(body["prompt"]["25"]["inputs"]["noise_seed"], 1);
+
+        let message = |raw: &str| format!("{:#}", parse(raw).unwrap_err());
+        let err = message("a cat -m flux --variation-strength 2");
+        assert!(err.contains("between 0 and 1"), "{err}");
+        let err = message("a cat -m foo --variation-seed 1");
+        assert!(err.contains("foo doesn't support variations"), "{err}");
+        // It's the workflow the request picks that counts. Flux's upscaler can't vary.
+        let err = message("a cat -m flux --init https://x.com/a.png --upscale --variation-seed 1");
+        assert!(err.contains("flux doesn't support variations"), "{err}");
+        let parsed = parse("a cat -m flux --init https://x.com/a.png --variation-seed 1").unwrap();
+        assert!(parsed.variation.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_fetch_image_stays_public() {
+        for url in [
+            "file:///etc/passwd",
+            "http://127.0.0.1:8188/view",
+            "http://localhost/",
+            "http://[::1]/",
+            "https://192.168.1.1/a.png",
+            "http://[::ffff:10.0.0.1]/a.png",
+        ] {
+            let err = ImageGeneratorModule::fetch_image(url).await.unwrap_err();
+            let err = format!("{err:#}");
+            assert!(
+                err.contains("isn't on the public internet") || err.contains("isn't an http"),
+                "{url}: {err}"
+            );
+        }
+        assert!(!is_internal("162.159.135.233".parse().unwrap()));
+        assert!(is_internal("100.64.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_init_image() {
+        let parsed = parse("a cat -m flux --init https://example.com/cat.png").unwrap();
+        assert_eq!(
+            parsed.init,
+            Some(InitImage {
+                url: "https://example.com/cat.png".to_string(),
+                strength: DEFAULT_INIT_STRENGTH,
+                mask: None,
+            })
+        );
+        // The img2img workflow loads the uploaded picture, and doesn't redraw all of it.
+        let body =
+            query(parse("a cat -m flux --init https://x.com/a.png --strength 0.25").unwrap());
+        assert_eq!(body["prompt"]["init"]["inputs"]["image"], "init.png");
+        assert_eq!(body["prompt"]["17"]["inputs"]["denoise"], 0.25);
+        let body = query(parse("a cat -m foo --init https://x.com/a.png").unwrap());
+        assert_eq!(body["prompt"]["init"]["inputs"]["image"], "init.png");
+        // Without --init, nothing changes.
+        let body = query(parse("a cat -m flux").unwrap());
+        assert_eq!(body["prompt"]["5"]["class_type"], "EmptyLatentImage");
+        assert_eq!(body["prompt"]["17"]["inputs"]["denoise"], 1.0);
+
+        let message = |raw: &str| format!("{:#}", parse(raw).unwrap_err());
+        let err = message("a cat --strength 0.5");
+        assert!(err.contains("use --init"), "{err}");
+        let err = message("a cat --init https://x.com/a.png --strength 1.5");
+        assert!(err.contains("between 0 and 1"), "{err}");
+        let err = message("a cat --init /etc/passwd");
+        assert!(err.contains("link to a picture"), "{err}");
+        let mut config = crate::config::testconfig();
+        config.models.get_mut("foo").unwrap().img2img_workflow = None;
+        let err = ParsedRequest::from_request(
+            &config,
+            request("a cat -m foo --init https://x.com/a.png"),
+            &UserSettings::default(),
+            &HashMap::new(),
+        )
+        .unwrap_err();
+        assert!(format!("{err:#}").contains("foo can't start from a picture"));
+    }
+
+    #[test]
+    fn test_inpainting() {
+        let parsed = parse("a cat -m flux --init https://x.com/a.png --mask alpha").unwrap();
+        let init = parsed.init.clone().unwrap();
+        assert_eq!(init.mask, Some(Mask::Alpha));
+        assert_eq!(init.strength, DEFAULT_INPAINT_STRENGTH);
+        let body = query(parsed);
+        assert_eq!(body["prompt"]["init"]["inputs"]["image"], "init.png");
+        assert_eq!(body["prompt"]["mask"]["inputs"]["image"], "mask.png");
+        assert_eq!(body["prompt"]["5"]["inputs"]["samples"][0], "init-masked");
+        let parsed = parse(
+            "a cat -m flux --init https://x.com/a.png --mask https://x.com/m.png --strength 0.8",
+        )
+        .unwrap();
+        let init = parsed.init.unwrap();
+        assert_eq!(
+            init.mask,
+            Some(Mask::Url("https://x.com/m.png".to_string()))
+        );
+        assert_eq!(init.strength, 0.8);
+
+        let message = |raw: &str| format!("{:#}", parse(raw).unwrap_err());
+        let err = message("a cat -m flux --mask alpha");
+        assert!(
+            err.contains("--mask needs a picture to start from"),
+            "{err}"
+        );
+        let err = message("a cat -m flux --init https://x.com/a.png --mask left");
+        assert!(err.contains("black and white picture"), "{err}");
+        let err = message("a cat -m foo --init https://x.com/a.png --mask alpha");
+        assert!(err.contains("foo can't inpaint"), "{err}");
+    }
+
+    #[test]
+    fn test_upscale() {
+        let parsed = parse("a cat -m flux --init https://x.com/a.png --upscale -c 3").unwrap();
+        assert!(parsed.upscale);
+        assert_eq!(
+            parsed.init.as_ref().unwrap().strength,
+            DEFAULT_UPSCALE_STRENGTH
+        );
+        // One picture in, one picture out.
+        assert_eq!(
+            parsed.max_batch_size(&crate::config::testconfig().models["flux"].capabilities),
+            1
+        );
+        let body = query(parsed);
+        assert_eq!(body["prompt"]["upscale"]["class_type"], "UltimateSDUpscale");
+        assert_eq!(body["prompt"]["upscale"]["inputs"]["denoise"], 0.35);
+        assert_eq!(body["prompt"]["init"]["inputs"]["image"], "init.png");
+
+        let message = |raw: &str| format!("{:#}", parse(raw).unwrap_err());
+        let err = message("a cat --upscale");
+        assert!(err.contains("--upscale needs a picture"), "{err}");
+        let err = message("a cat -m flux --init https://x.com/a.png --mask alpha --upscale");
+        assert!(err.contains("can't use --mask"), "{err}");
+    }
+
+    #[test]
+    fn test_loras() {
+        let parsed = parse("a cat <lora:ghibli:0.6> -m flux").unwrap();
+        // The tag stays in the prompt, so retries get the same LoRA.
+        assert_eq!(parsed.linguistic_prompt, "a cat <lora:ghibli:0.6>");
+        let body = query(parsed);
+        assert_eq!(body["prompt"]["6"]["inputs"]["text"], "a cat, ghibli style");
+        let lora = &body["prompt"]["lora-unet-loader-0"];
+        assert_eq!(lora["inputs"]["lora_name"], "flux/ghibli.safetensors");
+        assert_eq!(lora["inputs"]["strength_model"], 0.6);
+
+        let message = |raw: &str| format!("{:#}", parse(raw).unwrap_err());
+        let err = message("a cat <lora:ghibly> -m flux");
+        assert!(err.contains("Did you mean ghibli?"), "{err}");
+        let err = message("a cat <lora:ghibli>");
+        assert!(err.contains("ghibli is for flux models"), "{err}");
+    }
+
+    #[test]
+    fn test_every_workflow_fills_in() {
+        // Catches typos in placeholders, and templates that aren't JSON.
+        let mut config = crate::config::testconfig();
+        let foo = config.models.get_mut("foo").unwrap();
+        foo.default_sampler = Some("euler".to_string());
+        foo.default_scheduler = Some("normal".to_string());
+        let parsed = parse("a cat").unwrap();
+        for entry in std::fs::read_dir(".").unwrap() {
+            let path = entry.unwrap().path().to_string_lossy().to_string();
+            if !path.ends_with(".json.tmpl") {
+                continue;
+            }
+            config.models.get_mut("foo").unwrap().workflow = path.clone();
+            if let Err(e) = parsed.build_query(&config, &config.backend.servers()[0], 1, 0, None) {
+                panic!("{path}: {e:#}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_workflow_overrides() {
+        let mut config = crate::config::testconfig();
+        let build = |config: &BotConfig| {
+            let request = parse("a cat -m flux")
+                .unwrap()
+                .build_query(config, &config.backend.servers()[0], 1, 0, None)?
+                .request
+                .build()
+                .unwrap();
+            let body: serde_json::Value =
+                serde_json::from_slice(request.body().unwrap().as_bytes().unwrap()).unwrap();
+            Ok(body)
+        };
+        let flux = config.models.get_mut("flux").unwrap();
+        flux.overrides = HashMap::from([
+            ("BasicScheduler.denoise".to_string(), json!(0.9)),
+            ("6.text".to_string(), json!("a dog")),
+        ]);
+        let body = build(&config).unwrap();
+        assert_eq!(body["prompt"]["6"]["inputs"]["text"], "a dog");
+        let scheduler = body["prompt"]
+            .as_object()
+            .unwrap()
+            .values()
+            .find(|node| node["class_type"] == "BasicScheduler")
+            .unwrap();
+        assert_eq!(scheduler["inputs"]["denoise"], 0.9);
+
+        let flux = config.models.get_mut("flux").unwrap();
+        flux.overrides = HashMap::from([("NoSuchNode.x".to_string(), json!(1))]);
+        let err = format!("{:#}", build(&config).unwrap_err());
+        assert!(err.contains("no NoSuchNode node"), "{err}");
+    }
+
+    #[test]
+    fn test_progress_tracker() {
+        let stages = HashMap::from([
+            ("1".to_string(), "Load Checkpoint".to_string()),
+            ("2".to_string(), "KSampler".to_string()),
+            ("3".to_string(), "VAE Decode".to_string()),
+            ("4".to_string(), "Save Image".to_string()),
+        ]);
+        let mut tracker = ProgressTracker::new(stages);
+        tracker.prompt_id = Some("p".to_string());
+        let cached =
+            json!({"type": "execution_cached", "data": {"nodes": ["1"], "prompt_id": "p"}});
+        assert_eq!(tracker.update(&cached).unwrap().percent, 25);
+        // Someone else's prompt.
+        let other = json!({"type": "executing", "data": {"node": "3", "prompt_id": "q"}});
+        assert_eq!(tracker.update(&other), None);
+
+        let executing = json!({"type": "executing", "data": {"node": "2", "prompt_id": "p"}});
+        let progress = tracker.update(&executing).unwrap();
+        assert_eq!(progress.stage.as_deref(), Some("KSampler"));
+        assert_eq!(progress.to_string(), "25% (KSampler)");
+        let step = json!({"type": "progress", "data": {"value": 10, "max": 20, "node": "2", "prompt_id": "p"}});
+        assert_eq!(
+            tracker.update(&step).unwrap().to_string(),
+            "37% (KSampler, step 10/20)"
+        );
+        let executing = json!({"type": "executing", "data": {"node": "3", "prompt_id": "p"}});
+        assert_eq!(
+            tracker.update(&executing).unwrap().to_string(),
+            "50% (VAE Decode)"
+        );
+        // The end of the prompt isn't progress; the history has the results.
+        let done = json!({"type": "executing", "data": {"node": null, "prompt_id": "p"}});
+        assert_eq!(tracker.update(&done), None);
+    }
+
+    #[test]
+    fn test_queue_entry() {
+        let mut entry = QueueEntry {
+            id: 7,
+            request: parse("a cat in a hat -m flux -c 2").unwrap(),
+            running: false,
+            eta: Some(Eta {
+                start: Duration::from_secs(200),
+                finish: Duration::from_secs(300),
+            }),
+        };
+        assert_eq!(
+            entry.to_string(),
+            "#7 tester: flux, 2 pictures, \"a cat in a hat\" — starts in about 3 minutes, done in about 5 minutes"
+        );
+        entry.request.base.private = true;
+        entry.running = true;
+        entry.eta = None;
+        assert_eq!(
+            entry.to_string(),
+            "#7 tester: flux, 2 pictures, private — generating"
+        );
+    }
+
+    #[test]
+    fn test_cancel_matches() {
+        let mut request = parse("a cat").unwrap();
+        request.base.user = "alice".to_string();
+        request.base.source = Source::Irc;
+        let cancel = |id, user: &str, source, anyone| CancelRequest {
+            id,
+            user: user.to_string(),
+            source,
+            anyone,
+        };
+        assert!(cancel(None, "alice", Source::Irc, false).matches(3, &request));
+        assert!(cancel(Some(3), "alice", Source::Irc, false).matches(3, &request));
+        assert!(!cancel(Some(4), "alice", Source::Irc, false).matches(3, &request));
+        // Only your own, and an IRC nick isn't a Discord user.
+        assert!(!cancel(None, "bob", Source::Irc, false).matches(3, &request));
+        assert!(!cancel(None, "alice", Source::Discord, false).matches(3, &request));
+        assert!(cancel(Some(3), "owner", Source::Irc, true).matches(3, &request));
+    }
+
+    #[test]
+    fn test_preview_image() {
+        let jpeg = [0xff, 0xd8, 0xff];
+        let plain = [&[0, 0, 0, 1][..], &[0, 0, 0, 1], &jpeg].concat();
+        assert_eq!(preview_image(&plain), Some(&jpeg[..]));
+        let metadata = br#"{"prompt_id":"p"}"#;
+        let with_metadata = [
+            &[0, 0, 0, 4][..],
+            &(metadata.len() as u32).to_be_bytes(),
+            metadata,
+            &jpeg,
+        ]
+        .concat();
+        assert_eq!(preview_image(&with_metadata), Some(&jpeg[..]));
+        // Other kinds of frame, and truncated ones.
+        assert_eq!(preview_image(&[0, 0, 0, 2, 0, 0, 0, 1, 1]), None);
+        assert_eq!(preview_image(&plain[..8]), None);
+        assert_eq!(preview_image(&with_metadata[..10]), None);
+    }
+
+    #[test]
+    fn test_model_capabilities() {
+        let mut config = crate::config::testconfig();
+        let parse = |config: &BotConfig, raw: &str| {
+            ParsedRequest::from_request(
+                config,
+                request(raw),
+                &UserSettings::default(),
+                &HashMap::new(),
+            )
+        };
+        // Flux doesn't batch, and doesn't do negatives or CFG.
+        let parsed = parse(&config, "a cat -m flux -c 4 --no dogs --scale 7").unwrap();
+        assert_eq!(
+            parsed.max_batch_size(&config.models["flux"].capabilities),
+            1
+        );
+        assert_eq!(
+            parsed.warnings(&config),
+            vec!["flux ignores --no", "flux ignores --scale"]
+        );
+        let parsed = parse(&config, "a cat -m foo -c 4 --no dogs --scale 7").unwrap();
+        assert_eq!(parsed.max_batch_size(&config.models["foo"].capabilities), 4);
+        assert!(parsed.warnings(&config).is_empty());
+
+        let foo = &mut config.models.get_mut("foo").unwrap().capabilities;
+        foo.default_cfg = 3.5;
+        foo.min_cfg = 2.0;
+        foo.max_cfg = 10.0;
+        foo.resolution_stride = 16;
+        foo.pixel_budget = 1024 * 1024;
+        assert_eq!(parse(&config, "a cat").unwrap().guidance_scale, 3.5);
+        let err = format!("{:#}", parse(&config, "a cat --scale 12").unwrap_err());
+        assert!(err.contains("between 2 and 10 for foo"), "{err}");
+        let parsed = parse(&config, "a cat -w 1000 -h 504").unwrap();
+        assert_eq!((parsed.width, parsed.height), (992, 496));
+        let parsed = parse(&config, "a cat -c 4").unwrap();
+        assert_eq!(parsed.max_batch_size(&config.models["foo"].capabilities), 1);
+        let err = format!("{:#}", parse(&config, "a cat -w 2048").unwrap_err());
+        assert!(err.contains("at most 1.0 megapixels"), "{err}");
+    }
+
+    #[test]
+    fn test_wildcards() {
+        let config = crate::config::testconfig();
+        let parsed = parse("a {red|blue} __animal__ --no __nested/color__ -c 9").unwrap();
+        // The request itself keeps the unexpanded prompt, so retries can re-roll it.
+        assert_eq!(parsed.linguistic_prompt, "a {red|blue} __animal__");
+        let prompts = (0..parsed.count)
+            .map(|i| parsed.expand_for_image(&config, i).unwrap())
+            .collect::<Vec<_>>();
+        for p in &prompts {
+            assert!(!p.linguistic.contains('{') && !p.linguistic.contains("__"));
+            assert!(["red", "blue"].contains(&p.negative.as_str()));
+        }
+        assert!(prompts
+            .iter()
+            .any(|p| p.linguistic != prompts[0].linguistic));
+        // Same seed, same expansions.
+        assert_eq!(parsed.expand_for_image(&config, 3).unwrap(), prompts[3]);
+
+        let message = format!("{:#}", parse("a __animals__").unwrap_err());
+        assert!(message.contains("Did you mean __animal__?"), "{message}");
+        assert!(message.contains("at column 3"), "{message}");
+    }
+
+    #[test]
+    fn test_grid() {
+        let parsed = parse("a cat in a hat --seed 5 --xy steps=20,30,40 S/R=cat,dog").unwrap();
+        let grid = parsed.grid.as_ref().unwrap();
+        assert_eq!(parsed.count, 6);
+        assert_eq!(grid.cells.len(), 6);
+        // Row by row: Y picks the row, X the column.
+        assert_eq!(grid.cells[2].steps, Some(40));
+        assert_eq!(grid.cells[2].linguistic_prompt, "a cat in a hat");
+        assert_eq!(grid.cells[3].steps, Some(20));
+        assert_eq!(grid.cells[3].linguistic_prompt, "a dog in a hat");
+        assert!(grid.cells.iter().all(|c| c.seed == 5 && c.count == 1));
+        assert_eq!(
+            grid.labels(),
+            (
+                vec!["steps=20".into(), "steps=30".into(), "steps=40".into()],
+                vec!["cat".into(), "dog".into()]
+            )
+        );
+
+        // Cells resolve models and resolutions like any other request.
+        let parsed = parse("a cat --xy m=fu,flux ar=1:1,2:1").unwrap();
+        let cells = &parsed.grid.as_ref().unwrap().cells;
+        assert_eq!(cells[0].model_name, "foo");
+        assert_eq!((cells[3].width, cells[3].height), (1408, 704));
+        // All cells share the seed.
+        assert!(cells.iter().all(|c| c.seed == parsed.seed));
+
+        // Retries reproduce the grid.
+        for raw in [
+            "a cat in a hat --seed 5 --xy steps=20,30,40 S/R=cat,dog",
+            "a cat --ar 2:1 --xy model=foo,flux",
+            "a cat --xy ar=1:1,2:1 --scale 7",
+        ] {
+            let parsed = parse(raw).unwrap();
+            let line = parsed.to_command_line();
+            let mut reparsed = parse(&line).unwrap();
+            reparsed.base.raw = raw.to_string();
+            for cell in &mut reparsed.grid.as_mut().unwrap().cells {
+                cell.base.raw = raw.to_string();
+            }
+            assert_eq!(reparsed, parsed, "{line}");
+        }
+
+        let message = |raw: &str| format!("{:#}", parse(raw).unwrap_err());
+        assert!(message("a cat -c 2 --xy steps=1,2").contains("at column 10"));
+        assert!(message("a cat --xy steps=1,2,3,4,5 seed=1,2,3,4").contains("limited to 16"));
+        assert!(message("a cat --xy steps=1,0").contains("Wasn't that fast?"));
+        assert!(message("a cat --xy m=foo,nonexistent").contains("at column 12"));
+    }
+
+    /// Random text, heavy on characters that mean something to the parser.
+    fn random_text(rng: &mut impl rand::Rng, allow_empty: bool) -> String {
+        const ALPHABET: &[&str] = &[
+            "a", "b", "Z", "7", " ", " ", " ", "  ", "\t", "\n", "-", "--", "—", "\"", "\\", "=",
+            "(", ")", ":", ",", "'", "ō", "猫", "--no", "-m", "--style", "--xy", "{", "|", "}",
+            "{a|b}", "$", "$moody", "<", ">",
+        ];
+        let min = if allow_empty { 0 } else { 1 };
+        (0..rng.gen_range(min..12))
+            .map(|_| {
+                // Now and then, a wildcard.
+                if rng.gen_ratio(1, 40) {
+                    "__animal__"
+                } else {
+                    *ALPHABET.choose(rng).unwrap()
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_command_line_round_trip() {
+        let config = crate::config::testconfig();
+        let models = config.models.keys().cloned().collect::<Vec<_>>();
+        // Words that look like presets have to stay words.
+        let presets = HashMap::from([("moody".to_string(), "--style film noir".to_string())]);
+        for seed in 0..1000 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let original = ParsedRequest {
+                base: request(""),
+                model_name: models.choose(&mut rng).unwrap().clone(),
+                linguistic_prompt: random_text(&mut rng, false),
+                supporting_prompt: random_text(&mut rng, true),
+                negative_prompt: random_text(&mut rng, true),
+                use_pos_default: rng.gen(),
+                use_neg_default: rng.gen(),
+                guidance_scale: rng.gen_range(1.0..=80.0),
+                aesthetic_scale: *[rng.gen_range(1.0..=30.0), 20.0].choose(&mut rng).unwrap(),
+                steps: rng.gen_bool(0.5).then(|| rng.gen_range(1..200)),
+                count: rng.gen_range(1..=9),
+                seed: rng.gen(),
+                width: rng.gen_range(1..=16) * 64,
+                height: rng.gen_range(1..=16) * 64,
+                grid: None,
+                output_format: OutputFormat::default(),
+                sampler: None,
+                scheduler: None,
+                variation: None,
+                init: None,
+                upscale: false,
+            };
+            let model_config = &config.models[&original.model_name];
+            let original = ParsedRequest {
+                sampler: model_config.samplers.choose(&mut rng).cloned(),
+                scheduler: model_config.schedulers.choose(&mut rng).cloned(),
+                init: (model_config.img2img_workflow.is_some() && rng.gen()).then(|| InitImage {
+                    url: format!("https://example.com/{}.png", rng.gen::<u32>()),
+                    strength: rng.gen_range(0.0..=1.0),
+                    mask: match model_config.inpaint_workflow {
+                        Some(_) => [
+                            None,
+                            Some(Mask::Alpha),
+                            Some(Mask::Url("https://x.com/m.png".into())),
+                        ]
+                        .choose(&mut rng)
+                        .unwrap()
+                        .clone(),
+                        None => None,
+                    },
+                }),
+                ..original
+            };
+            let original = ParsedRequest {
+                upscale: original.init.as_ref().is_some_and(|i| i.mask.is_none())
+                    && model_config.upscale_workflow.is_some()
+                    && rng.gen(),
+                ..original
+            };
+            // Whether it can vary depends on the workflow the rest picked.
+            let can_vary = original
+                .workflow_placeholders(model_config)
+                .unwrap()
+                .iter()
+                .any(|p| p == "__VARIATION_SEED__");
+            let original = ParsedRequest {
+                variation: (can_vary && rng.gen()).then(|| Variation {
+                    seed: rng.gen(),
+                    strength: rng.gen_range(0.0..=1.0),
+                }),
+                ..original
+            };
+            let line = original.to_command_line();
+            let parsed =
+                ParsedRequest::from_request(&config, request(&line), &Default::default(), &presets)
+                    .unwrap_or_else(|e| panic!("seed {seed}: failed to parse {line:?}: {e:#}"));
+            let expected = ParsedRequest {
+                base: UserRequest {
+                    settings: Some(Default::default()),
+                    ..request(&line)
+                },
+                ..original
+            };
+            assert_eq!(parsed, expected, "seed {seed}: command line: {line:?}");
+        }
+
+        // Syntax that only makes sense in one piece, and grids.
+        for raw in [
+            "a {red|blue} cat, __animal__ --no {fur|whiskers}",
+            "a $moody cat",
+            "a cat, <lora:ghibli:0.6> -m flux --style <lora:watercolor>",
+            "a cat --xy steps=20,30 \"S/R=cat,dog\" --style {oil|watercolor}",
+        ] {
+            let parsed =
+                ParsedRequest::from_request(&config, request(raw), &Default::default(), &presets)
+                    .unwrap();
+            let line = parsed.to_command_line();
+            let mut reparsed =
+                ParsedRequest::from_request(&config, request(&line), &Default::default(), &presets)
+                    .unwrap_or_else(|e| panic!("failed to parse {line:?}: {e:#}"));
+            reparsed.base.raw = raw.to_string();
+            for cell in reparsed.grid.iter_mut().flat_map(|g| g.cells.iter_mut()) {
+                cell.base.raw = raw.to_string();
+            }
+            assert_eq!(reparsed, parsed, "{raw:?} became {line:?}");
+        }
+    }
+
+    #[test]
+    fn test_command_line_is_readable() {
+        let parsed = parse(r#"a sign saying "open" --style oil, \(impasto\) --no blur"#).unwrap();
+        let line = parsed.to_command_line();
+        assert!(
+            line.starts_with(r#"a sign saying "open" --model foo"#),
+            "{line}"
+        );
+        assert!(
+            line.ends_with(r#"--style oil, \(impasto\) --no blur"#),
+            "{line}"
+        );
+    }
+
     /// This test exists to determine the appropriate threshold, chiefly.
     #[test]
     fn test_model_similarity_threshold() {
@@ -198,8 +198,6 @@ async fn do_claude<T: DeserializeOwned + Clone>(
     }
     #[derive(Deserialize)]
     struct APIContent<T> {
-        #[serde(rename = "type")]
-        content_type: String,
         text: Option<String>,
         input: Option<T>,
     }
@@ -14,7 +14,7 @@ use crate::BotContext;
 /// Probably the right thing to do here is to return a lazily evaluated tree...
 /// But that's a lot of work, and computers are fast.
 /// So we just return a giant tree.
-
+///
 /// I think you get the picture.
 #[derive(Debug)]
 struct HelpText {
@@ -91,22 +91,35 @@ async fn root(context: &BotContext, prefix: &str) -> HelpText {
         - `{prefix}help` - This help system.
         - `{prefix}prompt` - Image-generation from a text prompt. You can choose model, aspect ratio and so on freely. Click the button to see the full explanation.
         - `{prefix}dream` - Image-generation from a loose description, using GPT-4 to fill in the blanks. This only works with the (highly flexible) baseline SDXL model; I recommend you use the output as a guide for how to start on your own prompts.
-        - `{prefix}settings` - Configure the bot's behavior. This is a work in progress.
+        - `{prefix}settings` - View or change your defaults: model, ar, negative, count, private (results by PM/DM) and format (jpeg, png or webp). For example `{prefix}settings model flux`, or `{prefix}settings clear model`. Flags in a prompt always win.
+        - `{prefix}preset` - Save a bit of prompt under a name, e.g. `{prefix}preset save moody --style film noir --ar 21:9`, then write `$moody` in any prompt to use it. Presets can be listed, deleted, shared and copied from other users.
+        - Changed your mind? On Discord, press Cancel while it's queued or generating. On IRC, `!cancel` stops all of yours, and `!cancel 12` just that one.
+        - Wondering if it's stuck? `!queue` (or /queue on Discord) lists what's queued and generating, with a guess at when each will be done.
+        - `!stats` shows how each model has been doing this week: how often it failed, and how long it takes. `!stats 30` looks further back.
 
         Common flags for /prompt:
-        - --style — The style to feed into the model; affects everything after the flag. See the Prompting help section for more information.
+        - --style — The style to feed into the model; affects everything after the flag. `-s word` adds just the one word. See the Prompting help section for more information.
         -- --no — Things to avoid; affects everything after the flag. See the Prompting help section for more information.
         - --model (-m) — The model to use. Defaults to SDXL. Every model has a different set of capabilities, but SDXL is by far the most flexible.
         - --ar — The aspect ratio to use. Defaults to 1:1.
-        - --seed (-s) — The seed to use. Defaults to a random number, but you should set this to a specific value when comparing prompts
+        - --seed — The seed to use. Defaults to a random number, but you should set this to a specific value when comparing prompts
         - --count (-c) — The number of pictures to generate. You can request up to 16, but this down-prioritizes your request.
+        - --sampler, --scheduler — Override the model's sampler or noise schedule, e.g. `--sampler euler --scheduler karras`. Not every model allows this, and each has its own list; if you pick one it doesn't have, I'll tell you what it does have.
+        - --variation-seed, --variation-strength — Small changes around a picture you like. Keep its --seed, and add e.g. `--variation-seed 1 --variation-strength 0.2`; 0 is the same picture, 1 a different one. On Discord, the Vary button does this for you. Flux models only, for now.
+        - --init, --strength — Start from a picture instead of noise, e.g. `--init https://example.com/cat.png --strength 0.6`. Strength is how much gets redrawn: 0 keeps the picture, 1 ignores it. The picture is cropped to the --ar you ask for. On Discord, you can attach the picture to /prompt instead. `{prefix}help models` shows which models can do this.
+        - --upscale — With --init, enlarges the picture to twice its size and redraws the fine details; --strength (default 0.35) is how much. On Discord, the U buttons do this for you, if the model can; the V buttons instead draw a new batch starting from the picture you pick, with --strength 0.4.
+        - --mask — Inpainting: only redraw part of the --init picture. Give a link to a black and white picture of the same shape, where white is redrawn, or `--mask alpha` to redraw the transparent part of the --init picture. On Discord, /prompt takes the mask as an attachment too, and pictures you pick with a U button get an Inpaint button.
+        - <lora:name:weight> — Not a flag, but goes in the prompt: adds a LoRA, for characters and styles the model doesn't know. `{prefix}help LoRA (specific characters)` lists them.
+        - --xy — Compare settings side by side, e.g. `--xy steps=20,30,40 scale=4,7`. Axes can be steps, scale, aesthetic, seed, model, ar, sampler, scheduler, or `S/R=cat,dog,fox` to swap words in the prompt. You get one labeled sheet of up to 16 pictures, all with the same seed.
+
+        Values can be written as `--seed 42` or `--seed=42`. Use double quotes for values with spaces (`--model \"x y\"`), and a backslash to escape a quote or a leading dash.
 
 
         You can also use `{prefix}help <arbitrary text>` to ask me questions. I'll try to answer them as best I can."),
         children: HashMap::from([
             ("Prompting", prompting(context)),
             ("Models", models(context).await),
-            ("LoRA (specific characters) (UNIMPLEMENTED)", loras(context).await),
+            ("LoRA (specific characters)", loras(context).await),
             ("Tips and tricks (UNIMPLEMENTED)", tips_and_tricks(context)),
         ]),
     }
@@ -139,6 +152,14 @@ fn prompting(_context: &BotContext) -> HelpText {
 
         ======
 
+        Dynamic prompts: `{red|blue|green}` picks one of the options, separately for each image in the batch. `__name__` does the same with a line from the wildcard file of that name. Use the same seed to get the same choices again.
+
+        ======
+
+        Emphasis: `(word:1.3)` weighs a word more heavily, `(word:0.7)` less. `(word)` and `[word]` are shorthand for a little more and a little less, and NovelAI-style `{word}` works too. I'll translate whichever you use into what the model understands. Flux doesn't do weighting, so there the brackets are simply removed. Use `\\(` for a literal parenthesis.
+
+        ======
+
         Besides all of that? ***Experiment.*** Prompting is an art, not a science. You'll get better at it with practice.
         ".to_string()
     }
@@ -150,7 +171,7 @@ async fn models(context: &BotContext) -> HelpText {
         .config
         .with_config(|c| (c.aliases.clone(), c.models.clone()))
         .await;
-    let text = "This list of models is sorted by genre, but also workflow. You should be able to tell which models are XL and which are 1.5.\n";
+    let text = "This list of models is sorted by genre, but also workflow. You should be able to tell which models are XL and which are 1.5. Each model lists its default CFG (--scale) and allowed range, along with any flags it ignores.\n";
     let mut text: Vec<String> = vec![text.into(), "\n".into(), "## Aliases\n".into()];
 
     // First the aliases, aka. "genres".
@@ -177,7 +198,28 @@ async fn models(context: &BotContext) -> HelpText {
         let models = models
             .iter()
             .filter(|(_, v)| v.workflow == *workflow)
-            .map(|(k, v)| format!("-m {} — {}", k, v.description))
+            .map(|(k, v)| {
+                let img2img = [
+                    (&v.img2img_workflow, "--init"),
+                    (&v.inpaint_workflow, "--mask"),
+                    (&v.upscale_workflow, "--upscale"),
+                ]
+                .iter()
+                .filter(|(workflow, _)| workflow.is_some())
+                .map(|(_, flag)| *flag)
+                .collect::<Vec<_>>();
+                let img2img = if img2img.is_empty() {
+                    String::new()
+                } else {
+                    format!(", can use {}", img2img.join(", "))
+                };
+                format!(
+                    "-m {} — {} ({}{img2img})",
+                    k,
+                    v.description,
+                    v.capabilities.describe()
+                )
+            })
             .collect::<Vec<_>>();
         format!("\n## {}\n{}", workflow, models.join("\n"))
     });
@@ -189,10 +231,55 @@ async fn models(context: &BotContext) -> HelpText {
     }
 }
 
-async fn loras(_context: &BotContext) -> HelpText {
+async fn loras(context: &BotContext) -> HelpText {
+    let (loras, models) = context
+        .config
+        .with_config(|c| (c.loras.clone(), c.models.clone()))
+        .await;
+    let mut text = vec!["LoRAs are add-ons that teach a model a character or a style it doesn't know. Put `<lora:name>` anywhere in the prompt to use one, or `<lora:name:0.8>` to set how strongly it applies; 1 is full strength, and negative weights push the other way. Some need trigger words in the prompt, which I'll add for you.\n\nEach LoRA only works with the kind of model it was made for.\n".to_string()];
+    if loras.is_empty() {
+        text.push("\nThere aren't any LoRAs set up yet.\n".to_string());
+    }
+
+    // One list per model family, with the models that can use them.
+    let mut families = loras
+        .values()
+        .flat_map(|l| l.families.iter())
+        .collect::<HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    families.sort();
+    for family in families {
+        let mut members = models
+            .iter()
+            .filter(|(_, m)| m.family.as_ref() == Some(family))
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        members.sort();
+        text.push(format!(
+            "\n## For {family} models ({})\n",
+            members.join(", ")
+        ));
+        let mut names = loras
+            .iter()
+            .filter(|(_, l)| l.families.contains(family))
+            .collect::<Vec<_>>();
+        names.sort_by_key(|(name, _)| *name);
+        for (name, lora) in names {
+            let mut line = format!(
+                "<lora:{name}:{}> — {}",
+                lora.default_weight, lora.description
+            );
+            if !lora.triggers.is_empty() {
+                line += &format!(" (adds: {})", lora.triggers.join(", "));
+            }
+            text.push(line + "\n");
+        }
+    }
+
     HelpText {
         children: HashMap::new(),
-        text: "Sorry, LoRAs aren't implemented yet. I'm working on it.".to_string(),
+        text: text.join(""),
     }
 }
 
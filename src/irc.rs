@@ -1,4 +1,7 @@
-use std::time::Duration;
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use base64::{engine::general_purpose::STANDARD, Engine as _};
 
@@ -6,14 +9,26 @@ use anyhow::{bail, Context, Ok, Result};
 use irc::client::prelude::*;
 use lazy_static::lazy_static;
 use log::{debug, error, info, trace};
-use tokio_stream::StreamExt;
+use tokio_stream::{Stream, StreamExt};
 
 use crate::gpt::claude_simple;
-use crate::{config::IrcConfig, generator::UserRequest, help, utils, BotContext};
+use crate::{
+    config::IrcConfig,
+    generator::{GenerationEvent, ReplyTo, Resumed, Source, UserRequest},
+    help, parser, presets, settings, telemetry, utils, BotContext,
+};
+
+/// How long a job runs before IRC hears how it's going, and how often after that.
+const LONG_JOB: Duration = Duration::from_secs(60);
+
+/// How far back !stats looks, unless asked otherwise.
+const STATS_DAYS: u32 = 7;
 
 pub struct IrcTask {
     context: BotContext,
     irc_config: IrcConfig,
+    /// Config problems the owner has been told about, so reconnecting doesn't repeat them.
+    reported_problems: Arc<Mutex<Vec<String>>>,
 }
 
 impl IrcTask {
@@ -21,6 +36,7 @@ impl IrcTask {
         Self {
             context,
             irc_config,
+            reported_problems: Default::default(),
         }
     }
 
@@ -146,7 +162,53 @@ impl IrcTask {
             self.irc_config.server, command_prefix
         );
 
+        // Tell the owner whenever the config has new problems. This stops with the connection.
+        let owner = self.context.config.with_config(|c| c.owner.clone()).await;
+        let mut problems = self.context.config.problems();
+        let reported = self.reported_problems.clone();
+        let sender = client.sender();
+        tokio::task::spawn(async move {
+            loop {
+                let lines = problems.borrow_and_update().clone();
+                for line in &lines {
+                    if reported.lock().unwrap().contains(line) {
+                        continue;
+                    }
+                    if send(&sender, &owner, line).await.is_err() {
+                        return;
+                    }
+                    reported.lock().unwrap().push(line.clone());
+                }
+                // Forget the ones that were fixed, in case they come back.
+                *reported.lock().unwrap() = lines;
+                if problems.changed().await.is_err() {
+                    return;
+                }
+            }
+        });
+
         while let Some(message) = stream.next().await.transpose()? {
+            // Requests from before a restart carry on once we're back where they were made.
+            if let Command::JOIN(ref channel, _, _) = message.command {
+                if message.source_nickname() == Some(client.current_nickname()) {
+                    let server = &self.irc_config.server;
+                    let resumed = self
+                        .context
+                        .image_generator
+                        .take_resumed(|r| {
+                            matches!(r, ReplyTo::Irc { server: s, target }
+                                if s == server && (target == channel || !target.starts_with('#')))
+                        })
+                        .await;
+                    for job in resumed {
+                        tokio::task::spawn(Self::resume(
+                            self.context.clone(),
+                            client.sender(),
+                            job,
+                        ));
+                    }
+                }
+            }
             if let Command::PRIVMSG(ref target, ref msg) = message.command {
                 if let Some((_, msg)) = msg.split_once(&command_prefix) {
                     debug!("Received command: {}", msg);
@@ -169,10 +231,12 @@ impl IrcTask {
                     let nick = nick.to_owned();
                     let cmd = cmd.to_owned();
                     let params = params.trim().to_owned();
+                    let server = self.irc_config.server.clone();
                     tokio::task::spawn(async move {
-                        if let Err(e) =
-                            Self::handle_command(&context, &sender, &target, &nick, &cmd, &params)
-                                .await
+                        if let Err(e) = Self::handle_command(
+                            &context, &sender, &server, &target, &nick, &cmd, &params,
+                        )
+                        .await
                         {
                             error!("Error while handling command: {:#}", e);
                             if let Err(e) =
@@ -191,6 +255,7 @@ impl IrcTask {
     async fn handle_command(
         context: &BotContext,
         sender: &Sender,
+        server: &str,
         target: &str,
         nick: &str,
         cmd: &str,
@@ -214,8 +279,7 @@ impl IrcTask {
             }
             "restart" => {
                 if nick == owner {
-                    // First, wait until the generator is idle.
-                    context.image_generator.wait_until_idle().await;
+                    // The queue is saved, so nothing gets lost.
                     send(sender, target, "Restarting...").await?;
                     // ssh saya.local systemctl restart comfyui.service
                     // tokio::process::Command::new("ssh")
@@ -238,6 +302,8 @@ impl IrcTask {
                 raw: params.into(),
                 source: crate::generator::Source::Irc,
                 comment: None,
+                parent: None,
+                settings: None,
                 private: !target.starts_with('#'),
             }],
             "prompt" => vec![UserRequest {
@@ -246,6 +312,8 @@ impl IrcTask {
                 raw: params.into(),
                 source: crate::generator::Source::Irc,
                 comment: None,
+                parent: None,
+                settings: None,
                 private: !target.starts_with('#'),
             }],
             "scan" => {
@@ -255,9 +323,11 @@ impl IrcTask {
                     requests.push(UserRequest {
                         user: nick.into(),
                         dream: None,
-                        raw: format!("{} -m {}", params, model),
+                        raw: format!("{} -m {}", params, parser::quote_value(&model)),
                         source: crate::generator::Source::Irc,
                         comment: None,
+                        parent: None,
+                        settings: None,
                         private: true,
                     });
                 }
@@ -283,9 +353,11 @@ impl IrcTask {
                         .map(|model| UserRequest {
                             user: nick.into(),
                             dream: None,
-                            raw: format!("{} -m {}", test_prompt, model),
+                            raw: format!("{} -m {}", test_prompt, parser::quote_value(&model)),
                             source: crate::generator::Source::Irc,
                             comment: None,
+                            parent: None,
+                            settings: None,
                             private: true,
                         })
                         .collect()
@@ -326,6 +398,76 @@ impl IrcTask {
                 send(sender, target, &text).await?;
                 return Ok(());
             }
+            "cancel" => {
+                let id = params.trim().trim_start_matches('#');
+                let id = if id.is_empty() {
+                    None
+                } else {
+                    Some(id.parse().context("Usage: !cancel [id]")?)
+                };
+                let cancelled = context
+                    .image_generator
+                    .cancel(id, nick, Source::Irc, nick == owner)
+                    .await?;
+                // The requests say so themselves.
+                if cancelled == 0 {
+                    send(sender, target, &format!("{}: Nothing to cancel.", nick)).await?;
+                }
+                return Ok(());
+            }
+            "queue" => {
+                let entries = context.image_generator.queue().await?;
+                if entries.is_empty() {
+                    send(sender, target, &format!("{}: Nothing's queued.", nick)).await?;
+                    return Ok(());
+                }
+                let text = entries
+                    .iter()
+                    .map(|e| e.to_string())
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                // A long queue would flood the channel.
+                if target.starts_with('#') && entries.len() > 4 {
+                    send(sender, target, "Sending the queue by PM.").await?;
+                    send(sender, nick, &text).await?;
+                } else {
+                    send(sender, target, &text).await?;
+                }
+                return Ok(());
+            }
+            "stats" => {
+                let days = match params.trim() {
+                    "" => STATS_DAYS,
+                    days => days.parse().context("Usage: !stats [days]")?,
+                };
+                let stats = context.db.get_stats(days).await?;
+                let text = telemetry::report(&stats, days);
+                if target.starts_with('#') && text.lines().count() > 6 {
+                    send(sender, target, "Sending stats by PM.").await?;
+                    send(sender, nick, &text).await?;
+                } else {
+                    send(sender, target, &text).await?;
+                }
+                return Ok(());
+            }
+            "preset" => {
+                let text = presets::handler(context, "!", Source::Irc, nick, params)
+                    .await
+                    .context("While handling presets")?;
+                send(sender, target, &text).await?;
+                return Ok(());
+            }
+            "settings" => {
+                let text = settings::handler(context, "!", Source::Irc, nick, params)
+                    .await
+                    .context("While handling settings")?;
+                // Settings are personal, and the listing is long.
+                if target.starts_with('#') {
+                    send(sender, target, "Sending settings by PM.").await?;
+                }
+                send(sender, nick, &text).await?;
+                return Ok(());
+            }
             "help" => {
                 let text = help::handler(context, "!", params)
                     .await
@@ -368,73 +510,164 @@ impl IrcTask {
         if let Some(entry) = crate::changelog::get_new_changelog_entry(context, &userid).await? {
             send(sender, target, &format!("{}: {}", nick, entry)).await?;
         }
+        // Users can ask for all their results by PM.
+        let settings = context.db.get_user_settings(&Source::Irc, nick).await?;
+        let target = if settings.is_private() { nick } else { target };
         // It's fine, generate the images.
         let verbose = requests.len() > 1;
-        for request in requests {
+        for mut request in requests {
+            request.private |= settings.is_private();
             let prompt = format!("{}: {}", nick, request.raw);
-            let mut events = Box::pin(
-                context
-                    .image_generator
-                    .generate(request, !target.starts_with('#'))
-                    .await,
-            );
-            while let Some(event) = events.next().await {
-                trace!("Event: {:?}", event);
-                match event {
-                    crate::generator::GenerationEvent::Completed(c) => {
-                        let overview = utils::overview_of_pictures(&c.images)?;
-                        let all: Vec<Vec<u8>> = std::iter::once(overview)
-                            .chain(c.images.into_iter())
-                            .collect();
-                        // Send the results to the user.
-                        let urls = utils::upload_images(&context.config, &c.uuid, all)
-                            .await
-                            .context("failed to upload images")?;
-                        if verbose {
-                            send(sender, target, &prompt).await?;
-                        }
-                        send(sender, target, &format!("{}: {}", nick, urls[0])).await?;
+            let reply_to = ReplyTo::Irc {
+                server: server.to_string(),
+                target: target.to_string(),
+            };
+            let events = context
+                .image_generator
+                .generate(request, !target.starts_with('#'), reply_to)
+                .await;
+            Self::follow(
+                context,
+                sender,
+                target,
+                nick,
+                verbose.then_some(&prompt),
+                events,
+            )
+            .await?;
+        }
+        debug!("Command completed");
+        Ok(())
+    }
+
+    /// Picks up a request from before a restart, where it was made.
+    async fn resume(context: BotContext, sender: Sender, job: Resumed) {
+        let ReplyTo::Irc { target, .. } = &job.reply_to else {
+            return;
+        };
+        let nick = &job.request.base.user;
+        let prompt = job
+            .request
+            .base
+            .dream
+            .as_ref()
+            .unwrap_or(&job.request.base.raw);
+        let result = async {
+            send(
+                &sender,
+                target,
+                &format!("{}: Picking up `{}` again after a restart.", nick, prompt),
+            )
+            .await?;
+            Self::follow(&context, &sender, target, nick, None, job.events).await
+        };
+        if let Err(e) = result.await {
+            error!("Error while resuming a request: {:#}", e);
+        }
+    }
+
+    /// Tells the user how their request is going, and gives them the pictures.
+    /// With several requests at once, `prompt` says which one a result is for.
+    async fn follow(
+        context: &BotContext,
+        sender: &Sender,
+        target: &str,
+        nick: &str,
+        prompt: Option<&String>,
+        events: impl Stream<Item = GenerationEvent>,
+    ) -> Result<()> {
+        let mut events = std::pin::pin!(events);
+        let mut job = None;
+        // When we last said how it's going, or when it started generating.
+        let mut last_report: Option<std::time::Instant> = None;
+        while let Some(event) = events.next().await {
+            trace!("Event: {:?}", event);
+            match event {
+                GenerationEvent::Completed(c) => {
+                    let overview = c.overview()?;
+                    let all: Vec<Vec<u8>> = std::iter::once(overview).chain(c.images).collect();
+                    // Send the results to the user.
+                    let extension = c.base.output_format.extension();
+                    let urls = utils::upload_images(&context.config, &c.uuid, all, extension)
+                        .await
+                        .context("failed to upload images")?;
+                    if let Some(prompt) = prompt {
+                        send(sender, target, prompt).await?;
                     }
-                    crate::generator::GenerationEvent::Error(e) => {
-                        if verbose {
-                            send(sender, target, &prompt).await?;
-                        }
-                        send(sender, target, &format!("{}: Error: {:#}", nick, e)).await?;
+                    send(sender, target, &format!("{}: {}", nick, urls[0])).await?;
+                }
+                GenerationEvent::Error(e) => {
+                    if let Some(prompt) = prompt {
+                        send(sender, target, prompt).await?;
                     }
-                    crate::generator::GenerationEvent::GPTCompleted(req) => {
-                        if req.dream.is_some() {
-                            let text = req.raw.split("\n\n").next().unwrap_or_default();
-                            send(
-                                sender,
-                                target,
-                                &format!("{}: Dreaming about `{}`", nick, text),
-                            )
-                            .await?;
-                        }
-                        if let Some(comment) = req.comment {
-                            send(sender, target, comment.as_ref()).await?;
-                        }
+                    send(sender, target, &format!("{}: Error: {:#}", nick, e)).await?;
+                }
+                GenerationEvent::GPTCompleted(req) => {
+                    if req.dream.is_some() {
+                        let text = req.raw.split("\n\n").next().unwrap_or_default();
+                        send(
+                            sender,
+                            target,
+                            &format!("{}: Dreaming about `{}`", nick, text),
+                        )
+                        .await?;
+                    }
+                    if let Some(comment) = req.comment {
+                        send(sender, target, comment.as_ref()).await?;
+                    }
+                }
+                GenerationEvent::Parsed(_parsed) => {
+                    // Do nothing.
+                }
+                GenerationEvent::Warning(warning) => {
+                    send(sender, target, &format!("{}: Note: {}", nick, warning)).await?;
+                }
+                GenerationEvent::Accepted(id) => {
+                    job = Some(id);
+                }
+                GenerationEvent::Queued(n, eta) => {
+                    if n >= 3 {
+                        let cancel =
+                            job.map_or(String::new(), |id| format!(" (!cancel {} to cancel)", id));
+                        let eta = eta.map_or(String::new(), |eta| format!("; {}", eta));
+                        send(
+                            sender,
+                            target,
+                            &format!(
+                                "{}: You're in position {} in the queue{}.{}",
+                                nick, n, eta, cancel
+                            ),
+                        )
+                        .await?;
                     }
-                    crate::generator::GenerationEvent::Parsed(_parsed) => {
-                        // Do nothing.
+                }
+                GenerationEvent::Cancelled => {
+                    if let Some(prompt) = prompt {
+                        send(sender, target, prompt).await?;
                     }
-                    crate::generator::GenerationEvent::Queued(n) => {
-                        if n >= 3 {
+                    send(sender, target, &format!("{}: Cancelled.", nick)).await?;
+                }
+                GenerationEvent::Preview(_) => {
+                    // No pictures on IRC until they're done.
+                }
+                GenerationEvent::Generating(progress) => {
+                    // Only worth a line for jobs that take a while.
+                    match last_report {
+                        None => last_report = Some(std::time::Instant::now()),
+                        Some(at) if at.elapsed() >= LONG_JOB => {
+                            last_report = Some(std::time::Instant::now());
                             send(
                                 sender,
                                 target,
-                                &format!("{}: You're in position {} in the queue.", nick, n),
+                                &format!("{}: Still working: {}", nick, progress),
                             )
                             .await?;
                         }
+                        Some(_) => {}
                     }
-                    crate::generator::GenerationEvent::Generating(_) => {
-                        // Ignoring this one.
-                    }
-                };
-            }
+                }
+            };
         }
-        debug!("Command completed");
         Ok(())
     }
 }
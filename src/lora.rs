@@ -0,0 +1,351 @@
+// LoRAs: small add-on models that teach a checkpoint a character or a style.
+//
+// Prompts ask for one with `<lora:name>` or `<lora:name:0.8>`, where the name is from the
+// [loras] table of the config, and the number is its weight. The tags are taken out of the
+// prompt before it reaches the model. Each LoRA becomes a LoraLoader node, chained between
+// the checkpoint loader and everything that used it.
+//
+// A LoRA only works with the kind of model it was trained for, so the config lists the
+// families each one fits, and each model says which family it belongs to.
+
+use std::fmt::Display;
+
+use anyhow::{bail, Result};
+use serde_json::json;
+
+use crate::{
+    config::{BotConfig, LoraConfig},
+    workflow::{float, link, Workflow},
+};
+
+/// Weights beyond this mostly produce noise.
+const MAX_WEIGHT: f32 = 2.0;
+
+/// A LoRA the prompt asked for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lora<'a> {
+    pub name: &'a str,
+    pub config: &'a LoraConfig,
+    pub weight: f32,
+}
+
+/// A problem with one of the tags in a prompt.
+#[derive(Debug, PartialEq)]
+pub struct TagError {
+    /// The tag as written, so callers can point at it.
+    pub tag: String,
+    pub message: String,
+}
+
+impl Display for TagError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for TagError {}
+
+/// Finds the `<lora:...>` tags in the text, returning each as written along with its contents.
+fn tags(text: &str) -> Vec<(&str, &str)> {
+    let mut tags = vec![];
+    let mut rest = text;
+    while let Some(start) = rest.find("<lora:") {
+        let after = &rest[start..];
+        let Some(end) = after.find('>') else {
+            break;
+        };
+        tags.push((&after[..=end], &after["<lora:".len()..end]));
+        rest = &after[end + 1..];
+    }
+    tags
+}
+
+/// Returns the text without its LoRA tags.
+pub fn strip(text: &str) -> String {
+    let tags = tags(text);
+    if tags.is_empty() {
+        return text.to_string();
+    }
+    let mut text = text.to_string();
+    for (tag, _) in tags {
+        text = text.replacen(tag, " ", 1);
+    }
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Looks up the LoRAs the text asks for, checking that they exist and that they fit the model.
+pub fn resolve<'a>(
+    config: &'a BotConfig,
+    model_name: &str,
+    text: &str,
+) -> std::result::Result<Vec<Lora<'a>>, TagError> {
+    let family = config
+        .models
+        .get(model_name)
+        .and_then(|m| m.family.as_ref());
+    let mut loras: Vec<Lora> = vec![];
+    for (tag, contents) in tags(text) {
+        let error = |message: String| TagError {
+            tag: tag.to_string(),
+            message,
+        };
+        let (name, weight) = match contents.rsplit_once(':') {
+            Some((name, weight)) => {
+                let weight = weight.trim().parse::<f32>().map_err(|_| {
+                    error(format!(
+                        "{weight} isn't a weight; write e.g. <lora:{name}:0.8>"
+                    ))
+                })?;
+                (name.trim(), Some(weight))
+            }
+            None => (contents.trim(), None),
+        };
+        let Some((name, lora)) = config.loras.get_key_value(name) else {
+            // Only suggest the ones that would work.
+            let suggestion = config
+                .loras
+                .iter()
+                .filter(|(_, l)| family.is_some_and(|f| l.families.contains(f)))
+                .map(|(n, _)| (strsim::jaro_winkler(name, n), n))
+                .filter(|(s, _)| *s >= crate::generator::SIMILARITY_THRESHOLD)
+                .max_by(|a, b| a.0.total_cmp(&b.0))
+                .map(|(_, n)| n);
+            let mut message = format!("Unknown LoRA {name}");
+            if let Some(suggestion) = suggestion {
+                message += &format!(". Did you mean {suggestion}?");
+            }
+            return Err(error(message));
+        };
+        let Some(family) = family else {
+            return Err(error(format!("{model_name} can't use LoRAs")));
+        };
+        if !lora.families.contains(family) {
+            let mut message = format!(
+                "{name} is for {} models, and {model_name} is {family}",
+                lora.families.join(" or ")
+            );
+            let mut alternatives = compatible(config, family);
+            if !alternatives.is_empty() {
+                alternatives.truncate(5);
+                message += &format!(". Try {}", alternatives.join(", "));
+            }
+            return Err(error(message));
+        }
+        let weight = weight.unwrap_or(lora.default_weight);
+        if !(-MAX_WEIGHT..=MAX_WEIGHT).contains(&weight) {
+            return Err(error(format!(
+                "LoRA weights go from -{MAX_WEIGHT} to {MAX_WEIGHT}"
+            )));
+        }
+        if loras.iter().any(|l| l.name == name) {
+            return Err(error(format!("{name} is in the prompt twice")));
+        }
+        loras.push(Lora {
+            name,
+            config: lora,
+            weight,
+        });
+    }
+    Ok(loras)
+}
+
+/// The LoRAs that fit a model family, sorted.
+pub fn compatible<'a>(config: &'a BotConfig, family: &str) -> Vec<&'a str> {
+    let mut names = config
+        .loras
+        .iter()
+        .filter(|(_, l)| l.families.iter().any(|f| f == family))
+        .map(|(n, _)| n.as_str())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
+
+/// Adds the LoRAs' trigger words to the prompt, unless it has them already.
+pub fn add_triggers(prompt: &str, loras: &[Lora]) -> String {
+    let mut prompt = prompt.to_string();
+    for trigger in loras.iter().flat_map(|l| &l.config.triggers) {
+        if !prompt.to_lowercase().contains(&trigger.to_lowercase()) {
+            if !prompt.is_empty() {
+                prompt += ", ";
+            }
+            prompt += trigger;
+        }
+    }
+    prompt
+}
+
+/// Chains LoraLoader nodes after every loader of the checkpoint, and points whatever used the
+/// checkpoint's model and text encoder at the last of them instead.
+pub fn inject(workflow: &mut Workflow, checkpoint: &str, loras: &[Lora]) -> Result<()> {
+    if loras.is_empty() {
+        return Ok(());
+    }
+    let checkpoint = json!(checkpoint);
+    // Checkpoint loaders have the model at output 0 and its text encoder at 1. UNet loaders
+    // only have the model, so the text encoder comes from a separate loader.
+    let mut sources = workflow
+        .find_by_input("ckpt_name", &checkpoint)
+        .into_iter()
+        .map(|id| (id.clone(), [link(&id, 0), link(&id, 1)]))
+        .collect::<Vec<_>>();
+    let unets = workflow.find_by_input("unet_name", &checkpoint);
+    if !unets.is_empty() {
+        let clip_loaders = ["CLIPLoader", "DualCLIPLoader", "TripleCLIPLoader"]
+            .iter()
+            .flat_map(|class_type| workflow.find(class_type))
+            .collect::<Vec<_>>();
+        let [clip_loader] = clip_loaders.as_slice() else {
+            bail!("can't tell which text encoder goes with {checkpoint}");
+        };
+        sources.extend(
+            unets
+                .into_iter()
+                .map(|id| (id.clone(), [link(&id, 0), link(clip_loader, 0)])),
+        );
+    }
+    if sources.is_empty() {
+        bail!("the workflow has no loader for {checkpoint} to attach LoRAs to");
+    }
+
+    for (source, outputs) in sources {
+        workflow.splice(&outputs, |workflow, outputs| {
+            let (mut model, mut clip) = (outputs[0].clone(), outputs[1].clone());
+            for (i, lora) in loras.iter().enumerate() {
+                let id = workflow.add_node(
+                    &format!("lora-{source}-{i}"),
+                    "LoraLoader",
+                    &format!("LoRA {}", lora.name),
+                    json!({
+                        "model": model,
+                        "clip": clip,
+                        "lora_name": lora.config.file,
+                        "strength_model": float(lora.weight),
+                        "strength_clip": float(lora.weight),
+                    }),
+                );
+                (model, clip) = (link(&id, 0), link(&id, 1));
+            }
+            Ok(vec![model, clip])
+        })?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::testconfig;
+    use serde_json::Value;
+
+    #[test]
+    fn test_strip() {
+        assert_eq!(strip("a cat"), "a cat");
+        assert_eq!(strip("a <lora:ghibli:0.8> cat, <lora:offset>"), "a cat,");
+        // Not a tag without the closing bracket.
+        assert_eq!(strip("a <lora:ghibli cat"), "a <lora:ghibli cat");
+    }
+
+    #[test]
+    fn test_resolve() {
+        let config = testconfig();
+        let names = |text: &str| {
+            resolve(&config, "flux", text)
+                .map(|loras| loras.iter().map(|l| (l.name, l.weight)).collect::<Vec<_>>())
+                .map_err(|e| e.message)
+        };
+        assert_eq!(names("a cat"), Ok(vec![]));
+        assert_eq!(
+            names("a <lora:ghibli> cat <lora:watercolor:-0.5>"),
+            Ok(vec![("ghibli", 0.8), ("watercolor", -0.5)])
+        );
+        assert_eq!(
+            names("<lora:ghibly>"),
+            Err("Unknown LoRA ghibly. Did you mean ghibli?".to_string())
+        );
+        assert_eq!(
+            names("<lora:offset>"),
+            Err("offset is for sdxl models, and flux is flux. Try ghibli, watercolor".to_string())
+        );
+        assert!(names("<lora:ghibli:lots>").is_err());
+        assert!(names("<lora:ghibli:3>").is_err());
+        assert!(names("<lora:ghibli> <lora:ghibli:0.5>").is_err());
+        let error = resolve(&config, "flux", "a <lora:nope:1> cat").unwrap_err();
+        assert_eq!(error.tag, "<lora:nope:1>");
+    }
+
+    #[test]
+    fn test_add_triggers() {
+        let config = testconfig();
+        let loras = resolve(&config, "flux", "<lora:ghibli>").unwrap();
+        assert_eq!(add_triggers("a cat", &loras), "a cat, ghibli style");
+        assert_eq!(
+            add_triggers("a cat in Ghibli style", &loras),
+            "a cat in Ghibli style"
+        );
+    }
+
+    #[test]
+    fn test_inject() {
+        let config = testconfig();
+        // SDXL: one checkpoint loader, with the text encoder built in.
+        let graph = |value: Value| Workflow::parse(&value.to_string()).unwrap();
+        let mut workflow = graph(json!({
+            "10": {"inputs": {"ckpt_name": "2"}, "class_type": "CheckpointLoaderSimple"},
+            "20": {"inputs": {"model": ["10", 0], "clip": ["10", 1]}, "class_type": "X"},
+            "30": {"inputs": {"vae": ["10", 2]}, "class_type": "VAEDecode"},
+        }));
+        let loras = resolve(&config, "foo", "<lora:offset:0.5>").unwrap();
+        inject(&mut workflow, "2", &loras).unwrap();
+        let workflow = workflow.into_value();
+        let lora = &workflow["lora-10-0"];
+        assert_eq!(lora["class_type"], "LoraLoader");
+        assert_eq!(lora["inputs"]["model"], json!(["10", 0]));
+        assert_eq!(lora["inputs"]["strength_model"], json!(0.5));
+        let uses = |output: Value| {
+            workflow
+                .as_object()
+                .unwrap()
+                .values()
+                .flat_map(|n| n["inputs"].as_object().into_iter().flat_map(|i| i.values()))
+                .filter(|v| **v == output)
+                .count()
+        };
+        assert_eq!(uses(json!(["10", 0])), 1);
+        assert_eq!(uses(json!(["10", 1])), 1);
+        assert_eq!(uses(json!(["10", 2])), 1);
+        assert_eq!(workflow["20"]["inputs"]["model"], json!(["lora-10-0", 0]));
+        assert_eq!(workflow["20"]["inputs"]["clip"], json!(["lora-10-0", 1]));
+
+        // Flux: a UNet, with a separate text encoder, and two LoRAs in a row.
+        let mut workflow = graph(json!({
+            "unet": {"inputs": {"unet_name": "flux.safetensors"}, "class_type": "UnetLoaderGGUF"},
+            "clip": {"inputs": {}, "class_type": "DualCLIPLoader"},
+            "text": {"inputs": {"clip": ["clip", 0]}, "class_type": "CLIPTextEncode"},
+            "sampler": {"inputs": {"model": ["unet", 0]}, "class_type": "BasicGuider"},
+        }));
+        let loras = resolve(&config, "flux", "<lora:ghibli> <lora:watercolor>").unwrap();
+        inject(&mut workflow, "flux.safetensors", &loras).unwrap();
+        let workflow = workflow.into_value();
+        assert_eq!(
+            workflow["lora-unet-0"]["inputs"]["clip"],
+            json!(["clip", 0])
+        );
+        assert_eq!(
+            workflow["lora-unet-1"]["inputs"]["model"],
+            json!(["lora-unet-0", 0])
+        );
+        assert_eq!(
+            workflow["text"]["inputs"]["clip"],
+            json!(["lora-unet-1", 1])
+        );
+        assert_eq!(
+            workflow["sampler"]["inputs"]["model"],
+            json!(["lora-unet-1", 0])
+        );
+
+        // Nothing to attach to.
+        let mut workflow = graph(json!({"1": {"inputs": {}, "class_type": "X"}}));
+        assert!(inject(&mut workflow, "flux.safetensors", &loras).is_err());
+    }
+}
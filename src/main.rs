@@ -9,6 +9,7 @@ use log::{error, info};
 
 use crate::{db::DatabaseModule, gpt::PromptGeneratorModule};
 
+mod backends;
 mod changelog;
 mod config;
 mod db;
@@ -17,7 +18,17 @@ mod generator;
 mod gpt;
 mod help;
 mod irc;
+mod lora;
+mod parser;
+mod presets;
+mod scheduler;
+mod settings;
+mod telemetry;
 mod utils;
+mod validation;
+mod weighting;
+mod wildcards;
+mod workflow;
 #[derive(Clone)]
 
 pub struct BotContext {
@@ -55,7 +66,7 @@ async fn main() -> Result<()> {
     let db = DatabaseModule::new(config.clone()).await?;
     let prompt_generator = PromptGeneratorModule::new(config.clone());
     let image_generator =
-        ImageGeneratorModule::new(db.clone(), config.clone(), prompt_generator.clone())?;
+        ImageGeneratorModule::new(db.clone(), config.clone(), prompt_generator.clone()).await?;
 
     let context = BotContext {
         config: config.clone(),
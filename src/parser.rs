@@ -0,0 +1,1059 @@
+// Lexer and parser for the !prompt command line.
+//
+// It's mostly a shell-style command line: whitespace separates tokens, double quotes group
+// text, and a backslash escapes the next character. The twist is that anything which isn't
+// an option belongs to one of the prompts, and --style / --no / --prompt switch which prompt
+// subsequent words go into.
+//
+// This module only deals with syntax. Anything that needs the config (models, resolutions,
+// and so on) is checked by ParsedRequest::from_request, using the spans recorded here so
+// that errors can point at the offending token.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    ops::Range,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// How much context to show on either side of the offending token in an error.
+const ERROR_CONTEXT_CHARS: usize = 30;
+
+/// All the options we understand, for suggestions.
+const KNOWN_OPTIONS: &[&str] = &[
+    "model",
+    "m",
+    "style",
+    "s",
+    "no",
+    "prompt",
+    "np",
+    "nn",
+    "scale",
+    "aesthetic",
+    "a",
+    "steps",
+    "count",
+    "c",
+    "seed",
+    "w",
+    "h",
+    "ar",
+    "xy",
+    "sampler",
+    "scheduler",
+    "variation-seed",
+    "variation-strength",
+    "init",
+    "strength",
+    "mask",
+    "upscale",
+];
+
+/// Settings that --xy can vary, and their aliases.
+const GRID_AXES: &[(&str, &str)] = &[
+    ("steps", "steps"),
+    ("scale", "scale"),
+    ("aesthetic", "aesthetic"),
+    ("a", "aesthetic"),
+    ("seed", "seed"),
+    ("model", "model"),
+    ("m", "model"),
+    ("ar", "ar"),
+    ("sampler", "sampler"),
+    ("scheduler", "scheduler"),
+    ("s/r", "S/R"),
+    ("sr", "S/R"),
+];
+
+/// How many axes a grid can have. X and Y.
+const MAX_GRID_AXES: usize = 2;
+
+/// A syntax (or semantic) error, with the location it happened at.
+#[derive(Debug)]
+pub struct ParseError {
+    message: String,
+    source: String,
+    span: Range<usize>,
+}
+
+impl ParseError {
+    /// Span is a byte range into source.
+    pub fn new(source: &str, span: Range<usize>, message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            source: source.to_string(),
+            span,
+        }
+    }
+
+    /// The message, without the location.
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let before = &self.source[..self.span.start];
+        let token = &self.source[self.span.clone()];
+        let after = &self.source[self.span.end..];
+        let column = before.chars().count() + 1;
+        writeln!(f, "{} (at column {})", self.message, column)?;
+
+        // Show a window around the token, with carets underneath it.
+        // Newlines would break the alignment, so they're flattened.
+        let flatten = |s: &str| s.replace(['\n', '\r', '\t'], " ");
+        let before_count = before.chars().count();
+        let mut before = flatten(
+            &before
+                .chars()
+                .skip(before_count.saturating_sub(ERROR_CONTEXT_CHARS))
+                .collect::<String>(),
+        );
+        if before_count > ERROR_CONTEXT_CHARS {
+            before.insert(0, '…');
+        }
+        let mut after = flatten(&after.chars().take(ERROR_CONTEXT_CHARS).collect::<String>());
+        if self.source[self.span.end..].chars().count() > ERROR_CONTEXT_CHARS {
+            after.push('…');
+        }
+        let token = flatten(token);
+        writeln!(f, "  {}{}{}", before, token, after)?;
+        write!(
+            f,
+            "  {}{}",
+            " ".repeat(before.chars().count()),
+            "^".repeat(token.chars().count().max(1))
+        )
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    /// Part of a prompt.
+    /// `literal` keeps any quotes, so `a sign saying "hello"` survives as written.
+    /// `value` has them removed, for when the word turns out to be an option value.
+    Word { literal: String, value: String },
+    /// `--name`, `-n`, `--name=value` or `-n=value`.
+    Option { name: String, value: Option<String> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    /// Byte range in the source.
+    pub span: Range<usize>,
+}
+
+/// Characters that a backslash escapes. Anything else keeps its backslash,
+/// so that prompts like `\(artist\)` survive unchanged.
+/// `\$name` is how to write `$name` without it being a preset.
+fn is_escapable(c: char) -> bool {
+    c == '"' || c == '\\' || c == '-' || c == '—' || c == '$' || c.is_whitespace()
+}
+
+/// Splits a command line into tokens.
+pub fn lex(source: &str) -> Result<Vec<Token>, ParseError> {
+    let mut tokens = vec![];
+    let mut chars = source.char_indices().peekable();
+    loop {
+        while chars.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+        let Some(&(start, _)) = chars.peek() else {
+            break;
+        };
+        let mut end = start;
+        let mut literal = String::new();
+        let mut value = String::new();
+        // Position in `value` of the first unquoted, unescaped '=', if any.
+        let mut equals = None;
+        // Set if the token started with an escape or a quote; such tokens are never options.
+        let mut protected_start = false;
+        let mut quote_start: Option<usize> = None;
+        while let Some(&(i, c)) = chars.peek() {
+            if quote_start.is_none() && c.is_whitespace() {
+                break;
+            }
+            chars.next();
+            end = i + c.len_utf8();
+            match c {
+                '\\' => {
+                    if let Some((j, escaped)) = chars.next_if(|(_, n)| is_escapable(*n)) {
+                        end = j + escaped.len_utf8();
+                        if literal.is_empty() {
+                            protected_start = true;
+                        }
+                        literal.push(escaped);
+                        value.push(escaped);
+                    } else {
+                        literal.push(c);
+                        value.push(c);
+                    }
+                }
+                '"' => {
+                    if literal.is_empty() {
+                        protected_start = true;
+                    }
+                    quote_start = match quote_start {
+                        Some(_) => None,
+                        None => Some(i),
+                    };
+                    literal.push(c);
+                }
+                '=' if quote_start.is_none() && equals.is_none() => {
+                    equals = Some(value.len());
+                    literal.push(c);
+                    value.push(c);
+                }
+                _ => {
+                    literal.push(c);
+                    value.push(c);
+                }
+            }
+        }
+        if let Some(quote) = quote_start {
+            return Err(ParseError::new(
+                source,
+                quote..quote + 1,
+                "Unterminated quote. Use \\\" for a literal quote",
+            ));
+        }
+
+        // Is it an option? Apple likes to turn -- into an em dash, so accept that too.
+        let option_prefix = if protected_start {
+            None
+        } else if literal.starts_with("--") && literal.len() > 2 {
+            Some("--".len())
+        } else if literal.starts_with('—') && literal.len() > '—'.len_utf8() {
+            Some('—'.len_utf8())
+        } else if literal.starts_with('-') && literal[1..].starts_with(|c: char| c.is_alphabetic())
+        {
+            Some("-".len())
+        } else {
+            None
+        };
+        let kind = match option_prefix {
+            Some(prefix) => match equals {
+                Some(eq) => TokenKind::Option {
+                    name: value[prefix..eq].to_string(),
+                    value: Some(value[eq + 1..].to_string()),
+                },
+                None => TokenKind::Option {
+                    name: value[prefix..].to_string(),
+                    value: None,
+                },
+            },
+            None => TokenKind::Word { literal, value },
+        };
+        tokens.push(Token {
+            kind,
+            span: start..end,
+        });
+    }
+    Ok(tokens)
+}
+
+/// A value, plus where in the command line it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spanned<T> {
+    pub value: T,
+    pub span: Range<usize>,
+}
+
+/// The result of parsing a command line, before any config-dependent validation.
+/// Options which weren't given are None.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CommandLine {
+    pub linguistic_prompt: Vec<String>,
+    pub supporting_prompt: Vec<String>,
+    pub negative_prompt: Vec<String>,
+    pub no_pos_default: bool,                     // --np
+    pub no_neg_default: bool,                     // --nn
+    pub model: Option<Spanned<String>>,           // --model, -m
+    pub guidance_scale: Option<Spanned<f32>>,     // --scale
+    pub aesthetic_scale: Option<Spanned<f32>>,    // --aesthetic, -a
+    pub steps: Option<Spanned<u32>>,              // --steps
+    pub count: Option<Spanned<u32>>,              // --count, -c
+    pub seed: Option<Spanned<u32>>,               // --seed
+    pub width: Option<Spanned<u32>>,              // -w
+    pub height: Option<Spanned<u32>>,             // -h
+    pub aspect_ratio: Option<Spanned<String>>,    // --ar
+    pub xy: Vec<Spanned<GridAxis>>,               // --xy
+    pub sampler: Option<Spanned<String>>,         // --sampler
+    pub scheduler: Option<Spanned<String>>,       // --scheduler
+    pub variation_seed: Option<Spanned<u32>>,     // --variation-seed
+    pub variation_strength: Option<Spanned<f32>>, // --variation-strength
+    pub init: Option<Spanned<String>>,            // --init
+    pub strength: Option<Spanned<f32>>,           // --strength
+    pub mask: Option<Spanned<String>>,            // --mask
+    pub upscale: Option<Range<usize>>,            // --upscale
+}
+
+/// One axis of an --xy grid, e.g. `steps=20,30,40`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GridAxis {
+    /// Canonical setting name; see GRID_AXES.
+    pub name: String,
+    pub values: Vec<String>,
+}
+
+impl fmt::Display for GridAxis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.values.join(","))
+    }
+}
+
+impl GridAxis {
+    /// Parses `name=a,b,c`. Returns None if the name isn't something we can vary.
+    fn parse(text: &str) -> Option<Result<Self, String>> {
+        let (name, values) = text.split_once('=')?;
+        let name = GRID_AXES
+            .iter()
+            .find(|(alias, _)| alias.eq_ignore_ascii_case(name))?
+            .1;
+        let values = values
+            .split(',')
+            .map(|v| v.trim().to_string())
+            .collect::<Vec<_>>();
+        if values.iter().any(|v| v.is_empty()) {
+            return Some(Err(format!("{name}: values can't be empty")));
+        }
+        Some(Ok(Self {
+            name: name.to_string(),
+            values,
+        }))
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Section {
+    Linguistic,
+    Supporting,
+    Negative,
+}
+
+/// Parses a command line.
+pub fn parse(source: &str) -> Result<CommandLine, ParseError> {
+    parse_with_presets(source, &HashMap::new())
+}
+
+/// The token stream, after preset expansion.
+/// Presets are bracketed so the parser can go back to whichever prompt it was in before.
+enum Item {
+    Token(Token),
+    PresetStart,
+    PresetEnd,
+}
+
+/// If the token is `$name`, unescaped, returns the name.
+fn dollar_word<'a>(source: &str, token: &'a Token) -> Option<&'a str> {
+    let TokenKind::Word { literal, .. } = &token.kind else {
+        return None;
+    };
+    if !source[token.span.clone()].starts_with('$') {
+        return None;
+    }
+    literal.strip_prefix('$')
+}
+
+/// If the token is `$name` for a known preset, returns the name.
+fn preset_reference<'a>(
+    source: &str,
+    token: &'a Token,
+    presets: &HashMap<String, String>,
+) -> Option<&'a str> {
+    let name = dollar_word(source, token)?;
+    presets.contains_key(&name.to_lowercase()).then_some(name)
+}
+
+/// Replaces `$name` words with the tokens of the named preset.
+/// Those tokens all get the span of the `$name`, so errors point at it.
+/// A `$word` that isn't a preset is left alone, unless it looks like a typo.
+fn expand_presets(
+    source: &str,
+    tokens: Vec<Token>,
+    presets: &HashMap<String, String>,
+) -> Result<VecDeque<Item>, ParseError> {
+    let mut items = VecDeque::new();
+    for token in tokens {
+        let Some(name) = preset_reference(source, &token, presets) else {
+            if let Some(name) = dollar_word(source, &token) {
+                let best = presets
+                    .keys()
+                    .map(|p| (strsim::jaro_winkler(&name.to_lowercase(), p), p))
+                    .max_by(|a, b| a.0.total_cmp(&b.0));
+                if let Some((similarity, best)) = best {
+                    if similarity >= crate::generator::SIMILARITY_THRESHOLD {
+                        return Err(ParseError::new(
+                            source,
+                            token.span.clone(),
+                            format!("Unknown preset ${name}. Did you mean ${best}?"),
+                        ));
+                    }
+                }
+            }
+            items.push_back(Item::Token(token));
+            continue;
+        };
+        // Presets can't refer to other presets, so there's no need to recurse.
+        let text = &presets[&name.to_lowercase()];
+        let expanded = lex(text).map_err(|e| {
+            ParseError::new(
+                source,
+                token.span.clone(),
+                format!("In preset ${name}: {}", e.message),
+            )
+        })?;
+        items.push_back(Item::PresetStart);
+        items.extend(expanded.into_iter().map(|t| {
+            Item::Token(Token {
+                span: token.span.clone(),
+                ..t
+            })
+        }));
+        items.push_back(Item::PresetEnd);
+    }
+    Ok(items)
+}
+
+/// Parses a command line, expanding `$name` presets along the way.
+/// Preset names are lowercase.
+pub fn parse_with_presets(
+    source: &str,
+    presets: &HashMap<String, String>,
+) -> Result<CommandLine, ParseError> {
+    let tokens = lex(source)?;
+    let mut parsed = CommandLine::default();
+    let mut section = Section::Linguistic;
+    let mut items = expand_presets(source, tokens, presets)?;
+    // The sections to go back to at the end of each preset.
+    let mut preset_sections = vec![];
+
+    while let Some(item) = items.pop_front() {
+        let token = match item {
+            Item::Token(token) => token,
+            Item::PresetStart => {
+                preset_sections.push(section);
+                continue;
+            }
+            Item::PresetEnd => {
+                section = preset_sections.pop().expect("presets are bracketed");
+                continue;
+            }
+        };
+        let (name, inline_value) = match token.kind {
+            TokenKind::Word { literal, .. } => {
+                match section {
+                    Section::Linguistic => parsed.linguistic_prompt.push(literal),
+                    Section::Supporting => parsed.supporting_prompt.push(literal),
+                    Section::Negative => parsed.negative_prompt.push(literal),
+                }
+                continue;
+            }
+            TokenKind::Option { name, value } => (name, value),
+        };
+        // Inside a preset, the span is the `$name`, so we have to spell the option ourselves.
+        let preset_option_text;
+        let option_text = if preset_sections.is_empty() {
+            &source[token.span.clone()]
+        } else {
+            let dashes = if name.len() == 1 { "-" } else { "--" };
+            preset_option_text = format!("{dashes}{name}");
+            &preset_option_text
+        };
+        let error = |span: Range<usize>, message: String| ParseError::new(source, span, message);
+
+        // First, the options that don't (necessarily) take a value.
+        let switch_to = match name.as_str() {
+            "np" | "nn" | "upscale" => {
+                if inline_value.is_some() {
+                    return Err(error(
+                        token.span,
+                        format!("{option_text}: this option doesn't take a value"),
+                    ));
+                }
+                match name.as_str() {
+                    "np" => parsed.no_pos_default = true,
+                    "nn" => parsed.no_neg_default = true,
+                    _ => parsed.upscale = Some(token.span),
+                }
+                continue;
+            }
+            "prompt" => Some(Section::Linguistic),
+            "style" => Some(Section::Supporting),
+            "no" => Some(Section::Negative),
+            _ => None,
+        };
+        if let (Some(new_section), None) = (switch_to, &inline_value) {
+            section = new_section;
+            continue;
+        }
+        if !KNOWN_OPTIONS.contains(&name.as_str()) {
+            return Err(error(
+                token.span,
+                unknown_option_message(option_text, &name),
+            ));
+        }
+
+        // Everything else takes a value, either inline or as the next token.
+        let value = match inline_value {
+            Some(value) => Spanned {
+                value,
+                span: token.span.clone(),
+            },
+            None => match items.pop_front() {
+                Some(Item::Token(Token {
+                    kind: TokenKind::Word { value, .. },
+                    span,
+                })) => Spanned { value, span },
+                Some(Item::Token(Token { span, .. })) => {
+                    return Err(error(
+                        span,
+                        format!("{option_text} needs a value, but got another option"),
+                    ))
+                }
+                // Values don't carry over into or out of presets.
+                Some(Item::PresetStart | Item::PresetEnd) | None => {
+                    return Err(error(token.span, format!("{option_text} needs a value")))
+                }
+            },
+        };
+        if value.value.is_empty() {
+            return Err(error(value.span, format!("{option_text} needs a value")));
+        }
+
+        if name == "xy" {
+            // --xy takes one or two axes. The second one is optional, so it's only an axis
+            // if it looks like one; otherwise it's part of the prompt.
+            let mut axes = vec![value];
+            if let Some(Item::Token(Token {
+                kind: TokenKind::Word { value, .. },
+                span,
+            })) = items.front()
+            {
+                if GridAxis::parse(value).is_some() {
+                    axes.push(Spanned {
+                        value: value.clone(),
+                        span: span.clone(),
+                    });
+                    items.pop_front();
+                }
+            }
+            for axis in axes {
+                let parsed_axis = match GridAxis::parse(&axis.value) {
+                    Some(Ok(parsed_axis)) => parsed_axis,
+                    Some(Err(message)) => return Err(error(axis.span, message)),
+                    None => {
+                        let names = GRID_AXES
+                            .iter()
+                            .map(|(alias, _)| *alias)
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        return Err(error(
+                            axis.span,
+                            format!("{option_text} takes name=value,value,... where name is one of {names}"),
+                        ));
+                    }
+                };
+                if parsed.xy.iter().any(|a| a.value.name == parsed_axis.name) {
+                    return Err(error(
+                        axis.span,
+                        format!("{} is already an axis", parsed_axis.name),
+                    ));
+                }
+                if parsed.xy.len() >= MAX_GRID_AXES {
+                    return Err(error(
+                        axis.span,
+                        format!("A grid has at most {MAX_GRID_AXES} axes"),
+                    ));
+                }
+                parsed.xy.push(Spanned {
+                    value: parsed_axis,
+                    span: axis.span,
+                });
+            }
+            continue;
+        }
+
+        match name.as_str() {
+            "model" | "m" => parsed.model = Some(value),
+            "prompt" => parsed.linguistic_prompt.push(value.value),
+            "style" | "s" => parsed.supporting_prompt.push(value.value),
+            "no" => parsed.negative_prompt.push(value.value),
+            "scale" => parsed.guidance_scale = number(source, value, "Scale")?,
+            "aesthetic" | "a" => parsed.aesthetic_scale = number(source, value, "Aesthetic scale")?,
+            "steps" => parsed.steps = number(source, value, "Steps")?,
+            "count" | "c" => parsed.count = number(source, value, "Count")?,
+            "seed" => parsed.seed = number(source, value, "Seed")?,
+            "w" => parsed.width = number(source, value, "Width")?,
+            "h" => parsed.height = number(source, value, "Height")?,
+            "ar" => parsed.aspect_ratio = Some(value),
+            "sampler" => parsed.sampler = Some(value),
+            "scheduler" => parsed.scheduler = Some(value),
+            "variation-seed" => parsed.variation_seed = number(source, value, "Variation seed")?,
+            "variation-strength" => {
+                parsed.variation_strength = number(source, value, "Variation strength")?
+            }
+            "init" => parsed.init = Some(value),
+            "strength" => parsed.strength = number(source, value, "Strength")?,
+            "mask" => parsed.mask = Some(value),
+            _ => unreachable!("option {name} is known but not handled"),
+        }
+    }
+
+    Ok(parsed)
+}
+
+impl CommandLine {
+    /// Returns a copy with one grid axis set to one of its values, as if that had been given
+    /// as a normal option. Errors point at the axis.
+    pub fn with_axis_value(
+        &self,
+        source: &str,
+        axis: &Spanned<GridAxis>,
+        value: &str,
+    ) -> Result<Self, ParseError> {
+        let mut cell = self.clone();
+        let spanned = || Spanned {
+            value: value.to_string(),
+            span: axis.span.clone(),
+        };
+        match axis.value.name.as_str() {
+            "steps" => cell.steps = number(source, spanned(), "Steps")?,
+            "scale" => cell.guidance_scale = number(source, spanned(), "Scale")?,
+            "aesthetic" => cell.aesthetic_scale = number(source, spanned(), "Aesthetic scale")?,
+            "seed" => cell.seed = number(source, spanned(), "Seed")?,
+            "model" => cell.model = Some(spanned()),
+            "ar" => cell.aspect_ratio = Some(spanned()),
+            "sampler" => cell.sampler = Some(spanned()),
+            "scheduler" => cell.scheduler = Some(spanned()),
+            "S/R" => {
+                // Search and replace. The first value is the text to look for.
+                let search = &axis.value.values[0];
+                let mut found = false;
+                for prompt in [
+                    &mut cell.linguistic_prompt,
+                    &mut cell.supporting_prompt,
+                    &mut cell.negative_prompt,
+                ] {
+                    let text = prompt.join(" ");
+                    if text.contains(search.as_str()) {
+                        found = true;
+                        *prompt = vec![text.replace(search.as_str(), value)];
+                    }
+                }
+                if !found {
+                    return Err(ParseError::new(
+                        source,
+                        axis.span.clone(),
+                        format!("S/R: {search:?} doesn't appear in the prompt"),
+                    ));
+                }
+            }
+            other => unreachable!("grid axis {other} is known but not handled"),
+        }
+        Ok(cell)
+    }
+}
+
+fn number<T: std::str::FromStr>(
+    source: &str,
+    value: Spanned<String>,
+    what: &str,
+) -> Result<Option<Spanned<T>>, ParseError> {
+    match value.value.parse() {
+        Ok(parsed) => Ok(Some(Spanned {
+            value: parsed,
+            span: value.span,
+        })),
+        Err(_) => Err(ParseError::new(
+            source,
+            value.span,
+            format!("{what} must be a number, not {:?}", value.value),
+        )),
+    }
+}
+
+fn unknown_option_message(option_text: &str, name: &str) -> String {
+    let best = KNOWN_OPTIONS
+        .iter()
+        .filter(|o| o.len() > 1)
+        .map(|o| (strsim::jaro_winkler(name, o), o))
+        .max_by(|a, b| a.0.total_cmp(&b.0));
+    match best {
+        Some((similarity, best)) if similarity >= crate::generator::SIMILARITY_THRESHOLD => {
+            format!("Unknown option {option_text}. Did you mean --{best}?")
+        }
+        _ => format!("Unknown option {option_text}"),
+    }
+}
+
+/// Escapes prompt text so that parse() turns it back into exactly the same words.
+/// Text that already survives a round trip is returned unchanged.
+/// Words starting with `$` are escaped, so that they can't turn into a preset later.
+pub fn escape_prompt(text: &str) -> String {
+    // A trailing backslash would escape whatever separator comes next.
+    if !text.ends_with('\\') {
+        if let Ok(tokens) = lex(text) {
+            let words = tokens
+                .iter()
+                .map(|t| match &t.kind {
+                    TokenKind::Word { literal, .. } if !literal.starts_with('$') => {
+                        Some(literal.as_str())
+                    }
+                    _ => None,
+                })
+                .collect::<Option<Vec<_>>>();
+            if words.map(|w| w.join(" ")).as_deref() == Some(text) {
+                return text.to_string();
+            }
+        }
+    }
+
+    // Otherwise, escape everything that could possibly matter.
+    // Single spaces between words are left alone, since that's what words are joined with.
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut escaped = String::new();
+    let mut word_start = true;
+    for (i, &c) in chars.iter().enumerate() {
+        let is_separator = c == ' '
+            && i > 0
+            && !chars[i - 1].is_whitespace()
+            && chars.get(i + 1).is_some_and(|n| !n.is_whitespace());
+        if is_separator {
+            escaped.push(c);
+            word_start = true;
+            continue;
+        }
+        if c == '\\'
+            || c == '"'
+            || c.is_whitespace()
+            || (word_start && (c == '-' || c == '—' || c == '$'))
+        {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+        word_start = false;
+    }
+    escaped
+}
+
+/// Quotes an option value, if it needs it.
+pub fn quote_value(value: &str) -> String {
+    if let Ok(tokens) = lex(value) {
+        if let [Token {
+            kind: TokenKind::Word { value: lexed, .. },
+            ..
+        }] = tokens.as_slice()
+        {
+            if lexed == value {
+                return value.to_string();
+            }
+        }
+    }
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+    use super::*;
+
+    fn words(tokens: &[Token]) -> Vec<&str> {
+        tokens
+            .iter()
+            .map(|t| match &t.kind {
+                TokenKind::Word { literal, .. } => literal.as_str(),
+                TokenKind::Option { name, .. } => name.as_str(),
+            })
+            .collect()
+    }
+
+    fn error_column(source: &str) -> usize {
+        let err = parse(source).unwrap_err();
+        source[..err.span.start].chars().count() + 1
+    }
+
+    #[test]
+    fn test_lex_plain() {
+        let tokens = lex("  a cat\tin\n a hat ").unwrap();
+        assert_eq!(words(&tokens), vec!["a", "cat", "in", "a", "hat"]);
+        assert_eq!(tokens[1].span, 4..7);
+        assert!(lex("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_lex_quotes() {
+        let tokens = lex(r#"a sign saying "Hello World" --model "x y""#).unwrap();
+        assert_eq!(
+            tokens[3].kind,
+            TokenKind::Word {
+                literal: r#""Hello World""#.into(),
+                value: "Hello World".into()
+            }
+        );
+        assert_eq!(
+            tokens[5].kind,
+            TokenKind::Word {
+                literal: r#""x y""#.into(),
+                value: "x y".into()
+            }
+        );
+        // Apostrophes aren't quotes.
+        assert_eq!(
+            words(&lex("a girl's hat").unwrap()),
+            vec!["a", "girl's", "hat"]
+        );
+    }
+
+    #[test]
+    fn test_lex_unterminated_quote() {
+        let err = lex(r#"a "cat"#).unwrap_err();
+        assert_eq!(err.span, 2..3);
+    }
+
+    #[test]
+    fn test_lex_escapes() {
+        let tokens = lex(r#"\"quoted\" a\ b \(artist\) c:\\ \--no"#).unwrap();
+        assert_eq!(
+            words(&tokens),
+            vec![r#""quoted""#, "a b", r#"\(artist\)"#, r#"c:\"#, "--no"]
+        );
+        // A trailing backslash is just a backslash.
+        assert_eq!(words(&lex(r"a\").unwrap()), vec![r"a\"]);
+    }
+
+    #[test]
+    fn test_lex_options() {
+        let tokens = lex(r#"--seed=5 -m=x —ar 3:2 --model="a b" - -- -5 -_-"#).unwrap();
+        let option = |name: &str, value: Option<&str>| TokenKind::Option {
+            name: name.into(),
+            value: value.map(Into::into),
+        };
+        assert_eq!(tokens[0].kind, option("seed", Some("5")));
+        assert_eq!(tokens[1].kind, option("m", Some("x")));
+        assert_eq!(tokens[2].kind, option("ar", None));
+        assert_eq!(tokens[4].kind, option("model", Some("a b")));
+        assert_eq!(words(&tokens[5..]), vec!["-", "--", "-5", "-_-"]);
+        assert!(tokens[5..]
+            .iter()
+            .all(|t| matches!(t.kind, TokenKind::Word { .. })));
+        // Quoted or escaped dashes aren't options.
+        assert!(matches!(
+            lex(r#""--seed""#).unwrap()[0].kind,
+            TokenKind::Word { .. }
+        ));
+    }
+
+    #[test]
+    fn test_parse_sections() {
+        let parsed =
+            parse("a cat --style film noir, rain --no sunshine --prompt in a hat --style=oil")
+                .unwrap();
+        assert_eq!(parsed.linguistic_prompt, vec!["a", "cat", "in", "a", "hat"]);
+        assert_eq!(
+            parsed.supporting_prompt,
+            vec!["film", "noir,", "rain", "oil"]
+        );
+        assert_eq!(parsed.negative_prompt, vec!["sunshine"]);
+
+        let parsed = parse("a cat -s watercolor --no=dogs --prompt=\"big hat\"").unwrap();
+        assert_eq!(parsed.linguistic_prompt, vec!["a", "cat", "big hat"]);
+        assert_eq!(parsed.supporting_prompt, vec!["watercolor"]);
+        assert_eq!(parsed.negative_prompt, vec!["dogs"]);
+    }
+
+    #[test]
+    fn test_parse_all_flags() {
+        let parsed = parse(
+            "cat --model sdxl --scale 7.5 --aesthetic 10 --steps 20 --count 3 --seed 42 -w 512 -h 768 --ar 16:9 --np --nn --sampler euler --scheduler karras --variation-seed 5 --variation-strength=0.2 --init https://example.com/cat.png --strength 0.6 --mask alpha --upscale",
+        )
+        .unwrap();
+        assert_eq!(parsed.model.unwrap().value, "sdxl");
+        assert_eq!(parsed.guidance_scale.unwrap().value, 7.5);
+        assert_eq!(parsed.aesthetic_scale.unwrap().value, 10.0);
+        assert_eq!(parsed.steps.unwrap().value, 20);
+        assert_eq!(parsed.count.unwrap().value, 3);
+        assert_eq!(parsed.seed.unwrap().value, 42);
+        assert_eq!(parsed.width.unwrap().value, 512);
+        assert_eq!(parsed.height.unwrap().value, 768);
+        assert_eq!(parsed.aspect_ratio.unwrap().value, "16:9");
+        assert!(parsed.no_pos_default);
+        assert!(parsed.no_neg_default);
+        assert_eq!(parsed.sampler.unwrap().value, "euler");
+        assert_eq!(parsed.scheduler.unwrap().value, "karras");
+        assert_eq!(parsed.variation_seed.unwrap().value, 5);
+        assert_eq!(parsed.variation_strength.unwrap().value, 0.2);
+        assert_eq!(parsed.init.unwrap().value, "https://example.com/cat.png");
+        assert_eq!(parsed.strength.unwrap().value, 0.6);
+        assert_eq!(parsed.mask.unwrap().value, "alpha");
+        assert!(parsed.upscale.is_some());
+
+        let parsed = parse("cat -m flux -a 3 -c 2").unwrap();
+        assert_eq!(parsed.model.unwrap().value, "flux");
+        assert_eq!(parsed.aesthetic_scale.unwrap().value, 3.0);
+        assert_eq!(parsed.count.unwrap().value, 2);
+
+        // Later options override earlier ones.
+        let parsed = parse("cat --seed 1 --seed=2").unwrap();
+        assert_eq!(parsed.seed.unwrap().value, 2);
+
+        // Nothing given, nothing set.
+        let parsed = parse("cat").unwrap();
+        assert_eq!(
+            parsed,
+            CommandLine {
+                linguistic_prompt: vec!["cat".into()],
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_quoted_values() {
+        let parsed = parse(r#"cat --model "x y" --ar="3:2""#).unwrap();
+        assert_eq!(parsed.model.unwrap().value, "x y");
+        assert_eq!(parsed.aspect_ratio.unwrap().value, "3:2");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(error_column("a cat --seed=abc"), 7);
+        assert_eq!(error_column("a cat --seed abc"), 14);
+        assert_eq!(error_column("a cat --seed="), 7);
+        assert_eq!(error_column("a cat --seed"), 7);
+        assert_eq!(error_column("a cat --seed --ar 1:1"), 14);
+        assert_eq!(error_column("a cat --np=1"), 7);
+        assert_eq!(error_column("a cat -x 1"), 7);
+        assert_eq!(error_column("a cät --steps -"), 15);
+
+        let err = parse("a cat --sed 4").unwrap_err();
+        assert!(err.to_string().contains("Did you mean --seed?"), "{err}");
+    }
+
+    #[test]
+    fn test_parse_xy() {
+        let axes = |source: &str| {
+            parse(source)
+                .unwrap()
+                .xy
+                .into_iter()
+                .map(|a| a.value.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            axes("a cat --xy steps=20,30,40 scale=4,7"),
+            vec!["steps=20,30,40", "scale=4,7"]
+        );
+        assert_eq!(axes("a cat --xy=S/R=cat,dog,fox"), vec!["S/R=cat,dog,fox"]);
+        assert_eq!(
+            axes(r#"--xy "sr=red hat, blue hat" M=a,b"#),
+            vec!["S/R=red hat,blue hat", "model=a,b"]
+        );
+        // A second axis is optional; anything else is prompt.
+        let parsed = parse("--xy steps=1,2 a cat").unwrap();
+        assert_eq!(parsed.linguistic_prompt, vec!["a", "cat"]);
+
+        assert_eq!(error_column("a cat --xy cat"), 12);
+        assert_eq!(error_column("a cat --xy steps=1,,2"), 12);
+        assert_eq!(error_column("a --xy steps=1 --xy steps=2"), 21);
+        assert_eq!(error_column("a --xy steps=1 scale=1 --xy seed=1"), 29);
+    }
+
+    #[test]
+    fn test_with_axis_value() {
+        let source = "a cat in a hat --xy S/R=cat,dog steps=1,x";
+        let cmd = parse(source).unwrap();
+        let cell = cmd.with_axis_value(source, &cmd.xy[0], "dog").unwrap();
+        assert_eq!(cell.linguistic_prompt, vec!["a dog in a hat"]);
+        let cell = cmd.with_axis_value(source, &cmd.xy[1], "1").unwrap();
+        assert_eq!(cell.steps.unwrap().value, 1);
+        let err = cmd.with_axis_value(source, &cmd.xy[1], "x").unwrap_err();
+        assert_eq!(err.span, cmd.xy[1].span);
+
+        let source = "a cat --xy S/R=fox,dog";
+        let cmd = parse(source).unwrap();
+        assert!(cmd.with_axis_value(source, &cmd.xy[0], "dog").is_err());
+    }
+
+    #[test]
+    fn test_quote_value_round_trip() {
+        assert_eq!(quote_value("sdxl"), "sdxl");
+        assert_eq!(quote_value("x y"), r#""x y""#);
+        assert_eq!(quote_value("-x"), r#""-x""#);
+        let alphabet = ['a', ' ', '"', '\\', '-', '—', '=', '\n', '猫', '$', ','];
+        for seed in 0..1000 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let value: String = (0..rng.gen_range(1..=8))
+                .map(|_| *alphabet.choose(&mut rng).unwrap())
+                .collect();
+            let tokens = lex(&quote_value(&value)).unwrap();
+            assert_eq!(tokens.len(), 1, "seed {seed}: {value:?}");
+            match &tokens[0].kind {
+                TokenKind::Word { value: lexed, .. } => {
+                    assert_eq!(lexed, &value, "seed {seed}")
+                }
+                other => panic!("seed {seed}: {value:?} lexed as {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_presets() {
+        let presets: HashMap<String, String> = [
+            (
+                "moody".to_string(),
+                "--style film noir --ar 21:9".to_string(),
+            ),
+            ("broken".to_string(), "--steps \"oops".to_string()),
+        ]
+        .into();
+        let parsed = parse_with_presets("a cat $Moody in the rain", &presets).unwrap();
+        assert_eq!(
+            parsed.linguistic_prompt,
+            vec!["a", "cat", "in", "the", "rain"]
+        );
+        assert_eq!(parsed.supporting_prompt, vec!["film", "noir"]);
+        let ar = parsed.aspect_ratio.unwrap();
+        assert_eq!(ar.value, "21:9");
+        assert_eq!(ar.span, 6..12);
+
+        // Errors inside a preset point at where it was used.
+        let err = parse_with_presets("a cat $broken", &presets).unwrap_err();
+        assert_eq!(err.span, 6..13);
+        assert!(err.message.starts_with("In preset $broken:"), "{err}");
+        let err = parse_with_presets("a cat $mody", &presets).unwrap_err();
+        assert!(err.message.contains("Did you mean $moody?"), "{err}");
+
+        // Anything else is just prompt.
+        let parsed = parse_with_presets("a $5 cat $", &presets).unwrap();
+        assert_eq!(parsed.linguistic_prompt, vec!["a", "$5", "cat", "$"]);
+        assert_eq!(parse("$moody").unwrap().linguistic_prompt, vec!["$moody"]);
+        // Retries keep words that look like presets as they were.
+        let escaped = escape_prompt("a $moody cat");
+        assert_eq!(escaped, r"a \$moody cat");
+        let parsed = parse_with_presets(&escaped, &presets).unwrap();
+        assert_eq!(parsed.linguistic_prompt, vec!["a", "$moody", "cat"]);
+    }
+
+    #[test]
+    fn test_error_display() {
+        let err = parse("a cat --count many").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Count must be a number, not \"many\" (at column 15)\n  a cat --count many\n                ^^^^"
+        );
+        // Long command lines are trimmed around the error.
+        let long = format!("{} --steps x {}", "a".repeat(100), "b".repeat(100));
+        let err = parse(&long).unwrap_err().to_string();
+        let lines: Vec<&str> = err.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].starts_with("  …") && lines[1].ends_with('…'));
+        let column = |line: &str, c: char| line.chars().position(|x| x == c);
+        assert_eq!(column(lines[1], 'x'), column(lines[2], '^'));
+    }
+}
@@ -0,0 +1,169 @@
+// Named prompt fragments. `!preset save moody --style film noir --ar 21:9` stores one,
+// and `$moody` in any prompt expands to it; see parser::parse_with_presets.
+//
+// Presets belong to a user, but can be shared so that others can copy them.
+
+use anyhow::{bail, Result};
+
+use crate::{
+    generator::{ParsedRequest, Source, UserRequest},
+    parser::ParseError,
+    BotContext,
+};
+
+/// Long enough for anything sensible, short enough to type.
+const MAX_NAME_LENGTH: usize = 32;
+
+fn check_name(name: &str) -> Result<String> {
+    let name = name.strip_prefix('$').unwrap_or(name).to_lowercase();
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if !valid {
+        bail!("Preset names are up to {MAX_NAME_LENGTH} letters, digits, _ or -");
+    }
+    Ok(name)
+}
+
+/// Discord mentions come in two flavours, depending on whether the user has a nickname.
+fn normalize_user(user: &str) -> String {
+    user.replace("<@!", "<@")
+}
+
+fn usage(prefix: &str) -> String {
+    format!(
+        "Usage: `{prefix}preset save <name> <flags and prompt>`, then use `$name` in a prompt.
+Also: `{prefix}preset list [user]`, `{prefix}preset delete <name>`, `{prefix}preset share <name>`, `{prefix}preset unshare <name>`, `{prefix}preset copy <user> <name>`."
+    )
+}
+
+fn format_list(presets: &[(String, String, bool)]) -> String {
+    presets
+        .iter()
+        .map(|(name, text, shared)| {
+            let shared = if *shared { " (shared)" } else { "" };
+            format!("- ${name}{shared}: {text}")
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Handles the preset command. Returns the text to show the user.
+pub async fn handler(
+    context: &BotContext,
+    prefix: &str,
+    source: Source,
+    user: &str,
+    params: &str,
+) -> Result<String> {
+    let db = &context.db;
+    let mut words = params.split_whitespace();
+    let command = words.next().unwrap_or("list").to_lowercase();
+    let rest = words.collect::<Vec<_>>();
+    match (command.as_str(), rest.as_slice()) {
+        ("list", []) => {
+            let presets = db.list_presets(&source, user, false).await?;
+            if presets.is_empty() {
+                return Ok(format!("You don't have any presets.\n{}", usage(prefix)));
+            }
+            Ok(format!("Your presets:\n{}", format_list(&presets)))
+        }
+        ("list", [owner]) => {
+            let presets = db
+                .list_presets(&source, &normalize_user(owner), true)
+                .await?;
+            if presets.is_empty() {
+                return Ok(format!("{owner} hasn't shared any presets."));
+            }
+            Ok(format!(
+                "Presets shared by {owner}:\n{}",
+                format_list(&presets)
+            ))
+        }
+        ("save", [name, ..]) if rest.len() > 1 => {
+            let name = check_name(name)?;
+            // Keep the fragment exactly as typed, apart from the command itself.
+            let text = params.trim_start()[command.len()..]
+                .trim_start()
+                .split_once(char::is_whitespace)
+                .map(|(_, text)| text.trim())
+                .unwrap_or_default();
+            // Make sure it can actually be used. Syntax errors are easier to read without the
+            // $name expansion.
+            crate::parser::parse(text)?;
+            let config = context.config.snapshot().await;
+            let test = UserRequest {
+                user: user.to_string(),
+                dream: None,
+                raw: format!("test ${name}"),
+                source: source.clone(),
+                private: false,
+                comment: None,
+                parent: None,
+                settings: None,
+            };
+            let presets = [(name.clone(), text.to_string())].into();
+            if let Err(e) =
+                ParsedRequest::from_request(&config, test, &Default::default(), &presets)
+            {
+                match e.downcast_ref::<ParseError>() {
+                    Some(e) => bail!("${name}: {}", e.message()),
+                    None => bail!("${name}: {e:#}"),
+                }
+            }
+            db.save_preset(&source, user, &name, text).await?;
+            Ok(format!("Saved ${name}."))
+        }
+        ("delete", [name]) => {
+            let name = check_name(name)?;
+            if !db.delete_preset(&source, user, &name).await? {
+                bail!("You don't have a preset called ${name}.");
+            }
+            Ok(format!("Deleted ${name}."))
+        }
+        ("share" | "unshare", [name]) => {
+            let name = check_name(name)?;
+            let shared = command == "share";
+            if !db.set_preset_shared(&source, user, &name, shared).await? {
+                bail!("You don't have a preset called ${name}.");
+            }
+            if shared {
+                Ok(format!(
+                    "Shared ${name}. Others can use `{prefix}preset copy {user} {name}` to get it."
+                ))
+            } else {
+                Ok(format!("${name} is no longer shared."))
+            }
+        }
+        ("copy", [owner, name]) => {
+            let name = check_name(name)?;
+            let shared = db
+                .list_presets(&source, &normalize_user(owner), true)
+                .await?;
+            let Some((_, text, _)) = shared.iter().find(|(n, _, _)| *n == name) else {
+                bail!("{owner} hasn't shared a preset called ${name}.");
+            };
+            db.save_preset(&source, user, &name, text).await?;
+            Ok(format!("Copied ${name}: {text}"))
+        }
+        _ => bail!(usage(prefix)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_name() {
+        assert_eq!(check_name("Moody").unwrap(), "moody");
+        assert_eq!(check_name("$film-noir_2").unwrap(), "film-noir_2");
+        assert!(check_name("").is_err());
+        assert!(check_name("$").is_err());
+        assert!(check_name("two words").is_err());
+        assert!(check_name(&"x".repeat(MAX_NAME_LENGTH + 1)).is_err());
+        assert_eq!(normalize_user("<@!123>"), "<@123>");
+    }
+}
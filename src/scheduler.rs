@@ -0,0 +1,461 @@
+// Decides whose picture goes next, by weighted fair queueing over GPU time.
+//
+// Everyone is charged for the GPU seconds their pictures take, and the charge decays with a
+// half-life, so what counts is recent use. The next job is the one that would leave its user
+// with the least use for their weight, counting its own expected cost. Someone asking for a
+// lot of big pictures still gets them, but anyone else who turns up goes next.
+//
+// Jobs are charged an estimate when they start, so one user can't take every free server at
+// once, and the estimate is corrected once we know how long it really took. Loading another
+// checkpoint costs the server time too, so that's added to jobs that would need it.
+//
+// The same rules, run ahead of time with the timings from the database, tell people roughly
+// when their pictures will be done.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{backends::Pool, config::BotConfig, generator::ParsedRequest};
+
+/// What we guess a megapixel costs for a model we haven't timed yet.
+const DEFAULT_SECONDS_PER_MEGAPIXEL: f64 = 8.0;
+
+/// How much each new timing moves the estimate for its model.
+const RATE_SMOOTHING: f64 = 0.3;
+
+#[derive(Clone, Debug, Default)]
+pub struct FairShare {
+    /// GPU seconds by user, as of when they were last charged.
+    usage: HashMap<String, (f64, Instant)>,
+    /// Measured seconds per megapixel, by model.
+    rates: HashMap<String, f64>,
+}
+
+impl FairShare {
+    /// A user's GPU seconds, decayed to `now`.
+    pub fn usage(&self, config: &BotConfig, user: &str, now: Instant) -> f64 {
+        let Some((seconds, at)) = self.usage.get(user) else {
+            return 0.0;
+        };
+        let half_life = config.scheduler.half_life_minutes * 60.0;
+        if half_life <= 0.0 {
+            return 0.0;
+        }
+        let age = now.saturating_duration_since(*at).as_secs_f64();
+        seconds * 0.5f64.powf(age / half_life)
+    }
+
+    /// Adds to a user's GPU seconds. Negative amounts are refunds.
+    pub fn charge(&mut self, config: &BotConfig, user: &str, seconds: f64, now: Instant) {
+        let usage = (self.usage(config, user, now) + seconds).max(0.0);
+        self.usage.insert(user.to_string(), (usage, now));
+    }
+
+    /// How many GPU seconds we expect the request to take.
+    pub fn estimate(&self, request: &ParsedRequest) -> f64 {
+        let rate = self
+            .rates
+            .get(&request.model_name)
+            .copied()
+            .unwrap_or(DEFAULT_SECONDS_PER_MEGAPIXEL);
+        rate * request.megapixels()
+    }
+
+    /// Learns from how long a request took.
+    pub fn measured(&mut self, request: &ParsedRequest, seconds: f64) {
+        let megapixels = request.megapixels();
+        if megapixels <= 0.0 || seconds <= 0.0 {
+            return;
+        }
+        let rate = seconds / megapixels;
+        self.rates
+            .entry(request.model_name.clone())
+            .and_modify(|r| *r += RATE_SMOOTHING * (rate - *r))
+            .or_insert(rate);
+    }
+
+    /// Lower goes first. `switching` says whether the server would need to load another
+    /// checkpoint for it.
+    pub fn priority(
+        &self,
+        config: &BotConfig,
+        request: &ParsedRequest,
+        switching: bool,
+        now: Instant,
+    ) -> f64 {
+        let user = &request.base.user;
+        let weight = config.scheduler.weight(user);
+        let mut priority = (self.usage(config, user, now) + self.estimate(request)) / weight;
+        if switching {
+            priority += config.scheduler.model_switch_seconds;
+        }
+        priority
+    }
+}
+
+/// Seconds per picture, by model and resolution, as measured.
+#[derive(Clone, Debug, Default)]
+pub struct Timings(HashMap<(String, u32, u32), f64>);
+
+impl Timings {
+    pub fn new(seconds_per_picture: HashMap<(String, u32, u32), f64>) -> Self {
+        Self(seconds_per_picture)
+    }
+
+    /// How many seconds the request should take. Resolutions we haven't timed are scaled from
+    /// the model's other ones, or failing that, from every model's.
+    pub fn estimate(&self, request: &ParsedRequest) -> f64 {
+        let picture = self.0.iter().find(|((model, width, height), _)| {
+            *model == request.model_name && *width == request.width && *height == request.height
+        });
+        if let Some((_, seconds)) = picture {
+            return seconds * request.count as f64;
+        }
+        let per_megapixel = |same_model: bool| {
+            let rates = self
+                .0
+                .iter()
+                .filter(|((model, _, _), _)| !same_model || *model == request.model_name)
+                .map(|((_, width, height), seconds)| {
+                    seconds / (*width as f64 * *height as f64 / 1e6)
+                })
+                .collect::<Vec<_>>();
+            (!rates.is_empty()).then(|| rates.iter().sum::<f64>() / rates.len() as f64)
+        };
+        let rate = per_megapixel(true)
+            .or_else(|| per_megapixel(false))
+            .unwrap_or(DEFAULT_SECONDS_PER_MEGAPIXEL);
+        rate * request.megapixels()
+    }
+}
+
+/// When a job should start and be done, from now.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Eta {
+    pub start: Duration,
+    pub finish: Duration,
+}
+
+impl std::fmt::Display for Eta {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if !self.start.is_zero() {
+            write!(f, "starts in {}, ", about(self.start))?;
+        }
+        write!(f, "done in {}", about(self.finish))
+    }
+}
+
+/// A rough, readable length of time.
+fn about(d: Duration) -> String {
+    let minutes = (d.as_secs_f64() / 60.0).round() as u64;
+    match minutes {
+        0 => "under a minute".to_string(),
+        1 => "about a minute".to_string(),
+        2..=90 => format!("about {minutes} minutes"),
+        _ => format!("about {} hours", (minutes + 30) / 60),
+    }
+}
+
+/// Runs the queue ahead of time, to see when everything should happen. That assumes the
+/// servers stay as they are, nothing else turns up, and the timings are right.
+///
+/// Running jobs are given as the server they're on, the request, and how long it's been going.
+/// Returns their ETAs, then the queue's; None for jobs no server can run right now.
+pub fn forecast<T>(
+    config: &BotConfig,
+    pool: &Pool,
+    fair: &FairShare,
+    timings: &Timings,
+    running: &[(String, &ParsedRequest, Duration)],
+    queue: &[(ParsedRequest, T)],
+    now: Instant,
+) -> (Vec<Eta>, Vec<Option<Eta>>) {
+    let mut pool = pool.clone();
+    let mut fair = fair.clone();
+    // When each server gets a slot back.
+    let mut finishing = vec![];
+    let running = running
+        .iter()
+        .map(|(address, request, elapsed)| {
+            let left = (timings.estimate(request) - elapsed.as_secs_f64()).max(0.0);
+            let finish = Duration::from_secs_f64(left);
+            finishing.push((finish, address.clone()));
+            Eta {
+                start: Duration::ZERO,
+                finish,
+            }
+        })
+        .collect();
+    let mut waiting = queue
+        .iter()
+        .enumerate()
+        .map(|(i, (request, _))| (request.clone(), i))
+        .collect::<Vec<_>>();
+    let mut etas = vec![None; queue.len()];
+    let mut t = Duration::ZERO;
+    loop {
+        while let Some((i, server)) = pool.pick(config, &fair, &waiting, now + t) {
+            let (request, index) = waiting.remove(i);
+            let seconds = timings.estimate(&request);
+            let finish = t + Duration::from_secs_f64(seconds);
+            pool.start(config, &server.address(), &request);
+            fair.charge(config, &request.base.user, seconds, now + t);
+            etas[index] = Some(Eta { start: t, finish });
+            finishing.push((finish, server.address()));
+        }
+        let Some(next) = (0..finishing.len()).min_by_key(|&i| finishing[i].0) else {
+            break;
+        };
+        let (at, address) = finishing.remove(next);
+        t = at;
+        pool.finish(&address);
+    }
+    (running, etas)
+}
+
+/// A queued job for tests: `count` one-megapixel pictures from `user`.
+#[cfg(test)]
+pub fn test_job(model: &str, user: &str, count: u32) -> (ParsedRequest, ()) {
+    let mut request = ParsedRequest {
+        model_name: model.to_string(),
+        count,
+        width: 1000,
+        height: 1000,
+        ..Default::default()
+    };
+    request.base.user = user.to_string();
+    (request, ())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::config::{testconfig, ComfyServer};
+
+    /// Runs everything to completion on one server, with every megapixel taking `rate` seconds.
+    /// Jobs turn up the given number of seconds after `now`, and the server starts out with
+    /// `loaded`'s checkpoint. Returns who went, in order.
+    fn simulate(
+        config: &BotConfig,
+        fair: &mut FairShare,
+        mut arrivals: Vec<(f64, (ParsedRequest, ()))>,
+        loaded: Option<&str>,
+        rate: f64,
+        start: Instant,
+    ) -> Vec<String> {
+        let mut pool = Pool::default();
+        pool.sync(&[ComfyServer {
+            host: "localhost".to_string(),
+            port: 8188,
+            concurrency: 1,
+            models: vec![],
+        }]);
+        if let Some(model) = loaded {
+            pool.start(config, "localhost:8188", &test_job(model, "", 0).0);
+            pool.finish("localhost:8188");
+        }
+        let mut now = start;
+        let mut queue = vec![];
+        let mut order = vec![];
+        loop {
+            // Whatever's turned up by now joins the queue. With nothing to do, skip ahead.
+            let elapsed = now.duration_since(start).as_secs_f64();
+            let (arrived, later) = arrivals.into_iter().partition(|(at, _)| *at <= elapsed);
+            arrivals = later;
+            queue.extend(arrived.into_iter().map(|(_, job)| job));
+            let Some((i, server)) = pool.pick(config, fair, &queue, now) else {
+                match arrivals.iter().map(|(at, _)| *at).reduce(f64::min) {
+                    Some(next) => {
+                        now = start + Duration::from_secs_f64(next);
+                        continue;
+                    }
+                    None => break,
+                }
+            };
+            let (request, ()) = queue.remove(i);
+            let estimate = fair.estimate(&request);
+            pool.start(config, &server.address(), &request);
+            fair.charge(config, &request.base.user, estimate, now);
+            let took = rate * request.megapixels();
+            now += Duration::from_secs_f64(took);
+            fair.charge(config, &request.base.user, took - estimate, now);
+            fair.measured(&request, took);
+            pool.finish(&server.address());
+            order.push(request.base.user);
+        }
+        order
+    }
+
+    #[test]
+    fn test_decay() {
+        let config = testconfig();
+        let mut fair = FairShare::default();
+        let now = Instant::now();
+        fair.charge(&config, "a", 100.0, now);
+        let later = now + Duration::from_secs(30 * 60);
+        assert!((fair.usage(&config, "a", later) - 50.0).abs() < 1e-6);
+        fair.charge(&config, "a", -1000.0, later);
+        assert_eq!(fair.usage(&config, "a", later), 0.0);
+        assert_eq!(fair.usage(&config, "b", later), 0.0);
+    }
+
+    #[test]
+    fn test_estimate_learns() {
+        let mut fair = FairShare::default();
+        let (request, ()) = test_job("flux", "a", 2);
+        assert_eq!(fair.estimate(&request), 2.0 * DEFAULT_SECONDS_PER_MEGAPIXEL);
+        fair.measured(&request, 60.0);
+        assert_eq!(fair.estimate(&request), 60.0);
+        fair.measured(&request, 30.0);
+        assert!(fair.estimate(&request) < 60.0);
+        // Other models are still a guess.
+        assert_eq!(
+            fair.estimate(&test_job("foo", "a", 1).0),
+            DEFAULT_SECONDS_PER_MEGAPIXEL
+        );
+    }
+
+    #[test]
+    fn test_spammer_doesnt_starve_others() {
+        let config = testconfig();
+        let mut fair = FairShare::default();
+        // Alice got there first with a pile of big batches. Bob and Carol turn up while she's
+        // busy, and want a picture or two each.
+        let mut arrivals = (0..5)
+            .map(|_| (0.0, test_job("flux", "alice", 9)))
+            .collect::<Vec<_>>();
+        arrivals.push((30.0, test_job("flux", "bob", 1)));
+        arrivals.push((100.0, test_job("flux", "carol", 2)));
+        let order = simulate(&config, &mut fair, arrivals, None, 10.0, Instant::now());
+        assert_eq!(
+            order,
+            vec!["alice", "bob", "carol", "alice", "alice", "alice", "alice"]
+        );
+    }
+
+    #[test]
+    fn test_heavy_user_waits_their_turn() {
+        let config = testconfig();
+        let mut fair = FairShare::default();
+        let now = Instant::now();
+        // Alice has had the GPU for the last ten minutes. Bob's request is newer, but goes first.
+        fair.charge(&config, "alice", 600.0, now);
+        let arrivals = vec![
+            (0.0, test_job("flux", "alice", 1)),
+            (0.0, test_job("flux", "bob", 4)),
+        ];
+        let order = simulate(&config, &mut fair, arrivals, None, 10.0, now);
+        assert_eq!(order, vec!["bob", "alice"]);
+    }
+
+    #[test]
+    fn test_weights() {
+        let mut config = testconfig();
+        config.scheduler.weights.insert("alice".to_string(), 2.0);
+        let mut fair = FairShare::default();
+        // Both keep the queue full; Alice should get about twice the pictures.
+        let mut arrivals = vec![];
+        for _ in 0..30 {
+            arrivals.push((0.0, test_job("flux", "alice", 1)));
+            arrivals.push((0.0, test_job("flux", "bob", 1)));
+        }
+        let order = simulate(&config, &mut fair, arrivals, None, 10.0, Instant::now());
+        let alice = order[..30].iter().filter(|u| *u == "alice").count();
+        assert!((19..=21).contains(&alice), "{order:?}");
+    }
+
+    #[test]
+    fn test_model_switch_cost() {
+        let config = testconfig();
+        let mut fair = FairShare::default();
+        let now = Instant::now();
+        // Bob is a little ahead of Alice, but his job would mean loading another checkpoint.
+        fair.charge(&config, "alice", 10.0, now);
+        let arrivals = || {
+            vec![
+                (0.0, test_job("flux", "alice", 1)),
+                (0.0, test_job("flux", "alice", 1)),
+                (0.0, test_job("foo", "bob", 1)),
+            ]
+        };
+        let order = simulate(&config, &mut fair, arrivals(), Some("flux"), 1.0, now);
+        assert_eq!(order, vec!["alice", "alice", "bob"]);
+        // Without the cost, Bob would have gone second.
+        let mut config = testconfig();
+        config.scheduler.model_switch_seconds = 0.0;
+        let mut fair = FairShare::default();
+        fair.charge(&config, "alice", 10.0, now);
+        let order = simulate(&config, &mut fair, arrivals(), Some("flux"), 1.0, now);
+        assert_eq!(order, vec!["bob", "alice", "alice"]);
+    }
+
+    #[test]
+    fn test_timings() {
+        let mut measured = HashMap::new();
+        measured.insert(("flux".to_string(), 1000, 1000), 10.0);
+        measured.insert(("foo".to_string(), 500, 500), 1.0);
+        let timings = Timings::new(measured);
+        let (mut request, ()) = test_job("flux", "a", 3);
+        assert_eq!(timings.estimate(&request), 30.0);
+        // Twice the pixels, twice the time.
+        request.width = 2000;
+        assert_eq!(timings.estimate(&request), 60.0);
+        // Models we haven't timed go by the average.
+        let (request, ()) = test_job("sdxl", "a", 1);
+        assert_eq!(timings.estimate(&request), (10.0 + 4.0) / 2.0);
+        assert_eq!(
+            Timings::default().estimate(&request),
+            DEFAULT_SECONDS_PER_MEGAPIXEL
+        );
+    }
+
+    #[test]
+    fn test_forecast() {
+        let mut config = testconfig();
+        config.scheduler.model_switch_seconds = 0.0;
+        let mut measured = HashMap::new();
+        measured.insert(("flux".to_string(), 1000, 1000), 10.0);
+        let timings = Timings::new(measured);
+        let mut pool = Pool::default();
+        pool.sync(&[ComfyServer {
+            host: "localhost".to_string(),
+            port: 8188,
+            concurrency: 1,
+            models: vec!["flux".to_string()],
+        }]);
+        // Alice's two pictures have been going for five seconds.
+        let (alice, ()) = test_job("flux", "alice", 2);
+        pool.start(&config, "localhost:8188", &alice);
+        let running = [("localhost:8188".to_string(), &alice, Duration::from_secs(5))];
+        // Carol asked first, but Bob wants less. Nothing can run foo.
+        let queue = vec![
+            test_job("flux", "carol", 2),
+            test_job("flux", "bob", 1),
+            test_job("foo", "dave", 1),
+        ];
+        let fair = FairShare::default();
+        let (running, queued) = forecast(
+            &config,
+            &pool,
+            &fair,
+            &timings,
+            &running,
+            &queue,
+            Instant::now(),
+        );
+        let eta = |start, finish| Eta {
+            start: Duration::from_secs(start),
+            finish: Duration::from_secs(finish),
+        };
+        assert_eq!(running, vec![eta(0, 15)]);
+        assert_eq!(queued, vec![Some(eta(25, 45)), Some(eta(15, 25)), None]);
+        assert_eq!(
+            queued[0].unwrap().to_string(),
+            "starts in under a minute, done in about a minute"
+        );
+    }
+}
@@ -0,0 +1,276 @@
+// Per-user defaults, set with !settings or /settings and stored in Users.settings.
+//
+// They sit underneath the command line: anything given explicitly in a request wins.
+// Privacy and output format aren't flags, so they only come from here.
+// Requests keep a copy of the settings they were made with, so that retrying one later
+// comes out the same even if the settings have changed.
+
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    generator::{OutputFormat, ParsedRequest, Source, UserRequest},
+    parser::{CommandLine, ParseError, Spanned},
+    BotContext,
+};
+
+/// The settings we understand, with a short description each.
+const KEYS: &[(&str, &str)] = &[
+    ("model", "Model to use when you don't pass --model."),
+    (
+        "ar",
+        "Aspect ratio to use when you don't pass --ar, -w or -h.",
+    ),
+    (
+        "negative",
+        "Negative prompt to use when you don't pass --no.",
+    ),
+    (
+        "count",
+        "Number of pictures to generate when you don't pass --count.",
+    ),
+    (
+        "private",
+        "on/off. Send your results privately, instead of to the channel.",
+    ),
+    (
+        "format",
+        "jpeg, png or webp. File format for your pictures.",
+    ),
+];
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserSettings {
+    pub model: Option<String>,
+    pub ar: Option<String>,
+    pub negative: Option<String>,
+    pub count: Option<u32>,
+    pub private: Option<bool>,
+    pub format: Option<OutputFormat>,
+}
+
+impl UserSettings {
+    /// Fills in whatever the command line left out.
+    pub fn apply(&self, cmd: &mut CommandLine) {
+        // There's no span to point at, since the user didn't type these.
+        let default = |value: &String| Spanned {
+            value: value.clone(),
+            span: 0..0,
+        };
+        if cmd.model.is_none() {
+            cmd.model = self.model.as_ref().map(default);
+        }
+        if cmd.aspect_ratio.is_none() && cmd.width.is_none() && cmd.height.is_none() {
+            cmd.aspect_ratio = self.ar.as_ref().map(default);
+        }
+        if cmd.negative_prompt.is_empty() {
+            if let Some(negative) = &self.negative {
+                cmd.negative_prompt.push(negative.clone());
+            }
+        }
+        // Grids decide their own count.
+        if cmd.count.is_none() && cmd.xy.is_empty() {
+            cmd.count = self.count.map(|value| Spanned { value, span: 0..0 });
+        }
+    }
+
+    pub fn is_private(&self) -> bool {
+        self.private.unwrap_or(false)
+    }
+
+    /// Sets a single setting from user input.
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        let value = value.trim();
+        if value.is_empty() {
+            bail!("{key} needs a value");
+        }
+        match key {
+            "model" => self.model = Some(value.to_string()),
+            "ar" => self.ar = Some(value.to_string()),
+            "negative" => self.negative = Some(value.to_string()),
+            "count" => {
+                let count =
+                    value.parse().ok().filter(|c| *c >= 1).with_context(|| {
+                        format!("count must be a positive number, not {value:?}")
+                    })?;
+                self.count = Some(count);
+            }
+            "private" => {
+                self.private = Some(match value.to_lowercase().as_str() {
+                    "on" | "yes" | "true" => true,
+                    "off" | "no" | "false" => false,
+                    _ => bail!("private must be on or off, not {value:?}"),
+                })
+            }
+            "format" => self.format = Some(OutputFormat::from_str(value)?),
+            _ => bail!("Unknown setting {key}. {}", Self::known_keys()),
+        }
+        Ok(())
+    }
+
+    pub fn clear(&mut self, key: &str) -> Result<()> {
+        match key {
+            "model" => self.model = None,
+            "ar" => self.ar = None,
+            "negative" => self.negative = None,
+            "count" => self.count = None,
+            "private" => self.private = None,
+            "format" => self.format = None,
+            _ => bail!("Unknown setting {key}. {}", Self::known_keys()),
+        }
+        Ok(())
+    }
+
+    fn known_keys() -> String {
+        let keys = KEYS.iter().map(|(k, _)| *k).collect::<Vec<_>>();
+        format!("Settings are: {}", keys.join(", "))
+    }
+
+    /// Lists every setting, set or not.
+    pub fn describe(&self) -> String {
+        KEYS.iter()
+            .map(|(key, description)| {
+                let value = match *key {
+                    "model" => self.model.clone(),
+                    "ar" => self.ar.clone(),
+                    "negative" => self.negative.clone(),
+                    "count" => self.count.map(|c| c.to_string()),
+                    "private" => self
+                        .private
+                        .map(|p| if p { "on" } else { "off" }.to_string()),
+                    "format" => self.format.map(|f| f.to_string()),
+                    _ => unreachable!("setting {key} is known but not described"),
+                };
+                let value = value.unwrap_or_else(|| "(not set)".to_string());
+                format!("- {key}: {value} — {description}")
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+/// Handles the settings command. Returns the text to show the user.
+pub async fn handler(
+    context: &BotContext,
+    prefix: &str,
+    source: Source,
+    user: &str,
+    params: &str,
+) -> Result<String> {
+    let mut settings = context.db.get_user_settings(&source, user).await?;
+    let params = params.trim();
+    let (key, value) = params.split_once(' ').unwrap_or((params, ""));
+    let key = match key.to_lowercase().as_str() {
+        "no" => "negative".to_string(),
+        "aspect" => "ar".to_string(),
+        key => key.to_string(),
+    };
+    match (key.as_str(), value.trim()) {
+        ("", _) => {
+            return Ok(format!(
+                "Your settings:\n{}\n\nUse `{prefix}settings <name> <value>` to change one, `{prefix}settings clear <name>` to reset it, or `{prefix}settings clear` to reset everything.",
+                settings.describe()
+            ))
+        }
+        ("clear", "") => settings = UserSettings::default(),
+        ("clear", name) => settings.clear(&name.to_lowercase())?,
+        (name, "") => {
+            if !KEYS.iter().any(|(k, _)| *k == name) {
+                bail!("Unknown setting {name}. {}", UserSettings::known_keys());
+            }
+            bail!("Usage: {prefix}settings {name} <value>");
+        }
+        (name, value) => {
+            settings.set(name, value)?;
+            // Make sure the result is something we can actually generate with.
+            let config = context.config.snapshot().await;
+            let test = UserRequest {
+                user: user.to_string(),
+                dream: None,
+                raw: "test".to_string(),
+                source: source.clone(),
+                private: false,
+                comment: None,
+                parent: None,
+                settings: None,
+            };
+            if let Err(e) = ParsedRequest::from_request(&config, test, &settings, &Default::default()) {
+                match e.downcast_ref::<ParseError>() {
+                    Some(e) => bail!("{name}: {}", e.message()),
+                    None => bail!("{name}: {e:#}"),
+                }
+            }
+        }
+    }
+    context
+        .db
+        .set_user_settings(&source, user, &settings)
+        .await?;
+    Ok(format!("Saved. Your settings:\n{}", settings.describe()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_and_clear() {
+        let mut settings = UserSettings::default();
+        settings.set("model", "flux").unwrap();
+        settings.set("count", "2").unwrap();
+        settings.set("private", "On").unwrap();
+        settings.set("format", "png").unwrap();
+        assert_eq!(settings.model.as_deref(), Some("flux"));
+        assert_eq!(settings.count, Some(2));
+        assert!(settings.is_private());
+        assert_eq!(settings.format, Some(OutputFormat::Png));
+        assert!(settings.set("count", "0").is_err());
+        assert!(settings.set("private", "maybe").is_err());
+        assert!(settings.set("format", "bmp").is_err());
+        assert!(settings.set("colour", "red").is_err());
+
+        settings.clear("model").unwrap();
+        assert_eq!(settings.model, None);
+        assert!(settings.describe().contains("- model: (not set)"));
+        assert!(settings.describe().contains("- count: 2"));
+
+        // Round trip through the database format.
+        let json = serde_json::to_string(&settings).unwrap();
+        assert_eq!(
+            serde_json::from_str::<UserSettings>(&json).unwrap(),
+            settings
+        );
+        assert_eq!(
+            serde_json::from_str::<UserSettings>("{}").unwrap(),
+            UserSettings::default()
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let settings = UserSettings {
+            model: Some("flux".to_string()),
+            ar: Some("16:9".to_string()),
+            negative: Some("blurry".to_string()),
+            count: Some(2),
+            ..Default::default()
+        };
+        let mut cmd = crate::parser::parse("a cat").unwrap();
+        settings.apply(&mut cmd);
+        assert_eq!(cmd.model.unwrap().value, "flux");
+        assert_eq!(cmd.aspect_ratio.unwrap().value, "16:9");
+        assert_eq!(cmd.negative_prompt, vec!["blurry"]);
+        assert_eq!(cmd.count.unwrap().value, 2);
+
+        // Explicit flags win.
+        let mut cmd = crate::parser::parse("a cat -m foo -w 512 --no dogs -c 3").unwrap();
+        settings.apply(&mut cmd);
+        assert_eq!(cmd.model.unwrap().value, "foo");
+        assert_eq!(cmd.aspect_ratio, None);
+        assert_eq!(cmd.negative_prompt, vec!["dogs"]);
+        assert_eq!(cmd.count.unwrap().value, 3);
+    }
+}
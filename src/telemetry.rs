@@ -0,0 +1,260 @@
+// A record of every generation attempt, whether it worked or not: what was asked for, where it
+// ran, how long it waited and took, and how it ended. The database keeps them, and they drive
+// the ETAs, `!stats`, and finding models that are slow or broken.
+//
+// A request that moves to another server after its own went away is one attempt per server.
+
+use std::time::Duration;
+
+use crate::{
+    config::{BotConfig, ComfyServer},
+    generator::ParsedRequest,
+};
+
+/// Models that fail at least this share of the time, not counting cancellations and lost
+/// servers, are probably broken.
+const BROKEN_FAILURE_RATE: f64 = 0.5;
+
+/// Models are slow if a picture takes this many times what the usual model needs.
+const SLOW_FACTOR: f64 = 3.0;
+
+/// How an attempt ended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Ok,
+    Cancelled,
+    /// The server stopped answering.
+    ServerLost,
+    /// ComfyUI wouldn't take the prompt, e.g. because the workflow doesn't fit it.
+    Rejected,
+    /// The prompt ran, but nothing came out. Usually an error while executing it.
+    NoImages,
+    /// It never finished.
+    Timeout,
+    /// Trouble talking to the server.
+    Network,
+    Other,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Ok => "ok",
+            Outcome::Cancelled => "cancelled",
+            Outcome::ServerLost => "server_lost",
+            Outcome::Rejected => "rejected",
+            Outcome::NoImages => "no_images",
+            Outcome::Timeout => "timeout",
+            Outcome::Network => "network",
+            Outcome::Other => "other",
+        }
+    }
+
+    /// What kind of error it was, for a server that's still up.
+    pub fn of_error(error: &anyhow::Error) -> Self {
+        let message = format!("{error:#}");
+        if message.contains("backend error") {
+            return Outcome::Rejected;
+        }
+        if message.contains("no images in history") {
+            return Outcome::NoImages;
+        }
+        if message.contains("timed out") {
+            return Outcome::Timeout;
+        }
+        for cause in error.chain() {
+            if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+                return if e.is_timeout() {
+                    Outcome::Timeout
+                } else {
+                    Outcome::Network
+                };
+            }
+            if cause.is::<tungstenite::Error>() {
+                return Outcome::Network;
+            }
+        }
+        Outcome::Other
+    }
+}
+
+/// One try at generating a request, on one server.
+#[derive(Clone, Debug)]
+pub struct Attempt {
+    pub model: String,
+    /// The workflow file it ran.
+    pub workflow: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    pub steps: Option<u32>,
+    /// Pictures in the whole request.
+    pub pictures: u32,
+    pub server: String,
+    /// How long it waited for a server.
+    pub queue_wait: Duration,
+    /// GPU time if it finished, or how long it ran if it didn't.
+    pub backend_time: Duration,
+    /// Batches that were sent to the server again after failing.
+    pub retries: u32,
+    pub outcome: Outcome,
+}
+
+impl Attempt {
+    pub fn new(
+        config: &BotConfig,
+        request: &ParsedRequest,
+        server: &ComfyServer,
+        queue_wait: Duration,
+        backend_time: Duration,
+        retries: u32,
+        outcome: Outcome,
+    ) -> Self {
+        let model = config
+            .aliases
+            .get(&request.model_name)
+            .unwrap_or(&request.model_name);
+        let model_config = config.models.get(model);
+        Self {
+            model: model.clone(),
+            workflow: model_config
+                .and_then(|m| request.workflow(m))
+                .map(str::to_string),
+            width: request.width,
+            height: request.height,
+            steps: model_config.map(|m| request.total_steps(m)),
+            pictures: request.count,
+            server: server.address(),
+            queue_wait,
+            backend_time,
+            retries,
+            outcome,
+        }
+    }
+}
+
+/// How a model has been doing lately.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ModelStats {
+    pub model: String,
+    pub attempts: u32,
+    pub cancelled: u32,
+    /// Attempts that didn't work, by outcome, most common first.
+    pub errors: Vec<(String, u32)>,
+    /// Over the attempts that worked.
+    pub seconds_per_picture: Option<f64>,
+    pub queue_wait: Option<f64>,
+}
+
+/// The !stats report, pointing out models that look slow or broken.
+pub fn report(stats: &[ModelStats], days: u32) -> String {
+    if stats.is_empty() {
+        return format!("Nothing's been generated in the last {days} days.");
+    }
+    let mut rates = stats
+        .iter()
+        .filter_map(|s| s.seconds_per_picture)
+        .collect::<Vec<_>>();
+    rates.sort_by(f64::total_cmp);
+    let usual = rates.get(rates.len() / 2).copied();
+    let mut lines = vec![format!("The last {days} days, by model:")];
+    for s in stats {
+        let mut line = format!("{}: {} tries", s.model, s.attempts);
+        let failed = s.errors.iter().map(|(_, n)| n).sum::<u32>();
+        if failed > 0 {
+            let errors = s
+                .errors
+                .iter()
+                .map(|(outcome, n)| format!("{outcome} ×{n}"))
+                .collect::<Vec<_>>();
+            line.push_str(&format!(", {failed} failed ({})", errors.join(", ")));
+        }
+        if s.cancelled > 0 {
+            line.push_str(&format!(", {} cancelled", s.cancelled));
+        }
+        if let Some(seconds) = s.seconds_per_picture {
+            line.push_str(&format!(", {seconds:.1}s per picture"));
+        }
+        if let Some(wait) = s.queue_wait {
+            line.push_str(&format!(", {wait:.0}s in the queue"));
+        }
+        let counted = s
+            .errors
+            .iter()
+            .filter(|(outcome, _)| outcome != Outcome::ServerLost.as_str())
+            .map(|(_, n)| n)
+            .sum::<u32>();
+        let finished = s.attempts - s.cancelled - (failed - counted);
+        if finished > 0 && counted as f64 >= BROKEN_FAILURE_RATE * finished as f64 {
+            line.push_str(". Looks broken!");
+        } else if s
+            .seconds_per_picture
+            .zip(usual)
+            .is_some_and(|(seconds, usual)| seconds > SLOW_FACTOR * usual)
+        {
+            line.push_str(". Slow.");
+        }
+        lines.push(line);
+    }
+    lines.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_of_error() {
+        let error = anyhow::anyhow!("backend error: Prompt outputs failed validation")
+            .context("Failed to generate batch");
+        assert_eq!(Outcome::of_error(&error), Outcome::Rejected);
+        let error = anyhow::anyhow!("no images in history").context("Ran out of retries");
+        assert_eq!(Outcome::of_error(&error), Outcome::NoImages);
+        let error = anyhow::anyhow!("timed out waiting for images");
+        assert_eq!(Outcome::of_error(&error), Outcome::Timeout);
+        let error = anyhow::Error::new(tungstenite::Error::ConnectionClosed)
+            .context("failed to connect to websocket");
+        assert_eq!(Outcome::of_error(&error), Outcome::Network);
+        assert_eq!(
+            Outcome::of_error(&anyhow::anyhow!("Model disappeared from the config")),
+            Outcome::Other
+        );
+    }
+
+    #[test]
+    fn test_report() {
+        assert_eq!(
+            report(&[], 7),
+            "Nothing's been generated in the last 7 days."
+        );
+        let stats = [
+            ModelStats {
+                model: "flux".to_string(),
+                attempts: 10,
+                cancelled: 1,
+                errors: vec![("server_lost".to_string(), 2)],
+                seconds_per_picture: Some(10.0),
+                queue_wait: Some(30.0),
+            },
+            ModelStats {
+                model: "foo".to_string(),
+                attempts: 5,
+                errors: vec![("rejected".to_string(), 3)],
+                seconds_per_picture: Some(2.0),
+                ..Default::default()
+            },
+            ModelStats {
+                model: "sana".to_string(),
+                attempts: 2,
+                seconds_per_picture: Some(40.0),
+                ..Default::default()
+            },
+        ];
+        assert_eq!(
+            report(&stats, 7),
+            "The last 7 days, by model:\n\
+             flux: 10 tries, 2 failed (server_lost ×2), 1 cancelled, 10.0s per picture, 30s in the queue\n\
+             foo: 5 tries, 3 failed (rejected ×3), 2.0s per picture. Looks broken!\n\
+             sana: 2 tries, 40.0s per picture. Slow."
+        );
+    }
+}
@@ -1,12 +1,13 @@
 use std::{io::Cursor, os::unix::prelude::PermissionsExt};
 
+use ab_glyph::{Font, ScaleFont};
 use anyhow::{bail, Context, Result};
 use image::GenericImage;
-use log::{debug, info};
+use log::debug;
 use unicode_segmentation::UnicodeSegmentation;
 use uuid::Uuid;
 
-use crate::config::BotConfigModule;
+use crate::{config::BotConfigModule, generator::OutputFormat};
 
 pub fn gallery_geometry(image_count: usize) -> (u32, u32) {
     let width = (image_count as f64).sqrt().ceil() as u32;
@@ -14,84 +15,223 @@ pub fn gallery_geometry(image_count: usize) -> (u32, u32) {
     (width, height)
 }
 
-/// Given a bunch of PNGs, generates a tiled overview of them.
+/// Given a bunch of PNGs, generates a tiled overview of them, in the same format as the
+/// pictures themselves are uploaded in.
 /// This is used to 'subtly' encourage people to use the upsize buttons.
-pub fn overview_of_pictures(pngs: &[Vec<u8>]) -> Result<Vec<u8>> {
-    const BORDER: u32 = 8;
-    // Parse the JPEGs.
+pub fn overview_of_pictures(pngs: &[Vec<u8>], format: OutputFormat) -> Result<Vec<u8>> {
+    let images = decode_pictures(pngs)?;
+    let (columns, _) = gallery_geometry(images.len());
+    let (overview, _) = tile_pictures(&images, columns)?;
+    encode_overview(&overview, format)
+}
+
+/// Like overview_of_pictures, but laid out as a grid with the given column and row labels
+/// drawn along the top and left edges. Row labels may be empty, for a single-row grid.
+pub fn labeled_overview_of_pictures(
+    pngs: &[Vec<u8>],
+    column_labels: &[String],
+    row_labels: &[String],
+    format: OutputFormat,
+) -> Result<Vec<u8>> {
+    let images = decode_pictures(pngs)?;
+    let columns = column_labels.len().max(1) as u32;
+    let (tiled, border_color) = tile_pictures(&images, columns)?;
+    let font = ab_glyph::FontRef::try_from_slice(include_bytes!("../assets/DejaVuSansMono.ttf"))
+        .context("failed to load font")?;
+    // Text is black or white, whichever stands out against the border.
+    let luminance = 0.299 * border_color[0] as f32
+        + 0.587 * border_color[1] as f32
+        + 0.114 * border_color[2] as f32;
+    let text_color = if luminance > 128.0 {
+        image::Rgb([0, 0, 0])
+    } else {
+        image::Rgb([255, 255, 255])
+    };
+    let (cell_width, cell_height) = (images[0].width(), images[0].height());
+    let font_size = (cell_height as f32 / 16.0).clamp(16.0, 48.0);
+    let top = (font_size * 1.5) as u32;
+    let left = row_labels
+        .iter()
+        .map(|l| text_width(&font, font_size, l) as u32 + 2 * OVERVIEW_BORDER)
+        .max()
+        .unwrap_or(0)
+        .min(cell_width);
+
+    let mut overview =
+        image::RgbImage::from_pixel(tiled.width() + left, tiled.height() + top, border_color);
+    overview
+        .copy_from(&tiled, left, top)
+        .context("failed to copy overview")?;
+    for (i, label) in column_labels.iter().enumerate() {
+        let x = left + OVERVIEW_BORDER + i as u32 * (cell_width + OVERVIEW_BORDER);
+        draw_label(
+            &mut overview,
+            &font,
+            font_size,
+            label,
+            text_color,
+            (x, 0),
+            (cell_width, top),
+        );
+    }
+    for (i, label) in row_labels.iter().enumerate() {
+        let y = top + OVERVIEW_BORDER + i as u32 * (cell_height + OVERVIEW_BORDER);
+        draw_label(
+            &mut overview,
+            &font,
+            font_size,
+            label,
+            text_color,
+            (0, y),
+            (left, cell_height),
+        );
+    }
+    encode_overview(&overview, format)
+}
+
+const OVERVIEW_BORDER: u32 = 8;
+
+fn decode_pictures(pngs: &[Vec<u8>]) -> Result<Vec<image::RgbImage>> {
     let images = pngs
         .iter()
-        .map(|jpeg| {
-            image::load_from_memory(jpeg)
+        .map(|png| {
+            image::load_from_memory(png)
                 .context("failed to parse PNG")
                 .map(|image| image.to_rgb8())
         })
         .collect::<Result<Vec<_>>>()
         .context("failed to parse PNGs")?;
-    if let Some(sample) = images.first() {
-        // Decide on a color for the border.
-        // We'll use the average color of all the images.
-        let mut border_color = [0, 0, 0];
-        for image in images.iter() {
-            let (width, height) = (image.width(), image.height());
-            let mut sum = [0, 0, 0];
-            for rgb in image.pixels() {
-                sum[0] += rgb.0[0] as u64;
-                sum[1] += rgb.0[1] as u64;
-                sum[2] += rgb.0[2] as u64;
-            }
-            let count = width as u64 * height as u64;
-            border_color[0] += sum[0] / count;
-            border_color[1] += sum[1] / count;
-            border_color[2] += sum[2] / count;
-        }
-        border_color[0] /= images.len() as u64;
-        border_color[1] /= images.len() as u64;
-        border_color[2] /= images.len() as u64;
-        let border_color = image::Rgb([
-            border_color[0] as u8,
-            border_color[1] as u8,
-            border_color[2] as u8,
-        ]);
-        // Figure out the size of the overview.
-        // We'll try to be square-ish.
-        let (width_images, height_images) = gallery_geometry(images.len());
-        let width = sample.width();
-        let height = sample.height();
-        // We'll add a border between all images, and around the outside.
-        let overview_width = width * width_images + BORDER * (width_images + 1);
-        let overview_height = height * height_images + BORDER * (height_images + 1);
-        let mut overview = image::RgbImage::new(overview_width, overview_height);
-        // Set the background color.
-        for pixel in overview.pixels_mut() {
-            *pixel = border_color;
+    if images.is_empty() {
+        bail!("No images");
+    }
+    Ok(images)
+}
+
+/// Tiles the images with a border between them, and returns the result plus the border color.
+/// Every cell is the size of the first image; any others are scaled to fit.
+fn tile_pictures(
+    images: &[image::RgbImage],
+    width_images: u32,
+) -> Result<(image::RgbImage, image::Rgb<u8>)> {
+    const BORDER: u32 = OVERVIEW_BORDER;
+    let sample = images.first().context("No images")?;
+    // Decide on a color for the border.
+    // We'll use the average color of all the images.
+    let mut border_color = [0, 0, 0];
+    for image in images.iter() {
+        let (width, height) = (image.width(), image.height());
+        let mut sum = [0, 0, 0];
+        for rgb in image.pixels() {
+            sum[0] += rgb.0[0] as u64;
+            sum[1] += rgb.0[1] as u64;
+            sum[2] += rgb.0[2] as u64;
         }
-        // Copy the images into the overview.
-        for (i, image) in images.iter().enumerate() {
-            let x = (i as u32 % width_images) * width + BORDER * (i as u32 % width_images + 1);
-            let y = (i as u32 / width_images) * height + BORDER * (i as u32 / width_images + 1);
-            overview
-                .copy_from(image, x, y)
-                .context("failed to copy image")?;
+        let count = width as u64 * height as u64;
+        border_color[0] += sum[0] / count;
+        border_color[1] += sum[1] / count;
+        border_color[2] += sum[2] / count;
+    }
+    border_color[0] /= images.len() as u64;
+    border_color[1] /= images.len() as u64;
+    border_color[2] /= images.len() as u64;
+    let border_color = image::Rgb([
+        border_color[0] as u8,
+        border_color[1] as u8,
+        border_color[2] as u8,
+    ]);
+    // Figure out the size of the overview.
+    let height_images = (images.len() as u32).div_ceil(width_images);
+    let width = sample.width();
+    let height = sample.height();
+    // We'll add a border between all images, and around the outside.
+    let overview_width = width * width_images + BORDER * (width_images + 1);
+    let overview_height = height * height_images + BORDER * (height_images + 1);
+    let mut overview = image::RgbImage::from_pixel(overview_width, overview_height, border_color);
+    // Copy the images into the overview.
+    for (i, image) in images.iter().enumerate() {
+        let mut x = (i as u32 % width_images) * width + BORDER * (i as u32 % width_images + 1);
+        let mut y = (i as u32 / width_images) * height + BORDER * (i as u32 / width_images + 1);
+        if image.dimensions() == sample.dimensions() {
+            overview.copy_from(image, x, y)
+        } else {
+            // Keep the aspect ratio, and center it in the cell.
+            let resized = image::DynamicImage::ImageRgb8(image.clone())
+                .resize(width, height, image::imageops::FilterType::Triangle)
+                .to_rgb8();
+            x += (width - resized.width()) / 2;
+            y += (height - resized.height()) / 2;
+            overview.copy_from(&resized, x, y)
         }
-        // Encode the overview.
-        let mut output = Vec::new();
-        overview
-            .write_to(
-                &mut Cursor::new(&mut output),
-                image::ImageOutputFormat::WebP,
-            )
-            .context("failed to encode WebP")?;
-        Ok(output)
+        .context("failed to copy image")?;
+    }
+    Ok((overview, border_color))
+}
+
+fn encode_overview(overview: &image::RgbImage, format: OutputFormat) -> Result<Vec<u8>> {
+    let mut output = Vec::new();
+    overview
+        .write_to(&mut Cursor::new(&mut output), encoding(format))
+        .with_context(|| format!("failed to encode {format}"))?;
+    Ok(output)
+}
+
+fn text_width(font: &ab_glyph::FontRef, size: f32, text: &str) -> f32 {
+    let font = font.as_scaled(size);
+    text.chars().map(|c| font.h_advance(font.glyph_id(c))).sum()
+}
+
+/// Draws text centered in the given box. Text that doesn't fit is shrunk, then clipped.
+fn draw_label(
+    image: &mut image::RgbImage,
+    font: &ab_glyph::FontRef,
+    size: f32,
+    text: &str,
+    color: image::Rgb<u8>,
+    (box_x, box_y): (u32, u32),
+    (box_width, box_height): (u32, u32),
+) {
+    let available = box_width.saturating_sub(2 * OVERVIEW_BORDER) as f32;
+    let width = text_width(font, size, text);
+    let size = if width > available {
+        (size * available / width).max(10.0)
     } else {
-        bail!("No images");
+        size
+    };
+    let scaled = font.as_scaled(size);
+    let width = text_width(font, size, text);
+    let mut x = box_x as f32 + (box_width as f32 - width).max(0.0) / 2.0;
+    let baseline = box_y as f32 + (box_height as f32 + scaled.ascent() + scaled.descent()) / 2.0;
+    let clip_x = box_x + box_width.saturating_sub(OVERVIEW_BORDER);
+    for c in text.chars() {
+        let mut glyph = scaled.scaled_glyph(c);
+        glyph.position = ab_glyph::point(x, baseline);
+        x += scaled.h_advance(glyph.id);
+        let Some(outlined) = font.outline_glyph(glyph) else {
+            continue;
+        };
+        let bounds = outlined.px_bounds();
+        outlined.draw(|gx, gy, coverage| {
+            let px = bounds.min.x as i64 + gx as i64;
+            let py = bounds.min.y as i64 + gy as i64;
+            if px < 0 || py < 0 || px as u32 >= clip_x || py as u32 >= image.height() {
+                return;
+            }
+            let pixel = image.get_pixel_mut(px as u32, py as u32);
+            for channel in 0..3 {
+                let blended =
+                    pixel.0[channel] as f32 * (1.0 - coverage) + color.0[channel] as f32 * coverage;
+                pixel.0[channel] = blended.round() as u8;
+            }
+        });
     }
 }
 
+/// Uploads a batch. The extension is shared by every file, since get_individual_url relies on it.
 pub async fn upload_images(
     config: &BotConfigModule,
     uuid: &Uuid,
     images: Vec<Vec<u8>>,
+    extension: &str,
 ) -> Result<Vec<String>> {
     let mut urls = Vec::new();
     // First, we save the images to temporary files.
@@ -108,8 +248,7 @@ pub async fn upload_images(
         .into_iter()
         .enumerate()
         .map(|(i, data)| {
-            // These should all be jpegs. We'll assume.
-            let filename = format!("{}.{}.jpeg", uuid, i);
+            let filename = format!("{}.{}.{}", uuid, i, extension);
             let path = tmp.path().join(&filename);
             std::fs::write(&path, data).context("failed to write temporary file")?;
             std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644))
@@ -225,16 +364,52 @@ pub fn hash(text: &str) -> String {
     hash.to_string()
 }
 
-/// Parses a serialized image (JPG, PNG, etc) and converts it to a JPEG.
-pub fn convert_to_jpeg(image: Vec<u8>) -> Result<Vec<u8>> {
+/// Parses a serialized image (JPG, PNG, etc) and converts it to the requested format.
+pub fn convert_image(image: Vec<u8>, format: OutputFormat) -> Result<Vec<u8>> {
     let image = image::load_from_memory(&image).context("failed to parse image")?;
     let mut output = Vec::new();
     image
-        .write_to(
-            &mut Cursor::new(&mut output),
-            image::ImageOutputFormat::Jpeg(90),
-        )
-        .context("failed to encode WebP")?;
+        .write_to(&mut Cursor::new(&mut output), encoding(format))
+        .with_context(|| format!("failed to encode {format}"))?;
+    Ok(output)
+}
+
+fn encoding(format: OutputFormat) -> image::ImageOutputFormat {
+    match format {
+        OutputFormat::Jpeg => image::ImageOutputFormat::Jpeg(90),
+        OutputFormat::Png => image::ImageOutputFormat::Png,
+        OutputFormat::Webp => image::ImageOutputFormat::WebP,
+    }
+}
+
+/// Makes an inpainting mask for the init image: a greyscale PNG of the same size, where white
+/// is redrawn. It comes from a separate picture if given, or else from the init image's
+/// transparency.
+pub fn inpaint_mask(init: &[u8], mask: Option<&[u8]>) -> Result<Vec<u8>> {
+    let init = image::load_from_memory(init).context("failed to parse image")?;
+    let mask = match mask {
+        Some(mask) => {
+            let mask = image::load_from_memory(mask).context("failed to parse mask")?;
+            image::imageops::resize(
+                &mask.to_luma8(),
+                init.width(),
+                init.height(),
+                image::imageops::FilterType::Triangle,
+            )
+        }
+        None => {
+            let alpha = init.to_rgba8();
+            image::GrayImage::from_fn(init.width(), init.height(), |x, y| {
+                image::Luma([255 - alpha.get_pixel(x, y)[3]])
+            })
+        }
+    };
+    if mask.pixels().all(|p| p[0] < 128) {
+        bail!("The mask is empty; there's nothing to redraw");
+    }
+    let mut output = Vec::new();
+    mask.write_to(&mut Cursor::new(&mut output), image::ImageOutputFormat::Png)
+        .context("failed to encode mask")?;
     Ok(output)
 }
 
@@ -261,11 +436,6 @@ pub fn get_individual_url(url: &str, replacement: &str) -> Result<String> {
     }
 }
 
-pub(crate) fn simplify_fraction(width: u32, height: u32) -> (u32, u32) {
-    let gcd = num::integer::gcd(width, height);
-    (width / gcd, height / gcd)
-}
-
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -286,6 +456,44 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_inpaint_mask() {
+        let encode = |image: image::DynamicImage| {
+            let mut png = Vec::new();
+            image
+                .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)
+                .unwrap();
+            png
+        };
+        // Transparent on the left half, so that's what gets redrawn.
+        let init = encode(image::DynamicImage::ImageRgba8(image::RgbaImage::from_fn(
+            8,
+            4,
+            |x, _| image::Rgba([10, 20, 30, if x < 4 { 0 } else { 255 }]),
+        )));
+        let mask = image::load_from_memory(&inpaint_mask(&init, None).unwrap())
+            .unwrap()
+            .to_luma8();
+        assert_eq!(mask.dimensions(), (8, 4));
+        assert_eq!(mask.get_pixel(0, 0)[0], 255);
+        assert_eq!(mask.get_pixel(7, 3)[0], 0);
+
+        // A separate mask is stretched to fit.
+        let white = encode(image::DynamicImage::ImageLuma8(
+            image::GrayImage::from_pixel(2, 2, image::Luma([255])),
+        ));
+        let mask = image::load_from_memory(&inpaint_mask(&init, Some(&white)).unwrap())
+            .unwrap()
+            .to_luma8();
+        assert_eq!(mask.dimensions(), (8, 4));
+        assert!(mask.pixels().all(|p| p[0] == 255));
+
+        // Nothing to redraw is a mistake.
+        let opaque = encode(image::DynamicImage::ImageRgb8(image::RgbImage::new(4, 4)));
+        assert!(inpaint_mask(&opaque, None).is_err());
+        assert!(inpaint_mask(&init, Some(&opaque)).is_err());
+    }
+
     #[test]
     fn test_extract_url() {
         assert_eq!(extract_url("hello world"), None);
@@ -299,18 +507,6 @@ mod tests {
         );
     }
 
-    #[test]
-    fn test_simplify_fraction() {
-        // Test 100 or so random fractions.
-        for _ in 0..100 {
-            let width = rand::random::<u32>() % 1000;
-            let height = rand::random::<u32>() % 1000;
-            let (w, h) = simplify_fraction(width, height);
-            // Assert the fraction doesn't change.
-            assert_eq!(width as f32 / height as f32, w as f32 / h as f32);
-        }
-    }
-
     #[test]
     fn test_segment_short() {
         assert_eq!(segment_lines("", 10).len(), 0);
@@ -330,6 +526,53 @@ mod tests {
         );
     }
 
+    fn solid_png(width: u32, height: u32, shade: u8) -> Vec<u8> {
+        let image = image::RgbImage::from_pixel(width, height, image::Rgb([shade; 3]));
+        let mut png = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut png), image::ImageOutputFormat::Png)
+            .unwrap();
+        png
+    }
+
+    #[test]
+    fn test_labeled_overview() {
+        let pngs = (0..6)
+            .map(|i| solid_png(64, 48, i * 40))
+            .collect::<Vec<_>>();
+        let labels = |names: &[&str]| names.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        let plain = overview_of_pictures(&pngs, OutputFormat::Jpeg).unwrap();
+        // It's uploaded next to the pictures, with the same extension.
+        assert_eq!(
+            image::guess_format(&plain).unwrap(),
+            image::ImageFormat::Jpeg
+        );
+        let plain = image::load_from_memory(&plain).unwrap();
+        let labeled = labeled_overview_of_pictures(
+            &pngs,
+            &labels(&["steps=20", "steps=30", "steps=40"]),
+            &labels(&["scale=4", "scale=7"]),
+            OutputFormat::Png,
+        )
+        .unwrap();
+        let labeled = image::load_from_memory(&labeled).unwrap().to_rgb8();
+        // Same 3x2 layout, plus room for the labels.
+        assert_eq!(plain.width(), 3 * 64 + 4 * 8);
+        assert!(labeled.width() > plain.width());
+        assert!(labeled.height() > plain.height());
+        // Something got drawn in the label strip along the top.
+        let background = *labeled.get_pixel(0, 0);
+        assert!(
+            (0..labeled.width()).any(|x| (0..24).any(|y| *labeled.get_pixel(x, y) != background))
+        );
+        // Mismatched sizes are scaled into place, rather than failing.
+        let mixed = vec![solid_png(64, 48, 0), solid_png(32, 48, 255)];
+        assert!(
+            labeled_overview_of_pictures(&mixed, &labels(&["a", "b"]), &[], OutputFormat::Png)
+                .is_ok()
+        );
+    }
+
     #[test]
     fn test_geometry() {
         for image_count in 1..=64 {
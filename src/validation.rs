@@ -0,0 +1,354 @@
+// Checks that a config's models will work, so a broken workflow shows up when the config is
+// loaded, rather than when someone picks the model.
+//
+// Each of a model's workflows is rendered with made-up settings, which catches templates that
+// aren't JSON, unknown placeholders, and links to nodes that don't exist. If the servers are
+// up, their /object_info says which nodes, inputs and files they have. That catches the rest: a
+// missing checkpoint, VAE or LoRA, or a custom node that isn't installed. Servers can be
+// limited to some models, so a model only has to work on one of the servers that may run it.
+
+use std::{collections::BTreeMap, time::Duration};
+
+use log::warn;
+use serde_json::Value;
+
+use crate::{
+    config::{BotBackend, BotConfig},
+    generator::{ParsedRequest, SAMPLE_INIT_IMAGE, SAMPLE_MASK_IMAGE},
+    workflow,
+};
+
+/// Problems by model or alias. Problems with the LoRA catalog are under LORAS, and with the
+/// list of servers under SERVERS.
+pub type Problems = BTreeMap<String, Vec<String>>;
+
+pub const LORAS: &str = "[loras]";
+pub const SERVERS: &str = "[backend.servers]";
+
+/// What each server has, by address.
+pub type ObjectInfo = BTreeMap<String, Value>;
+
+/// Asks every server what it has. Servers that aren't answering are left out, and models
+/// that only they could run just get their workflows checked.
+pub async fn object_info(backend: &BotBackend) -> ObjectInfo {
+    let servers = backend.servers();
+    let fetches = servers.iter().map(|server| async move {
+        let address = server.address();
+        let response = async {
+            let info = reqwest::Client::builder()
+                .timeout(Duration::from_secs(30))
+                .build()?
+                .get(format!("http://{address}/object_info"))
+                .send()
+                .await?
+                .error_for_status()?
+                .json::<Value>()
+                .await?;
+            anyhow::Ok(info)
+        };
+        match response.await {
+            Ok(info) => Some((address, info)),
+            Err(e) => {
+                warn!("Couldn't get /object_info from {address}, so not checking its files: {e:#}");
+                None
+            }
+        }
+    });
+    futures::future::join_all(fetches)
+        .await
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// Checks every model, alias and LoRA in the config.
+pub fn check(config: &BotConfig, object_info: &ObjectInfo) -> Problems {
+    let mut problems = Problems::new();
+    for name in config.models.keys() {
+        let found = check_model(config, name, object_info);
+        if !found.is_empty() {
+            problems.insert(name.clone(), found);
+        }
+    }
+    for (alias, target) in &config.aliases {
+        if !config.models.contains_key(target) && !config.aliases.contains_key(target) {
+            problems.insert(
+                alias.clone(),
+                vec![format!("it's an alias for {target}, which doesn't exist")],
+            );
+        }
+    }
+    let found = check_servers(config);
+    if !found.is_empty() {
+        problems.insert(SERVERS.to_string(), found);
+    }
+    let everywhere = object_info.values().collect::<Vec<_>>();
+    let found = on_every_server(&everywhere, |info| check_loras(config, info));
+    if !found.is_empty() {
+        problems.insert(LORAS.to_string(), found);
+    }
+    problems
+}
+
+/// The problems that all of the servers have; those that one of them has are fine, since
+/// jobs can go to that one. No servers means nothing to check.
+fn on_every_server(infos: &[&Value], check: impl Fn(&Value) -> Vec<String>) -> Vec<String> {
+    let Some((first, rest)) = infos.split_first() else {
+        return vec![];
+    };
+    let mut common = check(first);
+    for info in rest {
+        let found = check(info);
+        common.retain(|p| found.contains(p));
+    }
+    common
+}
+
+fn check_model(config: &BotConfig, name: &str, object_info: &ObjectInfo) -> Vec<String> {
+    let infos = config
+        .backend
+        .servers()
+        .iter()
+        .filter(|s| s.allows(name))
+        .filter_map(|s| object_info.get(&s.address()))
+        .collect::<Vec<_>>();
+    let mut problems = vec![];
+    for (workflow, rendered) in ParsedRequest::render_samples(config, name) {
+        let found = match rendered {
+            Ok(rendered) => {
+                let mut found = rendered.check_links();
+                found.extend(on_every_server(&infos, |info| {
+                    rendered.check_nodes(info, &[SAMPLE_INIT_IMAGE, SAMPLE_MASK_IMAGE])
+                }));
+                found
+            }
+            Err(e) => vec![format!("{e:#}")],
+        };
+        problems.extend(found.into_iter().map(|p| format!("{workflow}: {p}")));
+    }
+    // Only the defaults get rendered, so check the other choices separately.
+    if let Some(model) = config.models.get(name) {
+        for (flag, choices, input) in [
+            ("--sampler", &model.samplers, "sampler_name"),
+            ("--scheduler", &model.schedulers, "scheduler"),
+        ] {
+            problems.extend(on_every_server(&infos, |info| {
+                let Some(available) = choice_list(info, "KSampler", input) else {
+                    return vec![];
+                };
+                choices
+                    .iter()
+                    .filter(|choice| !available.contains(&choice.as_str()))
+                    .map(|choice| format!("it offers {flag} {choice}, which ComfyUI doesn't have"))
+                    .collect()
+            }));
+        }
+    }
+    problems
+}
+
+fn check_loras(config: &BotConfig, object_info: &Value) -> Vec<String> {
+    let Some(available) = choice_list(object_info, "LoraLoader", "lora_name") else {
+        return vec![];
+    };
+    let mut loras = config.loras.iter().collect::<Vec<_>>();
+    loras.sort_by_key(|(name, _)| *name);
+    loras
+        .into_iter()
+        .filter(|(_, lora)| !available.contains(&lora.file.as_str()))
+        .map(|(name, lora)| format!("{name} is {}, which ComfyUI doesn't have", lora.file))
+        .collect()
+}
+
+fn check_servers(config: &BotConfig) -> Vec<String> {
+    let mut problems = vec![];
+    for server in &config.backend.servers {
+        let address = server.address();
+        if server.concurrency == 0 {
+            problems.push(format!(
+                "{address} has a concurrency of 0, so it would never be used"
+            ));
+        }
+        for model in &server.models {
+            if !config.models.contains_key(model) {
+                problems.push(format!("{address} is for {model}, which doesn't exist"));
+            }
+        }
+    }
+    for model in config.models.keys() {
+        if !config.backend.servers().iter().any(|s| s.allows(model)) {
+            problems.push(format!("none of the servers can run {model}"));
+        }
+    }
+    problems
+}
+
+/// The choices ComfyUI allows for a node's input, if it's that kind of input.
+fn choice_list<'a>(object_info: &'a Value, class_type: &str, input: &str) -> Option<Vec<&'a str>> {
+    let choices = workflow::choices(&object_info[class_type]["input"]["required"][input])?;
+    Some(choices.iter().filter_map(|c| c.as_str()).collect())
+}
+
+/// The problems that weren't in the old config: those in models, aliases or LoRAs that are
+/// new or have changed. Broken parts the old config already had don't count; they're no worse.
+pub fn new_problems(old: &BotConfig, new: &BotConfig, problems: &Problems) -> Problems {
+    problems
+        .iter()
+        .filter(|(name, _)| match name.as_str() {
+            LORAS => old.loras != new.loras,
+            SERVERS => old.backend.servers != new.backend.servers || old.models != new.models,
+            name => {
+                old.models.get(name) != new.models.get(name)
+                    || old.aliases.get(name) != new.aliases.get(name)
+            }
+        })
+        .map(|(name, found)| (name.clone(), found.clone()))
+        .collect()
+}
+
+/// One line per problem, for the owner.
+pub fn describe(problems: &Problems) -> Vec<String> {
+    problems
+        .iter()
+        .flat_map(|(name, found)| found.iter().map(move |p| format!("{name}: {p}")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::config::{testconfig, ComfyServer};
+
+    #[test]
+    fn test_check() {
+        let mut config = testconfig();
+        assert_eq!(check(&config, &ObjectInfo::new()), Problems::new());
+
+        config.models.get_mut("foo").unwrap().workflow = "no-such-workflow.json".to_string();
+        config
+            .aliases
+            .insert("typo".to_string(), "nope".to_string());
+        let problems = check(&config, &ObjectInfo::new());
+        assert_eq!(problems.keys().collect::<Vec<_>>(), vec!["foo", "typo"]);
+        assert!(problems["foo"][0].starts_with("no-such-workflow.json: failed to read"));
+
+        // The old config had the alias too, so only foo is new.
+        let mut old = testconfig();
+        old.aliases.insert("typo".to_string(), "nope".to_string());
+        let new = new_problems(&old, &config, &problems);
+        assert_eq!(new.keys().collect::<Vec<_>>(), vec!["foo"]);
+    }
+
+    #[test]
+    fn test_check_servers() {
+        let mut config = testconfig();
+        config.backend.servers = vec![ComfyServer {
+            host: "localhost".to_string(),
+            port: 8188,
+            concurrency: 2,
+            models: vec!["foo".to_string(), "fooo".to_string()],
+        }];
+        let problems = check(&config, &ObjectInfo::new());
+        assert_eq!(
+            problems[SERVERS],
+            vec![
+                "localhost:8188 is for fooo, which doesn't exist",
+                "none of the servers can run flux",
+            ]
+        );
+        assert_eq!(
+            new_problems(&testconfig(), &config, &problems)
+                .keys()
+                .collect::<Vec<_>>(),
+            vec![SERVERS]
+        );
+    }
+
+    #[test]
+    fn test_check_with_object_info() {
+        let config = testconfig();
+        // A backend without any nodes but LoraLoader, which knows a single LoRA.
+        let info = json!({
+            "LoraLoader": {
+                "input": {"required": {"lora_name": ["COMBO", {"options": ["flux/ghibli.safetensors"]}]}},
+                "output": ["MODEL", "CLIP"],
+            },
+        });
+        let problems = check(
+            &config,
+            &ObjectInfo::from([("localhost:8188".to_string(), info)]),
+        );
+        assert_eq!(
+            problems[LORAS],
+            vec![
+                "offset is xl/sd_xl_offset_example-lora_1.0.safetensors, which ComfyUI doesn't have",
+                "watercolor is flux/watercolor.safetensors, which ComfyUI doesn't have",
+            ]
+        );
+        assert!(problems["flux"]
+            .iter()
+            .any(|p| p.contains("is a UnetLoaderGGUF, which ComfyUI doesn't have")));
+        assert!(describe(&problems)
+            .iter()
+            .all(|line| line.starts_with("flux: ")
+                || line.starts_with("foo: ")
+                || line.starts_with("[loras]: ")));
+    }
+
+    #[test]
+    fn test_check_servers_with_object_info() {
+        let mut config = testconfig();
+        let server = |port, model: &str| ComfyServer {
+            host: "localhost".to_string(),
+            port,
+            concurrency: 1,
+            models: vec![model.to_string()],
+        };
+        config.backend.servers = vec![server(1, "foo"), server(2, "flux")];
+        let loras = |files: &[&str]| {
+            json!({
+                "input": {"required": {"lora_name": ["COMBO", {"options": files}]}},
+                "output": ["MODEL", "CLIP"],
+            })
+        };
+        // Only the flux server has flux's loader, and the LoRAs are spread over both.
+        let flux_server = json!({
+            "UnetLoaderGGUF": {
+                "input": {"required": {"unet_name": ["COMBO", {"options": ["flux.safetensors"]}]}},
+                "output": ["MODEL"],
+            },
+            "LoraLoader": loras(&["flux/ghibli.safetensors", "flux/watercolor.safetensors"]),
+        });
+        let foo_server = json!({
+            "LoraLoader": loras(&["xl/sd_xl_offset_example-lora_1.0.safetensors"]),
+        });
+        let info = ObjectInfo::from([
+            ("localhost:1".to_string(), foo_server.clone()),
+            ("localhost:2".to_string(), flux_server.clone()),
+        ]);
+        let problems = check(&config, &info);
+        assert!(!problems.contains_key(LORAS), "{problems:?}");
+        assert!(!problems["flux"]
+            .iter()
+            .any(|p| p.contains("UnetLoaderGGUF")));
+        // Other nodes are missing everywhere, so flux still gets checked.
+        assert!(problems["flux"]
+            .iter()
+            .any(|p| p.contains("which ComfyUI doesn't have")));
+
+        // The other way around, flux's server is missing it.
+        let info = ObjectInfo::from([
+            ("localhost:1".to_string(), flux_server),
+            ("localhost:2".to_string(), foo_server),
+        ]);
+        let problems = check(&config, &info);
+        assert!(problems["flux"]
+            .iter()
+            .any(|p| p.contains("is a UnetLoaderGGUF, which ComfyUI doesn't have")));
+        // A server that isn't answering is no reason to complain.
+        let info = ObjectInfo::from([("localhost:1".to_string(), json!({}))]);
+        assert!(!check(&config, &info).contains_key("flux"));
+    }
+}
@@ -0,0 +1,350 @@
+// Prompt weighting, e.g. emphasis on a word.
+//
+// People paste prompts from all over, so we accept every dialect at once:
+// - A1111 / ComfyUI: `(word)` is 1.1x, `(word:1.3)` is 1.3x, `[word]` is 1/1.1x.
+// - NovelAI: `{word}` is 1.05x, `[word]` is 1/1.05x.
+// `[word]` means slightly different things in the two, so it's read as NovelAI if the prompt
+// also uses braces. Groups nest, and multiply.
+//
+// Before a prompt goes to the backend, it's rewritten in whatever dialect the model's
+// workflow understands, or stripped down to plain text if it doesn't understand any.
+// Unbalanced brackets are left as they are; `\(` is a literal parenthesis. So are the braces of
+// `{a|b}` alternations, which aren't weights; what's inside them can still be weighted.
+
+use serde::{Deserialize, Serialize};
+
+use crate::wildcards;
+
+const PAREN_WEIGHT: f32 = 1.1;
+const BRACE_WEIGHT: f32 = 1.05;
+/// More braces than this is silly, and a weight of zero would need infinitely many.
+const MAX_BRACES: i32 = 10;
+
+/// The weighting syntax a workflow expects.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PromptSyntax {
+    /// `(word:1.2)`. ComfyUI's CLIPTextEncode understands this.
+    #[default]
+    Comfy,
+    /// `{word}` and `[word]`.
+    NovelAi,
+    /// No weighting at all; the syntax is removed.
+    Plain,
+}
+
+/// A run of text with a single weight.
+#[derive(Clone, Debug, PartialEq)]
+struct Segment {
+    text: String,
+    weight: f32,
+}
+
+/// Rewrites the prompt's weighting for the given syntax.
+/// Prompts without any weighting come out unchanged.
+pub fn translate(prompt: &str, syntax: PromptSyntax) -> String {
+    let segments = parse(prompt);
+    match syntax {
+        PromptSyntax::Comfy => segments
+            .iter()
+            .map(|s| {
+                let text = escape_brackets(&s.text);
+                if is_neutral(s.weight) {
+                    text
+                } else {
+                    let weight = format!(":{})", format_weight(s.weight));
+                    wrap(&text, "(", &weight)
+                }
+            })
+            .collect(),
+        PromptSyntax::NovelAi => segments
+            .iter()
+            .map(|s| {
+                // NovelAI has no escapes, so literal brackets can't survive.
+                let text = s.text.replace(['{', '}', '[', ']'], "");
+                let steps = (s.weight.ln() / BRACE_WEIGHT.ln()).round() as i32;
+                let steps = steps.clamp(-MAX_BRACES, MAX_BRACES);
+                let (open, close) = if steps > 0 { ("{", "}") } else { ("[", "]") };
+                let n = steps.unsigned_abs() as usize;
+                wrap(&text, &open.repeat(n), &close.repeat(n))
+            })
+            .collect(),
+        PromptSyntax::Plain => segments.iter().map(|s| s.text.as_str()).collect(),
+    }
+}
+
+/// Whether anything in the prompt is emphasised or de-emphasised.
+pub fn has_weights(prompt: &str) -> bool {
+    parse(prompt).iter().any(|s| !is_neutral(s.weight))
+}
+
+fn is_neutral(weight: f32) -> bool {
+    weight == 1.0
+}
+
+/// Puts the text between the brackets, leaving any whitespace around it outside, where it
+/// reads better.
+fn wrap(text: &str, open: &str, close: &str) -> String {
+    let trimmed = text.trim();
+    if trimmed.is_empty() {
+        return text.to_string();
+    }
+    let start = text.len() - text.trim_start().len();
+    let end = start + trimmed.len();
+    format!("{}{open}{trimmed}{close}{}", &text[..start], &text[end..])
+}
+
+/// Two decimals are plenty, and nobody wants to read 1.2100000.
+fn format_weight(weight: f32) -> String {
+    let text = format!("{weight:.2}");
+    text.trim_end_matches('0').trim_end_matches('.').to_string()
+}
+
+/// Literal brackets of any kind are escaped, so that they can't pair up with each other
+/// when the prompt is read again.
+fn escape_brackets(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        if matches!(c, '(' | ')' | '[' | ']' | '{' | '}') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// A parsed piece of prompt.
+enum Node {
+    Text(String),
+    Group { weight: f32, children: Vec<Node> },
+}
+
+fn parse(prompt: &str) -> Vec<Segment> {
+    let chars = prompt.chars().collect::<Vec<_>>();
+    let literal = alternation_braces(&chars);
+    let has_braces = chars
+        .iter()
+        .enumerate()
+        .any(|(i, c)| *c == '{' && (i == 0 || chars[i - 1] != '\\') && !literal[i]);
+    let bracket_weight = if has_braces {
+        BRACE_WEIGHT
+    } else {
+        PAREN_WEIGHT
+    };
+    let mut pos = 0;
+    let (nodes, _) = parse_group(&chars, &mut pos, None, bracket_weight, &literal);
+    let mut segments: Vec<Segment> = vec![];
+    flatten(nodes, 1.0, &mut segments);
+    segments
+}
+
+/// Parses until the closing bracket, or the end of the text.
+/// Returns the nodes, and the explicit weight if this was `(text:weight)`.
+/// If the closing bracket never shows up, the caller gets None instead.
+fn parse_group(
+    chars: &[char],
+    pos: &mut usize,
+    closer: Option<char>,
+    bracket_weight: f32,
+    literal: &[bool],
+) -> (Vec<Node>, Option<Option<f32>>) {
+    let mut nodes = vec![];
+    let mut text = String::new();
+    while *pos < chars.len() {
+        let c = chars[*pos];
+        *pos += 1;
+        match c {
+            _ if literal[*pos - 1] => text.push(c),
+            '\\' if matches!(chars.get(*pos), Some('(' | ')' | '[' | ']' | '{' | '}')) => {
+                text.push(chars[*pos]);
+                *pos += 1;
+            }
+            ':' if closer == Some(')') => {
+                if let Some((weight, end)) = explicit_weight(chars, *pos) {
+                    *pos = end;
+                    push_text(&mut nodes, &mut text);
+                    return (nodes, Some(Some(weight)));
+                }
+                text.push(c);
+            }
+            '(' | '[' | '{' => {
+                let (close, weight) = match c {
+                    '(' => (')', PAREN_WEIGHT),
+                    '[' => (']', 1.0 / bracket_weight),
+                    _ => ('}', BRACE_WEIGHT),
+                };
+                let start = *pos;
+                let (children, closed) =
+                    parse_group(chars, pos, Some(close), bracket_weight, literal);
+                match closed {
+                    Some(explicit) => {
+                        push_text(&mut nodes, &mut text);
+                        nodes.push(Node::Group {
+                            weight: explicit.unwrap_or(weight),
+                            children,
+                        });
+                    }
+                    None => {
+                        // Unbalanced, so it was never a group. Take the bracket literally,
+                        // and carry on from just after it.
+                        text.push(c);
+                        *pos = start;
+                    }
+                }
+            }
+            c if Some(c) == closer => {
+                push_text(&mut nodes, &mut text);
+                return (nodes, Some(None));
+            }
+            _ => text.push(c),
+        }
+    }
+    push_text(&mut nodes, &mut text);
+    let closed = if closer.is_none() { Some(None) } else { None };
+    (nodes, closed)
+}
+
+/// Marks the braces that open and close `{a|b}` alternations.
+fn alternation_braces(chars: &[char]) -> Vec<bool> {
+    let mut literal = vec![false; chars.len()];
+    for (i, c) in chars.iter().enumerate() {
+        if *c != '{' || (i > 0 && chars[i - 1] == '\\') {
+            continue;
+        }
+        let rest = chars[i..].iter().collect::<String>();
+        if let Some((_, after)) = wildcards::split_alternation(&rest) {
+            literal[i] = true;
+            literal[chars.len() - after.chars().count() - 1] = true;
+        }
+    }
+    literal
+}
+
+/// Matches `  1.25 )` starting at pos, returning the weight and the position after the `)`.
+fn explicit_weight(chars: &[char], pos: usize) -> Option<(f32, usize)> {
+    let close = pos + chars[pos..].iter().position(|c| *c == ')')?;
+    let number = chars[pos..close].iter().collect::<String>();
+    let weight = number.trim().parse::<f32>().ok()?;
+    (weight.is_finite() && weight >= 0.0).then_some((weight, close + 1))
+}
+
+fn push_text(nodes: &mut Vec<Node>, text: &mut String) {
+    if !text.is_empty() {
+        nodes.push(Node::Text(std::mem::take(text)));
+    }
+}
+
+fn flatten(nodes: Vec<Node>, weight: f32, segments: &mut Vec<Segment>) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => {
+                // Round to what format_weight shows, so that weights which print the same
+                // are the same.
+                let rounded = (weight * 100.0).round() / 100.0;
+                match segments.last_mut() {
+                    Some(last) if last.weight == rounded => last.text += &text,
+                    _ => segments.push(Segment {
+                        text,
+                        weight: rounded,
+                    }),
+                }
+            }
+            Node::Group {
+                weight: inner,
+                children,
+            } => flatten(children, weight * inner, segments),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+    use super::*;
+
+    fn comfy(prompt: &str) -> String {
+        translate(prompt, PromptSyntax::Comfy)
+    }
+
+    fn nai(prompt: &str) -> String {
+        translate(prompt, PromptSyntax::NovelAi)
+    }
+
+    fn plain(prompt: &str) -> String {
+        translate(prompt, PromptSyntax::Plain)
+    }
+
+    #[test]
+    fn test_a1111_input() {
+        assert_eq!(comfy("a (red:1.3) cat"), "a (red:1.3) cat");
+        assert_eq!(comfy("a (red) cat"), "a (red:1.1) cat");
+        assert_eq!(comfy("a ((red)) cat"), "a (red:1.21) cat");
+        assert_eq!(comfy("a [red] cat"), "a (red:0.91) cat");
+        // 2 × 0.5 is no emphasis at all, so red ends up between two groups.
+        assert_eq!(comfy("(a (red:2) cat:0.5)"), "(a:0.5) red (cat:0.5)");
+        assert_eq!(comfy("a ( red :1.3) cat"), "a  (red:1.3)  cat");
+        assert_eq!(nai("a (red:1.1) cat"), "a {{red}} cat");
+        assert_eq!(nai("a (red:0.9) cat"), "a [[red]] cat");
+        assert_eq!(plain("a (red:1.3) [fluffy] cat"), "a red fluffy cat");
+    }
+
+    #[test]
+    fn test_novelai_input() {
+        assert_eq!(
+            nai("{masterpiece}, {{cat}}, [dog]"),
+            "{masterpiece}, {{cat}}, [dog]"
+        );
+        assert_eq!(
+            comfy("{masterpiece}, [dog]"),
+            "(masterpiece:1.05), (dog:0.95)"
+        );
+        assert_eq!(plain("{{cat}}"), "cat");
+        assert!(has_weights("{{cat}}"));
+        assert!(!has_weights(r"a \(cat\) :("));
+    }
+
+    #[test]
+    fn test_alternations() {
+        // Alternations aren't NovelAI emphasis, and don't make `[word]` NovelAI either.
+        assert!(!has_weights("a {red|blue} cat"));
+        assert_eq!(comfy("a {red|blue} [cat]"), r"a \{red|blue\} (cat:0.91)");
+        assert_eq!(plain("a {red|(blue:1.2)} cat"), "a {red|blue} cat");
+        assert!(has_weights("a {red|(blue:1.2)} cat"));
+        // Still emphasis without a `|`.
+        assert!(has_weights("a {red} cat"));
+    }
+
+    #[test]
+    fn test_literals() {
+        // No weighting, no change.
+        for prompt in ["a cat", "style: anime, 1girl", "embedding:foo", ""] {
+            assert_eq!(comfy(prompt), prompt);
+            assert_eq!(nai(prompt), prompt);
+            assert_eq!(plain(prompt), prompt);
+        }
+        // Escaped or unbalanced brackets are just text.
+        assert_eq!(comfy(r"oil, \(impasto\)"), r"oil, \(impasto\)");
+        assert_eq!(plain(r"oil, \(impasto\)"), "oil, (impasto)");
+        assert_eq!(comfy("smile :("), r"smile :\(");
+        assert_eq!(plain("a ((cat) dog"), "a (cat dog");
+        assert_eq!(plain("a cat) dog]"), "a cat) dog]");
+        // A colon without a number is part of the text.
+        assert_eq!(plain("(style: anime)"), "style: anime");
+    }
+
+    #[test]
+    fn test_comfy_round_trip() {
+        // No backslashes: ComfyUI has no way to write one just before a group.
+        let alphabet = ['a', ' ', '(', ')', '[', ']', '{', '}', '|', ':', '1', '.'];
+        for seed in 0..1000 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let prompt: String = (0..rng.gen_range(0..16))
+                .map(|_| *alphabet.choose(&mut rng).unwrap())
+                .collect();
+            let once = comfy(&prompt);
+            assert_eq!(comfy(&once), once, "seed {seed}: {prompt:?}");
+            assert_eq!(plain(&once), plain(&prompt), "seed {seed}: {prompt:?}");
+        }
+    }
+}
@@ -0,0 +1,327 @@
+// Dynamic prompts: `{red|blue|green}` alternation and `__name__` wildcard files.
+//
+// Expansion happens per image, so each picture in a batch can come out differently.
+// The choices are driven by an RNG seeded from the image's seed, which means retrying a
+// request with the same seed reproduces the same expansions.
+//
+// Braces without a top-level `|` are left alone, since NovelAI-style prompts use `{word}`
+// for emphasis. Likewise, `__name__` is only special if a wildcard directory is configured,
+// and then only if it names a file, or nearly does. Otherwise it's text.
+
+use std::path::PathBuf;
+
+use anyhow::{bail, Context, Result};
+use rand::{seq::SliceRandom, Rng};
+
+use crate::config::BotConfig;
+
+/// Wildcards may refer to other wildcards, but not forever.
+const MAX_DEPTH: usize = 10;
+
+/// Access to the wildcard files. Each file holds one option per line.
+/// Blank lines and lines starting with # are ignored.
+pub struct Wildcards {
+    dir: Option<PathBuf>,
+}
+
+impl Wildcards {
+    pub fn new(config: &BotConfig) -> Self {
+        Self {
+            dir: config.wildcard_dir.as_ref().map(PathBuf::from),
+        }
+    }
+
+    /// Returns the options for a wildcard, or None if there's no such file.
+    fn options(&self, name: &str) -> Result<Option<Vec<String>>> {
+        let Some(dir) = &self.dir else {
+            return Ok(None);
+        };
+        let path = dir.join(format!("{name}.txt"));
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("While reading wildcard file {:?}", path))?;
+        Ok(Some(
+            text.lines()
+                .map(str::trim)
+                .filter(|l| !l.is_empty() && !l.starts_with('#'))
+                .map(str::to_string)
+                .collect(),
+        ))
+    }
+
+    /// Lists the available wildcard names, sorted.
+    pub fn available(&self) -> Vec<String> {
+        let Some(dir) = &self.dir else {
+            return vec![];
+        };
+        let mut names = vec![];
+        let mut pending = vec![dir.clone()];
+        while let Some(current) = pending.pop() {
+            let Ok(entries) = std::fs::read_dir(&current) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.is_dir() {
+                    pending.push(path);
+                } else if path.extension().is_some_and(|e| e == "txt") {
+                    if let Ok(relative) = path.with_extension("").strip_prefix(dir) {
+                        names.push(relative.to_string_lossy().replace('\\', "/"));
+                    }
+                }
+            }
+        }
+        names.sort();
+        names
+    }
+
+    /// What a `__name__` means. It's a wildcard if the file exists, and most likely a typo
+    /// of one if it's close to a name that does; anything else, like `__init__`, is text.
+    fn resolve(&self, name: &str) -> Result<Reference> {
+        if let Some(options) = self.options(name)? {
+            return Ok(Reference::Wildcard(options));
+        }
+        let suggestion = self
+            .available()
+            .into_iter()
+            .map(|n| (strsim::jaro_winkler(name, &n), n))
+            .filter(|(s, _)| *s >= crate::generator::SIMILARITY_THRESHOLD)
+            .max_by(|a, b| a.0.total_cmp(&b.0))
+            .map(|(_, n)| n);
+        Ok(suggestion.map_or(Reference::Text, Reference::Typo))
+    }
+
+    /// Finds the first wildcard in the text that looks like a typo, with what it's close to.
+    pub fn find_unknown(&self, text: &str) -> Result<Option<(String, String)>> {
+        if self.dir.is_none() {
+            return Ok(None);
+        }
+        for name in referenced_names(text) {
+            if let Reference::Typo(suggestion) = self.resolve(name)? {
+                return Ok(Some((name.to_string(), suggestion)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Expands all alternations and wildcards in the text.
+    pub fn expand(&self, text: &str, rng: &mut impl Rng) -> Result<String> {
+        self.expand_inner(text, rng, 0)
+    }
+
+    fn expand_inner(&self, text: &str, rng: &mut impl Rng, depth: usize) -> Result<String> {
+        if depth > MAX_DEPTH {
+            bail!("Wildcards are nested too deeply; is one of them referring to itself?");
+        }
+        let mut output = String::new();
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            if c == '{' {
+                if let Some((options, after)) = split_alternation(rest) {
+                    let choice = options.choose(rng).expect("alternations are never empty");
+                    output += &self.expand_inner(choice, rng, depth + 1)?;
+                    rest = after;
+                    continue;
+                }
+            } else if let Some((name, after)) = wildcard_at(rest) {
+                match self.resolve(name)? {
+                    Reference::Wildcard(options) => {
+                        if let Some(choice) = options.choose(rng) {
+                            output += &self.expand_inner(choice, rng, depth + 1)?;
+                        }
+                        rest = after;
+                        continue;
+                    }
+                    Reference::Typo(suggestion) => {
+                        bail!("Unknown wildcard __{name}__. Did you mean __{suggestion}__?")
+                    }
+                    Reference::Text => {}
+                }
+            }
+            output.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+        Ok(output)
+    }
+}
+
+/// What a `__name__` turned out to be; see Wildcards::resolve.
+enum Reference {
+    Wildcard(Vec<String>),
+    /// There's no such file, but there is one with a similar name.
+    Typo(String),
+    Text,
+}
+
+/// If the text starts with `{a|b|...}`, returns the options and whatever follows the brace.
+/// Braces without a top-level `|`, or without a closing brace, aren't alternations.
+pub fn split_alternation(text: &str) -> Option<(Vec<&str>, &str)> {
+    let mut depth = 0;
+    let mut options = vec![];
+    let mut option_start = 1;
+    for (i, c) in text.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    if options.is_empty() {
+                        return None;
+                    }
+                    options.push(&text[option_start..i]);
+                    return Some((options, &text[i + 1..]));
+                }
+            }
+            '|' if depth == 1 => {
+                options.push(&text[option_start..i]);
+                option_start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// If the text starts with `__name__`, returns the name and whatever follows.
+fn wildcard_at(text: &str) -> Option<(&str, &str)> {
+    let inner = text.strip_prefix("__")?;
+    let end = inner.find("__")?;
+    let name = &inner[..end];
+    let valid = !name.is_empty()
+        && !name.starts_with('/')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '/');
+    valid.then(|| (name, &inner[end + 2..]))
+}
+
+/// Lists every `__name__` in the text, including inside alternations.
+pub fn referenced_names(text: &str) -> Vec<&str> {
+    let mut names = vec![];
+    let mut rest = text;
+    while let Some(c) = rest.chars().next() {
+        if let Some((name, after)) = wildcard_at(rest) {
+            names.push(name);
+            rest = after;
+        } else {
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    names
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, SeedableRng};
+
+    use super::*;
+
+    fn wildcards() -> Wildcards {
+        Wildcards::new(&crate::config::testconfig())
+    }
+
+    fn expand(text: &str, seed: u64) -> String {
+        wildcards()
+            .expand(text, &mut StdRng::seed_from_u64(seed))
+            .unwrap()
+    }
+
+    #[test]
+    fn test_alternation() {
+        let colors = ["a red cat", "a blue cat", "a green cat"];
+        let mut seen = std::collections::HashSet::new();
+        for seed in 0..100 {
+            let expanded = expand("a {red|blue|green} cat", seed);
+            assert!(colors.contains(&expanded.as_str()), "{expanded}");
+            seen.insert(expanded);
+        }
+        assert_eq!(seen.len(), 3);
+        // The same seed always gives the same answer.
+        assert_eq!(expand("{a|b|c|d|e|f}", 7), expand("{a|b|c|d|e|f}", 7));
+    }
+
+    #[test]
+    fn test_nesting_and_literals() {
+        for seed in 0..50 {
+            let expanded = expand("{big {red|blue}|small} cat", seed);
+            assert!(
+                ["big red cat", "big blue cat", "small cat"].contains(&expanded.as_str()),
+                "{expanded}"
+            );
+        }
+        // No top-level |, or no closing brace: leave it alone.
+        assert_eq!(
+            expand("{masterpiece}, {{cat}}", 0),
+            "{masterpiece}, {{cat}}"
+        );
+        assert_eq!(expand("{a|b", 0), "{a|b");
+        assert_eq!(expand("a || b }", 0), "a || b }");
+        // Empty options are allowed.
+        for seed in 0..20 {
+            assert!(["cat", "big cat"].contains(&expand("{|big }cat", seed).as_str()));
+        }
+    }
+
+    #[test]
+    fn test_wildcard_files() {
+        for seed in 0..50 {
+            let expanded = expand("a __animal__ in a hat", seed);
+            assert!(
+                ["a cat in a hat", "a dog in a hat", "a red fox in a hat"]
+                    .contains(&expanded.as_str()),
+                "{expanded}"
+            );
+            let expanded = expand("__nested/color__", seed);
+            assert!(["red", "blue"].contains(&expanded.as_str()), "{expanded}");
+        }
+        assert_eq!(
+            expand("__init__ and __ nothing__", 0),
+            "__init__ and __ nothing__"
+        );
+        assert_eq!(
+            wildcards().available(),
+            vec!["animal", "loop", "nested/color"]
+        );
+        assert_eq!(
+            wildcards()
+                .find_unknown("__animals__ __nested/color__")
+                .unwrap(),
+            Some(("animals".to_string(), "animal".to_string()))
+        );
+        assert_eq!(wildcards().find_unknown("__animal__").unwrap(), None);
+        // Checking and expanding agree on what's a wildcard.
+        assert_eq!(wildcards().find_unknown("__init__").unwrap(), None);
+        assert!(wildcards()
+            .expand("__animals__", &mut StdRng::seed_from_u64(0))
+            .is_err());
+    }
+
+    #[test]
+    fn test_recursion_limit() {
+        assert!(wildcards()
+            .expand("__loop__", &mut StdRng::seed_from_u64(0))
+            .is_err());
+    }
+
+    #[test]
+    fn test_no_wildcard_dir() {
+        let mut config = crate::config::testconfig();
+        config.wildcard_dir = None;
+        let wildcards = Wildcards::new(&config);
+        let expanded = wildcards
+            .expand("__animal__", &mut StdRng::seed_from_u64(0))
+            .unwrap();
+        assert_eq!(expanded, "__animal__");
+        assert_eq!(wildcards.find_unknown("__animal__").unwrap(), None);
+    }
+
+    #[test]
+    fn test_referenced_names() {
+        assert_eq!(
+            referenced_names("{__a__|b} __c/d__ __ e__"),
+            vec!["a", "c/d"]
+        );
+    }
+}
@@ -0,0 +1,614 @@
+// ComfyUI workflows, as graphs rather than text.
+//
+// A workflow is a JSON object of nodes by id. Each node has a class_type, an inputs object,
+// and usually a _meta.title. An input is either a value, or a link to another node's
+// output, written as [id, slot].
+//
+// Templates mark what the bot fills in with __PLACEHOLDER__s. Those can stand in for numbers,
+// so a template isn't valid JSON until it's filled in; on loading, bare placeholders are
+// quoted, and filling them in puts back a value of the right type. Placeholders can also
+// sit inside a longer string, e.g. "wrong, __NEGATIVE_PROMPT__". Any that are left over
+// once the workflow is filled in are an error, since they're almost certainly typos.
+
+use std::collections::HashMap;
+use std::ops::Range;
+
+use anyhow::{bail, Context, Result};
+use serde_json::{json, Map, Value};
+
+/// A link to a node's output.
+pub fn link(id: &str, slot: u32) -> Value {
+    json!([id, slot])
+}
+
+/// A float as JSON, written the way it reads in Rust rather than with f32 noise.
+pub fn float(value: f32) -> Value {
+    value
+        .to_string()
+        .parse::<f64>()
+        .map_or(json!(value), |v| json!(v))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Workflow(Map<String, Value>);
+
+impl Workflow {
+    /// Reads a template from disk.
+    pub fn load(path: &str) -> Result<Self> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("failed to read {path}"))?;
+        Self::parse(&text).with_context(|| format!("failed to parse {path}"))
+    }
+
+    pub fn parse(template: &str) -> Result<Self> {
+        let value: Value = serde_json::from_str(&quote_placeholders(template))?;
+        let Value::Object(nodes) = value else {
+            bail!("a workflow should be an object of nodes");
+        };
+        Ok(Self(nodes))
+    }
+
+    pub fn into_value(self) -> Value {
+        Value::Object(self.0)
+    }
+
+    /// All the placeholders left in the workflow, sorted.
+    pub fn placeholders(&self) -> Vec<String> {
+        let mut found = vec![];
+        visit_strings(&self.0, &mut |s| {
+            found.extend(placeholders_in(s).into_iter().map(str::to_string))
+        });
+        found.sort();
+        found.dedup();
+        found
+    }
+
+    /// Whether the workflow has the given placeholder.
+    pub fn uses(&self, placeholder: &str) -> bool {
+        self.placeholders().iter().any(|p| p == placeholder)
+    }
+
+    /// Fills in every placeholder. A string that's only a placeholder becomes the value;
+    /// one that merely contains a placeholder gets the value spliced in as text.
+    /// Placeholders without a value are an error.
+    pub fn fill(&mut self, values: &HashMap<&str, Value>) -> Result<()> {
+        let mut missing = vec![];
+        for node in self.0.values_mut() {
+            fill_value(node, values, &mut missing);
+        }
+        missing.sort();
+        missing.dedup();
+        if !missing.is_empty() {
+            bail!("unknown placeholders: {}", missing.join(", "));
+        }
+        Ok(())
+    }
+
+    /// Ids of the nodes matching the selector, which is an id, a title or a class_type.
+    /// An exact id wins; otherwise it's every node with that title or class_type.
+    pub fn find(&self, selector: &str) -> Vec<String> {
+        if self.0.contains_key(selector) {
+            return vec![selector.to_string()];
+        }
+        self.0
+            .iter()
+            .filter(|(_, node)| {
+                node["_meta"]["title"] == selector || node["class_type"] == selector
+            })
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// Nodes whose input has the given value, such as a loader for a particular file.
+    pub fn find_by_input(&self, input: &str, value: &Value) -> Vec<String> {
+        self.0
+            .iter()
+            .filter(|(_, node)| node["inputs"][input] == *value)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// What to call each node while it runs: its title, or failing that its class_type.
+    pub fn stage_names(&self) -> HashMap<String, String> {
+        self.0
+            .iter()
+            .map(|(id, node)| {
+                let name = node["_meta"]["title"]
+                    .as_str()
+                    .or(node["class_type"].as_str())
+                    .unwrap_or(id);
+                (id.clone(), name.to_string())
+            })
+            .collect()
+    }
+
+    /// The one node matching the selector; see find.
+    pub fn node_mut(&mut self, selector: &str) -> Result<&mut Value> {
+        let id = self.find_one(selector)?;
+        Ok(self.0.get_mut(&id).expect("found it a moment ago"))
+    }
+
+    fn find_one(&self, selector: &str) -> Result<String> {
+        match self.find(selector).as_slice() {
+            [id] => Ok(id.clone()),
+            [] => bail!("the workflow has no {selector} node"),
+            ids => bail!(
+                "the workflow has {} {selector} nodes: {}",
+                ids.len(),
+                ids.join(", ")
+            ),
+        }
+    }
+
+    /// Sets an input on the one node matching the selector.
+    pub fn set_input(
+        &mut self,
+        selector: &str,
+        input: &str,
+        value: impl Into<Value>,
+    ) -> Result<()> {
+        let node = self.node_mut(selector)?;
+        let inputs = node["inputs"]
+            .as_object_mut()
+            .with_context(|| format!("{selector} has no inputs"))?;
+        inputs.insert(input.to_string(), value.into());
+        Ok(())
+    }
+
+    /// Adds a node, returning its id. The id is made unique if it's taken.
+    pub fn add_node(&mut self, id: &str, class_type: &str, title: &str, inputs: Value) -> String {
+        let mut unique = id.to_string();
+        let mut n = 1;
+        while self.0.contains_key(&unique) {
+            n += 1;
+            unique = format!("{id}-{n}");
+        }
+        self.0.insert(
+            unique.clone(),
+            json!({
+                "inputs": inputs,
+                "class_type": class_type,
+                "_meta": { "title": title },
+            }),
+        );
+        unique
+    }
+
+    /// Points every input that uses the output `from` at `to` instead, except on the given
+    /// nodes. Returns how many inputs changed.
+    pub fn redirect(&mut self, from: &Value, to: &Value, except: &[String]) -> usize {
+        let mut changed = 0;
+        for (id, node) in self.0.iter_mut() {
+            if except.contains(id) {
+                continue;
+            }
+            let Some(inputs) = node["inputs"].as_object_mut() else {
+                continue;
+            };
+            for input in inputs.values_mut() {
+                if input == from {
+                    *input = to.clone();
+                    changed += 1;
+                }
+            }
+        }
+        changed
+    }
+
+    /// Splices a chain of nodes in after some outputs. The chain is built by `build`, which
+    /// gets the outputs to read from and returns the outputs that replace them; afterwards,
+    /// everything that used the old outputs uses the new ones.
+    ///
+    /// For example, a LoRA goes between a checkpoint's model and text encoder and everything
+    /// that used them.
+    pub fn splice(
+        &mut self,
+        outputs: &[Value],
+        build: impl FnOnce(&mut Self, &[Value]) -> Result<Vec<Value>>,
+    ) -> Result<()> {
+        let before = self.0.keys().cloned().collect::<Vec<_>>();
+        let replacements = build(self, outputs)?;
+        if replacements.len() != outputs.len() {
+            bail!(
+                "spliced in {} outputs in place of {}",
+                replacements.len(),
+                outputs.len()
+            );
+        }
+        let added = self
+            .0
+            .keys()
+            .filter(|id| !before.contains(id))
+            .cloned()
+            .collect::<Vec<_>>();
+        for (from, to) in outputs.iter().zip(&replacements) {
+            self.redirect(from, to, &added);
+        }
+        Ok(())
+    }
+
+    /// Links to nodes that aren't in the workflow.
+    pub fn check_links(&self) -> Vec<String> {
+        let mut problems = vec![];
+        for (id, node) in &self.0 {
+            for (input, value) in inputs(node) {
+                if let Some((target, _)) = as_link(value) {
+                    if !self.0.contains_key(target) {
+                        problems.push(format!(
+                            "node {id}'s {input} comes from node {target}, which doesn't exist"
+                        ));
+                    }
+                }
+            }
+        }
+        problems
+    }
+
+    /// Checks the nodes against ComfyUI's /object_info: that they exist, that their inputs
+    /// do, and that choices like checkpoint files are ones ComfyUI has. Values in `skip`
+    /// aren't checked against the choices; they're made up, and will be uploaded first.
+    pub fn check_nodes(&self, object_info: &Value, skip: &[&str]) -> Vec<String> {
+        let mut problems = vec![];
+        for (id, node) in &self.0 {
+            let class_type = node["class_type"].as_str().unwrap_or_default();
+            let info = &object_info[class_type];
+            if info.is_null() {
+                problems.push(format!(
+                    "node {id} is a {class_type}, which ComfyUI doesn't have"
+                ));
+                continue;
+            }
+            let spec = |input: &str| {
+                ["required", "optional", "hidden"]
+                    .iter()
+                    .map(|kind| &info["input"][kind][input])
+                    .find(|spec| !spec.is_null())
+            };
+            for (input, value) in inputs(node) {
+                let Some(spec) = spec(input) else {
+                    problems.push(format!("node {id}'s {class_type} has no input {input}"));
+                    continue;
+                };
+                if let Some((target, slot)) = as_link(value) {
+                    // Missing nodes are check_links' business.
+                    let outputs = self
+                        .0
+                        .get(target)
+                        .and_then(|t| t["class_type"].as_str())
+                        .and_then(|class_type| object_info[class_type]["output"].as_array())
+                        .map_or(0, |o| o.len());
+                    if outputs > 0 && slot >= outputs as u64 {
+                        problems.push(format!(
+                            "node {id}'s {input} comes from output {slot} of node {target}, which only has {outputs}"
+                        ));
+                    }
+                    continue;
+                }
+                if let (Some(choices), Some(value)) = (choices(spec), value.as_str()) {
+                    if !skip.contains(&value) && !choices.iter().any(|c| c == value) {
+                        problems.push(format!(
+                            "node {id}'s {input} is {value}, which ComfyUI doesn't have"
+                        ));
+                    }
+                }
+            }
+            if let Some(required) = info["input"]["required"].as_object() {
+                for input in required.keys() {
+                    if node["inputs"].get(input).is_none() {
+                        problems.push(format!("node {id}'s {class_type} needs an input {input}"));
+                    }
+                }
+            }
+        }
+        problems
+    }
+}
+
+/// The values an input allows, given its spec from /object_info, if it's that kind of input.
+/// They're written either as [[choices...], {...}], or as ["COMBO", {"options": [...]}].
+pub fn choices(spec: &Value) -> Option<&Vec<Value>> {
+    spec[0]
+        .as_array()
+        .or_else(|| spec[1]["options"].as_array().filter(|_| spec[0] == "COMBO"))
+}
+
+fn inputs(node: &Value) -> impl Iterator<Item = (&String, &Value)> {
+    node["inputs"].as_object().into_iter().flatten()
+}
+
+/// The node and output slot, if the input is a link.
+fn as_link(value: &Value) -> Option<(&str, u64)> {
+    match value.as_array()?.as_slice() {
+        [Value::String(id), Value::Number(slot)] => Some((id, slot.as_u64()?)),
+        _ => None,
+    }
+}
+
+/// Quotes the placeholders that stand in for numbers, so the template parses as JSON.
+fn quote_placeholders(template: &str) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut rest = template;
+    while let Some(c) = rest.chars().next() {
+        if !in_string && rest.starts_with("__") {
+            let run = word_at(rest);
+            if is_placeholder(run) {
+                output.push('"');
+                output.push_str(run);
+                output.push('"');
+                rest = &rest[run.len()..];
+                continue;
+            }
+        }
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            _ => {}
+        }
+        output.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    output
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// The run of word characters at the start of the text.
+fn word_at(text: &str) -> &str {
+    let end = text.find(|c| !is_word_char(c)).unwrap_or(text.len());
+    &text[..end]
+}
+
+fn is_placeholder(word: &str) -> bool {
+    word.len() > 4
+        && word.chars().all(is_word_char)
+        && word.starts_with("__")
+        && word.ends_with("__")
+        && word[2..word.len() - 2]
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphanumeric())
+}
+
+/// The placeholders in a string; each must be a whole word.
+fn placeholders_in(text: &str) -> Vec<&str> {
+    placeholder_spans(text)
+        .into_iter()
+        .map(|s| &text[s])
+        .collect()
+}
+
+/// Where the placeholders in the text are, as byte ranges.
+fn placeholder_spans(text: &str) -> Vec<Range<usize>> {
+    let mut spans = vec![];
+    let mut start = 0;
+    for (i, c) in text.char_indices().chain([(text.len(), ' ')]) {
+        if !is_word_char(c) {
+            if is_placeholder(&text[start..i]) {
+                spans.push(start..i);
+            }
+            start = i + c.len_utf8();
+        }
+    }
+    spans
+}
+
+fn visit_strings(nodes: &Map<String, Value>, f: &mut impl FnMut(&str)) {
+    fn visit(value: &Value, f: &mut impl FnMut(&str)) {
+        match value {
+            Value::String(s) => f(s),
+            Value::Array(values) => values.iter().for_each(|v| visit(v, f)),
+            Value::Object(map) => map.values().for_each(|v| visit(v, f)),
+            _ => {}
+        }
+    }
+    nodes.values().for_each(|v| visit(v, f));
+}
+
+fn fill_value(value: &mut Value, values: &HashMap<&str, Value>, missing: &mut Vec<String>) {
+    match value {
+        Value::String(s) if is_placeholder(s) => match values.get(s.as_str()) {
+            Some(v) => *value = v.clone(),
+            None => missing.push(s.clone()),
+        },
+        Value::String(s) => {
+            // One pass over the original, so nothing spliced in is looked at again.
+            let mut filled = String::with_capacity(s.len());
+            let mut end = 0;
+            for span in placeholder_spans(s) {
+                filled.push_str(&s[end..span.start]);
+                let placeholder = &s[span.clone()];
+                match values.get(placeholder) {
+                    Some(Value::String(v)) => filled.push_str(v),
+                    Some(v) => filled.push_str(&v.to_string()),
+                    None => {
+                        missing.push(placeholder.to_string());
+                        filled.push_str(placeholder);
+                    }
+                }
+                end = span.end;
+            }
+            filled.push_str(&s[end..]);
+            *s = filled;
+        }
+        Value::Array(items) => items
+            .iter_mut()
+            .for_each(|v| fill_value(v, values, missing)),
+        Value::Object(map) => map
+            .values_mut()
+            .for_each(|v| fill_value(v, values, missing)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEMPLATE: &str = r#"{
+        "loader": {
+            "inputs": {"ckpt_name": "__BASE_CHECKPOINT__"},
+            "class_type": "CheckpointLoaderSimple",
+            "_meta": {"title": "Load Checkpoint"}
+        },
+        "text": {
+            "inputs": {"text": "wrong, __NEGATIVE_PROMPT__", "clip": ["loader", 1]},
+            "class_type": "CLIPTextEncode"
+        },
+        "sampler": {
+            "inputs": {"seed": __SEED__, "cfg": __BASE_CFG__, "model": ["loader", 0]},
+            "class_type": "KSampler"
+        }
+    }"#;
+
+    fn values() -> HashMap<&'static str, Value> {
+        HashMap::from([
+            ("__BASE_CHECKPOINT__", json!("sd.safetensors")),
+            ("__NEGATIVE_PROMPT__", json!("a \"quoted\" __word__")),
+            ("__SEED__", json!(42)),
+            ("__BASE_CFG__", float(7.1)),
+        ])
+    }
+
+    #[test]
+    fn test_fill() {
+        let mut workflow = Workflow::parse(TEMPLATE).unwrap();
+        assert_eq!(
+            workflow.placeholders(),
+            vec![
+                "__BASE_CFG__",
+                "__BASE_CHECKPOINT__",
+                "__NEGATIVE_PROMPT__",
+                "__SEED__"
+            ]
+        );
+        workflow.fill(&values()).unwrap();
+        let value = workflow.into_value();
+        assert_eq!(value["loader"]["inputs"]["ckpt_name"], "sd.safetensors");
+        // Values are spliced in once; a placeholder-lookalike inside one is left alone.
+        assert_eq!(
+            value["text"]["inputs"]["text"],
+            "wrong, a \"quoted\" __word__"
+        );
+        assert_eq!(value["sampler"]["inputs"]["seed"], 42);
+        assert_eq!(value["sampler"]["inputs"]["cfg"], 7.1);
+    }
+
+    #[test]
+    fn test_fill_spliced_placeholder() {
+        // A prompt naming a placeholder that comes later in the same string keeps it as
+        // written, and the real one still gets its value.
+        let template = TEMPLATE.replace(
+            "wrong, __NEGATIVE_PROMPT__",
+            "__NEGATIVE_PROMPT__, seed __SEED__",
+        );
+        let mut workflow = Workflow::parse(&template).unwrap();
+        let mut values = values();
+        values.insert("__NEGATIVE_PROMPT__", json!("__SEED__ and __BASE_CFG__"));
+        workflow.fill(&values).unwrap();
+        assert_eq!(
+            workflow.into_value()["text"]["inputs"]["text"],
+            "__SEED__ and __BASE_CFG__, seed 42"
+        );
+    }
+
+    #[test]
+    fn test_unresolved_placeholders() {
+        let mut workflow = Workflow::parse(&TEMPLATE.replace("__SEED__", "__SEEED__")).unwrap();
+        let err = workflow.fill(&values()).unwrap_err().to_string();
+        assert_eq!(err, "unknown placeholders: __SEEED__");
+    }
+
+    #[test]
+    fn test_find() {
+        let workflow = Workflow::parse(TEMPLATE).unwrap();
+        assert_eq!(workflow.find("sampler"), vec!["sampler"]);
+        assert_eq!(workflow.find("Load Checkpoint"), vec!["loader"]);
+        assert_eq!(workflow.find("CLIPTextEncode"), vec!["text"]);
+        assert!(workflow.find("VAEDecode").is_empty());
+        let stages = workflow.stage_names();
+        assert_eq!(stages["loader"], "Load Checkpoint");
+        assert_eq!(stages["sampler"], "KSampler");
+        let mut workflow = workflow;
+        assert!(workflow.set_input("VAEDecode", "vae", 1).is_err());
+        assert_eq!(
+            workflow.find_by_input("ckpt_name", &json!("__BASE_CHECKPOINT__")),
+            vec!["loader"]
+        );
+    }
+
+    #[test]
+    fn test_splice() {
+        let mut workflow = Workflow::parse(TEMPLATE).unwrap();
+        workflow.set_input("KSampler", "steps", 20).unwrap();
+        workflow
+            .splice(&[link("loader", 0)], |w, outputs| {
+                let id = w.add_node(
+                    "sampler",
+                    "ModelSamplingDiscrete",
+                    "Sampling",
+                    json!({"model": outputs[0]}),
+                );
+                Ok(vec![link(&id, 0)])
+            })
+            .unwrap();
+        let value = workflow.into_value();
+        // The id was taken.
+        assert_eq!(value["sampler-2"]["inputs"]["model"], json!(["loader", 0]));
+        assert_eq!(value["sampler"]["inputs"]["model"], json!(["sampler-2", 0]));
+        assert_eq!(value["sampler"]["inputs"]["steps"], 20);
+        assert_eq!(value["text"]["inputs"]["clip"], json!(["loader", 1]));
+    }
+
+    #[test]
+    fn test_checks() {
+        let mut workflow = Workflow::parse(TEMPLATE).unwrap();
+        workflow.fill(&values()).unwrap();
+        assert!(workflow.check_links().is_empty());
+        workflow
+            .set_input("sampler", "positive", link("nope", 0))
+            .unwrap();
+        assert_eq!(
+            workflow.check_links(),
+            vec!["node sampler's positive comes from node nope, which doesn't exist"]
+        );
+
+        let object_info = json!({
+            "CheckpointLoaderSimple": {
+                "input": {"required": {"ckpt_name": [["other.safetensors"], {}]}},
+                "output": ["MODEL", "CLIP", "VAE"],
+            },
+            "KSampler": {
+                "input": {"required": {
+                    "seed": ["INT", {}], "cfg": ["FLOAT", {}], "model": ["MODEL"],
+                    "steps": ["INT", {}],
+                }},
+                "output": ["LATENT"],
+            },
+        });
+        let mut workflow = Workflow::parse(TEMPLATE).unwrap();
+        workflow.fill(&values()).unwrap();
+        workflow
+            .set_input("sampler", "model", link("loader", 3))
+            .unwrap();
+        assert_eq!(
+            workflow.check_nodes(&object_info, &[]),
+            vec![
+                "node loader's ckpt_name is sd.safetensors, which ComfyUI doesn't have",
+                "node sampler's model comes from output 3 of node loader, which only has 3",
+                "node sampler's KSampler needs an input steps",
+                "node text is a CLIPTextEncode, which ComfyUI doesn't have",
+            ]
+        );
+        assert_eq!(
+            workflow
+                .check_nodes(&object_info, &["sd.safetensors"])
+                .len(),
+            3
+        );
+    }
+}